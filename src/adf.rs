@@ -0,0 +1,14 @@
+//! Amiga ADF floppy disk images, an OFS or FFS filesystem in 512 byte blocks after a two block boot block. Workbench
+//! disks used with emulators and Gotek drives keep the Bridgeboard files in PC/System.
+
+use crate::amiga_filesystem::{AmigaFilesystemError, AmigaVolume};
+
+pub const ADF_BLOCK_SIZE: usize = 512;
+pub const ADF_RESERVED_BLOCKS: u32 = 2;
+
+/// The part of a path which separates the image from the path of the file inside it, e.g. workbench.adf:PC/System/pc.boot
+pub const ADF_PATH_SEPARATOR: &str = ".adf:";
+
+pub fn open_volume(image_bytes: &mut [u8]) -> Result<AmigaVolume<'_>, AmigaFilesystemError> {
+    AmigaVolume::open(image_bytes, ADF_BLOCK_SIZE, ADF_RESERVED_BLOCKS)
+}
@@ -0,0 +1,665 @@
+//! Reading and rewriting files in Amiga OFS and FFS filesystems, as found on ADF floppy images and hard disk partitions.
+//!
+//! The volume works on the bytes of the whole filesystem in memory, nothing is written anywhere until the caller saves
+//! the bytes, so a failure part way through a change never leaves a half written image behind.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const T_HEADER: u32 = 2;
+pub const T_DATA: u32 = 8;
+pub const T_LIST: u32 = 16;
+
+pub const ST_ROOT: u32 = 1;
+pub const ST_USERDIR: u32 = 2;
+pub const ST_FILE: u32 = -3i32 as u32;
+
+const DOS_TYPE_PREFIX: [u8; 3] = *b"DOS";
+const DOS_TYPE_FLAG_FFS: u8 = 1 << 0;
+const DOS_TYPE_FLAG_INTERNATIONAL: u8 = 1 << 1;
+const DOS_TYPE_FLAG_DIRCACHE: u8 = 1 << 2;
+/// DOS\6 and DOS\7 use long file names which change the block layout
+const DOS_TYPE_MAX_SUPPORTED: u8 = 5;
+
+/// OFS data blocks start with a 24 byte header
+const OFS_DATA_HEADER_SIZE: usize = 24;
+const HEADER_BLOCK_CHECKSUM_LONG: usize = 5;
+const HEADER_BLOCK_TABLE_START_LONG: usize = 6;
+/// Everything in a header block that isn't the hash or data block table
+const HEADER_BLOCK_NON_TABLE_LONGS: usize = 56;
+const MAX_NAME_LENGTH: usize = 30;
+const BITMAP_VALID: u32 = 0xFFFFFFFF;
+const ROOT_BITMAP_PAGES: usize = 25;
+
+/// Amiga dates count from 1st January 1978
+const AMIGA_EPOCH_UNIX_SECONDS: u64 = 252_460_800;
+const TICKS_PER_SECOND: u64 = 50;
+
+#[derive(Debug, PartialEq)]
+pub enum AmigaFilesystemError {
+    NotADosDisk,
+    UnsupportedDosType(u32),
+    BlockOutOfRange(u32),
+    BadBlockChecksum(u32),
+    UnexpectedBlockType(u32),
+    NotFound(String),
+    NotAFile(String),
+    NotADirectory(String),
+    AlreadyExists(String),
+    InvalidName(String),
+    BitmapInvalid,
+    DiskFull,
+}
+
+impl fmt::Display for AmigaFilesystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmigaFilesystemError::NotADosDisk => write!(f, "Not an AmigaDOS (OFS or FFS) filesystem"),
+            AmigaFilesystemError::UnsupportedDosType(dos_type) => write!(f, "The filesystem type DOS\\{} isn't supported for this", dos_type & 0xFF),
+            AmigaFilesystemError::BlockOutOfRange(block) => write!(f, "Block {} is outside the filesystem", block),
+            AmigaFilesystemError::BadBlockChecksum(block) => write!(f, "Block {} has a bad checksum", block),
+            AmigaFilesystemError::UnexpectedBlockType(block) => write!(f, "Block {} isn't the type of block expected", block),
+            AmigaFilesystemError::NotFound(path) => write!(f, "{} was not found in the filesystem", path),
+            AmigaFilesystemError::NotAFile(path) => write!(f, "{} is not a file", path),
+            AmigaFilesystemError::NotADirectory(path) => write!(f, "{} is not a directory", path),
+            AmigaFilesystemError::AlreadyExists(path) => write!(f, "{} already exists in the filesystem", path),
+            AmigaFilesystemError::InvalidName(name) => write!(f, "{} isn't a valid AmigaDOS file name", name),
+            AmigaFilesystemError::BitmapInvalid => write!(f, "The filesystem bitmap is marked invalid, validate the disk on an Amiga first"),
+            AmigaFilesystemError::DiskFull => write!(f, "There isn't enough free space in the filesystem"),
+        }
+    }
+}
+
+impl std::error::Error for AmigaFilesystemError {}
+
+pub struct AmigaVolume<'a> {
+    bytes: &'a mut [u8],
+    block_size: usize,
+    reserved_blocks: u32,
+    num_blocks: u32,
+    root_block: u32,
+    dos_type: u32,
+}
+
+impl<'a> AmigaVolume<'a> {
+    /// Open the filesystem in the bytes, the first reserved blocks are the boot block holding the DOS type
+    pub fn open(bytes: &'a mut [u8], block_size: usize, reserved_blocks: u32) -> Result<AmigaVolume<'a>, AmigaFilesystemError> {
+        if bytes.len() < block_size * (reserved_blocks as usize + 1) || ! bytes.starts_with(&DOS_TYPE_PREFIX) {
+            return Err(AmigaFilesystemError::NotADosDisk);
+        }
+
+        let dos_type = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if bytes[3] > DOS_TYPE_MAX_SUPPORTED {
+            return Err(AmigaFilesystemError::UnsupportedDosType(dos_type));
+        }
+
+        let num_blocks = (bytes.len() / block_size) as u32;
+        let volume = AmigaVolume {
+            bytes,
+            block_size,
+            reserved_blocks,
+            num_blocks,
+            root_block: (num_blocks - 1 + reserved_blocks) / 2,
+            dos_type,
+        };
+
+        volume.check_header_block(volume.root_block, T_HEADER, ST_ROOT)?;
+        Ok(volume)
+    }
+
+    pub fn is_ffs(&self) -> bool {
+        (self.dos_type as u8) & DOS_TYPE_FLAG_FFS != 0
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, AmigaFilesystemError> {
+        let header_block = self.find_file(path)?;
+        let byte_size = self.long(header_block, self.longs_per_block() - 47) as usize;
+
+        let mut contents: Vec<u8> = Vec::with_capacity(byte_size);
+        for data_block in self.file_data_blocks(header_block)? {
+            let remaining = byte_size - contents.len();
+            let data = if self.is_ffs() {
+                self.block(data_block)?
+            } else {
+                self.check_header_block(data_block, T_DATA, 0)?;
+                let data_size = self.long(data_block, 3) as usize;
+                &self.block(data_block)?[OFS_DATA_HEADER_SIZE..OFS_DATA_HEADER_SIZE + data_size.min(self.block_size - OFS_DATA_HEADER_SIZE)]
+            };
+            contents.extend_from_slice(&data[..data.len().min(remaining)]);
+        }
+
+        Ok(contents)
+    }
+
+    pub fn file_exists(&self, path: &str) -> bool {
+        self.find_file(path).is_ok()
+    }
+
+    /// Replace the contents of an existing file, freeing its old blocks and allocating new ones
+    pub fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), AmigaFilesystemError> {
+        self.check_writable()?;
+
+        let header_block = self.find_file(path)?;
+        let bitmap_blocks = self.bitmap_blocks()?;
+
+        let mut old_blocks = self.file_data_blocks(header_block)?;
+        old_blocks.extend(self.file_extension_blocks(header_block)?);
+        for block in old_blocks {
+            self.set_block_free(&bitmap_blocks, block, true)?;
+        }
+
+        self.write_file_blocks(&bitmap_blocks, header_block, contents)?;
+        self.update_bitmap_checksums(&bitmap_blocks)?;
+        Ok(())
+    }
+
+    /// Create a new file in an existing directory
+    pub fn create_file(&mut self, path: &str, contents: &[u8]) -> Result<(), AmigaFilesystemError> {
+        self.check_writable()?;
+
+        let trimmed_path = path.trim_matches('/');
+        let (directory_path, name) = trimmed_path.rsplit_once('/').unwrap_or(("", trimmed_path));
+        let is_invalid_name = name.is_empty() || name.chars().count() > MAX_NAME_LENGTH || name.chars().any(|c| c == ':' || c as u32 > 0xFF);
+        if is_invalid_name {
+            return Err(AmigaFilesystemError::InvalidName(name.into()));
+        }
+
+        if self.find(path).is_ok() {
+            return Err(AmigaFilesystemError::AlreadyExists(path.into()));
+        }
+
+        let directory_block = self.find(directory_path)?;
+        let directory_type = self.long(directory_block, self.longs_per_block() - 1);
+        if directory_type != ST_ROOT && directory_type != ST_USERDIR {
+            return Err(AmigaFilesystemError::NotADirectory(directory_path.into()));
+        }
+
+        let bitmap_blocks = self.bitmap_blocks()?;
+        let header_block = self.allocate_block(&bitmap_blocks)?;
+        self.clear_block(header_block);
+        self.set_long(header_block, 0, T_HEADER);
+        self.set_long(header_block, 1, header_block);
+        self.set_long(header_block, self.longs_per_block() - 3, directory_block);
+        self.set_long(header_block, self.longs_per_block() - 1, ST_FILE);
+
+        let name_offset = self.block_size - 80;
+        self.block_mut(header_block)[name_offset] = name.chars().count() as u8;
+        for (index, character) in name.chars().enumerate() {
+            self.block_mut(header_block)[name_offset + 1 + index] = character as u32 as u8;
+        }
+
+        self.link_into_directory(directory_block, header_block, name);
+        self.write_file_blocks(&bitmap_blocks, header_block, contents)?;
+        self.set_date(directory_block, self.longs_per_block() - 23);
+        self.update_header_checksum(directory_block);
+        self.update_bitmap_checksums(&bitmap_blocks)?;
+        Ok(())
+    }
+
+    /// Add a header block to its directory's hash chain, which AmigaDOS keeps sorted by block number
+    fn link_into_directory(&mut self, directory_block: u32, header_block: u32, name: &str) {
+        let hash_chain_long = self.longs_per_block() - 4;
+        let slot = HEADER_BLOCK_TABLE_START_LONG + self.name_hash(name);
+
+        let mut previous: Option<u32> = None;
+        let mut next = self.long(directory_block, slot);
+        while next != 0 && next < header_block {
+            previous = Some(next);
+            next = self.long(next, hash_chain_long);
+        }
+
+        self.set_long(header_block, hash_chain_long, next);
+        match previous {
+            Some(previous) => {
+                self.set_long(previous, hash_chain_long, header_block);
+                self.update_header_checksum(previous);
+            },
+            None => self.set_long(directory_block, slot, header_block),
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), AmigaFilesystemError> {
+        if (self.dos_type as u8) & DOS_TYPE_FLAG_DIRCACHE != 0 {
+            return Err(AmigaFilesystemError::UnsupportedDosType(self.dos_type));
+        }
+
+        if self.long(self.root_block, self.longs_per_block() - 50) != BITMAP_VALID {
+            return Err(AmigaFilesystemError::BitmapInvalid);
+        }
+
+        Ok(())
+    }
+
+    /// Write the contents into newly allocated data (and extension) blocks belonging to the file header
+    fn write_file_blocks(&mut self, bitmap_blocks: &[u32], header_block: u32, contents: &[u8]) -> Result<(), AmigaFilesystemError> {
+        let table_size = self.hash_table_size();
+        let data_per_block = if self.is_ffs() { self.block_size } else { self.block_size - OFS_DATA_HEADER_SIZE };
+        let chunks: Vec<&[u8]> = contents.chunks(data_per_block).collect();
+        let extension_count = chunks.len().saturating_sub(1) / table_size;
+
+        let mut data_blocks: Vec<u32> = Vec::with_capacity(chunks.len());
+        for _ in 0..chunks.len() {
+            data_blocks.push(self.allocate_block(bitmap_blocks)?);
+        }
+        let mut extension_blocks: Vec<u32> = Vec::with_capacity(extension_count);
+        for _ in 0..extension_count {
+            extension_blocks.push(self.allocate_block(bitmap_blocks)?);
+        }
+
+        for (index, chunk) in chunks.iter().enumerate() {
+            let data_block = data_blocks[index];
+            self.clear_block(data_block);
+
+            if self.is_ffs() {
+                self.block_mut(data_block)[..chunk.len()].copy_from_slice(chunk);
+            } else {
+                self.set_long(data_block, 0, T_DATA);
+                self.set_long(data_block, 1, header_block);
+                self.set_long(data_block, 2, index as u32 + 1);
+                self.set_long(data_block, 3, chunk.len() as u32);
+                self.set_long(data_block, 4, data_blocks.get(index + 1).copied().unwrap_or(0));
+                self.block_mut(data_block)[OFS_DATA_HEADER_SIZE..OFS_DATA_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+                self.update_header_checksum(data_block);
+            }
+        }
+
+        // The header and then each extension block hold the next table full of data block pointers, in reverse order
+        let table_holders: Vec<u32> = std::iter::once(header_block).chain(extension_blocks.iter().copied()).collect();
+        for (holder_index, holder) in table_holders.iter().enumerate() {
+            let table_blocks: Vec<u32> = data_blocks.iter().skip(holder_index * table_size).take(table_size).copied().collect();
+
+            if holder_index > 0 {
+                self.clear_block(*holder);
+                self.set_long(*holder, 0, T_LIST);
+                self.set_long(*holder, 1, *holder);
+                self.set_long(*holder, self.longs_per_block() - 3, header_block);
+                self.set_long(*holder, self.longs_per_block() - 1, ST_FILE);
+            }
+
+            for slot in 0..table_size {
+                self.set_long(*holder, HEADER_BLOCK_TABLE_START_LONG + slot, 0);
+            }
+            for (position, data_block) in table_blocks.iter().enumerate() {
+                self.set_long(*holder, HEADER_BLOCK_TABLE_START_LONG + table_size - 1 - position, *data_block);
+            }
+
+            self.set_long(*holder, 2, table_blocks.len() as u32);
+            self.set_long(*holder, self.longs_per_block() - 2, table_holders.get(holder_index + 1).copied().unwrap_or(0));
+        }
+
+        self.set_long(header_block, 4, data_blocks.first().copied().unwrap_or(0));
+        self.set_long(header_block, self.longs_per_block() - 47, contents.len() as u32);
+        self.set_date(header_block, self.longs_per_block() - 23);
+
+        for holder in table_holders {
+            self.update_header_checksum(holder);
+        }
+
+        Ok(())
+    }
+
+    /// Find a file by its path from the root of the volume, e.g. "PC/System/pc.boot"
+    fn find_file(&self, path: &str) -> Result<u32, AmigaFilesystemError> {
+        let block = self.find(path)?;
+
+        if self.long(block, self.longs_per_block() - 1) != ST_FILE {
+            return Err(AmigaFilesystemError::NotAFile(path.into()));
+        }
+
+        Ok(block)
+    }
+
+    fn find(&self, path: &str) -> Result<u32, AmigaFilesystemError> {
+        let mut block = self.root_block;
+
+        for (depth, name) in path.split('/').filter(|name| ! name.is_empty()).enumerate() {
+            let secondary_type = self.long(block, self.longs_per_block() - 1);
+            if secondary_type != ST_ROOT && secondary_type != ST_USERDIR {
+                let parent_path: Vec<&str> = path.split('/').filter(|name| ! name.is_empty()).take(depth).collect();
+                return Err(AmigaFilesystemError::NotADirectory(parent_path.join("/")));
+            }
+
+            block = self.find_in_directory(block, name)?.ok_or_else(|| AmigaFilesystemError::NotFound(path.into()))?;
+        }
+
+        Ok(block)
+    }
+
+    fn find_in_directory(&self, directory_block: u32, name: &str) -> Result<Option<u32>, AmigaFilesystemError> {
+        let hash = self.name_hash(name);
+        let mut block = self.long(directory_block, HEADER_BLOCK_TABLE_START_LONG + hash);
+
+        while block != 0 {
+            self.check_header_block(block, T_HEADER, 0)?;
+
+            if self.names_match(&self.block_name(block)?, name) {
+                return Ok(Some(block));
+            }
+
+            block = self.long(block, self.longs_per_block() - 4);
+        }
+
+        Ok(None)
+    }
+
+    /// The data blocks of a file in order, from the header block and its extension blocks
+    fn file_data_blocks(&self, header_block: u32) -> Result<Vec<u32>, AmigaFilesystemError> {
+        let table_size = self.hash_table_size();
+        let mut data_blocks: Vec<u32> = Vec::new();
+
+        for holder in std::iter::once(header_block).chain(self.file_extension_blocks(header_block)?) {
+            let count = (self.long(holder, 2) as usize).min(table_size);
+            for position in 0..count {
+                let data_block = self.long(holder, HEADER_BLOCK_TABLE_START_LONG + table_size - 1 - position);
+                self.check_block_in_range(data_block)?;
+                data_blocks.push(data_block);
+            }
+        }
+
+        Ok(data_blocks)
+    }
+
+    fn file_extension_blocks(&self, header_block: u32) -> Result<Vec<u32>, AmigaFilesystemError> {
+        let mut extension_blocks: Vec<u32> = Vec::new();
+        let mut block = self.long(header_block, self.longs_per_block() - 2);
+
+        while block != 0 {
+            self.check_header_block(block, T_LIST, ST_FILE)?;
+            if extension_blocks.contains(&block) {
+                return Err(AmigaFilesystemError::UnexpectedBlockType(block));
+            }
+            extension_blocks.push(block);
+            block = self.long(block, self.longs_per_block() - 2);
+        }
+
+        Ok(extension_blocks)
+    }
+
+    /// The bitmap block numbers, from the root block and then any bitmap extension blocks
+    fn bitmap_blocks(&self) -> Result<Vec<u32>, AmigaFilesystemError> {
+        let needed = (self.num_blocks - self.reserved_blocks) as usize;
+        let bits_per_bitmap_block = (self.longs_per_block() - 1) * 32;
+        let count = needed.div_ceil(bits_per_bitmap_block);
+
+        let root_pages_start = self.longs_per_block() - 49;
+        let mut bitmap_blocks: Vec<u32> = (0..ROOT_BITMAP_PAGES.min(count))
+            .map(|page| self.long(self.root_block, root_pages_start + page))
+            .collect();
+
+        let mut extension_block = self.long(self.root_block, self.longs_per_block() - 24);
+        while bitmap_blocks.len() < count && extension_block != 0 {
+            self.check_block_in_range(extension_block)?;
+            for page in 0..self.longs_per_block() - 1 {
+                if bitmap_blocks.len() < count {
+                    bitmap_blocks.push(self.long(extension_block, page));
+                }
+            }
+            extension_block = self.long(extension_block, self.longs_per_block() - 1);
+        }
+
+        if bitmap_blocks.len() < count || bitmap_blocks.contains(&0) {
+            return Err(AmigaFilesystemError::BitmapInvalid);
+        }
+        for block in &bitmap_blocks {
+            self.check_block_in_range(*block)?;
+        }
+
+        Ok(bitmap_blocks)
+    }
+
+    fn bitmap_position(&self, bitmap_blocks: &[u32], block: u32) -> Result<(u32, usize, u32), AmigaFilesystemError> {
+        if block < self.reserved_blocks || block >= self.num_blocks {
+            return Err(AmigaFilesystemError::BlockOutOfRange(block));
+        }
+
+        let index = (block - self.reserved_blocks) as usize;
+        let bits_per_bitmap_block = (self.longs_per_block() - 1) * 32;
+        let bitmap_block = bitmap_blocks[index / bits_per_bitmap_block];
+        let bit_index = index % bits_per_bitmap_block;
+
+        Ok((bitmap_block, 1 + bit_index / 32, 1 << (bit_index % 32)))
+    }
+
+    /// A set bit in the bitmap means the block is free
+    fn is_block_free(&self, bitmap_blocks: &[u32], block: u32) -> Result<bool, AmigaFilesystemError> {
+        let (bitmap_block, long, mask) = self.bitmap_position(bitmap_blocks, block)?;
+        Ok(self.long(bitmap_block, long) & mask != 0)
+    }
+
+    fn set_block_free(&mut self, bitmap_blocks: &[u32], block: u32, free: bool) -> Result<(), AmigaFilesystemError> {
+        let (bitmap_block, long, mask) = self.bitmap_position(bitmap_blocks, block)?;
+        let value = self.long(bitmap_block, long);
+        self.set_long(bitmap_block, long, if free { value | mask } else { value & ! mask });
+        Ok(())
+    }
+
+    /// Allocate the first free block after the root block, wrapping around to the start like AmigaDOS does
+    fn allocate_block(&mut self, bitmap_blocks: &[u32]) -> Result<u32, AmigaFilesystemError> {
+        let candidates = (self.root_block..self.num_blocks).chain(self.reserved_blocks..self.root_block);
+
+        for block in candidates {
+            if self.is_block_free(bitmap_blocks, block)? {
+                self.set_block_free(bitmap_blocks, block, false)?;
+                return Ok(block);
+            }
+        }
+
+        Err(AmigaFilesystemError::DiskFull)
+    }
+
+    fn update_bitmap_checksums(&mut self, bitmap_blocks: &[u32]) -> Result<(), AmigaFilesystemError> {
+        for bitmap_block in bitmap_blocks {
+            self.set_long(*bitmap_block, 0, 0);
+            let sum = self.block_sum(*bitmap_block);
+            self.set_long(*bitmap_block, 0, sum.wrapping_neg());
+        }
+        Ok(())
+    }
+
+    fn update_header_checksum(&mut self, block: u32) {
+        self.set_long(block, HEADER_BLOCK_CHECKSUM_LONG, 0);
+        let sum = self.block_sum(block);
+        self.set_long(block, HEADER_BLOCK_CHECKSUM_LONG, sum.wrapping_neg());
+    }
+
+    /// Check a header style block has the expected type (and secondary type unless 0) and a valid checksum
+    fn check_header_block(&self, block: u32, block_type: u32, secondary_type: u32) -> Result<(), AmigaFilesystemError> {
+        self.check_block_in_range(block)?;
+
+        if self.block_sum(block) != 0 {
+            return Err(AmigaFilesystemError::BadBlockChecksum(block));
+        }
+
+        if self.long(block, 0) != block_type || (secondary_type != 0 && self.long(block, self.longs_per_block() - 1) != secondary_type) {
+            return Err(AmigaFilesystemError::UnexpectedBlockType(block));
+        }
+
+        Ok(())
+    }
+
+    fn check_block_in_range(&self, block: u32) -> Result<(), AmigaFilesystemError> {
+        if block < self.reserved_blocks || block >= self.num_blocks {
+            return Err(AmigaFilesystemError::BlockOutOfRange(block));
+        }
+        Ok(())
+    }
+
+    fn block_name(&self, block: u32) -> Result<String, AmigaFilesystemError> {
+        let name_offset = self.block_size - 80;
+        let bytes = self.block(block)?;
+        let length = (bytes[name_offset] as usize).min(MAX_NAME_LENGTH);
+
+        Ok(bytes[name_offset + 1..name_offset + 1 + length].iter().map(|byte| *byte as char).collect())
+    }
+
+    fn is_international(&self) -> bool {
+        (self.dos_type as u8) & (DOS_TYPE_FLAG_INTERNATIONAL | DOS_TYPE_FLAG_DIRCACHE) != 0
+    }
+
+    fn to_upper(&self, character: char) -> u8 {
+        let byte = character as u32 as u8;
+        let is_international_lower = self.is_international() && (224..=254).contains(&byte) && byte != 247;
+
+        if byte.is_ascii_lowercase() || is_international_lower {
+            byte - 32
+        } else {
+            byte
+        }
+    }
+
+    fn names_match(&self, a: &str, b: &str) -> bool {
+        a.chars().count() == b.chars().count() && a.chars().zip(b.chars()).all(|(a, b)| self.to_upper(a) == self.to_upper(b))
+    }
+
+    fn name_hash(&self, name: &str) -> usize {
+        let hash = name.chars().fold(name.chars().count() as u32, |hash, character| {
+            (hash.wrapping_mul(13) + self.to_upper(character) as u32) & 0x7FF
+        });
+
+        hash as usize % self.hash_table_size()
+    }
+
+    fn set_date(&mut self, block: u32, first_long: usize) {
+        let unix_seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        let seconds = unix_seconds.saturating_sub(AMIGA_EPOCH_UNIX_SECONDS);
+
+        self.set_long(block, first_long, (seconds / 86400) as u32);
+        self.set_long(block, first_long + 1, ((seconds % 86400) / 60) as u32);
+        self.set_long(block, first_long + 2, ((seconds % 60) * TICKS_PER_SECOND) as u32);
+    }
+
+    fn hash_table_size(&self) -> usize {
+        self.longs_per_block() - HEADER_BLOCK_NON_TABLE_LONGS
+    }
+
+    fn longs_per_block(&self) -> usize {
+        self.block_size / 4
+    }
+
+    fn block(&self, block: u32) -> Result<&[u8], AmigaFilesystemError> {
+        self.check_block_in_range(block)?;
+        let start = block as usize * self.block_size;
+        Ok(&self.bytes[start..start + self.block_size])
+    }
+
+    fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        let start = block as usize * self.block_size;
+        &mut self.bytes[start..start + self.block_size]
+    }
+
+    fn clear_block(&mut self, block: u32) {
+        self.block_mut(block).fill(0);
+    }
+
+    fn block_sum(&self, block: u32) -> u32 {
+        (0..self.longs_per_block()).fold(0u32, |sum, long| sum.wrapping_add(self.long(block, long)))
+    }
+
+    /// Read a big endian long from a block, blocks out of range read as 0 (callers check the range first)
+    fn long(&self, block: u32, long: usize) -> u32 {
+        let offset = block as usize * self.block_size + long * 4;
+        match self.bytes.get(offset..offset + 4) {
+            Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => 0,
+        }
+    }
+
+    fn set_long(&mut self, block: u32, long: usize, value: u32) {
+        let offset = block as usize * self.block_size + long * 4;
+        self.bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_amiga_volume, ADF_DD_BLOCKS};
+
+    fn pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_open_not_a_dos_disk() {
+        let mut bytes = vec![0u8; 512 * 10];
+        assert_eq!(AmigaVolume::open(&mut bytes, 512, 2).err(), Some(AmigaFilesystemError::NotADosDisk));
+    }
+
+    #[test]
+    fn test_read_file_ofs_and_ffs() {
+        for dos_type in [0, 1] {
+            let contents = pattern(12288);
+            let mut bytes = build_amiga_volume(ADF_DD_BLOCKS, 512, dos_type, &[("PC/System/pc.boot", &contents), ("S/Startup-Sequence", b"LoadWB")]);
+            let volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+
+            assert_eq!(volume.read_file("PC/System/pc.boot"), Ok(contents.clone()));
+            assert_eq!(volume.read_file("pc/system/PC.BOOT"), Ok(contents));
+            assert_eq!(volume.read_file("S/Startup-Sequence"), Ok(b"LoadWB".to_vec()));
+            assert_eq!(volume.read_file("PC/System/aboot.ctrl"), Err(AmigaFilesystemError::NotFound("PC/System/aboot.ctrl".into())));
+            assert_eq!(volume.read_file("PC/System"), Err(AmigaFilesystemError::NotAFile("PC/System".into())));
+        }
+    }
+
+    #[test]
+    fn test_write_file_ofs_and_ffs() {
+        for dos_type in [0, 1] {
+            // Big enough to need extension blocks for the data block table
+            let original = pattern(12288);
+            let replacement: Vec<u8> = pattern(60000).iter().map(|byte| byte ^ 0x55).collect();
+            let mut bytes = build_amiga_volume(ADF_DD_BLOCKS, 512, dos_type, &[("PC/System/pc.boot", &original), ("S/Startup-Sequence", b"LoadWB")]);
+
+            let mut volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+            let bitmap_blocks = volume.bitmap_blocks().unwrap();
+            let free_before = (2..ADF_DD_BLOCKS).filter(|block| volume.is_block_free(&bitmap_blocks, *block).unwrap()).count();
+
+            volume.write_file("PC/System/pc.boot", &replacement).unwrap();
+
+            let volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+            let free_after = (2..ADF_DD_BLOCKS).filter(|block| volume.is_block_free(&bitmap_blocks, *block).unwrap()).count();
+            let data_per_block = if dos_type == 1 { 512 } else { 488 };
+            let blocks_for = |length: usize| length.div_ceil(data_per_block) + (length.div_ceil(data_per_block) - 1) / 72;
+
+            assert_eq!(volume.read_file("PC/System/pc.boot"), Ok(replacement));
+            assert_eq!(volume.read_file("S/Startup-Sequence"), Ok(b"LoadWB".to_vec()));
+            assert_eq!(free_before - free_after, blocks_for(60000) - blocks_for(12288));
+            for bitmap_block in &bitmap_blocks {
+                assert_eq!(volume.block_sum(*bitmap_block), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_create_file() {
+        let mut bytes = build_amiga_volume(ADF_DD_BLOCKS, 512, 1, &[("PC/System/pc.boot", b"pc.boot"), ("PC/System/aboot.ctrl", b"aboot")]);
+        let mut volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+
+        volume.create_file("PC/System/pc.boot.original", b"original").unwrap();
+        assert_eq!(volume.create_file("PC/System/pc.boot.original", b"again"), Err(AmigaFilesystemError::AlreadyExists("PC/System/pc.boot.original".into())));
+        assert_eq!(volume.create_file("PC/Missing/pc.boot", b""), Err(AmigaFilesystemError::NotFound("PC/Missing".into())));
+        assert_eq!(volume.create_file("PC/System/pc.boot/x", b""), Err(AmigaFilesystemError::NotADirectory("PC/System/pc.boot".into())));
+        assert_eq!(volume.create_file("PC/System/a:b", b""), Err(AmigaFilesystemError::InvalidName("a:b".into())));
+
+        let volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+        assert_eq!(volume.read_file("PC/System/pc.boot.original"), Ok(b"original".to_vec()));
+        assert_eq!(volume.read_file("PC/System/pc.boot"), Ok(b"pc.boot".to_vec()));
+        assert_eq!(volume.read_file("PC/System/aboot.ctrl"), Ok(b"aboot".to_vec()));
+        assert_eq!(volume.check_header_block(volume.find("PC/System").unwrap(), T_HEADER, ST_USERDIR), Ok(()));
+    }
+
+    #[test]
+    fn test_write_file_disk_full() {
+        let mut bytes = build_amiga_volume(40, 512, 1, &[("pc.boot", b"small")]);
+        let mut volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+
+        assert_eq!(volume.write_file("pc.boot", &pattern(512 * 40)), Err(AmigaFilesystemError::DiskFull));
+    }
+
+    #[test]
+    fn test_write_file_refuses_dircache() {
+        let mut bytes = build_amiga_volume(ADF_DD_BLOCKS, 512, 5, &[("pc.boot", b"small")]);
+        let mut volume = AmigaVolume::open(&mut bytes, 512, 2).unwrap();
+
+        assert_eq!(volume.read_file("pc.boot"), Ok(b"small".to_vec()));
+        assert_eq!(volume.write_file("pc.boot", b"bigger"), Err(AmigaFilesystemError::UnsupportedDosType(0x444F5305)));
+    }
+}
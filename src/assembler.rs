@@ -0,0 +1,921 @@
+//! A small assembler for the 8086, so the code a patch adds can be written as assembly rather than opcode constants:
+//!
+//! ```text
+//! stub:   pop dx
+//!         pop ax
+//!         jnc ready
+//!         jmp not_ready           ; a symbol given with the rom offset it stands for
+//! ready:  push ax
+//!         mov ah, 02h
+//!         int 16h
+//! ```
+//!
+//! It covers the common instructions with register, immediate and memory operands (`[bx+si+4]`, `es:[0x46C]`,
+//! `byte [di]`), labels, `$` for the current offset and `db`/`dw` data. Numbers are decimal, `0x1F` or `1Fh`, and `'c'`
+//! is a character. Sizes never depend on the value of a label: `jmp` is always near unless `short` is given and
+//! conditional jumps, `loop` and `jcxz` are always short, so two passes resolve every label.
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum AssemblerError {
+    UnknownInstruction(usize, String),
+    InvalidOperands(usize, String),
+    InvalidNumber(usize, String),
+    UnknownLabel(usize, String),
+    DuplicateLabel(usize, String),
+    OutOfRange(usize, String),
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::UnknownInstruction(line, mnemonic) => write!(f, "Line {}: {} isn't an instruction the assembler knows", line, mnemonic),
+            AssemblerError::InvalidOperands(line, message) => write!(f, "Line {}: {}", line, message),
+            AssemblerError::InvalidNumber(line, text) => write!(f, "Line {}: {} isn't a number, register or label", line, text),
+            AssemblerError::UnknownLabel(line, label) => write!(f, "Line {}: the label {} isn't defined", line, label),
+            AssemblerError::DuplicateLabel(line, label) => write!(f, "Line {}: the label {} is already defined", line, label),
+            AssemblerError::OutOfRange(line, message) => write!(f, "Line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+const REGISTERS_8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REGISTERS_16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const SEGMENT_REGISTERS: [&str; 4] = ["es", "cs", "ss", "ds"];
+
+/// AL or AX, which have shorter forms of some instructions
+const REGISTER_ACCUMULATOR: u8 = 0;
+const REGISTER_CL: u8 = 1;
+const REGISTER_DX: u8 = 2;
+
+/// The ModRM r/m of an address with no base or index register, which is followed by a 16 bit address
+const MODRM_DIRECT_ADDRESS: u8 = 0b110;
+
+const NO_OPERAND_INSTRUCTIONS: [(&str, u8); 34] = [
+    ("nop", 0x90), ("ret", 0xc3), ("retf", 0xcb), ("iret", 0xcf), ("int3", 0xcc), ("into", 0xce), ("hlt", 0xf4),
+    ("clc", 0xf8), ("stc", 0xf9), ("cmc", 0xf5), ("cli", 0xfa), ("sti", 0xfb), ("cld", 0xfc), ("std", 0xfd),
+    ("pushf", 0x9c), ("popf", 0x9d), ("lahf", 0x9f), ("sahf", 0x9e), ("cbw", 0x98), ("cwd", 0x99), ("xlat", 0xd7),
+    ("movsb", 0xa4), ("movsw", 0xa5), ("cmpsb", 0xa6), ("cmpsw", 0xa7), ("stosb", 0xaa), ("stosw", 0xab),
+    ("lodsb", 0xac), ("lodsw", 0xad), ("scasb", 0xae), ("scasw", 0xaf), ("rep", 0xf3), ("repne", 0xf2), ("lock", 0xf0),
+];
+
+/// Prefixes which can go before another instruction on the same line, e.g. rep movsb
+const PREFIXES: [(&str, u8); 6] = [("rep", 0xf3), ("repe", 0xf3), ("repz", 0xf3), ("repne", 0xf2), ("repnz", 0xf2), ("lock", 0xf0)];
+
+const SHORT_JUMPS: [(&str, u8); 34] = [
+    ("jo", 0x70), ("jno", 0x71), ("jb", 0x72), ("jc", 0x72), ("jnae", 0x72), ("jnb", 0x73), ("jnc", 0x73),
+    ("jae", 0x73), ("je", 0x74), ("jz", 0x74), ("jne", 0x75), ("jnz", 0x75), ("jbe", 0x76), ("jna", 0x76),
+    ("ja", 0x77), ("jnbe", 0x77), ("js", 0x78), ("jns", 0x79), ("jp", 0x7a), ("jpe", 0x7a), ("jnp", 0x7b),
+    ("jpo", 0x7b), ("jl", 0x7c), ("jnge", 0x7c), ("jge", 0x7d), ("jnl", 0x7d), ("jle", 0x7e), ("jng", 0x7e),
+    ("jg", 0x7f), ("jnle", 0x7f), ("loopnz", 0xe0), ("loopz", 0xe1), ("loop", 0xe2), ("jcxz", 0xe3),
+];
+
+/// The instructions which differ only in the ModRM reg field: the arithmetic ones, the F6/F7 group and the shifts
+const ARITHMETIC: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const UNARY: [(&str, u8); 6] = [("not", 2), ("neg", 3), ("mul", 4), ("imul", 5), ("div", 6), ("idiv", 7)];
+const SHIFTS: [(&str, u8); 8] = [("rol", 0), ("ror", 1), ("rcl", 2), ("rcr", 3), ("shl", 4), ("sal", 4), ("shr", 5), ("sar", 7)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Word,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Number(i64),
+    Label(String),
+    /// $, the offset of the instruction
+    Here,
+}
+
+/// A sum of terms, each added or subtracted
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Expression(Vec<(bool, Term)>);
+
+impl Expression {
+    /// Whether the value is known in the first pass, so it can decide the size of an instruction
+    fn is_literal(&self) -> bool {
+        self.0.iter().all(|(_, term)| ! matches!(term, Term::Label(_)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Memory {
+    segment: Option<u8>,
+    /// The ModRM r/m of the base and index registers, None for a direct address
+    registers: Option<u8>,
+    displacement: Expression,
+    size: Option<Size>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Register8(u8),
+    Register16(u8),
+    Segment(u8),
+    Immediate(Expression),
+    Memory(Memory),
+}
+
+impl Operand {
+    fn size(&self) -> Option<Size> {
+        match self {
+            Operand::Register8(_) => Some(Size::Byte),
+            Operand::Register16(_) | Operand::Segment(_) => Some(Size::Word),
+            Operand::Memory(memory) => memory.size,
+            Operand::Immediate(_) => None,
+        }
+    }
+
+    fn is_register_or_memory(&self) -> bool {
+        matches!(self, Operand::Register8(_) | Operand::Register16(_) | Operand::Memory(_))
+    }
+}
+
+fn register_number(registers: &[&str], name: &str) -> Option<u8> {
+    registers.iter().position(|register| *register == name).map(|number| number as u8)
+}
+
+fn lookup(table: &[(&str, u8)], mnemonic: &str) -> Option<u8> {
+    table.iter().find(|(name, _)| *name == mnemonic).map(|(_, value)| *value)
+}
+
+/// The statement of a line of source with the label before it and the comment after it taken off
+struct Line<'a> {
+    number: usize,
+    label: Option<&'a str>,
+    statement: &'a str,
+}
+
+fn is_label_name(text: &str) -> bool {
+    let mut characters = text.chars();
+    matches!(characters.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && characters.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Where the comment starts, ignoring a ; in quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote: Option<char> = None;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &text[..index],
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            _ => {},
+        }
+    }
+    text
+}
+
+fn parse_line(number: usize, text: &str) -> Line<'_> {
+    let text = strip_comment(text).trim();
+    match text.split_once(':') {
+        Some((label, statement)) if is_label_name(label.trim()) && ! SEGMENT_REGISTERS.contains(&label.trim().to_ascii_lowercase().as_str()) => {
+            Line { number, label: Some(label.trim()), statement: statement.trim() }
+        },
+        _ => Line { number, label: None, statement: text },
+    }
+}
+
+/// Split operands at the commas which aren't in quotes
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut operands: Vec<&str> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            },
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            _ => {},
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn parse_number(line: usize, text: &str) -> Result<i64, AssemblerError> {
+    let invalid = || AssemblerError::InvalidNumber(line, text.to_string());
+    let lower = text.to_ascii_lowercase();
+
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).map_err(|_| invalid())
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        i64::from_str_radix(hex, 16).map_err(|_| invalid())
+    } else if let Some(character) = text.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        match character.as_bytes() {
+            [byte] => Ok(*byte as i64),
+            _ => Err(invalid()),
+        }
+    } else {
+        lower.parse::<i64>().map_err(|_| invalid())
+    }
+}
+
+fn parse_term(line: usize, text: &str) -> Result<Term, AssemblerError> {
+    if text == "$" {
+        Ok(Term::Here)
+    } else if text.starts_with(|c: char| c.is_ascii_digit() || c == '\'') {
+        parse_number(line, text).map(Term::Number)
+    } else if is_label_name(text) {
+        Ok(Term::Label(text.to_string()))
+    } else {
+        Err(AssemblerError::InvalidNumber(line, text.to_string()))
+    }
+}
+
+/// Split text at + and -, giving each part with whether it is added
+fn split_terms(text: &str) -> Vec<(bool, &str)> {
+    let mut terms: Vec<(bool, &str)> = Vec::new();
+    let mut add = true;
+    let mut start = 0;
+    let mut in_quote = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => in_quote = ! in_quote,
+            '+' | '-' if ! in_quote => {
+                if ! text[start..index].trim().is_empty() {
+                    terms.push((add, text[start..index].trim()));
+                }
+                add = c == '+';
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    terms.push((add, text[start..].trim()));
+    terms
+}
+
+fn parse_expression(line: usize, text: &str) -> Result<Expression, AssemblerError> {
+    let terms = split_terms(text).into_iter()
+        .map(|(add, term)| parse_term(line, term).map(|term| (add, term)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Expression(terms))
+}
+
+fn parse_memory(line: usize, text: &str, segment: Option<u8>, size: Option<Size>) -> Result<Operand, AssemblerError> {
+    let mut registers: Vec<String> = Vec::new();
+    let mut displacement = Expression::default();
+    for (add, term) in split_terms(text) {
+        let lower = term.to_ascii_lowercase();
+        if ["bx", "bp", "si", "di"].contains(&lower.as_str()) {
+            if ! add {
+                return Err(AssemblerError::InvalidOperands(line, format!("[{}] can't subtract a register", text)));
+            }
+            registers.push(lower);
+        } else {
+            displacement.0.push((add, parse_term(line, term)?));
+        }
+    }
+
+    registers.sort_unstable();
+    let registers: Vec<&str> = registers.iter().map(String::as_str).collect();
+    let registers = match registers.as_slice() {
+        [] => None,
+        ["bx", "si"] => Some(0),
+        ["bx", "di"] => Some(1),
+        ["bp", "si"] => Some(2),
+        ["bp", "di"] => Some(3),
+        ["si"] => Some(4),
+        ["di"] => Some(5),
+        ["bp"] => Some(6),
+        ["bx"] => Some(7),
+        _ => return Err(AssemblerError::InvalidOperands(line, format!("[{}] isn't an address the 8086 can use", text))),
+    };
+    Ok(Operand::Memory(Memory { segment, registers, displacement, size }))
+}
+
+fn parse_operand(line: usize, text: &str) -> Result<Operand, AssemblerError> {
+    let lower = text.to_ascii_lowercase();
+    let (size, rest) = if let Some(rest) = lower.strip_prefix("byte ") {
+        (Some(Size::Byte), rest.trim_start())
+    } else if let Some(rest) = lower.strip_prefix("word ") {
+        (Some(Size::Word), rest.trim_start())
+    } else {
+        (None, lower.as_str())
+    };
+    let rest = rest.strip_prefix("ptr ").unwrap_or(rest).trim_start();
+    // Labels are case sensitive, so they come from the original text
+    let original = &text[text.len() - rest.len()..];
+
+    let (segment, address) = match rest.split_once(':') {
+        Some((segment, address)) if address.trim_start().starts_with('[') => match register_number(&SEGMENT_REGISTERS, segment.trim()) {
+            Some(segment) => (Some(segment), original[original.len() - address.len()..].trim()),
+            None => return Err(AssemblerError::InvalidOperands(line, format!("{} isn't a segment register", segment))),
+        },
+        _ => (None, original),
+    };
+    if let Some(inner) = address.strip_prefix('[').and_then(|address| address.strip_suffix(']')) {
+        return parse_memory(line, inner, segment, size);
+    }
+
+    if size.is_some() {
+        return Err(AssemblerError::InvalidOperands(line, format!("{} only needs a size for a memory operand", text)));
+    }
+    if let Some(register) = register_number(&REGISTERS_8, rest) {
+        Ok(Operand::Register8(register))
+    } else if let Some(register) = register_number(&REGISTERS_16, rest) {
+        Ok(Operand::Register16(register))
+    } else if let Some(register) = register_number(&SEGMENT_REGISTERS, rest) {
+        Ok(Operand::Segment(register))
+    } else {
+        parse_expression(line, original).map(Operand::Immediate)
+    }
+}
+
+/// Assembles one statement at a time, knowing the labels and whether this is the final pass
+struct Encoder<'a> {
+    labels: &'a HashMap<String, usize>,
+    line: usize,
+    address: usize,
+    final_pass: bool,
+    bytes: Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn invalid(&self, message: impl Into<String>) -> AssemblerError {
+        AssemblerError::InvalidOperands(self.line, message.into())
+    }
+
+    fn value(&self, expression: &Expression) -> Result<i64, AssemblerError> {
+        let mut value: i64 = 0;
+        for (add, term) in &expression.0 {
+            let term_value = match term {
+                Term::Number(number) => *number,
+                Term::Here => self.address as i64,
+                Term::Label(label) => match self.labels.get(label) {
+                    Some(address) => *address as i64,
+                    // Forward labels aren't known until the final pass, and sizes don't depend on them
+                    None if ! self.final_pass => 0,
+                    None => return Err(AssemblerError::UnknownLabel(self.line, label.clone())),
+                },
+            };
+            value = if *add { value.wrapping_add(term_value) } else { value.wrapping_sub(term_value) };
+        }
+        Ok(value)
+    }
+
+    fn emit_immediate(&mut self, expression: &Expression, size: Size) -> Result<(), AssemblerError> {
+        let value = self.value(expression)?;
+        let (min, max) = match size {
+            Size::Byte => (-0x80, 0xFF),
+            Size::Word => (-0x8000, 0xFFFF),
+        };
+        if self.final_pass && ! (min..=max).contains(&value) {
+            return Err(AssemblerError::OutOfRange(self.line, format!("{} doesn't fit in a {}", value, if size == Size::Byte { "byte" } else { "word" })));
+        }
+        match size {
+            Size::Byte => self.bytes.push(value as u8),
+            Size::Word => self.bytes.extend_from_slice(&(value as u16).to_le_bytes()),
+        }
+        Ok(())
+    }
+
+    fn is_signed_byte(&self, expression: &Expression) -> Result<bool, AssemblerError> {
+        Ok(expression.is_literal() && (-0x80..=0x7F).contains(&self.value(expression)?))
+    }
+
+    /// The segment override of any memory operand, which goes before the opcode
+    fn emit_prefix(&mut self, operands: &[&Operand]) {
+        for operand in operands {
+            if let Operand::Memory(Memory { segment: Some(segment), .. }) = operand {
+                self.bytes.push(0x26 | (segment << 3));
+            }
+        }
+    }
+
+    /// The ModRM byte and displacement for the register or memory operand, with reg the register or opcode extension
+    fn emit_modrm(&mut self, reg: u8, operand: &Operand) -> Result<(), AssemblerError> {
+        match operand {
+            Operand::Register8(register) | Operand::Register16(register) => self.bytes.push(0xc0 | (reg << 3) | register),
+            Operand::Memory(Memory { registers: None, displacement, .. }) => {
+                self.bytes.push((reg << 3) | MODRM_DIRECT_ADDRESS);
+                self.emit_immediate(displacement, Size::Word)?;
+            },
+            Operand::Memory(Memory { registers: Some(registers), displacement, .. }) => {
+                // [bp] on its own is how a direct address is encoded, so it needs a displacement
+                if displacement.0.is_empty() && *registers != MODRM_DIRECT_ADDRESS {
+                    self.bytes.push((reg << 3) | registers);
+                } else if self.is_signed_byte(displacement)? {
+                    self.bytes.push(0x40 | (reg << 3) | registers);
+                    self.emit_immediate(displacement, Size::Byte)?;
+                } else {
+                    self.bytes.push(0x80 | (reg << 3) | registers);
+                    self.emit_immediate(displacement, Size::Word)?;
+                }
+            },
+            _ => return Err(self.invalid("expected a register or memory operand")),
+        }
+        Ok(())
+    }
+
+    fn operation_size(&self, operands: &[&Operand]) -> Result<Size, AssemblerError> {
+        let sizes: Vec<Size> = operands.iter().filter_map(|operand| operand.size()).collect();
+        match sizes.as_slice() {
+            [] => Err(self.invalid("the size isn't known, give it with byte or word")),
+            [size, rest @ ..] if rest.iter().all(|other| other == size) => Ok(*size),
+            _ => Err(self.invalid("the operands are different sizes")),
+        }
+    }
+
+    fn emit_relative(&mut self, target: &Expression, size: Size) -> Result<(), AssemblerError> {
+        let length = match size {
+            Size::Byte => 1,
+            Size::Word => 2,
+        };
+        let end = self.address + self.bytes.len() + length;
+        let displacement = self.value(target)? - end as i64;
+        match size {
+            Size::Byte => {
+                if self.final_pass && ! (-0x80..=0x7F).contains(&displacement) {
+                    return Err(AssemblerError::OutOfRange(self.line, format!("the jump of {} bytes is too far for a short jump", displacement)));
+                }
+                self.bytes.push(displacement as u8);
+            },
+            // Near jumps wrap around within the segment
+            Size::Word => self.bytes.extend_from_slice(&(displacement as u16).to_le_bytes()),
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, statement: &str) -> Result<(), AssemblerError> {
+        if statement.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = match statement.split_once(char::is_whitespace) {
+            Some((mnemonic, rest)) => (mnemonic.to_ascii_lowercase(), rest.trim()),
+            None => (statement.to_ascii_lowercase(), ""),
+        };
+
+        if let Some(prefix) = lookup(&PREFIXES, &mnemonic).filter(|_| ! rest.is_empty()) {
+            self.bytes.push(prefix);
+            return self.encode(rest);
+        }
+
+        match mnemonic.as_str() {
+            "db" | "dw" => return self.encode_data(&mnemonic, rest),
+            "jmp" | "call" => return self.encode_jump_or_call(&mnemonic, rest),
+            _ => {},
+        }
+        if let Some(opcode) = lookup(&SHORT_JUMPS, &mnemonic) {
+            let target = rest.strip_prefix("short ").unwrap_or(rest).trim();
+            self.bytes.push(opcode);
+            return self.emit_relative(&parse_expression(self.line, target)?, Size::Byte);
+        }
+
+        let operands = split_operands(rest).into_iter()
+            .map(|operand| parse_operand(self.line, operand))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match (mnemonic.as_str(), operands.as_slice()) {
+            (_, []) if lookup(&NO_OPERAND_INSTRUCTIONS, &mnemonic).is_some() => {
+                self.bytes.push(lookup(&NO_OPERAND_INSTRUCTIONS, &mnemonic).unwrap_or_default());
+                Ok(())
+            },
+            ("mov", [destination, source]) => self.encode_mov(destination, source),
+            ("test", [destination, source]) => self.encode_test(destination, source),
+            ("xchg", [destination, source]) => self.encode_xchg(destination, source),
+            ("lea", [Operand::Register16(register), memory @ Operand::Memory(_)]) => {
+                self.emit_prefix(&[memory]);
+                self.bytes.push(0x8d);
+                self.emit_modrm(*register, memory)
+            },
+            ("int", [Operand::Immediate(number)]) => {
+                self.bytes.push(0xcd);
+                self.emit_immediate(number, Size::Byte)
+            },
+            ("ret" | "retf", [Operand::Immediate(count)]) => {
+                self.bytes.push(if mnemonic == "ret" { 0xc2 } else { 0xca });
+                self.emit_immediate(count, Size::Word)
+            },
+            ("push" | "pop", [operand]) => self.encode_push_or_pop(&mnemonic, operand),
+            ("inc" | "dec", [operand]) => self.encode_inc_or_dec(&mnemonic, operand),
+            ("in", [destination, source]) => self.encode_in_or_out(0xe4, destination, source),
+            ("out", [destination, source]) => self.encode_in_or_out(0xe6, source, destination),
+            (_, [destination, source]) if ARITHMETIC.contains(&mnemonic.as_str()) => {
+                let operation = ARITHMETIC.iter().position(|name| *name == mnemonic).unwrap_or_default() as u8;
+                self.encode_arithmetic(operation, destination, source)
+            },
+            (_, [operand]) if lookup(&UNARY, &mnemonic).is_some() => {
+                let size = self.operation_size(&[operand])?;
+                self.emit_prefix(&[operand]);
+                self.bytes.push(if size == Size::Byte { 0xf6 } else { 0xf7 });
+                self.emit_modrm(lookup(&UNARY, &mnemonic).unwrap_or_default(), operand)
+            },
+            (_, [operand, count]) if lookup(&SHIFTS, &mnemonic).is_some() => self.encode_shift(lookup(&SHIFTS, &mnemonic).unwrap_or_default(), operand, count),
+            _ if lookup(&NO_OPERAND_INSTRUCTIONS, &mnemonic).is_some() || [
+                "mov", "test", "xchg", "lea", "int", "push", "pop", "inc", "dec", "in", "out",
+            ].contains(&mnemonic.as_str()) || ARITHMETIC.contains(&mnemonic.as_str()) || lookup(&UNARY, &mnemonic).is_some() || lookup(&SHIFTS, &mnemonic).is_some() => {
+                Err(self.invalid(format!("{} can't take the operands {}", mnemonic, rest)))
+            },
+            _ => Err(AssemblerError::UnknownInstruction(self.line, mnemonic)),
+        }
+    }
+
+    fn encode_data(&mut self, mnemonic: &str, rest: &str) -> Result<(), AssemblerError> {
+        let size = if mnemonic == "db" { Size::Byte } else { Size::Word };
+        for item in split_operands(rest) {
+            let string = item.strip_prefix('"').and_then(|item| item.strip_suffix('"'))
+                .or_else(|| item.strip_prefix('\'').and_then(|item| item.strip_suffix('\'')).filter(|text| text.len() != 1));
+            match string {
+                Some(text) if size == Size::Byte => self.bytes.extend_from_slice(text.as_bytes()),
+                Some(_) => return Err(self.invalid("dw can't hold a string")),
+                None => self.emit_immediate(&parse_expression(self.line, item)?, size)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_jump_or_call(&mut self, mnemonic: &str, rest: &str) -> Result<(), AssemblerError> {
+        if mnemonic == "jmp" {
+            if let Some(target) = rest.strip_prefix("short ") {
+                self.bytes.push(0xeb);
+                return self.emit_relative(&parse_expression(self.line, target.trim())?, Size::Byte);
+            }
+        }
+
+        match parse_operand(self.line, rest)? {
+            Operand::Immediate(target) => {
+                self.bytes.push(if mnemonic == "jmp" { 0xe9 } else { 0xe8 });
+                self.emit_relative(&target, Size::Word)
+            },
+            operand @ (Operand::Register16(_) | Operand::Memory(_)) => {
+                self.emit_prefix(&[&operand]);
+                self.bytes.push(0xff);
+                self.emit_modrm(if mnemonic == "jmp" { 4 } else { 2 }, &operand)
+            },
+            _ => Err(self.invalid(format!("{} can't go to {}", mnemonic, rest))),
+        }
+    }
+
+    fn encode_mov(&mut self, destination: &Operand, source: &Operand) -> Result<(), AssemblerError> {
+        self.emit_prefix(&[destination, source]);
+        match (destination, source) {
+            (Operand::Register8(register), Operand::Immediate(value)) => {
+                self.bytes.push(0xb0 + register);
+                self.emit_immediate(value, Size::Byte)
+            },
+            (Operand::Register16(register), Operand::Immediate(value)) => {
+                self.bytes.push(0xb8 + register);
+                self.emit_immediate(value, Size::Word)
+            },
+            (Operand::Memory(_), Operand::Immediate(value)) => {
+                let size = self.operation_size(&[destination])?;
+                self.bytes.push(if size == Size::Byte { 0xc6 } else { 0xc7 });
+                self.emit_modrm(0, destination)?;
+                self.emit_immediate(value, size)
+            },
+            (Operand::Segment(segment), Operand::Register16(_) | Operand::Memory(_)) => {
+                self.operation_size(&[destination, source])?;
+                self.bytes.push(0x8e);
+                self.emit_modrm(*segment, source)
+            },
+            (Operand::Register16(_) | Operand::Memory(_), Operand::Segment(segment)) => {
+                self.operation_size(&[destination, source])?;
+                self.bytes.push(0x8c);
+                self.emit_modrm(*segment, destination)
+            },
+            _ => self.encode_register_and_memory(0x88, destination, source, true),
+        }
+    }
+
+    /// The forms of an instruction with a register and a register or memory operand: the opcode for a byte operation
+    /// writing to the r/m operand, then +1 for a word and +2 when the register is written to (if there is that form)
+    fn encode_register_and_memory(&mut self, opcode: u8, destination: &Operand, source: &Operand, has_reverse: bool) -> Result<(), AssemblerError> {
+        let size = self.operation_size(&[destination, source])?;
+        let word = if size == Size::Word { 1 } else { 0 };
+        match (destination, source) {
+            (_, Operand::Register8(register) | Operand::Register16(register)) if destination.is_register_or_memory() => {
+                self.bytes.push(opcode + word);
+                self.emit_modrm(*register, destination)
+            },
+            (Operand::Register8(register) | Operand::Register16(register), Operand::Memory(_)) => {
+                self.bytes.push(opcode + word + if has_reverse { 2 } else { 0 });
+                self.emit_modrm(*register, source)
+            },
+            _ => Err(self.invalid("the operands can't be used together")),
+        }
+    }
+
+    fn encode_arithmetic(&mut self, operation: u8, destination: &Operand, source: &Operand) -> Result<(), AssemblerError> {
+        self.emit_prefix(&[destination, source]);
+        let Operand::Immediate(value) = source else {
+            return self.encode_register_and_memory(operation << 3, destination, source, true);
+        };
+
+        match destination {
+            Operand::Register8(REGISTER_ACCUMULATOR) => {
+                self.bytes.push((operation << 3) | 0x04);
+                self.emit_immediate(value, Size::Byte)
+            },
+            // AX gets the short form unless the value fits in a sign extended byte, as NASM does
+            Operand::Register16(REGISTER_ACCUMULATOR) if ! self.is_signed_byte(value)? => {
+                self.bytes.push((operation << 3) | 0x05);
+                self.emit_immediate(value, Size::Word)
+            },
+            _ => {
+                let size = self.operation_size(&[destination])?;
+                if size == Size::Byte {
+                    self.bytes.push(0x80);
+                    self.emit_modrm(operation, destination)?;
+                    self.emit_immediate(value, Size::Byte)
+                } else if self.is_signed_byte(value)? {
+                    self.bytes.push(0x83);
+                    self.emit_modrm(operation, destination)?;
+                    self.emit_immediate(value, Size::Byte)
+                } else {
+                    self.bytes.push(0x81);
+                    self.emit_modrm(operation, destination)?;
+                    self.emit_immediate(value, Size::Word)
+                }
+            },
+        }
+    }
+
+    fn encode_test(&mut self, destination: &Operand, source: &Operand) -> Result<(), AssemblerError> {
+        self.emit_prefix(&[destination, source]);
+        match (destination, source) {
+            (Operand::Register8(REGISTER_ACCUMULATOR), Operand::Immediate(value)) => {
+                self.bytes.push(0xa8);
+                self.emit_immediate(value, Size::Byte)
+            },
+            (Operand::Register16(REGISTER_ACCUMULATOR), Operand::Immediate(value)) => {
+                self.bytes.push(0xa9);
+                self.emit_immediate(value, Size::Word)
+            },
+            (_, Operand::Immediate(value)) => {
+                let size = self.operation_size(&[destination])?;
+                self.bytes.push(if size == Size::Byte { 0xf6 } else { 0xf7 });
+                self.emit_modrm(0, destination)?;
+                self.emit_immediate(value, size)
+            },
+            _ => self.encode_register_and_memory(0x84, destination, source, false),
+        }
+    }
+
+    fn encode_xchg(&mut self, destination: &Operand, source: &Operand) -> Result<(), AssemblerError> {
+        match (destination, source) {
+            (Operand::Register16(REGISTER_ACCUMULATOR), Operand::Register16(register)) | (Operand::Register16(register), Operand::Register16(REGISTER_ACCUMULATOR)) => {
+                self.bytes.push(0x90 + register);
+                Ok(())
+            },
+            _ => {
+                self.emit_prefix(&[destination, source]);
+                self.encode_register_and_memory(0x86, destination, source, false)
+            },
+        }
+    }
+
+    fn encode_push_or_pop(&mut self, mnemonic: &str, operand: &Operand) -> Result<(), AssemblerError> {
+        let push = mnemonic == "push";
+        match operand {
+            Operand::Register16(register) => self.bytes.push(if push { 0x50 } else { 0x58 } + register),
+            // There is no POP CS
+            Operand::Segment(segment) if push || *segment != 1 => self.bytes.push((segment << 3) | if push { 0x06 } else { 0x07 }),
+            Operand::Memory(_) => {
+                if self.operation_size(&[operand]).unwrap_or(Size::Word) != Size::Word {
+                    return Err(self.invalid(format!("{} only works with words", mnemonic)));
+                }
+                self.emit_prefix(&[operand]);
+                self.bytes.push(if push { 0xff } else { 0x8f });
+                self.emit_modrm(if push { 6 } else { 0 }, operand)?;
+            },
+            _ => return Err(self.invalid(format!("{} can't take that operand", mnemonic))),
+        }
+        Ok(())
+    }
+
+    fn encode_inc_or_dec(&mut self, mnemonic: &str, operand: &Operand) -> Result<(), AssemblerError> {
+        let extension = if mnemonic == "inc" { 0 } else { 1 };
+        if let Operand::Register16(register) = operand {
+            self.bytes.push(0x40 + (extension << 3) + register);
+            return Ok(());
+        }
+
+        let size = self.operation_size(&[operand])?;
+        self.emit_prefix(&[operand]);
+        self.bytes.push(if size == Size::Byte { 0xfe } else { 0xff });
+        self.emit_modrm(extension, operand)
+    }
+
+    fn encode_shift(&mut self, extension: u8, operand: &Operand, count: &Operand) -> Result<(), AssemblerError> {
+        let size = self.operation_size(&[operand])?;
+        let word = if size == Size::Word { 1 } else { 0 };
+        let opcode = match count {
+            Operand::Immediate(value) if value.is_literal() && self.value(value)? == 1 => 0xd0,
+            Operand::Register8(REGISTER_CL) => 0xd2,
+            _ => return Err(self.invalid("the 8086 only shifts by 1 or CL")),
+        };
+        self.emit_prefix(&[operand]);
+        self.bytes.push(opcode + word);
+        self.emit_modrm(extension, operand)
+    }
+
+    /// IN and OUT, with the port and the accumulator, opcode being the form with an immediate port and AL
+    fn encode_in_or_out(&mut self, opcode: u8, accumulator: &Operand, port: &Operand) -> Result<(), AssemblerError> {
+        let word = match accumulator {
+            Operand::Register8(REGISTER_ACCUMULATOR) => 0,
+            Operand::Register16(REGISTER_ACCUMULATOR) => 1,
+            _ => return Err(self.invalid("IN and OUT only work with AL or AX")),
+        };
+        match port {
+            Operand::Immediate(port) => {
+                self.bytes.push(opcode + word);
+                self.emit_immediate(port, Size::Byte)
+            },
+            Operand::Register16(REGISTER_DX) => {
+                self.bytes.push(opcode + 0x08 + word);
+                Ok(())
+            },
+            _ => Err(self.invalid("the port is a byte or DX")),
+        }
+    }
+}
+
+/// Assemble the source as if it was put at the origin, with the symbols as labels for the offsets of code outside it,
+/// such as where a patch jumps back into the rom
+pub fn assemble(source: &str, origin: usize, symbols: &HashMap<String, usize>) -> Result<Vec<u8>, AssemblerError> {
+    let lines: Vec<Line> = source.lines().enumerate().map(|(index, text)| parse_line(index + 1, text)).collect();
+
+    // The first pass finds the label addresses, which is possible as no instruction's size depends on a label
+    let mut labels = symbols.clone();
+    let mut address = origin;
+    for line in &lines {
+        if let Some(label) = line.label {
+            if labels.insert(label.to_string(), address).is_some() {
+                return Err(AssemblerError::DuplicateLabel(line.number, label.to_string()));
+            }
+        }
+        let mut encoder = Encoder { labels: &labels, line: line.number, address, final_pass: false, bytes: Vec::new() };
+        encoder.encode(line.statement)?;
+        address += encoder.bytes.len();
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    for line in &lines {
+        let mut encoder = Encoder { labels: &labels, line: line.number, address: origin + bytes.len(), final_pass: true, bytes: Vec::new() };
+        encoder.encode(line.statement)?;
+        bytes.extend(encoder.bytes);
+    }
+    Ok(bytes)
+}
+
+/// Parse NAME=OFFSET for a symbol given on the command line
+pub fn parse_symbol(text: &str) -> Result<(String, usize), String> {
+    let (name, offset) = text.split_once('=').ok_or_else(|| format!("{} isn't NAME=OFFSET", text))?;
+    if ! is_label_name(name) {
+        return Err(format!("{} isn't a valid label", name));
+    }
+    let offset = parse_number(0, offset).ok().and_then(|offset| usize::try_from(offset).ok())
+        .ok_or_else(|| format!("{} isn't an offset", offset))?;
+    Ok((name.to_string(), offset))
+}
+
+#[cfg(test)]
+mod test {
+    //! The expected bytes were checked by disassembling them with objdump -m i8086
+
+    use super::*;
+
+    fn assemble_at_zero(source: &str) -> Result<Vec<u8>, String> {
+        assemble(source, 0, &HashMap::new()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_assemble_instructions() -> Result<(), String> {
+        let source = "
+            mov ah, 10h
+            mov dl, 80h
+            int 13h
+            pop dx
+            pop ax
+            push es
+            mov [0x201E], es        ; the end of setting the INT 13 handler
+            mov [0x201C], di
+            mov al, 13h
+            mov di, 0x026E
+            cmp dl, 0x80
+            test al, 3
+            add sp, 4
+            add ax, 0x1234
+            and byte [bx+si+2], 0x7F
+            mov ax, es:[bp]
+            lea si, [di-2]
+            xor ax, ax
+            mov ds, ax
+            shl bx, 1
+            shr word [0x46C], cl
+            inc cx
+            dec byte [di]
+            rep movsb
+            out dx, al
+            in al, 0x60
+            xchg ax, bx
+            mul cx
+            call [bx+0x100]
+            retf 2
+        ";
+        assert_eq!(assemble_at_zero(source)?, vec![
+            0xb4, 0x10, 0xb2, 0x80, 0xcd, 0x13, 0x5a, 0x58, 0x06, 0x8c, 0x06, 0x1e, 0x20, 0x89, 0x3e, 0x1c, 0x20,
+            0xb0, 0x13, 0xbf, 0x6e, 0x02, 0x80, 0xfa, 0x80, 0xa8, 0x03, 0x83, 0xc4, 0x04, 0x05, 0x34, 0x12,
+            0x80, 0x60, 0x02, 0x7f, 0x26, 0x8b, 0x46, 0x00, 0x8d, 0x75, 0xfe, 0x31, 0xc0, 0x8e, 0xd8, 0xd1, 0xe3,
+            0xd3, 0x2e, 0x6c, 0x04, 0x41, 0xfe, 0x0d, 0xf3, 0xa4, 0xee, 0xe4, 0x60, 0x93, 0xf7, 0xe1,
+            0xff, 0x97, 0x00, 0x01, 0xca, 0x02, 0x00,
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels_and_relative_jumps() -> Result<(), String> {
+        let source = "
+            start:  jnc ready
+                    jmp not_ready           ; outside the code
+            ready:  push ax
+                    jmp short start
+                    loop ready
+            message: db 'Hi', 0
+                    dw message, $
+        ";
+        let symbols = HashMap::from([("not_ready".to_string(), 0x0100)]);
+        assert_eq!(assemble(source, 0x1F00, &symbols).map_err(|e| e.to_string())?, vec![
+            0x73, 0x03, 0xe9, 0xfb, 0xe1, 0x50, 0xeb, 0xf8, 0xe2, 0xfb, b'H', b'i', 0x00, 0x0a, 0x1f, 0x0d, 0x1f,
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_and_near_jumps() -> Result<(), String> {
+        // forward is used before it's defined by jumps, a call, an immediate and dw
+        let source = "
+            back:   nop
+                    jmp back
+                    jmp short back
+                    jmp forward
+                    jmp short forward
+                    jz forward
+                    call forward
+                    mov si, forward
+                    dw forward
+            forward: ret
+        ";
+        assert_eq!(assemble(source, 0x100, &HashMap::new()).map_err(|e| e.to_string())?, vec![
+            0x90, 0xe9, 0xfc, 0xff, 0xeb, 0xfa, 0xe9, 0x0c, 0x00, 0xeb, 0x0a, 0x74, 0x08, 0xe8, 0x05, 0x00,
+            0xbe, 0x15, 0x01, 0x15, 0x01, 0xc3,
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mov_with_segment_overrides() -> Result<(), String> {
+        let source = "
+            mov ax, es:[0x201C]
+            mov es:[0x201C], ax
+            mov bl, cs:[si+4]
+            mov ss:[bx], dx
+            mov word ds:[bp+2], 0x1234
+            mov byte es:[di], 0x7F
+            mov al, [0x46C]
+            mov [0x46C], al
+        ";
+        assert_eq!(assemble_at_zero(source)?, vec![
+            0x26, 0x8b, 0x06, 0x1c, 0x20, 0x26, 0x89, 0x06, 0x1c, 0x20, 0x2e, 0x8a, 0x5c, 0x04, 0x36, 0x89, 0x17,
+            0x3e, 0xc7, 0x46, 0x02, 0x34, 0x12, 0x26, 0xc6, 0x05, 0x7f, 0x8a, 0x06, 0x6c, 0x04, 0x88, 0x06, 0x6c, 0x04,
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assembler_errors() -> Result<(), String> {
+        let error = |source: &str| assemble(source, 0, &HashMap::from([("far".to_string(), 0x1000)])).unwrap_err();
+        assert_eq!(error("nop\nmovx ax, 1").to_string(), "Line 2: movx isn't an instruction the assembler knows");
+
+        assert_eq!(error("nop\nmovx ax, 1"), AssemblerError::UnknownInstruction(2, "movx".into()));
+        assert_eq!(error("jmp nowhere"), AssemblerError::UnknownLabel(1, "nowhere".into()));
+        assert_eq!(error("a: nop\na: nop"), AssemblerError::DuplicateLabel(2, "a".into()));
+        assert_eq!(error("mov ax, 12zz"), AssemblerError::InvalidNumber(1, "12zz".into()));
+        assert_eq!(error("mov [bx], 1"), AssemblerError::InvalidOperands(1, "the size isn't known, give it with byte or word".into()));
+        assert_eq!(error("mov ax, bl"), AssemblerError::InvalidOperands(1, "the operands are different sizes".into()));
+        assert_eq!(error("mov ax, [si-bx]"), AssemblerError::InvalidOperands(1, "[si-bx] can't subtract a register".into()));
+        assert_eq!(error("mov ax, fs:[bx]"), AssemblerError::InvalidOperands(1, "fs isn't a segment register".into()));
+        assert_eq!(error("mov al, 0x100"), AssemblerError::OutOfRange(1, "256 doesn't fit in a byte".into()));
+        assert_eq!(error("jmp short far"), AssemblerError::OutOfRange(1, "the jump of 4094 bytes is too far for a short jump".into()));
+
+        let far_away = format!("jz end\n{}end: nop", "nop\n".repeat(0x80));
+        assert_eq!(error(&far_away), AssemblerError::OutOfRange(1, "the jump of 128 bytes is too far for a short jump".into()));
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! Checking a library of pc.boot files at once: every file under a directory is scanned for its rom, validated and
+//! given a trial patch in memory, and the results are reported as a table or as JSON.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::option_rom::{ChecksumByteLocation, OptionRom};
+use crate::option_rom_patcher::{self, PatchState};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumState {
+    Valid,
+    Invalid,
+}
+
+/// What the trial patch made of a file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchResult {
+    Patchable,
+    AlreadyPatched,
+    Failed,
+    NoOptionRom,
+    Unreadable,
+}
+
+impl fmt::Display for PatchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchResult::Patchable => write!(f, "patchable"),
+            PatchResult::AlreadyPatched => write!(f, "already patched"),
+            PatchResult::Failed => write!(f, "failed"),
+            PatchResult::NoOptionRom => write!(f, "no option rom"),
+            PatchResult::Unreadable => write!(f, "unreadable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchEntry {
+    /// The path of the file relative to the directory being checked
+    pub path: String,
+    pub rom_location: Option<usize>,
+    pub rom_size: Option<usize>,
+    pub checksum: Option<ChecksumState>,
+    pub patch_state: Option<PatchState>,
+    pub patch_result: PatchResult,
+    /// The OptionRomPatcherError variant when the trial patch failed
+    pub patch_error: Option<&'static str>,
+    pub error: Option<String>,
+}
+
+impl BatchEntry {
+    fn failed(path: String, patch_result: PatchResult, error: String) -> BatchEntry {
+        BatchEntry {
+            path,
+            rom_location: None,
+            rom_size: None,
+            checksum: None,
+            patch_state: None,
+            patch_result,
+            patch_error: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Scan, validate and trial patch the bytes of one file
+pub fn check_bytes(path: String, bytes: Vec<u8>, checksum_byte_location: ChecksumByteLocation) -> BatchEntry {
+    let rom_location = match OptionRom::find_option_rom_start_in_bytes(&bytes) {
+        Ok(rom_location) => rom_location,
+        Err(e) => return BatchEntry::failed(path, PatchResult::NoOptionRom, format!("{}", e)),
+    };
+    let option_rom = match OptionRom::from(bytes, rom_location) {
+        Ok(option_rom) => option_rom,
+        Err(e) => return BatchEntry::failed(path, PatchResult::NoOptionRom, format!("{}", e)),
+    };
+
+    let checksum = match option_rom.clone().validate_checksum() {
+        Ok(_) => ChecksumState::Valid,
+        Err(_) => ChecksumState::Invalid,
+    };
+    let patch_state = option_rom_patcher::detect_patch_state(&option_rom);
+
+    let (patch_result, patch_error, error) = match patch_state {
+        PatchState::Patched => (PatchResult::AlreadyPatched, None, None),
+        // An unknown rom is still given the trial patch, to find out why it can't be patched
+        PatchState::Unpatched | PatchState::Unknown => match option_rom_patcher::build_patched_rom(&option_rom, checksum_byte_location) {
+            Ok(_) => (PatchResult::Patchable, None, None),
+            Err(e) => (PatchResult::Failed, Some(e.variant_name()), Some(format!("{}", e))),
+        },
+    };
+
+    BatchEntry {
+        path,
+        rom_location: Some(rom_location),
+        rom_size: Some(option_rom.rom_size_in_bytes),
+        checksum: Some(checksum),
+        patch_state: Some(patch_state),
+        patch_result,
+        patch_error,
+        error,
+    }
+}
+
+/// All the files under the directory, in sorted order. Symbolic links to directories aren't followed
+pub fn find_files(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(directory)?.collect::<std::io::Result<Vec<fs::DirEntry>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            files.extend(find_files(&path)?);
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchReport {
+    pub entries: Vec<BatchEntry>,
+}
+
+impl BatchReport {
+    /// Check every file under the directory
+    pub fn check_directory(directory: &Path, checksum_byte_location: ChecksumByteLocation) -> std::io::Result<BatchReport> {
+        let entries = find_files(directory)?.into_iter()
+            .map(|file| {
+                let path = file.strip_prefix(directory).unwrap_or(&file).display().to_string();
+                match fs::read(&file) {
+                    Ok(bytes) => check_bytes(path, bytes, checksum_byte_location),
+                    Err(e) => BatchEntry::failed(path, PatchResult::Unreadable, format!("{}", e)),
+                }
+            })
+            .collect();
+
+        Ok(BatchReport { entries })
+    }
+
+    pub fn to_json(&self) -> String {
+        // Serializing plain structs and strings can't fail
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    fn count(&self, patch_result: PatchResult) -> usize {
+        self.entries.iter().filter(|entry| entry.patch_result == patch_result).count()
+    }
+}
+
+fn optional<T: fmt::Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_else(|| "-".into())
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let headings = ["File", "Location", "Size", "Checksum", "Patch state", "Result", "Error"];
+        let rows: Vec<[String; 7]> = self.entries.iter()
+            .map(|entry| [
+                entry.path.clone(),
+                optional(entry.rom_location.map(|location| format!("0x{:X}", location))),
+                optional(entry.rom_size.map(|size| format!("0x{:X}", size))),
+                optional(entry.checksum.map(|checksum| match checksum {
+                    ChecksumState::Valid => "valid",
+                    ChecksumState::Invalid => "invalid",
+                })),
+                optional(entry.patch_state.map(|patch_state| match patch_state {
+                    PatchState::Unpatched => "unpatched",
+                    PatchState::Patched => "patched",
+                    PatchState::Unknown => "unknown",
+                })),
+                entry.patch_result.to_string(),
+                optional(entry.patch_error.or(entry.error.as_deref())),
+            ])
+            .collect();
+
+        let mut widths = headings.map(|heading| heading.len());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let line = |cells: Vec<&str>| -> String {
+            let padded: Vec<String> = cells.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+            padded.join("  ").trim_end().to_string()
+        };
+
+        writeln!(f, "{}", line(headings.to_vec()))?;
+        for row in &rows {
+            writeln!(f, "{}", line(row.iter().map(String::as_str).collect()))?;
+        }
+
+        writeln!(f)?;
+        write!(
+            f, "{} files: {} patchable, {} already patched, {} failed, {} without an option rom, {} unreadable",
+            self.entries.len(), self.count(PatchResult::Patchable), self.count(PatchResult::AlreadyPatched),
+            self.count(PatchResult::Failed), self.count(PatchResult::NoOptionRom), self.count(PatchResult::Unreadable),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_patchable_rom, create_temp_dir, load_fixture};
+
+    fn library() -> Result<PathBuf, String> {
+        let directory = create_temp_dir()?.into_path();
+        let patchable = build_patchable_rom();
+        let patched = option_rom_patcher::build_patched_rom(&OptionRom::from(patchable.clone(), 0).unwrap(), ChecksumByteLocation::FinalByte).unwrap();
+
+        let files: Vec<(&str, Vec<u8>)> = vec![
+            ("a/pc.boot", patchable),
+            ("a/pc.boot.patched", patched.bytes),
+            ("b/pc.boot", load_fixture("pc.boot.valid")?),
+            ("readme.txt", b"not a rom".to_vec()),
+        ];
+        for (name, bytes) in files {
+            let path = directory.join(name);
+            if let Err(e) = fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(&path, bytes)) {
+                return Err(format!("Failed to write {} with error {}", name, e));
+            }
+        }
+        Ok(directory)
+    }
+
+    #[test]
+    fn test_check_directory() -> Result<(), String> {
+        let directory = library()?;
+        let report = BatchReport::check_directory(&directory, ChecksumByteLocation::FinalByte).map_err(|e| format!("{}", e))?;
+
+        let results: Vec<(&str, PatchResult, Option<&str>)> = report.entries.iter()
+            .map(|entry| (entry.path.as_str(), entry.patch_result, entry.patch_error))
+            .collect();
+        assert_eq!(results, vec![
+            ("a/pc.boot", PatchResult::Patchable, None),
+            ("a/pc.boot.patched", PatchResult::AlreadyPatched, None),
+            ("b/pc.boot", PatchResult::Failed, Some("CouldntLocateHddReadyCheck")),
+            ("readme.txt", PatchResult::NoOptionRom, None),
+        ]);
+        assert_eq!(report.entries[2].checksum, Some(ChecksumState::Valid));
+        assert_eq!(report.entries[2].rom_size, Some(0x2000));
+
+        let table = report.to_string();
+        assert!(table.starts_with("File               Location  Size    Checksum  Patch state  Result           Error\n"));
+        assert!(table.ends_with("4 files: 1 patchable, 1 already patched, 1 failed, 1 without an option rom, 0 unreadable"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_report() -> Result<(), String> {
+        let entry = check_bytes("pc.boot".into(), load_fixture("pc.boot.valid")?, ChecksumByteLocation::FinalByte);
+        let json = BatchReport { entries: vec![entry] }.to_json();
+
+        let value: serde_json::Value = serde_json::from_str(&json).map_err(|e| format!("{}", e))?;
+        assert_eq!(value["entries"][0]["rom_location"], 0);
+        assert_eq!(value["entries"][0]["checksum"], "valid");
+        assert_eq!(value["entries"][0]["patch_state"], "unknown");
+        assert_eq!(value["entries"][0]["patch_result"], "failed");
+        assert_eq!(value["entries"][0]["patch_error"], "CouldntLocateHddReadyCheck");
+        Ok(())
+    }
+}
@@ -1,6 +1,17 @@
-use clap::{Parser, Subcommand, Args};
+use clap::{ArgAction, Parser, Subcommand, Args};
 use clap_num::maybe_hex;
 
+use crate::assembler::parse_symbol;
+use crate::batch::ReportFormat;
+use crate::eprom::{EpromFill, EpromType};
+use crate::file_handler::{FileLocation, WritePolicy};
+use crate::memory_map::{parse_segment, parse_segment_range, DEFAULT_JANUS_WINDOW, DEFAULT_JANUS_WINDOW_SIZE};
+use crate::option_rom::ChecksumByteLocation;
+use crate::rom_formats::RomFormat;
+use crate::rom_patch::parse_patch_name;
+use crate::xtide::Bridgeboard;
+use crate::xtide_romvars::SETTINGS_HELP;
+
 #[derive(Parser)]
 #[command(author, version)]
 pub struct Cli {
@@ -9,6 +20,14 @@ pub struct Cli {
 
     #[clap(flatten)]
     pub source_args: SourceArgs,
+
+    /// Log more detail to stderr, e.g. where the patch was made and the checksum byte before and after (can be repeated)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Log less to stderr, only warnings, or with -qq nothing but the result
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub quiet: u8,
 }
 
 impl Cli {
@@ -19,14 +38,41 @@ impl Cli {
 
 #[derive(Debug, Subcommand)]
 pub enum Commands {
-    Validate {},
+    Validate(ValidateArgs),
     WriteRom(WriteRomArgs),
+    /// Identify an XTIDE Universal BIOS and check it will work alongside the pc.boot
+    CheckXtide(CheckXtideArgs),
+    /// Show or change the configuration of an XTIDE Universal BIOS image (the settings xtidecfg changes)
+    XtideConfig(XtideConfigArgs),
+    /// Show the upper memory (C000-FFFF) map with the Janus window, pc.boot and other option roms
+    Memmap(MemmapArgs),
+    /// Install the patched pc.boot into the directory given as the source, which mirrors SYS:PC/System, backing up
+    /// pc.boot and disabling aboot.ctrl
+    Install(InstallArgs),
+    /// Put back the original pc.boot and aboot.ctrl in the directory given as the source
+    Uninstall,
+    /// Scan, validate and trial patch every file under the directory given as the source, reporting which can be patched
+    Batch(BatchArgs),
+    /// Explore and edit the source full screen: a hex view, the disassembly and the option roms found, with the patch
+    /// sites and the checksum byte highlighted
+    Tui(TuiArgs),
+    /// List the patches --patch can apply and whether each is applied to the rom in the source
+    ListPatches(ListPatchesArgs),
+    /// List the unused space in the rom where a patch could put code, with how sure we are that nothing uses it
+    CodeCaves,
+    /// Assemble the 8086 assembly file given as the source and print the bytes, e.g. to check a stub for a patch
+    Assemble(AssembleArgs),
+    /// Check the file given as the source against the manifest write-rom wrote with it, reporting anything changed
+    CheckManifest(CheckManifestArgs),
 }
 
 #[derive(Debug, Args)]
 pub struct SourceArgs {
-    /// The path to the file to read
-    pub source_path: std::path::PathBuf,
+    /// The path to the file to read, or to a file inside an ADF or HDF image or an LHA archive, e.g.
+    /// workbench.adf:PC/System/pc.boot, card.hdf:SYS:PC/System/pc.boot or janus.lha:pc.boot, or - for standard input
+    /// (for install and uninstall, the directory which mirrors SYS:PC/System, for batch the directory of files to check
+    /// and for assemble the assembly file)
+    pub source_path: FileLocation,
 
     /// ROM location in the file (in hex if specified with a leading 0x)
     #[arg(short, long, conflicts_with = "scan", value_parser=maybe_hex::<usize>)]
@@ -34,27 +80,252 @@ pub struct SourceArgs {
 
     /// Scan in the source file for an Option Rom
     #[arg(long, conflicts_with = "location")]
-    pub scan: bool
+    pub scan: bool,
+
+    /// The dump of the odd chip of an even/odd pair, with the source being the even chip. The two are merged into
+    /// one image before looking for the rom
+    #[arg(long)]
+    pub odd: Option<FileLocation>,
+}
+
+#[derive(Debug, Args)]
+pub struct ValidateArgs {
+    /// The byte which would be altered to fix the checksum: final, auto (a byte in the padding) or an offset in the rom
+    /// (in hex if specified with a leading 0x)
+    #[arg(short, long, default_value = "final", value_parser=parse_checksum_byte_location)]
+    pub checksum_byte: ChecksumByteLocation,
 }
 
 #[derive(Debug, Args)]
 pub struct WriteRomArgs {
-    /// File path to write the output to
-    pub output_path: std::path::PathBuf,
+    /// File path to write the output to, which can be inside an ADF or HDF image like the source, or - for standard
+    /// output
+    pub output_path: FileLocation,
 
     /// Force overwrite an existing output file
     #[arg(short, long)]
     pub force: bool,
 
+    /// Copy an existing output file to the same name with .original added before overwriting it (inside the image
+    /// for ADF and HDF files), an existing backup is kept
+    #[arg(short, long)]
+    pub backup: bool,
+
     /// Only write the discovered ROM and not the whole file
     #[arg(short, long)]
     pub rom_only: bool,
 
-    /// Fix the checksum by altering the final byte of the rom
+    /// Split the output between an even/odd pair of chips, writing the even chip to the output path and the odd chip
+    /// here
+    #[arg(long, conflicts_with_all = ["format", "load_address"])]
+    pub odd_output: Option<FileLocation>,
+
+    /// The format to write the ROM in with --rom-only, binary or Intel HEX or S-records for EPROM programmers
+    #[arg(long, value_enum, default_value_t = RomFormat::Binary, requires = "rom_only")]
+    pub format: RomFormat,
+
+    /// The address of the first byte of the ROM in Intel HEX and S-records, as a segment (e.g. D000 or D000:0000).
+    /// Defaults to where the ROM is in the Janus window
+    #[arg(long, value_parser = parse_segment, requires = "rom_only")]
+    pub load_address: Option<u32>,
+
+    /// Fill an EPROM of this type with --rom-only, placing the ROM where the load address puts it on the chip
+    #[arg(long, value_enum, requires = "rom_only", conflicts_with = "odd_output")]
+    pub eprom: Option<EpromType>,
+
+    /// What fills the rest of the EPROM
+    #[arg(long, value_enum, default_value_t = EpromFill::Ff, requires = "eprom")]
+    pub fill: EpromFill,
+
+    /// Fix the checksum by altering the byte chosen with --checksum-byte
     #[arg(short, long)]
     pub update_checksum: bool,
 
-    /// Patch the ROM with our hack
+    /// The byte altered to fix the checksum: final, auto (a byte in the padding) or an offset in the rom (in hex if
+    /// specified with a leading 0x)
+    #[arg(short, long, default_value = "final", value_parser=parse_checksum_byte_location)]
+    pub checksum_byte: ChecksumByteLocation,
+
+    /// Patch the ROM with our hack, the same as --patch skip-hdd-ready-check
     #[arg(short, long)]
     pub patch_rom: bool,
+
+    /// Apply a patch by name, see list-patches (can be repeated)
+    #[arg(long = "patch", value_name = "NAME", value_parser = parse_patch_name)]
+    pub patches: Vec<String>,
+
+    /// Take a patch out again by name, before any are applied (can be repeated)
+    #[arg(long = "revert-patch", value_name = "NAME", value_parser = parse_patch_name)]
+    pub reverted_patches: Vec<String>,
+
+    /// Move the Janus hard disk to this BIOS drive number (in hex if specified with a leading 0x), like --patch
+    /// janus-drive-81 for another unit
+    #[arg(long, value_name = "UNIT", value_parser = parse_janus_drive)]
+    pub janus_drive: Option<u8>,
+
+    /// Apply the patch described in a TOML patch file, after any given by name (can be repeated)
+    #[arg(long = "patch-file", value_name = "PATH")]
+    pub patch_files: Vec<std::path::PathBuf>,
+
+    /// Take out the patch described in a TOML patch file, before any are applied (can be repeated)
+    #[arg(long = "revert-patch-file", value_name = "PATH")]
+    pub reverted_patch_files: Vec<std::path::PathBuf>,
+
+    /// Let patches which add code put it in a low confidence code cave, a run of a byte which could be data, when
+    /// there's no better one (see code-caves)
+    #[arg(long)]
+    pub allow_low_confidence_cave: bool,
+
+    /// What may happen to the file length when the ROM doesn't fit inside the source file (ignored with --rom-only)
+    #[arg(short, long, value_enum, default_value_t = WritePolicy::Strict)]
+    pub write_policy: WritePolicy,
+
+    /// Don't read the output back to check the rom, its patches and the rest of the file were written as intended
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Write a JSON manifest here recording the source, the rom, its checksum byte and the bytes each patch changed,
+    /// which check-manifest can check the output against later
+    #[arg(long, value_name = "PATH")]
+    pub manifest: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct InstallArgs {
+    /// The byte altered to fix the checksum after patching: final, auto (a byte in the padding) or an offset in the rom
+    /// (in hex if specified with a leading 0x)
+    #[arg(short, long, default_value = "final", value_parser=parse_checksum_byte_location)]
+    pub checksum_byte: ChecksumByteLocation,
+}
+
+#[derive(Debug, Args)]
+pub struct BatchArgs {
+    /// How the report is written
+    #[arg(short, long, value_enum, default_value_t = ReportFormat::Table)]
+    pub format: ReportFormat,
+
+    /// The byte altered to fix the checksum in the trial patch: final, auto (a byte in the padding) or an offset in the
+    /// rom (in hex if specified with a leading 0x)
+    #[arg(short, long, default_value = "final", value_parser=parse_checksum_byte_location)]
+    pub checksum_byte: ChecksumByteLocation,
+}
+
+#[derive(Debug, Args)]
+pub struct AssembleArgs {
+    /// The offset in the rom where the code goes, which labels and $ count from
+    #[arg(long, default_value_t = 0, value_parser = maybe_hex::<usize>)]
+    pub origin: usize,
+
+    /// A label for an offset in the rom outside the code, e.g. --symbol int13_set=0x1A2 (can be repeated)
+    #[arg(long = "symbol", value_name = "NAME=OFFSET", value_parser = parse_symbol)]
+    pub symbols: Vec<(String, usize)>,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckManifestArgs {
+    /// The manifest written by write-rom --manifest
+    pub manifest_path: std::path::PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ListPatchesArgs {
+    /// A TOML patch file to list as well (can be repeated)
+    #[arg(long = "patch-file", value_name = "PATH")]
+    pub patch_files: Vec<std::path::PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct TuiArgs {
+    /// Where the whole file is saved, e.g. pc.boot.new or workbench.adf:PC/System/pc.boot
+    #[arg(short, long)]
+    pub output_path: Option<FileLocation>,
+
+    /// Force overwrite an existing output file
+    #[arg(short, long, requires = "output_path")]
+    pub force: bool,
+
+    /// The byte highlighted and altered to fix the checksum: final, auto (a byte in the padding) or an offset in the
+    /// rom (in hex if specified with a leading 0x)
+    #[arg(short, long, default_value = "final", value_parser=parse_checksum_byte_location)]
+    pub checksum_byte: ChecksumByteLocation,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckXtideArgs {
+    /// The XTIDE Universal BIOS image or flash dump to check
+    pub xtide_path: std::path::PathBuf,
+
+    /// The Bridgeboard the XTIDE will be used with
+    #[arg(short, long, value_enum, default_value_t = Bridgeboard::A2286)]
+    pub bridgeboard: Bridgeboard,
+}
+
+#[derive(Debug, Args)]
+pub struct XtideConfigArgs {
+    /// Change a setting, given as setting=value (can be repeated)
+    #[arg(short, long = "set", value_parser = parse_setting, requires = "output_path", long_help = format!("Change a setting, given as setting=value (can be repeated). The settings are: {}", SETTINGS_HELP))]
+    pub settings: Vec<(String, String)>,
+
+    /// File path to write the reconfigured image to, or - for standard output
+    #[arg(short, long)]
+    pub output_path: Option<FileLocation>,
+
+    /// Force overwrite an existing output file
+    #[arg(short, long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct MemmapArgs {
+    /// The Janus memory window chosen in PCPrefs, as a segment (e.g. D000 or D000:0000)
+    #[arg(short, long, default_value = DEFAULT_JANUS_WINDOW, value_parser = parse_segment)]
+    pub window: u32,
+
+    /// The size of the Janus memory window in bytes (in hex if specified with a leading 0x)
+    #[arg(long, default_value_t = DEFAULT_JANUS_WINDOW_SIZE, value_parser = maybe_hex::<u32>)]
+    pub window_size: u32,
+
+    /// An option rom file and the segment it will be at, e.g. ide_xt.bin@CC00 (can be repeated)
+    #[arg(short, long = "rom", value_parser = parse_planned_rom)]
+    pub roms: Vec<PlannedRom>,
+
+    /// A range of segments which is already in use, e.g. C000-C7FF for a VGA BIOS (can be repeated)
+    #[arg(long, value_parser = parse_segment_range)]
+    pub reserved: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlannedRom {
+    pub path: std::path::PathBuf,
+    /// The linear address the rom will start at
+    pub segment: u32,
+}
+
+fn parse_janus_drive(value: &str) -> Result<u8, String> {
+    match maybe_hex::<u8>(value)? {
+        unit @ 0x81..=0xFF => Ok(unit),
+        _ => Err(format!("{} isn't a fixed disk other than the first, use e.g. 0x81", value)),
+    }
+}
+
+fn parse_planned_rom(value: &str) -> Result<PlannedRom, String> {
+    match value.rsplit_once('@') {
+        Some((path, segment)) => Ok(PlannedRom { path: path.into(), segment: parse_segment(segment)? }),
+        None => Err("Option roms must be given as path@segment, e.g. ide_xt.bin@CC00".into()),
+    }
+}
+
+fn parse_setting(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((setting, value)) => Ok((setting.trim().into(), value.trim().into())),
+        None => Err("Settings must be given as setting=value".into()),
+    }
+}
+
+fn parse_checksum_byte_location(value: &str) -> Result<ChecksumByteLocation, String> {
+    match value {
+        "final" => Ok(ChecksumByteLocation::FinalByte),
+        "auto" => Ok(ChecksumByteLocation::InPadding),
+        offset => maybe_hex::<usize>(offset).map(ChecksumByteLocation::Offset),
+    }
 }
@@ -0,0 +1,176 @@
+//! Finding space in an option rom for patches which add code: runs of one repeated byte which aren't part of the code
+//! reachable from the entry point, and the alignment padding which rounds the rom up to its size before the checksum
+//! byte.
+
+use std::fmt;
+
+use crate::disassembler;
+use crate::option_rom::{OptionRom, MIN_PADDING_RUN_LENGTH, OPTION_ROM_ENTRY_POINT, OPTION_ROM_HEADER_LENGTH};
+
+/// The shortest run before the checksum byte we consider to be alignment padding. It is shorter than other padding
+/// as nothing but the checksum byte follows it
+const MIN_ALIGNMENT_RUN_LENGTH: usize = 2;
+
+/// Bytes a linker or EPROM programmer fills unused space with, or which an assembler pads with between routines
+const ERASED_FILLERS: [u8; 2] = [0x00, 0xFF];
+const INSTRUCTION_FILLERS: [u8; 2] = [0x90, 0xCC];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaveKind {
+    /// A run between or after the code
+    Padding,
+    /// The run which goes up to the checksum byte, padding the rom to a multiple of 512 bytes
+    Alignment,
+}
+
+/// How sure we are nothing reads the cave. The code map only follows code, so a run could still be a table the code
+/// reads, which is more likely the less the filler looks like unused space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// Some other repeated byte, which may be data
+    Low,
+    /// NOP or INT 3 between routines, or erased bytes between the code
+    Medium,
+    /// Erased bytes padding the end of the rom
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodeCave {
+    pub offset: usize,
+    pub length: usize,
+    /// The byte repeated through the cave
+    pub filler: u8,
+    pub kind: CaveKind,
+    pub confidence: Confidence,
+}
+
+impl fmt::Display for CaveKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaveKind::Padding => write!(f, "padding"),
+            CaveKind::Alignment => write!(f, "alignment"),
+        }
+    }
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Confidence::Low => write!(f, "low"),
+            Confidence::Medium => write!(f, "medium"),
+            Confidence::High => write!(f, "high"),
+        }
+    }
+}
+
+fn confidence(filler: u8, kind: CaveKind) -> Confidence {
+    match kind {
+        _ if ! ERASED_FILLERS.contains(&filler) && ! INSTRUCTION_FILLERS.contains(&filler) => Confidence::Low,
+        CaveKind::Alignment if ERASED_FILLERS.contains(&filler) => Confidence::High,
+        _ => Confidence::Medium,
+    }
+}
+
+/// The caves in the rom in order, leaving out the final byte which holds the checksum
+pub fn find_code_caves(option_rom: &OptionRom) -> Vec<CodeCave> {
+    let bytes = &option_rom.bytes;
+    let code_map = disassembler::code_map(bytes, &[OPTION_ROM_ENTRY_POINT]);
+    let end = bytes.len().saturating_sub(1);
+
+    // Without any code to go on every byte would look unused
+    if ! code_map.get(OPTION_ROM_ENTRY_POINT).copied().unwrap_or(false) {
+        log::warn!("The instruction at the entry point couldn't be decoded, so no unused space can be found");
+        return Vec::new();
+    }
+
+    let mut caves: Vec<CodeCave> = Vec::new();
+    let mut run_start = OPTION_ROM_HEADER_LENGTH;
+    while run_start < end {
+        if code_map[run_start] {
+            run_start += 1;
+            continue;
+        }
+
+        let mut run_end = run_start + 1;
+        while run_end < end && bytes[run_end] == bytes[run_start] && ! code_map[run_end] {
+            run_end += 1;
+        }
+
+        let kind = if run_end == end { CaveKind::Alignment } else { CaveKind::Padding };
+        let min_length = match kind {
+            CaveKind::Padding => MIN_PADDING_RUN_LENGTH,
+            CaveKind::Alignment => MIN_ALIGNMENT_RUN_LENGTH,
+        };
+        if run_end - run_start >= min_length {
+            let filler = bytes[run_start];
+            caves.push(CodeCave { offset: run_start, length: run_end - run_start, filler, kind, confidence: confidence(filler, kind) });
+        }
+        run_start = run_end;
+    }
+
+    caves
+}
+
+/// Where to put code of the length, at the start of a cave with room for it and at least one filler byte after it.
+/// The most confident cave is used, and of those the last, as padding at the end of the rom is least likely to be
+/// data the code uses. It's up to the patch whether a low confidence cave will do
+pub fn find_space(option_rom: &OptionRom, length: usize) -> Option<CodeCave> {
+    find_code_caves(option_rom).into_iter()
+        .filter(|cave| cave.length > length)
+        .max_by_key(|cave| (cave.confidence, cave.offset))
+}
+
+/// A table of the caves in the rom for the code-caves command
+pub fn report(option_rom: &OptionRom) -> String {
+    let caves = find_code_caves(option_rom);
+    if caves.is_empty() {
+        return "No unused space was found in the option rom".into();
+    }
+
+    let mut lines: Vec<String> = vec!["Offset  Length  Filler  Kind       Confidence".into()];
+    lines.extend(caves.iter().map(|cave| {
+        format!("0x{:04X}  {:>6}  {:02X}      {:<9}  {}", cave.offset, cave.length, cave.filler, cave.kind, cave.confidence)
+    }));
+
+    let total: usize = caves.iter().filter(|cave| cave.confidence > Confidence::Low).map(|cave| cave.length).sum();
+    lines.push(format!("{} bytes in medium or high confidence caves", total));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::build_patchable_rom;
+
+    #[test]
+    fn test_find_code_caves() -> Result<(), String> {
+        let mut bytes = build_patchable_rom();
+        bytes[0x100..0x110].fill(0x00);
+        let option_rom = OptionRom::from(bytes, 0).unwrap();
+
+        assert_eq!(find_code_caves(&option_rom), vec![
+            CodeCave { offset: 31, length: 0x100 - 31, filler: 0x61, kind: CaveKind::Padding, confidence: Confidence::Low },
+            CodeCave { offset: 0x100, length: 0x10, filler: 0x00, kind: CaveKind::Padding, confidence: Confidence::Medium },
+            CodeCave { offset: 0x110, length: 0x1FF - 0x110, filler: 0x61, kind: CaveKind::Alignment, confidence: Confidence::Low },
+        ]);
+        assert_eq!(find_space(&option_rom, 0x20).map(|cave| cave.offset), Some(0x110));
+        assert_eq!(find_space(&option_rom, 0x08).map(|cave| cave.offset), Some(0x100));
+        assert_eq!(find_space(&option_rom, 0x100).map(|cave| cave.offset), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alignment_padding_before_checksum_byte() -> Result<(), String> {
+        let mut bytes = build_patchable_rom();
+        // Code up to the last few bytes, as if the rom only just fit, then erased bytes and the checksum
+        bytes[31..0x1FB].fill(0x90);
+        bytes[0x1FB..0x1FF].fill(0xFF);
+        let option_rom = OptionRom::from(bytes, 0).unwrap();
+
+        let caves = find_code_caves(&option_rom);
+        assert_eq!(caves.last(), Some(&CodeCave { offset: 0x1FB, length: 4, filler: 0xFF, kind: CaveKind::Alignment, confidence: Confidence::High }));
+        assert!(report(&option_rom).contains("0x01FB       4  FF      alignment  high"));
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+use crate::FileHandler;
+use crate::assembler;
+use crate::cli::{AssembleArgs, SourceArgs};
+use crate::error::Error;
+
+/// The bytes in rows of 16, each starting with its offset
+pub fn assemble(assemble_args: AssembleArgs, source_args: SourceArgs) -> Result<String, Error> {
+    let bytes = FileHandler::read_source(&source_args.source_path)?;
+    let source = String::from_utf8(bytes).map_err(|_| format!("{} isn't a text file", source_args.source_path))?;
+
+    let symbols: HashMap<String, usize> = assemble_args.symbols.into_iter().collect();
+    let code = assembler::assemble(&source, assemble_args.origin, &symbols)?;
+    log::info!("Assembled {} bytes at 0x{:04X}", code.len(), assemble_args.origin);
+
+    let rows: Vec<String> = code.chunks(16).enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:04X}: {}", assemble_args.origin + row * 16, hex.join(" "))
+        })
+        .collect();
+    Ok(rows.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_handler::FileLocation;
+
+    #[test]
+    fn test_assemble_file() -> Result<(), String> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let path = directory.path().join("stub.asm");
+        std::fs::write(&path, "stub:  mov ah, 02h\n       int 16h\n       jz install\n").map_err(|e| e.to_string())?;
+
+        let assemble_args = AssembleArgs { origin: 0x1F00, symbols: vec![("install".to_string(), 0x1F10)] };
+        let source_args = SourceArgs { source_path: FileLocation::from(path), odd: None, location: None, scan: false };
+        assert_eq!(assemble(assemble_args, source_args).map_err(|e| e.to_string())?, "1F00: B4 02 CD 16 74 0A");
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+use crate::batch::{BatchReport, ReportFormat};
+use crate::cli::{BatchArgs, SourceArgs};
+use crate::error::Error;
+use crate::file_handler::FileLocation;
+
+pub fn batch(batch_args: BatchArgs, source_args: SourceArgs) -> Result<String, Error> {
+    let directory = match &source_args.source_path {
+        FileLocation::Path(directory) if directory.is_dir() => directory,
+        location => return Err(format!("{} is not a directory, batch needs the directory of pc.boot files to check", location).into()),
+    };
+
+    let report = match BatchReport::check_directory(directory, batch_args.checksum_byte) {
+        Ok(report) => report,
+        Err(e) => return Err(Error::from(e).context(|e| format!("Couldn't read the files in {} with error {}", directory.display(), e))),
+    };
+
+    match batch_args.format {
+        ReportFormat::Table => Ok(format!("{}", report)),
+        ReportFormat::Json => Ok(report.to_json()),
+    }
+}
@@ -0,0 +1,16 @@
+use crate::FileHandler;
+use crate::cli::{CheckManifestArgs, SourceArgs};
+use crate::error::Error;
+use crate::manifest::Manifest;
+
+pub fn check_manifest(check_manifest_args: CheckManifestArgs, source_args: SourceArgs) -> Result<String, Error> {
+    let manifest_path = &check_manifest_args.manifest_path;
+    let text = match std::fs::read_to_string(manifest_path) {
+        Ok(text) => text,
+        Err(e) => return Err(Error::from(e).context(|e| format!("Couldn't read the manifest {}: {}", manifest_path.display(), e))),
+    };
+    let manifest = Manifest::parse(&text)?;
+
+    let bytes = FileHandler::read_source_pair(&source_args.source_path, source_args.odd.as_ref())?;
+    Ok(manifest.check(&bytes)?)
+}
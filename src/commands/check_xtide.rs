@@ -0,0 +1,77 @@
+use crate::FileHandler;
+use crate::cli::CheckXtideArgs;
+use crate::error::Error;
+use crate::option_rom::OptionRom;
+use crate::option_rom_patcher;
+use crate::xtide::XtideBuild;
+
+pub fn check_xtide(option_rom: OptionRom, check_xtide_args: CheckXtideArgs) -> Result<String, Error> {
+    let xtide_bytes = FileHandler::read_source(&check_xtide_args.xtide_path.clone().into())?;
+
+    let builds = XtideBuild::find_all_in_bytes(&xtide_bytes);
+    if builds.is_empty() {
+        return Err(format!("No XTIDE Universal BIOS was found in {}", check_xtide_args.xtide_path.display()).into());
+    }
+
+    let patch_state = option_rom_patcher::detect_patch_state(&option_rom);
+    let mut report: Vec<String> = vec![format!("pc.boot patch state: {}", patch_state)];
+    let mut has_warnings = false;
+
+    for build in builds {
+        report.push(format!("{}", build));
+
+        let warnings = build.compatibility_warnings(check_xtide_args.bridgeboard, patch_state);
+        has_warnings |= ! warnings.is_empty();
+        report.extend(warnings.iter().map(|warning| format!("  WARNING: {}", warning)));
+    }
+
+    if has_warnings {
+        Err(report.join("\n").into())
+    } else {
+        report.push("No known problems with this combination".into());
+        Ok(report.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_xtide_rom, create_temp_dir, fixture_path, load_option_rom_fixture};
+    use crate::xtide::{Bridgeboard, FLG_ROMVARS_MODULE_VERY_LATE_INIT};
+
+    #[test]
+    fn check_xtide_without_xtide_rom() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        let check_xtide_args = CheckXtideArgs { xtide_path: fixture_path("pc.boot.valid"), bridgeboard: Bridgeboard::A2286 };
+
+        match check_xtide(option_rom, check_xtide_args) {
+            Ok(_) => Err("Expected an error when there is no XTIDE rom but got Ok".into()),
+            Err(e) => {
+                assert!(e.to_string().starts_with("No XTIDE Universal BIOS was found in"));
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn check_xtide_warns_about_unpatched_pc_boot() -> Result<(), String> {
+        let tempdir = create_temp_dir()?;
+        let mut xtide_path = tempdir.into_path();
+        xtide_path.push("xtide.bin");
+        let xtide_bytes = build_xtide_rom("-=XTIDE Universal BIOS (XT)=-", "v2.0.0B3+ r625", FLG_ROMVARS_MODULE_VERY_LATE_INIT);
+        if let Err(e) = std::fs::write(&xtide_path, xtide_bytes) {
+            return Err(format!("Failed to write the XTIDE rom with error {}", e));
+        }
+
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        let check_xtide_args = CheckXtideArgs { xtide_path, bridgeboard: Bridgeboard::A2286 };
+
+        match check_xtide(option_rom, check_xtide_args) {
+            Ok(_) => Err("Expected a warning when the pc.boot patch state is unknown but got Ok".into()),
+            Err(e) => {
+                assert!(e.to_string().ends_with("WARNING: Couldn't tell whether pc.boot is patched"));
+                Ok(())
+            },
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crate::cli::{InstallArgs, SourceArgs};
+use crate::error::Error;
+use crate::FileHandler;
+use crate::file_handler::{FileLocation, WritePolicy};
+use crate::installer::{self, InstallStep, PC_BOOT_FILE_NAME};
+use crate::option_rom_patcher::{self, PatchState};
+
+use super::process::load_option_rom;
+
+fn directory(source_args: &SourceArgs) -> Result<&std::path::PathBuf, Error> {
+    let source = match &source_args.source_path {
+        FileLocation::Path(directory) => return Ok(directory),
+        location @ FileLocation::ImageMember { .. } => format!("{} is inside a disk image", location),
+        location @ FileLocation::ArchiveMember { .. } => format!("{} is inside an LHA archive", location),
+        FileLocation::Stdio => "Standard input isn't a directory".to_string(),
+    };
+    Err(format!("{}, install and uninstall need a directory which mirrors SYS:PC/System", source).into())
+}
+
+fn manifest(heading: &str, steps: &[InstallStep]) -> String {
+    let lines: Vec<String> = steps.iter().map(|step| format!("  {}", step)).collect();
+    format!("{}\n{}", heading, lines.join("\n"))
+}
+
+pub fn install(install_args: InstallArgs, source_args: SourceArgs) -> Result<String, Error> {
+    let directory = directory(&source_args)?;
+    let pc_boot_location = FileLocation::Path(directory.join(PC_BOOT_FILE_NAME));
+    let (option_rom, rom_start_location) = load_option_rom(&pc_boot_location, None, source_args.location, source_args.scan)?;
+
+    let patched_pc_boot = match option_rom_patcher::detect_patch_state(&option_rom) {
+        PatchState::Patched => None,
+        PatchState::Unknown => return Err(Error::UnknownPatchState(pc_boot_location)),
+        PatchState::Unpatched => {
+            let option_rom = match option_rom.validate_checksum() {
+                Ok(option_rom) => option_rom,
+                Err(e) => return Err(Error::from(e).context(|e| format!("{}, check it with validate first", e))),
+            };
+            let patched_rom = match option_rom_patcher::patch_rom(&option_rom, install_args.checksum_byte) {
+                Ok(patched_rom) => patched_rom,
+                Err(e) => return Err(Error::from(e).context(|e| format!("Failed patching ROM with error: {}", e))),
+            };
+
+            let pc_boot_bytes = FileHandler::read_source(&pc_boot_location)?;
+            Some(FileHandler::place_rom_in_bytes(&pc_boot_bytes, patched_rom, rom_start_location, WritePolicy::Strict)?)
+        },
+    };
+
+    let steps = installer::plan_install(directory, patched_pc_boot)?;
+    if steps.is_empty() {
+        return Ok(format!("{} is already installed, nothing was changed", directory.display()));
+    }
+
+    installer::apply(&steps)?;
+    Ok(manifest(&format!("Installed into {}:", directory.display()), &steps))
+}
+
+pub fn uninstall(source_args: SourceArgs) -> Result<String, Error> {
+    let directory = directory(&source_args)?;
+
+    let steps = installer::plan_uninstall(directory)?;
+    installer::apply(&steps)?;
+    Ok(manifest(&format!("Uninstalled from {}:", directory.display()), &steps))
+}
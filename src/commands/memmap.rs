@@ -0,0 +1,98 @@
+use crate::FileHandler;
+use crate::cli::{MemmapArgs, PlannedRom};
+use crate::error::Error;
+use crate::memory_map::{MemoryMap, RegionKind};
+use crate::option_rom::OptionRom;
+
+pub fn memmap(option_rom: OptionRom, memmap_args: MemmapArgs, rom_start_location: usize) -> Result<String, Error> {
+    let mut memory_map = MemoryMap::default();
+
+    memory_map.add("Janus window", RegionKind::JanusWindow, memmap_args.window, memmap_args.window_size);
+
+    // pc.boot is loaded at the start of the Janus window, so its rom is at the same offset into the window as the file
+    let pc_boot_rom_start = memmap_args.window + rom_start_location as u32;
+    memory_map.add("pc.boot", RegionKind::OptionRom { in_janus_window: true }, pc_boot_rom_start, option_rom.rom_size_in_bytes as u32);
+
+    for planned_rom in &memmap_args.roms {
+        let rom_size = read_planned_rom_size(planned_rom)?;
+        let name = match planned_rom.path.file_name() {
+            Some(file_name) => file_name.to_string_lossy().to_string(),
+            None => planned_rom.path.display().to_string(),
+        };
+        memory_map.add(&name, RegionKind::OptionRom { in_janus_window: false }, planned_rom.segment, rom_size);
+    }
+
+    for (index, (start, length)) in memmap_args.reserved.iter().enumerate() {
+        memory_map.add(&format!("Reserved {}", index + 1), RegionKind::Reserved, *start, *length);
+    }
+
+    let has_problems = ! memory_map.problems().is_empty();
+    if has_problems {
+        Err(format!("{}", memory_map).into())
+    } else {
+        Ok(format!("{}", memory_map))
+    }
+}
+
+fn read_planned_rom_size(planned_rom: &PlannedRom) -> Result<u32, Error> {
+    let in_planned_rom = |e: &Error| format!("{}: {}", planned_rom.path.display(), e);
+
+    let bytes = match FileHandler::read_source(&planned_rom.path.clone().into()) {
+        Ok(bytes) => bytes,
+        Err(e) => return Err(Error::from(e).context(in_planned_rom)),
+    };
+
+    let rom_start_location = match OptionRom::find_option_rom_start_in_bytes(&bytes) {
+        Ok(rom_start_location) => rom_start_location,
+        Err(e) => return Err(Error::from(e).context(in_planned_rom)),
+    };
+
+    match OptionRom::from(bytes, rom_start_location) {
+        Ok(option_rom) => Ok(option_rom.rom_size_in_bytes as u32),
+        Err(e) => Err(Error::from(e).context(|e| format!("{}: Option rom error: {}", planned_rom.path.display(), e))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_map::{parse_segment, DEFAULT_JANUS_WINDOW, DEFAULT_JANUS_WINDOW_SIZE};
+    use crate::test_helpers::{fixture_path, load_option_rom_fixture};
+
+    fn memmap_args(roms: Vec<PlannedRom>) -> MemmapArgs {
+        MemmapArgs {
+            window: parse_segment(DEFAULT_JANUS_WINDOW).unwrap(),
+            window_size: DEFAULT_JANUS_WINDOW_SIZE,
+            roms,
+            reserved: vec![(0xC0000, 0x8000)],
+        }
+    }
+
+    #[test]
+    fn memmap_with_rom_below_window() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        let roms = vec![PlannedRom { path: fixture_path("8k-option-rom"), segment: 0xCC000 }];
+
+        match memmap(option_rom, memmap_args(roms), 0) {
+            Ok(map) => {
+                assert!(map.contains("B  8k-option-rom            CC00:0000-CDFF:000F (8K)"));
+                Ok(())
+            },
+            Err(e) => Err(format!("Expected no problems in the memory map but got\n{}", e)),
+        }
+    }
+
+    #[test]
+    fn memmap_with_rom_overlapping_window() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        let roms = vec![PlannedRom { path: fixture_path("8k-option-rom"), segment: 0xDF000 }];
+
+        match memmap(option_rom, memmap_args(roms), 0) {
+            Ok(map) => Err(format!("Expected the overlap to be reported but got\n{}", map)),
+            Err(e) => {
+                assert!(e.to_string().contains("Janus window overlaps 8k-option-rom"));
+                Ok(())
+            },
+        }
+    }
+}
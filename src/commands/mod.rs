@@ -1,3 +1,11 @@
 pub mod process;
+mod assemble;
+mod batch;
+mod check_manifest;
+mod check_xtide;
+mod install;
+mod memmap;
+mod tui;
 mod validate;
 mod write_rom;
+mod xtide_config;
@@ -1,44 +1,101 @@
 use crate::cli::{Cli, Commands};
+use crate::code_cave;
 use crate::commands::*;
 use crate::FileHandler;
+use crate::error::Error;
+use crate::file_handler::FileLocation;
 use crate::option_rom::OptionRom;
+use crate::patch_file::PatchFile;
+use crate::rom_patch::{self, RomPatch};
 
+use assemble::assemble;
+use batch::batch;
+use check_manifest::check_manifest;
+use check_xtide::check_xtide;
+use install::{install, uninstall};
+use memmap::memmap;
+use tui::tui;
 use validate::validate;
 use write_rom::write_rom;
+use xtide_config::xtide_config;
 
-pub fn process(args: Cli) -> Result<String, String> {
-    if ! args.source_args.source_path.exists() {
-        return Err("The specified source file does not exist".into());
+pub fn process(args: Cli) -> Result<String, Error> {
+    if args.source_args.source_path != FileLocation::Stdio && ! args.source_args.source_path.exists() {
+        return Err(Error::SourceNotFound(args.source_args.source_path));
+    }
+    if args.source_args.source_path == FileLocation::Stdio && args.source_args.odd == Some(FileLocation::Stdio) {
+        return Err(Error::Failed("Standard input can only be read once, give a file for the source or --odd".into()));
     }
 
-    let bytes = match FileHandler::read_source(&args.source_args.source_path) {
-        Ok(bytes) => bytes,
-        Err(file_handler_error) => return Err(format!("{}", file_handler_error)),
-    };
+    let source_args = args.source_args;
+    // Standard input can only be read once, so commands which write the source back out are given the bytes read
+    let read = || FileHandler::read_source_pair(&source_args.source_path, source_args.odd.as_ref());
+    let load = || find_option_rom(read()?, source_args.location, source_args.scan);
+
+    match args.command {
+        Commands::Validate(validate_args) => validate(load()?.0, validate_args),
+        Commands::CheckXtide(check_xtide_args) => check_xtide(load()?.0, check_xtide_args),
+        Commands::Memmap(memmap_args) => {
+            let (option_rom, rom_start_location) = load()?;
+            memmap(option_rom, memmap_args, rom_start_location)
+        },
+        Commands::XtideConfig(xtide_config_args) => {
+            let source_bytes = read()?;
+            let (option_rom, rom_start_location) = find_option_rom(source_bytes.clone(), source_args.location, source_args.scan)?;
+            xtide_config(option_rom, xtide_config_args, &source_bytes, rom_start_location)
+        },
+        Commands::WriteRom(write_rom_args) => {
+            let source_bytes = read()?;
+            let (option_rom, rom_start_location) = find_option_rom(source_bytes.clone(), source_args.location, source_args.scan)?;
+            write_rom(option_rom, write_rom_args, source_args, &source_bytes, rom_start_location)
+        },
+        Commands::Install(install_args) => install(install_args, source_args),
+        Commands::Uninstall => uninstall(source_args),
+        Commands::Batch(batch_args) => batch(batch_args, source_args),
+        Commands::Tui(tui_args) => tui(tui_args, source_args),
+        Commands::ListPatches(list_patches_args) => {
+            let mut patches = rom_patch::built_in_patches();
+            patches.extend(load_patch_files(&list_patches_args.patch_files)?);
+            Ok(rom_patch::list(&patches, &load()?.0))
+        },
+        Commands::CodeCaves => Ok(code_cave::report(&load()?.0)),
+        Commands::Assemble(assemble_args) => assemble(assemble_args, source_args),
+        Commands::CheckManifest(check_manifest_args) => check_manifest(check_manifest_args, source_args),
+    }
+}
+
+/// Read the source file and find the option rom in it, at the location given or by scanning for it
+pub fn load_option_rom(source_path: &FileLocation, odd_path: Option<&FileLocation>, location: Option<usize>, scan: bool) -> Result<(OptionRom, usize), Error> {
+    find_option_rom(FileHandler::read_source_pair(source_path, odd_path)?, location, scan)
+}
 
-    let rom_start_location: usize = if args.source_args.scan {
-        println!("Scanning for possible option rom");
+/// Find the option rom in the bytes read from the source, at the location given or by scanning for it
+fn find_option_rom(bytes: Vec<u8>, location: Option<usize>, scan: bool) -> Result<(OptionRom, usize), Error> {
+    let rom_start_location: usize = if scan {
+        log::info!("Scanning for possible option rom");
         match OptionRom::find_option_rom_start_in_bytes(&bytes) {
             Ok(rom_start_location) => {
-                println!("Option rom located at byte {:x}", rom_start_location);
+                log::info!("Option rom located at byte {:x}", rom_start_location);
                 rom_start_location
             },
-            Err(e) => return Err(format!("{}", e)),
+            Err(e) => return Err(e.into()),
         }
     } else {
-        match args.source_args.location {
-            Some(location) => location,
-            None => 0,
-        }
-    };
-
-    let option_rom = match OptionRom::from(bytes, rom_start_location) {
-        Ok(option_rom) => option_rom,
-        Err(option_rom_error) => return Err(format!("Option rom error: {}", option_rom_error)),
+        location.unwrap_or_default()
     };
 
-    match args.command {
-        Commands::Validate {..} => validate(option_rom),
-        Commands::WriteRom(write_rom_args) => write_rom(option_rom,write_rom_args, args.source_args, rom_start_location),
+    match OptionRom::from(bytes, rom_start_location) {
+        Ok(option_rom) => Ok((option_rom, rom_start_location)),
+        Err(option_rom_error) => Err(Error::from(option_rom_error).context(|e| format!("Option rom error: {}", e))),
     }
 }
+
+/// Load the patches described in patch files
+pub fn load_patch_files(paths: &[std::path::PathBuf]) -> Result<Vec<Box<dyn RomPatch>>, Error> {
+    paths.iter()
+        .map(|path| match PatchFile::load(path) {
+            Ok(patch_file) => Ok(Box::new(patch_file) as Box<dyn RomPatch>),
+            Err(e) => Err(Error::from(e).context(|e| format!("{}: {}", path.display(), e))),
+        })
+        .collect()
+}
@@ -0,0 +1,35 @@
+use crate::FileHandler;
+use crate::cli::{SourceArgs, TuiArgs};
+use crate::error::Error;
+use crate::option_rom::{OptionRom, OptionRomError};
+use crate::tui::{self, Editor};
+
+pub fn tui(tui_args: TuiArgs, source_args: SourceArgs) -> Result<String, Error> {
+    let bytes = FileHandler::read_source_pair(&source_args.source_path, source_args.odd.as_ref())?;
+
+    let mut rom_starts = tui::find_option_roms(&bytes);
+    if let Some(location) = source_args.location {
+        if ! rom_starts.contains(&location) {
+            if let Err(e) = OptionRom::from(bytes.clone(), location) {
+                return Err(Error::from(e).context(|e| format!("Option rom error: {}", e)));
+            }
+            rom_starts.push(location);
+            rom_starts.sort();
+        }
+    }
+    if rom_starts.is_empty() {
+        return Err(OptionRomError::NoOptionRomFoundInScan.into());
+    }
+
+    let selected_rom = source_args.location
+        .and_then(|location| rom_starts.iter().position(|rom_start| *rom_start == location))
+        .unwrap_or_default();
+
+    let mut editor = Editor::new(bytes, rom_starts, selected_rom, tui_args.checksum_byte, tui_args.output_path, tui_args.force);
+    tui::run(&mut editor)?;
+
+    match editor.saved_to() {
+        Some(output_path) => Ok(format!("Rom written to {}", output_path)),
+        None => Ok("Nothing was saved".into()),
+    }
+}
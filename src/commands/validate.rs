@@ -1,39 +1,82 @@
+use crate::cli::ValidateArgs;
+use crate::error::Error;
 use crate::option_rom::{OptionRom, OptionRomError};
 
-pub fn validate(option_rom: OptionRom) -> Result<String, String> {
+pub fn validate(option_rom: OptionRom, validate_args: ValidateArgs) -> Result<String, Error> {
     match option_rom.validate_checksum() {
-        Ok(_) => Ok("Option Rom read and validated".into()),
+        Ok(option_rom) => match option_rom.checksum_byte_offset(validate_args.checksum_byte) {
+            Ok(offset) => Ok(format!("Option Rom read and validated. Patching would correct the checksum in byte {:02X} at offset 0x{:X}", option_rom.bytes[offset], offset)),
+            Err(e) => Ok(format!("Option Rom read and validated, but the checksum byte can't be changed: {}", e)),
+        },
         Err(OptionRomError::OptionRomChecksumInvalid(bad_option_rom)) => {
-            let required_checksum_byte = bad_option_rom.required_checksum_byte();
-            Err(format!("Option Rom Checksum Invalid. Requires checksum byte {:02X?}", required_checksum_byte))
+            let message = match bad_option_rom.checksum_byte_offset(validate_args.checksum_byte) {
+                Ok(offset) => {
+                    let required_checksum_byte = bad_option_rom.required_checksum_byte_at(offset);
+                    format!("Option Rom Checksum Invalid. Requires checksum byte {:02X?} at offset 0x{:X}", required_checksum_byte, offset)
+                },
+                Err(e) => format!("Option Rom Checksum Invalid and the checksum byte can't be changed: {}", e),
+            };
+            Err(Error::from(OptionRomError::OptionRomChecksumInvalid(bad_option_rom)).context(|_| message))
         },
-        Err(e) => Err(format!("{}", e)),
+        Err(e) => Err(e.into()),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::error::EXIT_BAD_CHECKSUM;
+    use crate::option_rom::ChecksumByteLocation;
     use crate::test_helpers::load_option_rom_fixture;
 
+    fn validate_args(checksum_byte: ChecksumByteLocation) -> ValidateArgs {
+        ValidateArgs { checksum_byte }
+    }
+
     #[test]
     fn validate_with_valid_rom() -> Result<(), String> {
         let option_rom = load_option_rom_fixture("pc.boot.valid")?;
 
-        match validate(option_rom) {
-            Ok(_) => Ok(()),
+        match validate(option_rom, validate_args(ChecksumByteLocation::FinalByte)) {
+            Ok(message) => {
+                assert_eq!(message, "Option Rom read and validated. Patching would correct the checksum in byte F1 at offset 0x1FFF");
+                Ok(())
+            },
             Err(e) => Err(format!("Expected Ok but got error {}", e)),
         }
     }
 
+    #[test]
+    fn validate_with_valid_rom_and_checksum_byte_in_header() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+
+        let message = validate(option_rom, validate_args(ChecksumByteLocation::Offset(1))).map_err(|e| e.to_string())?;
+        assert_eq!(message, "Option Rom read and validated, but the checksum byte can't be changed: The checksum byte at offset 0x1 is part of the Option Rom header");
+        Ok(())
+    }
+
     #[test]
     fn validate_with_bad_checksum() -> Result<(), String> {
         let option_rom = load_option_rom_fixture("pc.boot.invalid_checksum")?;
 
-        match validate(option_rom) {
+        match validate(option_rom, validate_args(ChecksumByteLocation::FinalByte)) {
+            Ok(_) => Err("Expected an error validating checksum on pc.boot.invalid but got Ok".into()),
+            Err(e) => {
+                assert_eq!(e.to_string(), "Option Rom Checksum Invalid. Requires checksum byte F1 at offset 0x1FFF");
+                assert_eq!(e.exit_code(), EXIT_BAD_CHECKSUM);
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn validate_with_bad_checksum_and_checksum_byte_in_header() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.invalid_checksum")?;
+
+        match validate(option_rom, validate_args(ChecksumByteLocation::Offset(1))) {
             Ok(_) => Err("Expected an error validating checksum on pc.boot.invalid but got Ok".into()),
-            Err(message) => {
-                assert_eq!(message, "Option Rom Checksum Invalid. Requires checksum byte F1");
+            Err(e) => {
+                assert_eq!(e.to_string(), "Option Rom Checksum Invalid and the checksum byte can't be changed: The checksum byte at offset 0x1 is part of the Option Rom header");
                 Ok(())
             },
         }
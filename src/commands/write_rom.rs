@@ -1,42 +1,221 @@
 use crate::FileHandler;
+use crate::eprom::{self, EpromWarning};
+use crate::error::Error;
+use crate::file_handler::{FileHandlerError, FileLocation};
+use crate::janus_drive_patch::JanusDriveNumber;
+use crate::manifest::{ChecksumByteRecord, FileRecord, Manifest, PatchAction, PatchRecord, RomRecord};
 use crate::option_rom::{OptionRom, OptionRomError};
-use crate::option_rom_patcher;
+use crate::option_rom_patcher::{self, SkipHddReadyCheck};
+use crate::rom_formats::RomFormat;
+use crate::rom_patch::{self, RomPatch};
 use crate::cli::{SourceArgs, WriteRomArgs};
+use crate::memory_map::{parse_segment, DEFAULT_JANUS_WINDOW};
+use crate::verify::{self, VerifyError};
 
-pub fn write_rom(option_rom: OptionRom, write_rom_args: WriteRomArgs, source_args: SourceArgs, rom_start_location: usize) -> Result<String, String> {
+use super::process::load_patch_files;
+
+pub fn write_rom(option_rom: OptionRom, write_rom_args: WriteRomArgs, source_args: SourceArgs, source_bytes: &[u8], rom_start_location: usize) -> Result<String, Error> {
     if write_rom_args.output_path.exists() && ! write_rom_args.force {
-        return Err("The output file exists and the force option was not specified".into());
+        return Err(Error::OutputExists(write_rom_args.output_path));
+    }
+    if let Some(manifest_path) = write_rom_args.manifest.as_ref().filter(|path| path.exists() && ! write_rom_args.force) {
+        return Err(Error::OutputExists(manifest_path.clone().into()));
     }
 
-    let mut option_rom = match option_rom.validate_checksum() {
-        Ok(option_rom) => option_rom,
+    let original_rom = option_rom.clone();
+
+    // The byte altered to correct the checksum, once there is something to correct
+    let (mut option_rom, mut checksum_byte_offset) = match option_rom.validate_checksum() {
+        Ok(option_rom) => (option_rom, None),
         Err(OptionRomError::OptionRomChecksumInvalid(mut bad_option_rom)) => {
             if ! write_rom_args.update_checksum {
-                let required_checksum_byte = bad_option_rom.required_checksum_byte();
-                return Err(format!("Option Rom Checksum Invalid and update_checksum was not specified. Requires checksum byte {:02X?}", required_checksum_byte));
+                let message = match bad_option_rom.checksum_byte_offset(write_rom_args.checksum_byte) {
+                    Ok(offset) => {
+                        let required_checksum_byte = bad_option_rom.required_checksum_byte_at(offset);
+                        format!("Option Rom Checksum Invalid and update_checksum was not specified. Requires checksum byte {:02X?} at offset 0x{:X}", required_checksum_byte, offset)
+                    },
+                    Err(e) => format!("Option Rom Checksum Invalid and update_checksum was not specified, and the checksum byte can't be changed: {}", e),
+                };
+                return Err(Error::from(OptionRomError::OptionRomChecksumInvalid(bad_option_rom)).context(|_| message));
+            }
+            match bad_option_rom.correct_checksum_at(write_rom_args.checksum_byte) {
+                Ok(offset) => (bad_option_rom, Some(offset)),
+                Err(e) => return Err(Error::from(e).context(|e| format!("Couldn't update the checksum: {}", e))),
             }
-            bad_option_rom.correct_checksum_in_final_byte();
-            bad_option_rom
         },
-        Err(e) => return Err(format!("Unrecoverable option rom error: {}", e)),
+        Err(e) => return Err(Error::from(e).context(|e| format!("Unrecoverable option rom error: {}", e))),
     };
 
+    // The patches used, each of which should be found in the output read back as it is in the final rom, and the bytes
+    // each changed for the manifest
+    let mut used_patches: Vec<Box<dyn RomPatch>> = Vec::new();
+    let mut patch_records: Vec<PatchRecord> = Vec::new();
+
+    let mut reverted_patches = find_patches(&write_rom_args.reverted_patches, write_rom_args.allow_low_confidence_cave)?;
+    reverted_patches.extend(load_patch_files(&write_rom_args.reverted_patch_files)?);
+    let mut patches = find_patches(&write_rom_args.patches, write_rom_args.allow_low_confidence_cave)?;
+    if let Some(unit) = write_rom_args.janus_drive {
+        patches.push(Box::new(JanusDriveNumber::new(unit)));
+    }
+    patches.extend(load_patch_files(&write_rom_args.patch_files)?);
+
+    let changes_rom = write_rom_args.patch_rom || ! reverted_patches.is_empty() || ! patches.is_empty();
+    if checksum_byte_offset.is_none() && changes_rom {
+        match option_rom.checksum_byte_offset(write_rom_args.checksum_byte) {
+            Ok(offset) => checksum_byte_offset = Some(offset),
+            Err(e) => return Err(Error::from(e).context(|e| format!("The checksum byte can't be changed: {}", e))),
+        }
+    }
+
+    for patch in reverted_patches {
+        let before = option_rom.clone();
+        option_rom = match rom_patch::revert(patch.as_ref(), &option_rom, write_rom_args.checksum_byte) {
+            Ok(option_rom) => option_rom,
+            Err(e) => return Err(Error::from(e).context(|e| format!("Failed reverting {} with error: {}", patch.name(), e))),
+        };
+        patch_records.push(PatchRecord::new(patch.name(), PatchAction::Reverted, &before, &option_rom, checksum_byte_offset));
+        used_patches.push(patch);
+    }
+
     if write_rom_args.patch_rom {
-        option_rom = match option_rom_patcher::patch_rom(&option_rom) {
+        let before = option_rom.clone();
+        option_rom = match option_rom_patcher::patch_rom(&option_rom, write_rom_args.checksum_byte) {
+            Ok(option_rom) => option_rom,
+            Err(e) => return Err(Error::from(e).context(|e| format!("Failed patching ROM with error: {}", e))),
+        };
+        patch_records.push(PatchRecord::new(SkipHddReadyCheck.name(), PatchAction::Applied, &before, &option_rom, checksum_byte_offset));
+        used_patches.push(Box::new(SkipHddReadyCheck));
+    }
+
+    for patch in patches {
+        let before = option_rom.clone();
+        option_rom = match rom_patch::apply(patch.as_ref(), &option_rom, write_rom_args.checksum_byte) {
             Ok(option_rom) => option_rom,
-            Err(e) => return Err(format!("Failed patching ROM with error: {}", e)),
+            Err(e) => return Err(Error::from(e).context(|e| format!("Failed applying {} with error: {}", patch.name(), e))),
         };
+        patch_records.push(PatchRecord::new(patch.name(), PatchAction::Applied, &before, &option_rom, checksum_byte_offset));
+        used_patches.push(patch);
     }
 
-    if write_rom_args.rom_only {
-        match FileHandler::write_rom_only(&write_rom_args.output_path, option_rom) {
-            Ok(..) => Ok(format!("Rom written to {}", write_rom_args.output_path.display())),
-            Err(e) => Err(format!("{}", e)),
+    let backup_message = if write_rom_args.backup && write_rom_args.output_path.exists() {
+        match FileHandler::backup(&write_rom_args.output_path) {
+            Ok(backup) => format!("{}\n", backup),
+            Err(e) => return Err(Error::from(e).context(|e| format!("Couldn't back up the output file: {}", e))),
         }
     } else {
-        match FileHandler::write_rom_in_file(&source_args.source_path, &write_rom_args.output_path, option_rom, rom_start_location) {
-            Ok(..) => Ok(format!("Rom written to {}", write_rom_args.output_path.display())),
-            Err(e) => Err(format!("{}", e)),
+        String::new()
+    };
+
+    let mut eprom_warnings: Vec<EpromWarning> = Vec::new();
+    // Each write gives the whole output as one image and where the rom is in it, for the verification
+    let result = if write_rom_args.odd_output.is_some() || (source_args.odd.is_some() && ! write_rom_args.rom_only) {
+        let rom_offset = if write_rom_args.rom_only { 0 } else { rom_start_location };
+        write_interleaved(option_rom.clone(), &write_rom_args, source_bytes, rom_start_location).map(|bytes| (bytes, rom_offset))
+    } else if write_rom_args.rom_only {
+        // pc.boot is loaded at the start of the Janus window, so the rom is at the same offset into the window as the file
+        let load_address = match write_rom_args.load_address {
+            Some(load_address) => load_address,
+            None => parse_segment(DEFAULT_JANUS_WINDOW).map_err(Error::Failed)? + rom_start_location as u32,
+        };
+
+        match write_rom_args.eprom {
+            Some(eprom_type) => match eprom::fill_eprom(&option_rom.bytes, eprom_type, write_rom_args.fill, load_address) {
+                Ok((eprom_bytes, eprom_start, warnings)) => {
+                    eprom_warnings = warnings;
+                    FileHandler::write_image(&write_rom_args.output_path, &eprom_bytes, write_rom_args.format, eprom_start)
+                        .map(|_| (eprom_bytes, (load_address - eprom_start) as usize))
+                },
+                Err(e) => return Err(e.into()),
+            },
+            None => FileHandler::write_rom_only(&write_rom_args.output_path, option_rom.clone(), write_rom_args.format, load_address)
+                .map(|_| (option_rom.bytes.clone(), 0)),
         }
+    } else {
+        FileHandler::write_rom_in_file(source_bytes, &write_rom_args.output_path, option_rom.clone(), rom_start_location, write_rom_args.write_policy)
+            .map(|bytes| (bytes, rom_start_location))
+    };
+
+    let warning_message: String = eprom_warnings.iter().map(|warning| format!("Warning: {}\n", warning)).collect();
+
+    let (written_bytes, rom_offset) = result?;
+    let to_stdout = write_rom_args.output_path == FileLocation::Stdio || write_rom_args.odd_output == Some(FileLocation::Stdio);
+    if to_stdout && ! write_rom_args.no_verify {
+        log::info!("Not verifying the output as standard output can't be read back");
+    } else if ! write_rom_args.no_verify {
+        let used_patches: Vec<&dyn RomPatch> = used_patches.iter().map(|patch| patch.as_ref()).collect();
+        // Only the rom is written with --rom-only, the rest of the output is the EPROM fill or nothing
+        let source_bytes = if write_rom_args.rom_only { None } else { Some(source_bytes) };
+        verify::verify_output(&read_back(&write_rom_args)?, &written_bytes, source_bytes, rom_offset, &option_rom, &used_patches)?;
+        log::info!("Verified {} by reading it back", write_rom_args.output_path);
+    }
+
+    let manifest_message = match &write_rom_args.manifest {
+        Some(manifest_path) => {
+            let checksum_byte = checksum_byte_offset.map(|offset| ChecksumByteRecord {
+                offset,
+                original: original_rom.bytes[offset],
+                new: option_rom.bytes[offset],
+            });
+            let rom = RomRecord { offset: rom_offset, size: option_rom.rom_size_in_bytes, checksum_byte };
+            let manifest = Manifest::new(
+                FileRecord::new(source_args.source_path.to_string(), source_bytes),
+                FileRecord::new(write_rom_args.output_path.to_string(), &written_bytes),
+                rom,
+                patch_records,
+            );
+            if let Err(e) = std::fs::write(manifest_path, manifest.to_json() + "\n") {
+                return Err(Error::from(e).context(|e| format!("Couldn't write the manifest {}: {}", manifest_path.display(), e)));
+            }
+            format!("\nManifest written to {}", manifest_path.display())
+        },
+        None => String::new(),
+    };
+
+    let message = match &write_rom_args.odd_output {
+        Some(odd_output_path) => format!("{}Rom written to {} (even) and {} (odd){}", backup_message, write_rom_args.output_path, odd_output_path, manifest_message),
+        None => format!("{}{}Rom written to {}{}", backup_message, warning_message, write_rom_args.output_path, manifest_message),
+    };
+
+    // Standard output holds the rom, so the message goes to the log instead
+    if to_stdout {
+        log::info!("{}", message);
+        return Ok(String::new());
     }
+    Ok(message)
+}
+
+/// Write the output when the source or the output is an even/odd pair of chips, either merged into one image or split
+/// between two
+fn write_interleaved(option_rom: OptionRom, write_rom_args: &WriteRomArgs, source_bytes: &[u8], rom_start_location: usize) -> Result<Vec<u8>, FileHandlerError> {
+    let output_bytes = if write_rom_args.rom_only {
+        option_rom.bytes
+    } else {
+        FileHandler::place_rom_in_bytes(source_bytes, option_rom, rom_start_location, write_rom_args.write_policy)?
+    };
+
+    match &write_rom_args.odd_output {
+        Some(odd_output_path) => FileHandler::write_interleaved(&write_rom_args.output_path, odd_output_path, &output_bytes)?,
+        None => FileHandler::write_output(&write_rom_args.output_path, &output_bytes)?,
+    }
+    Ok(output_bytes)
+}
+
+/// The output as one image, merging the pair of chips back together when it was split
+fn read_back(write_rom_args: &WriteRomArgs) -> Result<Vec<u8>, VerifyError> {
+    let format = if write_rom_args.rom_only { write_rom_args.format } else { RomFormat::Binary };
+    let even = FileHandler::read_written(&write_rom_args.output_path, format).map_err(VerifyError::CouldntReadBack)?;
+
+    match &write_rom_args.odd_output {
+        Some(odd_output_path) => {
+            let odd = FileHandler::read_written(odd_output_path, RomFormat::Binary).map_err(VerifyError::CouldntReadBack)?;
+            eprom::interleave(&even, &odd).map_err(|e| VerifyError::CouldntReadBack(FileHandlerError::Eprom(e)))
+        },
+        None => Ok(even),
+    }
+}
+
+fn find_patches(names: &[String], allow_low_confidence_cave: bool) -> Result<Vec<Box<dyn RomPatch>>, Error> {
+    names.iter()
+        .map(|name| rom_patch::find_patch_allowing(name, allow_low_confidence_cave).ok_or_else(|| Error::Failed(format!("{} isn't a known patch", name))))
+        .collect()
 }
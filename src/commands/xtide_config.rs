@@ -0,0 +1,70 @@
+use crate::FileHandler;
+use crate::cli::XtideConfigArgs;
+use crate::error::Error;
+use crate::file_handler::{FileLocation, WritePolicy};
+use crate::option_rom::OptionRom;
+use crate::xtide_romvars::RomVars;
+
+pub fn xtide_config(mut option_rom: OptionRom, xtide_config_args: XtideConfigArgs, source_bytes: &[u8], rom_start_location: usize) -> Result<String, Error> {
+    let mut rom_vars = RomVars::read(&option_rom)?;
+
+    let output_path = match xtide_config_args.output_path {
+        Some(output_path) => output_path,
+        None => return Ok(format!("{}", rom_vars)),
+    };
+
+    if output_path.exists() && ! xtide_config_args.force {
+        return Err(Error::OutputExists(output_path));
+    }
+
+    for (setting, value) in xtide_config_args.settings {
+        rom_vars.set(&setting, &value)?;
+    }
+
+    rom_vars.write(&mut option_rom)?;
+
+    // An even/odd pair is written merged into one image, ready to be split again with write-rom --odd-output
+    let output_bytes = FileHandler::place_rom_in_bytes(source_bytes, option_rom, rom_start_location, WritePolicy::Strict)?;
+    FileHandler::write_output(&output_path, &output_bytes)?;
+
+    // Standard output holds the rom, so the settings go to the log instead
+    let message = format!("{}\nRom written to {}", rom_vars, output_path);
+    if output_path == FileLocation::Stdio {
+        log::info!("{}", message);
+        return Ok(String::new());
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_xtide_rom, create_temp_dir};
+    use crate::xtide::FLG_ROMVARS_MODULE_VERY_LATE_INIT;
+
+    #[test]
+    fn xtide_config_changes_settings_in_output() -> Result<(), String> {
+        let tempdir = create_temp_dir()?;
+        let directory = tempdir.into_path();
+        let output_path = directory.join("xtide.new.bin");
+
+        let xtide_bytes = build_xtide_rom("-=XTIDE Universal BIOS (XT)=-", "v2.0.0B3+ r625", FLG_ROMVARS_MODULE_VERY_LATE_INIT);
+
+        let option_rom = OptionRom::from(xtide_bytes.clone(), 0).unwrap();
+        let xtide_config_args = XtideConfigArgs {
+            settings: vec![("controllers".into(), "1".into()), ("ide0.port".into(), "0x300".into())],
+            output_path: Some(output_path.clone().into()),
+            force: false,
+        };
+
+        if let Err(e) = xtide_config(option_rom, xtide_config_args, &xtide_bytes, 0) {
+            return Err(format!("Expected Ok writing the XTIDE config but got {}", e));
+        }
+
+        let written_rom = OptionRom::from(std::fs::read(&output_path).unwrap(), 0).unwrap();
+        let rom_vars = RomVars::read(&written_rom).unwrap();
+        assert_eq!(rom_vars.controllers[0].base_port, 0x300);
+        assert!(written_rom.validate_checksum().is_ok());
+        Ok(())
+    }
+}
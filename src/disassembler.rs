@@ -0,0 +1,316 @@
+//! A minimal 16-bit x86 (8086/80186) instruction decoder.
+//!
+//! This only decodes enough of each instruction to know how long it is and where execution can go next, which is all
+//! we need to tell code apart from data and padding inside an option rom.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    /// Execution continues with the next instruction
+    Sequential,
+    /// An unconditional jump to the offset
+    Jump(usize),
+    /// A conditional jump to the offset, execution may also continue with the next instruction
+    Branch(usize),
+    /// A call to the offset, execution continues with the next instruction when it returns
+    Call(usize),
+    /// Execution returns to the caller
+    Return,
+    /// Execution goes somewhere we can't follow (indirect or far jumps, HLT)
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub length: usize,
+    pub flow: Flow,
+}
+
+impl Instruction {
+    pub fn end(&self) -> usize {
+        self.offset + self.length
+    }
+}
+
+const SEGMENT_OVERRIDE_PREFIXES: [u8; 4] = [0x26, 0x2e, 0x36, 0x3e];
+const LOCK_AND_REP_PREFIXES: [u8; 3] = [0xf0, 0xf2, 0xf3];
+const MAX_PREFIXES: usize = 4;
+
+/// Decode the instruction starting at offset, returning None if it is invalid or runs past the end of the bytes.
+///
+/// Two zero bytes (ADD [BX+SI], AL) are treated as invalid since they almost always mean we have wandered into zeroed
+/// memory rather than real code.
+pub fn decode(bytes: &[u8], offset: usize) -> Option<Instruction> {
+    let mut position = offset;
+
+    while position - offset < MAX_PREFIXES {
+        let byte = *bytes.get(position)?;
+        if ! (SEGMENT_OVERRIDE_PREFIXES.contains(&byte) || LOCK_AND_REP_PREFIXES.contains(&byte)) {
+            break;
+        }
+        position += 1;
+    }
+
+    let opcode = *bytes.get(position)?;
+    position += 1;
+
+    let (operand_length, flow) = match opcode {
+        0x00 if bytes.get(position) == Some(&0x00) => return None,
+
+        // ALU operations on r/m and registers
+        0x00..=0x3f if opcode & 0x07 <= 0x03 => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        0x00..=0x3f if opcode & 0x07 == 0x04 => (1, FlowKind::Sequential),
+        0x00..=0x3f if opcode & 0x07 == 0x05 => (2, FlowKind::Sequential),
+        // PUSH/POP segment registers, DAA, DAS, AAA, AAS
+        0x06 | 0x07 | 0x0e | 0x16 | 0x17 | 0x1e | 0x1f | 0x27 | 0x2f | 0x37 | 0x3f => (0, FlowKind::Sequential),
+        // INC, DEC, PUSH, POP registers, PUSHA, POPA
+        0x40..=0x61 => (0, FlowKind::Sequential),
+        // BOUND
+        0x62 => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        // PUSH imm16, IMUL r, r/m, imm16, PUSH imm8, IMUL r, r/m, imm8
+        0x68 => (2, FlowKind::Sequential),
+        0x69 => (modrm_length(bytes, position)? + 2, FlowKind::Sequential),
+        0x6a => (1, FlowKind::Sequential),
+        0x6b => (modrm_length(bytes, position)? + 1, FlowKind::Sequential),
+        // INS, OUTS
+        0x6c..=0x6f => (0, FlowKind::Sequential),
+        // Jcc rel8
+        0x70..=0x7f => (1, FlowKind::Branch8),
+        // Immediate group
+        0x80 | 0x82 | 0x83 => (modrm_length(bytes, position)? + 1, FlowKind::Sequential),
+        0x81 => (modrm_length(bytes, position)? + 2, FlowKind::Sequential),
+        // TEST, XCHG, MOV, LEA, POP r/m
+        0x84..=0x8f => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        // NOP, XCHG AX, CBW, CWD
+        0x90..=0x99 => (0, FlowKind::Sequential),
+        // CALL far ptr16:16, execution continues after the call
+        0x9a => (4, FlowKind::Sequential),
+        // WAIT, PUSHF, POPF, SAHF, LAHF
+        0x9b..=0x9f => (0, FlowKind::Sequential),
+        // MOV AL/AX to and from moffs16
+        0xa0..=0xa3 => (2, FlowKind::Sequential),
+        // String operations
+        0xa4..=0xa7 | 0xaa..=0xaf => (0, FlowKind::Sequential),
+        // TEST AL/AX, imm
+        0xa8 => (1, FlowKind::Sequential),
+        0xa9 => (2, FlowKind::Sequential),
+        // MOV reg, imm
+        0xb0..=0xb7 => (1, FlowKind::Sequential),
+        0xb8..=0xbf => (2, FlowKind::Sequential),
+        // Shift group with imm8
+        0xc0 | 0xc1 => (modrm_length(bytes, position)? + 1, FlowKind::Sequential),
+        // RET imm16, RET
+        0xc2 => (2, FlowKind::Return),
+        0xc3 => (0, FlowKind::Return),
+        // LES, LDS
+        0xc4 | 0xc5 => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        // MOV r/m, imm
+        0xc6 => (modrm_length(bytes, position)? + 1, FlowKind::Sequential),
+        0xc7 => (modrm_length(bytes, position)? + 2, FlowKind::Sequential),
+        // ENTER, LEAVE
+        0xc8 => (3, FlowKind::Sequential),
+        0xc9 => (0, FlowKind::Sequential),
+        // RETF imm16, RETF
+        0xca => (2, FlowKind::Return),
+        0xcb => (0, FlowKind::Return),
+        // INT3, INT imm8, INTO
+        0xcc => (0, FlowKind::Sequential),
+        0xcd => (1, FlowKind::Sequential),
+        0xce => (0, FlowKind::Sequential),
+        // IRET
+        0xcf => (0, FlowKind::Return),
+        // Shift group by 1 or CL
+        0xd0..=0xd3 => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        // AAM, AAD
+        0xd4 | 0xd5 => (1, FlowKind::Sequential),
+        // SALC, XLAT
+        0xd6 | 0xd7 => (0, FlowKind::Sequential),
+        // FPU escape
+        0xd8..=0xdf => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        // LOOPNZ, LOOPZ, LOOP, JCXZ
+        0xe0..=0xe3 => (1, FlowKind::Branch8),
+        // IN, OUT with imm8 port
+        0xe4..=0xe7 => (1, FlowKind::Sequential),
+        // CALL rel16, JMP rel16, JMP far ptr16:16, JMP rel8
+        0xe8 => (2, FlowKind::Call16),
+        0xe9 => (2, FlowKind::Jump16),
+        0xea => (4, FlowKind::Stop),
+        0xeb => (1, FlowKind::Jump8),
+        // IN, OUT with DX port
+        0xec..=0xef => (0, FlowKind::Sequential),
+        // HLT
+        0xf4 => (0, FlowKind::Stop),
+        // CMC
+        0xf5 => (0, FlowKind::Sequential),
+        // Unary group, TEST has an immediate
+        0xf6 => (modrm_length(bytes, position)? + if modrm_reg(bytes, position)? <= 1 { 1 } else { 0 }, FlowKind::Sequential),
+        0xf7 => (modrm_length(bytes, position)? + if modrm_reg(bytes, position)? <= 1 { 2 } else { 0 }, FlowKind::Sequential),
+        // CLC, STC, CLI, STI, CLD, STD
+        0xf8..=0xfd => (0, FlowKind::Sequential),
+        // INC/DEC r/m8
+        0xfe if modrm_reg(bytes, position)? <= 1 => (modrm_length(bytes, position)?, FlowKind::Sequential),
+        // INC, DEC, CALL, CALL far, JMP, JMP far, PUSH r/m16
+        0xff => match modrm_reg(bytes, position)? {
+            0..=3 | 6 => (modrm_length(bytes, position)?, FlowKind::Sequential),
+            4 | 5 => (modrm_length(bytes, position)?, FlowKind::Stop),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let length = position - offset + operand_length;
+    if offset + length > bytes.len() {
+        return None;
+    }
+
+    let next_instruction = offset + length;
+    let flow = match flow {
+        FlowKind::Sequential => Flow::Sequential,
+        FlowKind::Return => Flow::Return,
+        FlowKind::Stop => Flow::Stop,
+        FlowKind::Branch8 => Flow::Branch(relative_target(next_instruction, bytes[next_instruction - 1] as i8 as i32)),
+        FlowKind::Jump8 => Flow::Jump(relative_target(next_instruction, bytes[next_instruction - 1] as i8 as i32)),
+        FlowKind::Jump16 => Flow::Jump(relative_target(next_instruction, read_i16(bytes, next_instruction - 2))),
+        FlowKind::Call16 => Flow::Call(relative_target(next_instruction, read_i16(bytes, next_instruction - 2))),
+    };
+
+    Some(Instruction { offset, length, flow })
+}
+
+/// Follow every path of execution from the entry points, returning each instruction reached sorted by offset.
+///
+/// Code that is only reached through interrupt vectors or indirect jumps won't be found.
+pub fn trace_reachable(bytes: &[u8], entry_points: &[usize]) -> Vec<Instruction> {
+    let mut visited = vec![false; bytes.len()];
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut pending: Vec<usize> = entry_points.to_vec();
+
+    while let Some(mut offset) = pending.pop() {
+        while offset < bytes.len() && ! visited[offset] {
+            let instruction = match decode(bytes, offset) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+
+            visited[offset] = true;
+            instructions.push(instruction);
+
+            match instruction.flow {
+                Flow::Sequential => {},
+                Flow::Branch(target) | Flow::Call(target) => pending.push(target),
+                Flow::Jump(target) => {
+                    pending.push(target);
+                    break;
+                },
+                Flow::Return | Flow::Stop => break,
+            }
+
+            offset = instruction.end();
+        }
+    }
+
+    instructions.sort_by_key(|instruction| instruction.offset);
+    instructions
+}
+
+/// A map with an entry per byte which is true when the byte is part of a reachable instruction
+pub fn code_map(bytes: &[u8], entry_points: &[usize]) -> Vec<bool> {
+    let mut map = vec![false; bytes.len()];
+
+    for instruction in trace_reachable(bytes, entry_points) {
+        map[instruction.offset..instruction.end()].iter_mut().for_each(|is_code| *is_code = true);
+    }
+
+    map
+}
+
+enum FlowKind {
+    Sequential,
+    Branch8,
+    Jump8,
+    Jump16,
+    Call16,
+    Return,
+    Stop,
+}
+
+fn modrm_reg(bytes: &[u8], position: usize) -> Option<u8> {
+    Some((bytes.get(position)? >> 3) & 0x07)
+}
+
+/// The length of the ModRM byte and any displacement that follows it
+fn modrm_length(bytes: &[u8], position: usize) -> Option<usize> {
+    let modrm = *bytes.get(position)?;
+
+    let displacement_length = match (modrm >> 6, modrm & 0x07) {
+        (0b00, 0b110) => 2,
+        (0b00, _) => 0,
+        (0b01, _) => 1,
+        (0b10, _) => 2,
+        _ => 0,
+    };
+
+    Some(1 + displacement_length)
+}
+
+fn read_i16(bytes: &[u8], position: usize) -> i32 {
+    i16::from_le_bytes([bytes[position], bytes[position + 1]]) as i32
+}
+
+/// Relative jumps wrap around within the 64K code segment
+fn relative_target(next_instruction: usize, displacement: i32) -> usize {
+    ((next_instruction as i32 + displacement) & 0xffff) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_lengths() {
+        let cases: Vec<(Vec<u8>, usize)> = vec![
+            (vec![0xfa], 1),                                 // CLI
+            (vec![0xb8, 0x24, 0x00], 3),                     // MOV AX, 0x24
+            (vec![0x26, 0x39, 0x06, 0x08, 0x00], 5),         // CMP ES:[0x8], AX
+            (vec![0x26, 0xc7, 0x06, 0x2e, 0x00, 0x24, 0x00], 7), // MOV WORD ES:[0x2e], 0x24
+            (vec![0x8b, 0x46, 0x02], 3),                     // MOV AX, [BP+2]
+            (vec![0xf6, 0xc4, 0x80], 3),                     // TEST AH, 0x80
+            (vec![0xf7, 0xf3], 2),                           // DIV BX
+            (vec![0x9a, 0x00, 0x00, 0x00, 0xf0], 5),         // CALL F000:0000
+        ];
+
+        for (bytes, expected_length) in cases {
+            match decode(&bytes, 0) {
+                Some(instruction) => assert_eq!(instruction.length, expected_length, "Decoding {:02X?}", bytes),
+                None => panic!("Failed to decode {:02X?}", bytes),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_flow() {
+        assert_eq!(decode(&[0x90, 0x72, 0x07], 1).map(|i| i.flow), Some(Flow::Branch(0x0a)));
+        assert_eq!(decode(&[0xeb, 0xfe], 0).map(|i| i.flow), Some(Flow::Jump(0x00)));
+        assert_eq!(decode(&[0xe8, 0x7b, 0x14], 0).map(|i| i.flow), Some(Flow::Call(0x147e)));
+        assert_eq!(decode(&[0xe9, 0x0b, 0x01], 0).map(|i| i.flow), Some(Flow::Jump(0x10e)));
+        assert_eq!(decode(&[0xc3], 0).map(|i| i.flow), Some(Flow::Return));
+        assert_eq!(decode(&[0xff, 0xe0], 0).map(|i| i.flow), Some(Flow::Stop));
+    }
+
+    #[test]
+    fn test_decode_invalid_or_truncated() {
+        assert_eq!(decode(&[0x00, 0x00], 0), None);
+        assert_eq!(decode(&[0xb8, 0x24], 0), None);
+        assert_eq!(decode(&[0x0f, 0x01], 0), None);
+        assert_eq!(decode(&[0xff, 0xf8], 0), None);
+    }
+
+    #[test]
+    fn test_trace_reachable_follows_jumps_and_skips_data() {
+        // JMP over two data bytes to a CALL, which returns to a RET
+        let bytes = vec![0xeb, 0x02, 0x61, 0x61, 0xe8, 0x01, 0x00, 0xc3, 0x90, 0xc3];
+        let map = code_map(&bytes, &[0]);
+
+        assert_eq!(map, vec![true, true, false, false, true, true, true, true, true, true]);
+    }
+}
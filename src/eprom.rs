@@ -0,0 +1,203 @@
+//! Laying rom images out for EPROMs. An option rom is usually smaller than the chip it goes on, so it is padded or
+//! mirrored to fill it. 16 bit boards such as the A2286 and A2386 Bridgeboards keep their BIOS in an even/odd pair of
+//! chips, with the even addressed bytes in one chip and the odd addressed bytes in the other.
+
+use std::fmt;
+
+use crate::memory_map::{format_segment, OPTION_ROM_ALIGNMENT, OPTION_ROM_SCAN_END, OPTION_ROM_SCAN_START};
+
+/// The value of erased EPROM, used for padding
+const ERASED_BYTE: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum EpromType {
+    #[value(name = "2764")]
+    Eprom2764,
+    #[value(name = "27128")]
+    Eprom27128,
+    #[value(name = "27256")]
+    Eprom27256,
+    #[value(name = "27512")]
+    Eprom27512,
+}
+
+impl EpromType {
+    pub fn size(&self) -> usize {
+        match self {
+            EpromType::Eprom2764 => 0x2000,
+            EpromType::Eprom27128 => 0x4000,
+            EpromType::Eprom27256 => 0x8000,
+            EpromType::Eprom27512 => 0x10000,
+        }
+    }
+}
+
+/// What fills the rest of the chip
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum EpromFill {
+    /// Erased EPROM (FF), the rom only appears once
+    Ff,
+    /// Copies of the rom, so it is seen whichever of the upper address lines the socket leaves unconnected
+    Mirror,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EpromError {
+    ChipSizesDiffer { even_length: usize, odd_length: usize },
+    RomDoesntFit { rom_end: usize, eprom_size: usize },
+}
+
+/// Reasons the rom on the chip might not be seen where it was meant to be
+#[derive(Debug, PartialEq)]
+pub enum EpromWarning {
+    NotOnOptionRomBoundary(u32),
+    OutsideOptionRomScan(u32),
+    MirroredCopies(Vec<u32>),
+}
+
+impl fmt::Display for EpromWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EpromWarning::NotOnOptionRomBoundary(address) => write!(f, "{} isn't on a 2K boundary so the BIOS won't find the rom there", format_segment(*address)),
+            EpromWarning::OutsideOptionRomScan(address) => write!(f, "{} is outside C800-EFFF where the BIOS looks for option roms", format_segment(*address)),
+            EpromWarning::MirroredCopies(addresses) => {
+                let addresses: Vec<String> = addresses.iter().map(|address| format_segment(*address)).collect();
+                write!(f, "If the socket decodes every address line the BIOS will also find the mirrored rom at {}", addresses.join(", "))
+            },
+        }
+    }
+}
+
+impl fmt::Display for EpromError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EpromError::ChipSizesDiffer { even_length, odd_length } => write!(
+                f, "The even chip is 0x{:X} bytes but the odd chip is 0x{:X} bytes, they should be dumps of the same size of chip",
+                even_length, odd_length,
+            ),
+            EpromError::RomDoesntFit { rom_end, eprom_size } => write!(
+                f, "The rom would end at offset 0x{:X} in the 0x{:X} byte EPROM, it needs a bigger EPROM or a load address nearer the start of one",
+                rom_end, eprom_size,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EpromError {}
+
+/// The contents of an EPROM holding the rom so that it is at load_address, as a chip's address lines are the low
+/// address lines of the PC the rom is at load_address modulo the size of the EPROM. Also returns the address of the
+/// start of the EPROM, and anything which might stop the rom being seen at load_address
+pub fn fill_eprom(rom: &[u8], eprom_type: EpromType, fill: EpromFill, load_address: u32) -> Result<(Vec<u8>, u32, Vec<EpromWarning>), EpromError> {
+    let eprom_size = eprom_type.size();
+    let offset = load_address as usize % eprom_size;
+    let eprom_start = load_address - offset as u32;
+
+    if offset + rom.len() > eprom_size {
+        return Err(EpromError::RomDoesntFit { rom_end: offset + rom.len(), eprom_size });
+    }
+
+    let mut warnings: Vec<EpromWarning> = Vec::new();
+    if ! load_address.is_multiple_of(OPTION_ROM_ALIGNMENT) {
+        warnings.push(EpromWarning::NotOnOptionRomBoundary(load_address));
+    }
+    if ! (OPTION_ROM_SCAN_START..OPTION_ROM_SCAN_END).contains(&load_address) {
+        warnings.push(EpromWarning::OutsideOptionRomScan(load_address));
+    }
+
+    let bytes: Vec<u8> = match fill {
+        EpromFill::Ff => {
+            let mut bytes = vec![ERASED_BYTE; eprom_size];
+            bytes[offset..offset + rom.len()].copy_from_slice(rom);
+            bytes
+        },
+        EpromFill::Mirror if rom.is_empty() => vec![ERASED_BYTE; eprom_size],
+        EpromFill::Mirror => {
+            // An unconnected address line repeats the chip every power of two, so a rom of any other size is padded
+            // to the next one. The chip size is a power of two the rom fits in, so the copies fill it exactly
+            let period = rom.len().next_power_of_two();
+            let copies: Vec<u32> = (0..eprom_size / period)
+                .map(|copy| eprom_start + ((offset + copy * period) % eprom_size) as u32)
+                .filter(|address| *address != load_address)
+                .collect();
+            if ! copies.is_empty() {
+                warnings.push(EpromWarning::MirroredCopies(copies));
+            }
+
+            (0..eprom_size).map(|index| rom.get((index + eprom_size - offset) % period).copied().unwrap_or(ERASED_BYTE)).collect()
+        },
+    };
+
+    Ok((bytes, eprom_start, warnings))
+}
+
+/// Merge the dumps of an even/odd pair of chips into one image
+pub fn interleave(even: &[u8], odd: &[u8]) -> Result<Vec<u8>, EpromError> {
+    if even.len() != odd.len() {
+        return Err(EpromError::ChipSizesDiffer { even_length: even.len(), odd_length: odd.len() });
+    }
+
+    Ok(even.iter().zip(odd).flat_map(|(even_byte, odd_byte)| [*even_byte, *odd_byte]).collect())
+}
+
+/// Split an image into the contents of the even and odd chips
+pub fn deinterleave(bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let even: Vec<u8> = bytes.iter().step_by(2).copied().collect();
+    let mut odd: Vec<u8> = bytes.iter().skip(1).step_by(2).copied().collect();
+    odd.resize(even.len(), ERASED_BYTE);
+
+    (even, odd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::option_rom::OptionRom;
+    use crate::test_helpers::load_fixture;
+
+    #[test]
+    fn test_interleave_and_deinterleave() -> Result<(), String> {
+        let image = load_fixture("pc.boot.8k-in-middle")?;
+        let (even, odd) = deinterleave(&image);
+
+        assert_eq!(even.len(), image.len() / 2);
+        // A single chip doesn't have a rom header at the rom's offset, the pair does
+        assert_ne!(OptionRom::find_option_rom_start_in_bytes(&even), Ok(0x1000));
+        let merged = interleave(&even, &odd).map_err(|e| format!("{}", e))?;
+        assert_eq!(OptionRom::find_option_rom_start_in_bytes(&merged), Ok(0x1000));
+        assert_eq!(merged, image);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_eprom() {
+        let rom = [0x55, 0xAA, 0x01, 0xCB];
+
+        let (bytes, eprom_start, warnings) = fill_eprom(&rom, EpromType::Eprom27128, EpromFill::Ff, 0xCA000).unwrap();
+        assert_eq!((bytes.len(), eprom_start, warnings), (0x4000, 0xC8000, vec![]));
+        assert_eq!(bytes[0x2000..0x2004], rom);
+        assert!(bytes[..0x2000].iter().chain(&bytes[0x2004..]).all(|byte| *byte == 0xFF));
+
+        let (bytes, _, warnings) = fill_eprom(&rom, EpromType::Eprom2764, EpromFill::Mirror, 0xD0004).unwrap();
+        assert_eq!(bytes[0x4..0x8], rom);
+        assert_eq!(bytes[0x1FFC..], rom);
+        assert_eq!(warnings[0], EpromWarning::NotOnOptionRomBoundary(0xD0004));
+        assert_eq!(warnings.len(), 2);
+
+        // A 6K rom is mirrored every 8K, padded with FF
+        let rom: Vec<u8> = [0x55, 0xAA, 0x0C].into_iter().chain(std::iter::repeat_n(0x00, 0x17FD)).collect();
+        let (bytes, _, warnings) = fill_eprom(&rom, EpromType::Eprom27128, EpromFill::Mirror, 0xC8000).unwrap();
+        assert_eq!(bytes[..0x1800], rom[..]);
+        assert_eq!(bytes[0x2000..0x3800], rom[..]);
+        assert!(bytes[0x1800..0x2000].iter().chain(&bytes[0x3800..]).all(|byte| *byte == 0xFF));
+        assert_eq!(warnings, vec![EpromWarning::MirroredCopies(vec![0xCA000])]);
+
+        assert_eq!(fill_eprom(&[0u8; 0x2000], EpromType::Eprom27256, EpromFill::Ff, 0xF7000), Err(EpromError::RomDoesntFit { rom_end: 0x9000, eprom_size: 0x8000 }));
+    }
+
+    #[test]
+    fn test_odd_lengths() {
+        assert_eq!(deinterleave(&[1, 2, 3]), (vec![1, 3], vec![2, 0xFF]));
+        assert_eq!(interleave(&[1, 3], &[2]), Err(EpromError::ChipSizesDiffer { even_length: 2, odd_length: 1 }));
+    }
+}
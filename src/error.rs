@@ -0,0 +1,241 @@
+//! The error every command returns. It keeps the error it came from, so the cause can be followed with source(), and
+//! decides the exit code, so scripts can tell e.g. a bad checksum apart from a missing file.
+
+use std::fmt;
+
+use crate::assembler::AssemblerError;
+use crate::eprom::EpromError;
+use crate::file_handler::{FileHandlerError, FileLocation};
+use crate::installer::InstallerError;
+use crate::option_rom::OptionRomError;
+use crate::option_rom_patcher::OptionRomPatcherError;
+use crate::manifest::ManifestError;
+use crate::patch_file::PatchFileError;
+use crate::verify::VerifyError;
+use crate::xtide_romvars::RomVarsError;
+
+/// Any other failure, including problems reported by memmap and check-xtide. 2 is used by the argument parser for
+/// invalid arguments
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_SOURCE_NOT_FOUND: i32 = 3;
+/// No option rom header was found, or the rom is cut short by the end of the file
+pub const EXIT_NO_ROM_FOUND: i32 = 4;
+pub const EXIT_BAD_CHECKSUM: i32 = 5;
+/// The code the patch changes couldn't be found, so the rom can't be patched
+pub const EXIT_PATCH_SITE_NOT_FOUND: i32 = 6;
+/// The output exists and would be overwritten
+pub const EXIT_OUTPUT_EXISTS: i32 = 7;
+/// A file couldn't be read or written
+pub const EXIT_IO_FAILURE: i32 = 8;
+/// The output read back isn't what was written, or a file doesn't match its manifest
+pub const EXIT_VERIFY_FAILED: i32 = 9;
+
+#[derive(Debug)]
+pub enum Error {
+    SourceNotFound(FileLocation),
+    OutputExists(FileLocation),
+    /// The rom doesn't contain the code the patch expects, so it isn't a pc.boot this can patch
+    UnknownPatchState(FileLocation),
+    OptionRom(OptionRomError),
+    Patcher(OptionRomPatcherError),
+    FileHandler(FileHandlerError),
+    Installer(InstallerError),
+    RomVars(RomVarsError),
+    Eprom(EpromError),
+    PatchFile(PatchFileError),
+    Assembler(AssemblerError),
+    Verify(VerifyError),
+    Manifest(ManifestError),
+    Io(std::io::Error),
+    /// A problem with nothing underneath it, such as a conflict in the memory map
+    Failed(String),
+    /// An error with a message saying what was being done, the message already includes the error's own message
+    Context { message: String, source: Box<Error> },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::SourceNotFound(location) => write!(f, "The specified source file {} does not exist", location),
+            Error::OutputExists(location) => write!(f, "The output file {} exists and the force option was not specified", location),
+            Error::UnknownPatchState(location) => write!(f, "{} doesn't look like a pc.boot this can patch", location),
+            Error::OptionRom(e) => write!(f, "{}", e),
+            Error::Patcher(e) => write!(f, "{}", e),
+            Error::FileHandler(e) => write!(f, "{}", e),
+            Error::Installer(e) => write!(f, "{}", e),
+            Error::RomVars(e) => write!(f, "{}", e),
+            Error::Eprom(e) => write!(f, "{}", e),
+            Error::PatchFile(e) => write!(f, "{}", e),
+            Error::Assembler(e) => write!(f, "{}", e),
+            Error::Verify(e) => write!(f, "{}", e),
+            Error::Manifest(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Failed(message) | Error::Context { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::OptionRom(e) => Some(e),
+            Error::Patcher(e) => Some(e),
+            Error::FileHandler(e) => Some(e),
+            Error::Installer(e) => Some(e),
+            Error::RomVars(e) => Some(e),
+            Error::Eprom(e) => Some(e),
+            Error::PatchFile(e) => Some(e),
+            Error::Assembler(e) => Some(e),
+            Error::Verify(e) => Some(e),
+            Error::Manifest(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            Error::SourceNotFound(_) | Error::OutputExists(_) | Error::UnknownPatchState(_) | Error::Failed(_) => None,
+        }
+    }
+}
+
+impl Error {
+    /// Add a message saying what was being done, made from the error so it can include its message
+    pub fn context(self, message: impl FnOnce(&Error) -> String) -> Error {
+        Error::Context { message: message(&self), source: Box::new(self) }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::SourceNotFound(_) => EXIT_SOURCE_NOT_FOUND,
+            Error::OutputExists(_) => EXIT_OUTPUT_EXISTS,
+            Error::UnknownPatchState(_) => EXIT_PATCH_SITE_NOT_FOUND,
+            Error::OptionRom(e) => match e {
+                OptionRomError::InvalidOptionRomHeader | OptionRomError::OptionRomTooSmall | OptionRomError::NoOptionRomFoundInScan => EXIT_NO_ROM_FOUND,
+                OptionRomError::OptionRomChecksumInvalid(_) => EXIT_BAD_CHECKSUM,
+                _ => EXIT_FAILURE,
+            },
+            Error::Patcher(e) => match e {
+                OptionRomPatcherError::CouldntLocateHddReadyCheck
+                | OptionRomPatcherError::CouldntLocateAfterInt13Set
+                | OptionRomPatcherError::PatchSiteNotFound(_) => EXIT_PATCH_SITE_NOT_FOUND,
+                _ => EXIT_FAILURE,
+            },
+            Error::FileHandler(e) => match e {
+                FileHandlerError::CouldntReadSourceFile(_) | FileHandlerError::CouldntWriteOutputFile(_) => EXIT_IO_FAILURE,
+                _ => EXIT_FAILURE,
+            },
+            Error::Installer(e) => match e {
+                InstallerError::WouldOverwrite(_) => EXIT_OUTPUT_EXISTS,
+                InstallerError::CouldntChange(..) => EXIT_IO_FAILURE,
+                _ => EXIT_FAILURE,
+            },
+            Error::Io(_) => EXIT_IO_FAILURE,
+            Error::PatchFile(e) => match e {
+                PatchFileError::CouldntRead(_) => EXIT_IO_FAILURE,
+                _ => EXIT_FAILURE,
+            },
+            Error::Verify(e) => match e {
+                VerifyError::CouldntReadBack(_) => EXIT_IO_FAILURE,
+                _ => EXIT_VERIFY_FAILED,
+            },
+            Error::Manifest(e) => match e {
+                ManifestError::DoesntMatch(_) => EXIT_VERIFY_FAILED,
+                ManifestError::Invalid(_) => EXIT_FAILURE,
+            },
+            Error::RomVars(_) | Error::Eprom(_) | Error::Assembler(_) | Error::Failed(_) => EXIT_FAILURE,
+            Error::Context { source, .. } => source.exit_code(),
+        }
+    }
+}
+
+impl From<OptionRomError> for Error {
+    fn from(e: OptionRomError) -> Error {
+        Error::OptionRom(e)
+    }
+}
+
+impl From<OptionRomPatcherError> for Error {
+    fn from(e: OptionRomPatcherError) -> Error {
+        Error::Patcher(e)
+    }
+}
+
+impl From<FileHandlerError> for Error {
+    fn from(e: FileHandlerError) -> Error {
+        Error::FileHandler(e)
+    }
+}
+
+impl From<InstallerError> for Error {
+    fn from(e: InstallerError) -> Error {
+        Error::Installer(e)
+    }
+}
+
+impl From<RomVarsError> for Error {
+    fn from(e: RomVarsError) -> Error {
+        Error::RomVars(e)
+    }
+}
+
+impl From<EpromError> for Error {
+    fn from(e: EpromError) -> Error {
+        Error::Eprom(e)
+    }
+}
+
+impl From<PatchFileError> for Error {
+    fn from(e: PatchFileError) -> Error {
+        Error::PatchFile(e)
+    }
+}
+
+impl From<AssemblerError> for Error {
+    fn from(e: AssemblerError) -> Error {
+        Error::Assembler(e)
+    }
+}
+
+impl From<VerifyError> for Error {
+    fn from(e: VerifyError) -> Error {
+        Error::Verify(e)
+    }
+}
+
+impl From<ManifestError> for Error {
+    fn from(e: ManifestError) -> Error {
+        Error::Manifest(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Failed(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_follow_the_source() {
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let error = Error::from(FileHandlerError::CouldntReadSourceFile(missing)).context(|e| format!("Reading pc.boot: {}", e));
+
+        assert_eq!(error.exit_code(), EXIT_IO_FAILURE);
+        assert_eq!(error.to_string(), "Reading pc.boot: Couldn't read source file with error missing");
+        let mut cause: &dyn std::error::Error = &error;
+        while let Some(source) = cause.source() {
+            cause = source;
+        }
+        assert_eq!(cause.to_string(), "missing");
+
+        assert_eq!(Error::from(OptionRomError::NoOptionRomFoundInScan).exit_code(), EXIT_NO_ROM_FOUND);
+        assert_eq!(Error::from(OptionRomPatcherError::CouldntLocateHddReadyCheck).exit_code(), EXIT_PATCH_SITE_NOT_FOUND);
+        assert_eq!(Error::from("A conflict".to_string()).exit_code(), EXIT_FAILURE);
+    }
+}
@@ -1,9 +1,15 @@
 use std::fs;
-use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use crate::adf::{self, ADF_PATH_SEPARATOR};
+use crate::amiga_filesystem::{AmigaFilesystemError, AmigaVolume};
+use crate::eprom::{self, EpromError};
+use crate::hdf::{self, HdfError, BOOT_PARTITION_NAME, HDF_PATH_SEPARATOR};
+use crate::lha::{self, LhaError, LHA_PATH_SEPARATORS};
 use crate::option_rom::OptionRom;
+use crate::rom_formats::{self, RomFormat, RomFormatError};
 
 use std::fmt;
 
@@ -11,6 +17,14 @@ use std::fmt;
 pub enum FileHandlerError {
     CouldntReadSourceFile(std::io::Error),
     CouldntWriteOutputFile(std::io::Error),
+    RomExtendsPastSourceEnd { rom_end_byte: usize, source_length: usize },
+    RomStartsPastSourceEnd { rom_start_byte: usize, source_length: usize },
+    AmigaFilesystem(AmigaFilesystemError),
+    Hardfile(HdfError),
+    Archive(LhaError),
+    CantWriteIntoArchive(PathBuf),
+    RomFormat(RomFormatError),
+    Eprom(EpromError),
 }
 
 impl fmt::Display for FileHandlerError {
@@ -18,84 +32,400 @@ impl fmt::Display for FileHandlerError {
         match self {
             FileHandlerError::CouldntReadSourceFile(e) => write!(f, "Couldn't read source file with error {}", e),
             FileHandlerError::CouldntWriteOutputFile(e) => write!(f, "Couldn't write the output file with error {}", e),
+            FileHandlerError::RomExtendsPastSourceEnd { rom_end_byte, source_length } => write!(
+                f,
+                "The ROM would end at byte 0x{:X} which is beyond the end of the 0x{:X} byte source file, this needs the allow-extend or allow-pad write policy",
+                rom_end_byte, source_length,
+            ),
+            FileHandlerError::RomStartsPastSourceEnd { rom_start_byte, source_length } => write!(
+                f,
+                "The ROM would start at byte 0x{:X} which is beyond the end of the 0x{:X} byte source file, this needs the allow-pad write policy",
+                rom_start_byte, source_length,
+            ),
+            FileHandlerError::AmigaFilesystem(e) => write!(f, "Amiga filesystem error: {}", e),
+            FileHandlerError::Hardfile(e) => write!(f, "Hardfile error: {}", e),
+            FileHandlerError::Archive(e) => write!(f, "LHA archive error: {}", e),
+            FileHandlerError::RomFormat(e) => write!(f, "Intel HEX or S-record error: {}", e),
+            FileHandlerError::Eprom(e) => write!(f, "{}", e),
+            FileHandlerError::CantWriteIntoArchive(path) => write!(f, "Files can't be written into the archive {}, write to a normal file instead", path.display()),
         }
     }
 }
 
-pub struct FileHandler {}
+impl std::error::Error for FileHandlerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileHandlerError::CouldntReadSourceFile(e) | FileHandlerError::CouldntWriteOutputFile(e) => Some(e),
+            FileHandlerError::AmigaFilesystem(e) => Some(e),
+            FileHandlerError::Hardfile(e) => Some(e),
+            FileHandlerError::Archive(e) => Some(e),
+            FileHandlerError::RomFormat(e) => Some(e),
+            FileHandlerError::Eprom(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-impl FileHandler {
-    pub fn read_source(path: &PathBuf) -> Result<Vec<u8>, FileHandlerError> {
-        match fs::read(path) {
-            Ok(bytes) => Ok(bytes),
-            Err(e) => Err(FileHandlerError::CouldntReadSourceFile(e)),
+/// Controls what write_rom_in_file may do to the length of the output file when the ROM doesn't fit inside the
+/// source file
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum WritePolicy {
+    /// The ROM must fit entirely inside the source file, the output is always the same length as the source
+    Strict,
+    /// The ROM may overlap the end of the source file, growing the output
+    AllowExtend,
+    /// The ROM may start beyond the end of the source file, the gap is filled with zeros
+    AllowPad,
+}
+
+impl WritePolicy {
+    fn check(&self, rom_start_byte: usize, rom_end_byte: usize, source_length: usize) -> Result<(), FileHandlerError> {
+        if rom_start_byte > source_length && *self != WritePolicy::AllowPad {
+            return Err(FileHandlerError::RomStartsPastSourceEnd { rom_start_byte, source_length });
+        }
+
+        if rom_end_byte > source_length && *self == WritePolicy::Strict {
+            return Err(FileHandlerError::RomExtendsPastSourceEnd { rom_end_byte, source_length });
         }
+
+        Ok(())
     }
+}
 
-    pub fn write_rom_only(path: &PathBuf, option_rom: OptionRom) -> Result<(), FileHandlerError> {
-        match fs::write(path, option_rom.bytes) {
-            Ok(..) => Ok(()),
-            Err(e) => Err(FileHandlerError::CouldntWriteOutputFile(e)),
+/// The kind of Amiga disk image a file is inside
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiskImage {
+    Adf,
+    /// A hardfile, with the partition's device name (e.g. DH0) or SYS for the one the Amiga boots from
+    Hdf { partition: String },
+}
+
+/// Where a file is, either a normal path, a file inside an Amiga disk image, e.g. workbench.adf:PC/System/pc.boot or
+/// card.hdf:SYS:PC/System/pc.boot, or a file inside a read only LHA archive, e.g. janus.lha:pc.boot, or - for standard
+/// input or output
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileLocation {
+    Path(PathBuf),
+    ImageMember { image_path: PathBuf, image: DiskImage, member_path: String },
+    ArchiveMember { archive_path: PathBuf, member_path: String },
+    /// Read from standard input or written to standard output, which can only be read once and can't be read back
+    Stdio,
+}
+
+pub const STDIO_PATH: &str = "-";
+
+impl FileLocation {
+    /// Whether there is a file here, standard input and output are never an existing file which would be overwritten
+    pub fn exists(&self) -> bool {
+        match self {
+            FileLocation::Stdio => false,
+            FileLocation::Path(path) => path.exists(),
+            FileLocation::ImageMember { image_path, image, member_path } => match fs::read(image_path) {
+                Ok(mut image_bytes) => match open_volume(&mut image_bytes, image) {
+                    Ok(volume) => volume.file_exists(member_path),
+                    Err(_) => false,
+                },
+                Err(_) => false,
+            },
+            FileLocation::ArchiveMember { archive_path, member_path } => match fs::read(archive_path) {
+                Ok(archive_bytes) => lha::find_member(&archive_bytes, member_path).is_ok(),
+                Err(_) => false,
+            },
         }
     }
 
-    pub fn write_rom_in_file(source_file: &PathBuf, output_path: &PathBuf, option_rom: OptionRom, rom_start_byte: usize) -> Result<(), FileHandlerError> {
-        let source_file_bytes = FileHandler::read_source(source_file)?;
-        let rom_end_location: usize = rom_start_byte + option_rom.rom_size_in_bytes;
+    /// The name of the file, for telling Intel HEX and S-record files apart from binary images
+    fn file_name(&self) -> String {
+        match self {
+            FileLocation::Path(path) => path.to_string_lossy().into_owned(),
+            FileLocation::ImageMember { member_path, .. } | FileLocation::ArchiveMember { member_path, .. } => member_path.clone(),
+            FileLocation::Stdio => STDIO_PATH.to_string(),
+        }
+    }
 
-        let mut output_file = match File::create(output_path) {
-            Ok(f) => f,
-            Err(e) => return Err(FileHandlerError::CouldntWriteOutputFile(e))
+    /// The same location with a suffix added to the file name, e.g. for pc.boot.original
+    pub fn with_suffix(&self, suffix: &str) -> FileLocation {
+        match self {
+            FileLocation::Path(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push(suffix);
+                FileLocation::Path(path.into())
+            },
+            FileLocation::ImageMember { image_path, image, member_path } => FileLocation::ImageMember {
+                image_path: image_path.clone(),
+                image: image.clone(),
+                member_path: format!("{}{}", member_path, suffix),
+            },
+            FileLocation::ArchiveMember { archive_path, member_path } => FileLocation::ArchiveMember {
+                archive_path: archive_path.clone(),
+                member_path: format!("{}{}", member_path, suffix),
+            },
+            FileLocation::Stdio => FileLocation::Stdio,
+        }
+    }
+}
+
+impl FromStr for FileLocation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<FileLocation, String> {
+        if value == STDIO_PATH {
+            return Ok(FileLocation::Stdio);
+        }
+
+        // Lowercasing ASCII doesn't move any bytes, so the indexes are valid in the original value
+        let lowercase_value = value.to_ascii_lowercase();
+        let separator = [ADF_PATH_SEPARATOR, HDF_PATH_SEPARATOR].into_iter().chain(LHA_PATH_SEPARATORS)
+            .filter_map(|separator| lowercase_value.find(separator).map(|index| (index, separator)))
+            .min();
+
+        let (image_end, separator) = match separator {
+            Some((index, separator)) => (index + separator.len() - 1, separator),
+            None => return Ok(FileLocation::Path(value.into())),
         };
 
-        if rom_start_byte != 0 {
-            let write_upto_byte = if rom_start_byte <= source_file_bytes.len() {
-                rom_start_byte
-            } else {
-                source_file_bytes.len()
-            };
-                
-            match output_file.write_all(&source_file_bytes[0..write_upto_byte]) {
-                Ok(..) => {},
-                Err(e) => return Err(FileHandlerError::CouldntWriteOutputFile(e))
+        let image_path: PathBuf = value[..image_end].into();
+        let member_path = &value[image_end + 1..];
+
+        if LHA_PATH_SEPARATORS.contains(&separator) {
+            if member_path.is_empty() {
+                return Err("Give the path of the file inside the archive, e.g. janus.lha:pc.boot".into());
             }
+            return Ok(FileLocation::ArchiveMember { archive_path: image_path, member_path: member_path.into() });
         }
 
-        if rom_start_byte > source_file_bytes.len() {
-            let empty_space_size = rom_start_byte - source_file_bytes.len();
-            let empty_space = vec![0u8; empty_space_size];
+        let (image, member_path) = if separator == HDF_PATH_SEPARATOR {
+            let (partition, member_path) = member_path.split_once(':').unwrap_or((BOOT_PARTITION_NAME, member_path));
+            (DiskImage::Hdf { partition: partition.into() }, member_path)
+        } else {
+            (DiskImage::Adf, member_path)
+        };
 
-            match output_file.write_all(&empty_space) {
-                Ok(..) => {},
-                Err(e) => return Err(FileHandlerError::CouldntWriteOutputFile(e))
-            }
+        if member_path.is_empty() {
+            return Err("Give the path of the file inside the image, e.g. workbench.adf:PC/System/pc.boot or card.hdf:SYS:PC/System/pc.boot".into());
+        }
+
+        Ok(FileLocation::ImageMember { image_path, image, member_path: member_path.into() })
+    }
+}
+
+impl From<PathBuf> for FileLocation {
+    fn from(path: PathBuf) -> FileLocation {
+        FileLocation::Path(path)
+    }
+}
+
+impl fmt::Display for FileLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileLocation::Path(path) => write!(f, "{}", path.display()),
+            FileLocation::ImageMember { image_path, image: DiskImage::Adf, member_path } => write!(f, "{}:{}", image_path.display(), member_path),
+            FileLocation::ImageMember { image_path, image: DiskImage::Hdf { partition }, member_path } => {
+                write!(f, "{}:{}:{}", image_path.display(), partition, member_path)
+            },
+            FileLocation::ArchiveMember { archive_path, member_path } => write!(f, "{}:{}", archive_path.display(), member_path),
+            FileLocation::Stdio => write!(f, "{}", STDIO_PATH),
+        }
+    }
+}
+
+/// The outcome of backing up a file before it is overwritten
+#[derive(Debug, PartialEq)]
+pub enum Backup {
+    Written(FileLocation),
+    /// An earlier backup is kept, as it is more likely to be the real original
+    AlreadyExists(FileLocation),
+}
+
+impl fmt::Display for Backup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backup::Written(location) => write!(f, "Original backed up to {}", location),
+            Backup::AlreadyExists(location) => write!(f, "Kept the existing backup {}", location),
+        }
+    }
+}
+
+/// Open the Amiga filesystem holding files inside a disk image
+fn open_volume<'a>(image_bytes: &'a mut [u8], image: &DiskImage) -> Result<AmigaVolume<'a>, FileHandlerError> {
+    let volume = match image {
+        DiskImage::Adf => adf::open_volume(image_bytes),
+        DiskImage::Hdf { partition } => {
+            let partition = hdf::find_partition(image_bytes, partition).map_err(FileHandlerError::Hardfile)?;
+            AmigaVolume::open(&mut image_bytes[partition.start_byte..partition.end_byte], partition.block_size, partition.reserved_blocks)
+        },
+    };
+
+    volume.map_err(FileHandlerError::AmigaFilesystem)
+}
+
+/// Added to the file name of the backup of a file which is about to be overwritten
+pub const BACKUP_SUFFIX: &str = ".original";
+
+pub struct FileHandler {}
+
+impl FileHandler {
+    /// Read the whole file, converting Intel HEX and S-record files (going by their extension) to a binary image
+    pub fn read_source(location: &FileLocation) -> Result<Vec<u8>, FileHandlerError> {
+        let bytes = FileHandler::read_file(location)?;
+
+        if rom_formats::is_text_format_name(&location.file_name()) {
+            return rom_formats::decode(&bytes).map_err(FileHandlerError::RomFormat);
         }
+        Ok(bytes)
+    }
+
+    /// Read the source, merged into one image with the dump of the odd chip when the source is the even chip of an
+    /// even/odd pair
+    pub fn read_source_pair(location: &FileLocation, odd_location: Option<&FileLocation>) -> Result<Vec<u8>, FileHandlerError> {
+        let bytes = FileHandler::read_source(location)?;
 
-        match output_file.write_all(&option_rom.bytes) {
-            Ok(..) => {},
-            Err(e) => return Err(FileHandlerError::CouldntWriteOutputFile(e))
+        match odd_location {
+            Some(odd_location) => eprom::interleave(&bytes, &FileHandler::read_source(odd_location)?).map_err(FileHandlerError::Eprom),
+            None => Ok(bytes),
         }
+    }
+
+    /// Read back an output written in the format, whatever its name, as the binary image it holds
+    pub fn read_written(location: &FileLocation, format: RomFormat) -> Result<Vec<u8>, FileHandlerError> {
+        let bytes = FileHandler::read_file(location)?;
+
+        match format {
+            RomFormat::Binary => Ok(bytes),
+            _ => rom_formats::decode(&bytes).map_err(FileHandlerError::RomFormat),
+        }
+    }
+
+    fn read_file(location: &FileLocation) -> Result<Vec<u8>, FileHandlerError> {
+        match location {
+            FileLocation::Stdio => {
+                let mut bytes = Vec::new();
+                match std::io::stdin().lock().read_to_end(&mut bytes) {
+                    Ok(_) => Ok(bytes),
+                    Err(e) => Err(FileHandlerError::CouldntReadSourceFile(e)),
+                }
+            },
+            FileLocation::Path(path) => match fs::read(path) {
+                Ok(bytes) => Ok(bytes),
+                Err(e) => Err(FileHandlerError::CouldntReadSourceFile(e)),
+            },
+            FileLocation::ImageMember { image_path, image, member_path } => {
+                let mut image_bytes = match fs::read(image_path) {
+                    Ok(image_bytes) => image_bytes,
+                    Err(e) => return Err(FileHandlerError::CouldntReadSourceFile(e)),
+                };
+
+                open_volume(&mut image_bytes, image)?.read_file(member_path).map_err(FileHandlerError::AmigaFilesystem)
+            },
+            FileLocation::ArchiveMember { archive_path, member_path } => match fs::read(archive_path) {
+                Ok(archive_bytes) => lha::extract(&archive_bytes, member_path).map_err(FileHandlerError::Archive),
+                Err(e) => Err(FileHandlerError::CouldntReadSourceFile(e)),
+            },
+        }
+    }
+
+    /// Write just the rom, as a binary image or in a format for EPROM programmers starting at load_address
+    pub fn write_rom_only(location: &FileLocation, option_rom: OptionRom, format: RomFormat, load_address: u32) -> Result<(), FileHandlerError> {
+        FileHandler::write_image(location, &option_rom.bytes, format, load_address)
+    }
+
+    /// Write an image such as a filled EPROM, as a binary or in a format for EPROM programmers starting at load_address
+    pub fn write_image(location: &FileLocation, bytes: &[u8], format: RomFormat, load_address: u32) -> Result<(), FileHandlerError> {
+        let bytes = rom_formats::encode(bytes, format, load_address).map_err(FileHandlerError::RomFormat)?;
+        FileHandler::write_output(location, &bytes)
+    }
+
+    /// Write the bytes read from the source file with the ROM in them to the output, returning the bytes written
+    pub fn write_rom_in_file(source_file_bytes: &[u8], output_location: &FileLocation, option_rom: OptionRom, rom_start_byte: usize, write_policy: WritePolicy) -> Result<Vec<u8>, FileHandlerError> {
+        let output_bytes = FileHandler::place_rom_in_bytes(source_file_bytes, option_rom, rom_start_byte, write_policy)?;
+
+        FileHandler::write_output(output_location, &output_bytes)?;
+        Ok(output_bytes)
+    }
+
+    /// The source file with the ROM written over it at rom_start_byte, as far as the write policy allows
+    pub fn place_rom_in_bytes(source_file_bytes: &[u8], option_rom: OptionRom, rom_start_byte: usize, write_policy: WritePolicy) -> Result<Vec<u8>, FileHandlerError> {
+        let rom_end_location: usize = rom_start_byte + option_rom.rom_size_in_bytes;
+
+        write_policy.check(rom_start_byte, rom_end_location, source_file_bytes.len())?;
+
+        let mut output_bytes: Vec<u8> = Vec::with_capacity(source_file_bytes.len().max(rom_end_location));
+        output_bytes.extend_from_slice(&source_file_bytes[0..rom_start_byte.min(source_file_bytes.len())]);
+        output_bytes.resize(rom_start_byte, 0);
+        output_bytes.extend_from_slice(&option_rom.bytes);
 
         if rom_end_location < source_file_bytes.len() {
-            match output_file.write_all(&source_file_bytes[rom_end_location..]) {
-                Ok(..) => {},
-                Err(e) => return Err(FileHandlerError::CouldntWriteOutputFile(e))
-            }
+            output_bytes.extend_from_slice(&source_file_bytes[rom_end_location..]);
         }
 
-        Ok(())
+        Ok(output_bytes)
+    }
+
+    /// Copy a file to the same name with .original added (inside the image for a file in an Amiga disk image), the
+    /// step the Bridgeboard install instructions have you do by hand
+    pub fn backup(location: &FileLocation) -> Result<Backup, FileHandlerError> {
+        let backup_location = location.with_suffix(BACKUP_SUFFIX);
+        if backup_location.exists() {
+            return Ok(Backup::AlreadyExists(backup_location));
+        }
+
+        let bytes = FileHandler::read_file(location)?;
+        FileHandler::write_output(&backup_location, &bytes)?;
+        Ok(Backup::Written(backup_location))
+    }
+
+    /// Split the image between an even/odd pair of chips and write both
+    pub fn write_interleaved(even_location: &FileLocation, odd_location: &FileLocation, bytes: &[u8]) -> Result<(), FileHandlerError> {
+        let (even, odd) = eprom::deinterleave(bytes);
+
+        FileHandler::write_output(even_location, &even)?;
+        FileHandler::write_output(odd_location, &odd)
+    }
+
+    /// Write the whole output file, a file inside an Amiga disk image is replaced or created and the image is only
+    /// saved if that succeeds
+    pub fn write_output(location: &FileLocation, bytes: &[u8]) -> Result<(), FileHandlerError> {
+        match location {
+            FileLocation::Stdio => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(bytes).and_then(|_| stdout.flush()).map_err(FileHandlerError::CouldntWriteOutputFile)
+            },
+            FileLocation::Path(path) => match fs::write(path, bytes) {
+                Ok(..) => Ok(()),
+                Err(e) => Err(FileHandlerError::CouldntWriteOutputFile(e)),
+            },
+            FileLocation::ImageMember { image_path, image, member_path } => {
+                let mut image_bytes = match fs::read(image_path) {
+                    Ok(image_bytes) => image_bytes,
+                    Err(e) => return Err(FileHandlerError::CouldntWriteOutputFile(e)),
+                };
+
+                let mut volume = open_volume(&mut image_bytes, image)?;
+                let result = if volume.file_exists(member_path) {
+                    volume.write_file(member_path, bytes)
+                } else {
+                    volume.create_file(member_path, bytes)
+                };
+                result.map_err(FileHandlerError::AmigaFilesystem)?;
+
+                match fs::write(image_path, image_bytes) {
+                    Ok(..) => Ok(()),
+                    Err(e) => Err(FileHandlerError::CouldntWriteOutputFile(e)),
+                }
+            },
+            FileLocation::ArchiveMember { archive_path, .. } => Err(FileHandlerError::CantWriteIntoArchive(archive_path.clone())),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_helpers::{assert_file_has_bytes, create_temp_dir, fixture_path, load_fixture, load_option_rom_fixture};
-    use md5;
+    use crate::test_helpers::{assert_file_has_bytes, build_amiga_volume, build_rdb_hardfile, create_temp_dir, fixture_path, load_fixture, load_option_rom_fixture, ADF_DD_BLOCKS};
 
     #[test]
     fn test_read_source_with_fs_error() -> Result<(), String> {
-        match FileHandler::read_source(&fixture_path("foo")) {
+        match FileHandler::read_source(&fixture_path("foo").into()) {
             Ok(_) => Err("Expected an error when the file didn't exist, but got Ok".into()),
             Err(e) => match e {
                 FileHandlerError::CouldntReadSourceFile(original_error) => {
@@ -109,7 +439,7 @@ mod test {
 
     #[test]
     fn test_read_source() -> Result<(), String> {
-        match FileHandler::read_source(&fixture_path("pc.boot.valid")) {
+        match FileHandler::read_source(&fixture_path("pc.boot.valid").into()) {
             Ok(bytes) => {
                 assert_eq!(bytes.len(), 10240);
                 let digest = md5::compute(bytes);
@@ -129,7 +459,7 @@ mod test {
         let mut output_path = tempdir.into_path();
         output_path.push("test_output.rom");
 
-        match FileHandler::write_rom_only(&output_path, option_rom) {
+        match FileHandler::write_rom_only(&output_path.clone().into(), option_rom, RomFormat::Binary, 0) {
             Err(e) => Err(format!("Failed to write rom to output path {} with error {}", output_path.display(), e)),
             Ok(()) => {
                 assert_file_has_bytes(&output_path, &option_rom_bytes)?;
@@ -139,47 +469,61 @@ mod test {
     }
 
     #[test]
-    fn test_write_rom_only_failure() -> Result<(), String> {
+    fn test_write_rom_only_as_intel_hex_and_read_back() -> Result<(), String> {
         let tempdir = create_temp_dir()?;
         let option_rom = load_option_rom_fixture("pc.boot.valid")?;
-        let output_path = tempdir.into_path();
+        let option_rom_bytes = option_rom.bytes.clone();
+        let output_path = tempdir.path().join("PCBOOT.HEX");
 
-        match FileHandler::write_rom_only(&output_path, option_rom) {
-            Ok(()) => Err(format!("Expected failure to write to file {}, but it was successful!", output_path.display())),
-            Err(e) => match e {
-                FileHandlerError::CouldntWriteOutputFile(_) => Ok(()),
-                _ => Err(format!("Expected a FileHandlerError::CouldntWriteOutputFile but got {}", e)),
-            }
+        if let Err(e) = FileHandler::write_rom_only(&output_path.clone().into(), option_rom, RomFormat::Ihex, 0xD0000) {
+            return Err(format!("Expected Ok writing Intel HEX but got error {}", e));
         }
+
+        let text = fs::read_to_string(&output_path).map_err(|e| format!("{}", e))?;
+        assert!(text.starts_with(":02000004000DED\r\n:1000000055AA10"));
+        assert_eq!(FileHandler::read_source(&output_path.into()).ok(), Some(option_rom_bytes));
+        Ok(())
     }
 
     #[test]
-    fn test_write_rom_in_file_source_file_does_not_exist() -> Result<(), String> {
-        let bad_path = fixture_path("foo.rom");
-        let output_path = fixture_path("non-existing-output.rom");
+    fn test_write_interleaved_and_read_pair() -> Result<(), String> {
+        let tempdir = create_temp_dir()?;
+        let image = load_fixture("pc.boot.8k-in-middle")?;
+        let even_location: FileLocation = tempdir.path().join("even.bin").into();
+        let odd_location: FileLocation = tempdir.path().join("odd.bin").into();
+
+        FileHandler::write_interleaved(&even_location, &odd_location, &image).map_err(|e| format!("{}", e))?;
+
+        assert_eq!(FileHandler::read_source(&even_location).map(|even| even.len()).ok(), Some(image.len() / 2));
+        assert_eq!(FileHandler::read_source_pair(&even_location, Some(&odd_location)).ok(), Some(image));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_rom_only_failure() -> Result<(), String> {
+        let tempdir = create_temp_dir()?;
         let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        let output_path = tempdir.into_path();
 
-        match FileHandler::write_rom_in_file(&bad_path, &output_path, option_rom, 0) {
-            Err(e) => {
-                match e {
-                    FileHandlerError::CouldntReadSourceFile(_) => Ok(()),
-                    _ => Err(format!("Expected FileHanderError::CouldntReadSourceFile but got {}", e)),
-                }
-            },
-            Ok(_) => Err("Expected an Error reading a non-existing source file, but got Ok".into()),
+        match FileHandler::write_rom_only(&output_path.clone().into(), option_rom, RomFormat::Binary, 0) {
+            Ok(_) => Err(format!("Expected failure to write to file {}, but it was successful!", output_path.display())),
+            Err(e) => match e {
+                FileHandlerError::CouldntWriteOutputFile(_) => Ok(()),
+                _ => Err(format!("Expected a FileHandlerError::CouldntWriteOutputFile but got {}", e)),
+            }
         }
     }
 
     #[test]
     fn test_write_rom_in_file_cannot_write_to_output_file() -> Result<(), String> {
-        let source_path = fixture_path("pc.boot.valid");
+        let source_bytes = load_fixture("pc.boot.valid")?;
         let option_rom = load_option_rom_fixture("pc.boot.valid")?;
 
         let tempdir = create_temp_dir()?;
         let output_path = tempdir.into_path();
 
-        match FileHandler::write_rom_in_file(&source_path, &output_path, option_rom, 0) {
-            Ok(()) => Err(format!("Expected failure to write to file {}, but it was successful!", output_path.display())),
+        match FileHandler::write_rom_in_file(&source_bytes, &output_path.clone().into(), option_rom, 0, WritePolicy::Strict) {
+            Ok(_) => Err(format!("Expected failure to write to file {}, but it was successful!", output_path.display())),
             Err(e) => match e {
                 FileHandlerError::CouldntWriteOutputFile(_) => Ok(()),
                 _ => Err(format!("Expected a FileHandlerError::CouldntWriteOutputFile but got {}", e)),
@@ -187,8 +531,8 @@ mod test {
         }
     }
 
-    fn test_write_rom_in_file_against_expected(expected_output_file: &str, rom_start_byte: usize) -> Result<(), String> {
-        let source_file = fixture_path("pc.boot.no-rom");
+    fn test_write_rom_in_file_against_expected(expected_output_file: &str, rom_start_byte: usize, write_policy: WritePolicy) -> Result<(), String> {
+        let source_bytes = load_fixture("pc.boot.no-rom")?;
         let option_rom = load_option_rom_fixture("8k-option-rom")?;
         let expected_output_bytes = load_fixture(expected_output_file)?;
 
@@ -196,10 +540,9 @@ mod test {
         let mut output_path = tempdir.into_path();
         output_path.push("8k-rom-in-file");
 
-        match FileHandler::write_rom_in_file(&source_file, &output_path, option_rom, rom_start_byte) {
-            Err(e) => return Err(format!("Expected Ok writing rom in file, but got error {}", e)),
-            Ok(()) => () ,
-        };
+        if let Err(e) = FileHandler::write_rom_in_file(&source_bytes, &output_path.clone().into(), option_rom, rom_start_byte, write_policy) {
+            return Err(format!("Expected Ok writing rom in file, but got error {}", e));
+        }
 
         assert_file_has_bytes(&output_path, &expected_output_bytes)?;
 
@@ -208,31 +551,182 @@ mod test {
 
     #[test]
     fn test_write_rom_in_file_start_at_0() -> Result<(), String> {
-        test_write_rom_in_file_against_expected("pc.boot.8k-at-start", 0)
+        test_write_rom_in_file_against_expected("pc.boot.8k-at-start", 0, WritePolicy::Strict)
     }
 
     #[test]
     fn test_write_rom_in_file_start_with_offset() -> Result<(), String> {
-        test_write_rom_in_file_against_expected("pc.boot.8k-in-middle", 0x1000)
+        test_write_rom_in_file_against_expected("pc.boot.8k-in-middle", 0x1000, WritePolicy::Strict)
     }
 
     #[test]
     fn test_write_rom_in_file_start_with_offset_at_end_of_file() -> Result<(), String> {
-        test_write_rom_in_file_against_expected("pc.boot.8k-at-end", 0x2000)
+        test_write_rom_in_file_against_expected("pc.boot.8k-at-end", 0x2000, WritePolicy::Strict)
     }
 
     #[test]
     fn test_write_rom_in_file_start_with_offset_which_causes_rom_to_overlap_end() -> Result<(), String> {
-        test_write_rom_in_file_against_expected("pc.boot.8k-overlapping-end", 0x3000)
+        test_write_rom_in_file_against_expected("pc.boot.8k-overlapping-end", 0x3000, WritePolicy::AllowExtend)
     }
 
     #[test]
     fn test_write_rom_in_file_start_with_offset_which_causes_to_be_appended() -> Result<(), String> {
-        test_write_rom_in_file_against_expected("pc.boot.8k-appended", 0x4000)
+        test_write_rom_in_file_against_expected("pc.boot.8k-appended", 0x4000, WritePolicy::AllowExtend)
     }
 
     #[test]
     fn test_write_rom_in_file_start_with_offset_which_causes_rom_beyond_end_of_file() -> Result<(), String> {
-        test_write_rom_in_file_against_expected("pc.boot.8k-beyond-end", 0x4400)
+        test_write_rom_in_file_against_expected("pc.boot.8k-beyond-end", 0x4400, WritePolicy::AllowPad)
+    }
+
+    fn test_write_rom_in_file_rejected_by_policy(rom_start_byte: usize, write_policy: WritePolicy) -> Result<FileHandlerError, String> {
+        let source_bytes = load_fixture("pc.boot.no-rom")?;
+        let option_rom = load_option_rom_fixture("8k-option-rom")?;
+
+        let tempdir = create_temp_dir()?;
+        let mut output_path = tempdir.into_path();
+        output_path.push("8k-rom-in-file");
+
+        match FileHandler::write_rom_in_file(&source_bytes, &output_path.clone().into(), option_rom, rom_start_byte, write_policy) {
+            Ok(_) => Err(format!("Expected the {:?} write policy to reject a ROM at 0x{:X}, but got Ok", write_policy, rom_start_byte)),
+            Err(e) => {
+                assert!(!output_path.exists(), "The output file should not be created when the write policy rejects the ROM");
+                Ok(e)
+            },
+        }
+    }
+
+    #[test]
+    fn test_write_rom_in_file_strict_refuses_to_extend_file() -> Result<(), String> {
+        match test_write_rom_in_file_rejected_by_policy(0x3000, WritePolicy::Strict)? {
+            FileHandlerError::RomExtendsPastSourceEnd { rom_end_byte: 0x5000, source_length: 0x4000 } => Ok(()),
+            e => Err(format!("Expected FileHandlerError::RomExtendsPastSourceEnd but got {}", e)),
+        }
+    }
+
+    #[test]
+    fn test_write_rom_in_file_strict_refuses_to_pad_file() -> Result<(), String> {
+        match test_write_rom_in_file_rejected_by_policy(0x4400, WritePolicy::Strict)? {
+            FileHandlerError::RomStartsPastSourceEnd { rom_start_byte: 0x4400, source_length: 0x4000 } => Ok(()),
+            e => Err(format!("Expected FileHandlerError::RomStartsPastSourceEnd but got {}", e)),
+        }
+    }
+
+    #[test]
+    fn test_write_rom_in_file_allow_extend_refuses_to_pad_file() -> Result<(), String> {
+        match test_write_rom_in_file_rejected_by_policy(0x4400, WritePolicy::AllowExtend)? {
+            FileHandlerError::RomStartsPastSourceEnd { rom_start_byte: 0x4400, source_length: 0x4000 } => Ok(()),
+            e => Err(format!("Expected FileHandlerError::RomStartsPastSourceEnd but got {}", e)),
+        }
+    }
+
+    #[test]
+    fn test_file_location_from_str() {
+        assert_eq!("pc.boot".parse(), Ok(FileLocation::Path("pc.boot".into())));
+        assert_eq!("-".parse(), Ok(FileLocation::Stdio));
+        assert_eq!(
+            "disks/Workbench.ADF:PC/System/pc.boot".parse(),
+            Ok(FileLocation::ImageMember { image_path: "disks/Workbench.ADF".into(), image: DiskImage::Adf, member_path: "PC/System/pc.boot".into() }),
+        );
+        assert_eq!(
+            "card.hdf:DH0:PC/System/pc.boot".parse(),
+            Ok(FileLocation::ImageMember { image_path: "card.hdf".into(), image: DiskImage::Hdf { partition: "DH0".into() }, member_path: "PC/System/pc.boot".into() }),
+        );
+        assert_eq!(
+            "card.hdf:PC/System/pc.boot".parse(),
+            Ok(FileLocation::ImageMember { image_path: "card.hdf".into(), image: DiskImage::Hdf { partition: "SYS".into() }, member_path: "PC/System/pc.boot".into() }),
+        );
+        assert!("workbench.adf:".parse::<FileLocation>().is_err());
+        assert!("card.hdf:SYS:".parse::<FileLocation>().is_err());
+        assert_eq!(
+            "Janus.LHA:PC/System/pc.boot".parse(),
+            Ok(FileLocation::ArchiveMember { archive_path: "Janus.LHA".into(), member_path: "PC/System/pc.boot".into() }),
+        );
+        assert!("janus.lzh:".parse::<FileLocation>().is_err());
+    }
+
+    #[test]
+    fn test_read_source_from_archive() -> Result<(), String> {
+        let location: FileLocation = format!("{}:pc.boot", fixture_path("janus-lh5.lha").display()).parse()?;
+
+        assert!(location.exists());
+        assert_eq!(FileHandler::read_source(&location).ok(), Some(load_fixture("pc.boot.valid")?));
+        match FileHandler::write_rom_only(&location, load_option_rom_fixture("pc.boot.valid")?, RomFormat::Binary, 0) {
+            Err(FileHandlerError::CantWriteIntoArchive(path)) => assert_eq!(path, fixture_path("janus-lh5.lha")),
+            other => return Err(format!("Expected FileHandlerError::CantWriteIntoArchive but got {:?}", other)),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_rom_in_file_inside_adf() -> Result<(), String> {
+        let source_bytes = load_fixture("pc.boot.no-rom")?;
+        let option_rom = load_option_rom_fixture("8k-option-rom")?;
+        let expected_output_bytes = load_fixture("pc.boot.8k-in-middle")?;
+
+        let tempdir = create_temp_dir()?;
+        let image_path = tempdir.into_path().join("workbench.adf");
+        let image_bytes = build_amiga_volume(ADF_DD_BLOCKS, adf::ADF_BLOCK_SIZE, 0, &[("PC/System/pc.boot", &source_bytes)]);
+        if let Err(e) = fs::write(&image_path, image_bytes) {
+            return Err(format!("Failed to write the ADF image with error {}", e));
+        }
+
+        let location: FileLocation = format!("{}:PC/System/pc.boot", image_path.display()).parse()?;
+        assert!(location.exists());
+        let read_bytes = FileHandler::read_source(&location).map_err(|e| e.to_string())?;
+        assert_eq!(read_bytes, source_bytes);
+
+        if let Err(e) = FileHandler::write_rom_in_file(&read_bytes, &location, option_rom, 0x1000, WritePolicy::Strict) {
+            return Err(format!("Expected Ok writing rom in the ADF, but got error {}", e));
+        }
+
+        match FileHandler::read_source(&location) {
+            Ok(bytes) => {
+                assert_eq!(bytes, expected_output_bytes);
+                Ok(())
+            },
+            Err(e) => Err(format!("Failed to read back the file in the ADF with error {}", e)),
+        }
+    }
+
+    #[test]
+    fn test_backup_and_write_inside_hdf() -> Result<(), String> {
+        let work = build_amiga_volume(64, 512, 1, &[]);
+        let system = build_amiga_volume(128, 512, 1, &[("PC/System/pc.boot", b"original pc.boot")]);
+        let tempdir = create_temp_dir()?;
+        let image_path = tempdir.into_path().join("card.hdf");
+        if let Err(e) = fs::write(&image_path, build_rdb_hardfile(&[("DH1", false, 0, &work), ("DH0", true, 0, &system)])) {
+            return Err(format!("Failed to write the hardfile with error {}", e));
+        }
+
+        let location: FileLocation = format!("{}:SYS:PC/System/pc.boot", image_path.display()).parse()?;
+        let backup_location: FileLocation = format!("{}:DH0:PC/System/pc.boot.original", image_path.display()).parse()?;
+
+        let backup = FileHandler::backup(&location).map_err(|e| format!("Expected Ok backing up but got {}", e))?;
+        assert_eq!(backup, Backup::Written(location.with_suffix(BACKUP_SUFFIX)));
+        FileHandler::write_output(&location, b"patched pc.boot").map_err(|e| format!("Expected Ok writing but got {}", e))?;
+        let backup = FileHandler::backup(&location).map_err(|e| format!("Expected Ok backing up but got {}", e))?;
+        assert_eq!(backup, Backup::AlreadyExists(location.with_suffix(BACKUP_SUFFIX)));
+
+        assert_eq!(FileHandler::read_source(&location).ok(), Some(b"patched pc.boot".to_vec()));
+        assert_eq!(FileHandler::read_source(&backup_location).ok(), Some(b"original pc.boot".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_write_leaves_image_unchanged() -> Result<(), String> {
+        let image_bytes = build_amiga_volume(ADF_DD_BLOCKS, adf::ADF_BLOCK_SIZE, 0, &[("PC/System/pc.boot", b"pc.boot")]);
+        let tempdir = create_temp_dir()?;
+        let image_path = tempdir.into_path().join("workbench.adf");
+        if let Err(e) = fs::write(&image_path, &image_bytes) {
+            return Err(format!("Failed to write the ADF image with error {}", e));
+        }
+
+        let location: FileLocation = format!("{}:PC/System/pc.boot", image_path.display()).parse()?;
+        match FileHandler::write_output(&location, &vec![0x61u8; image_bytes.len()]) {
+            Err(FileHandlerError::AmigaFilesystem(AmigaFilesystemError::DiskFull)) => assert_file_has_bytes(&image_path, &image_bytes),
+            Err(e) => Err(format!("Expected a disk full error but got {}", e)),
+            Ok(()) => Err("Expected a disk full error but got Ok".into()),
+        }
     }
 }
@@ -0,0 +1,205 @@
+//! Amiga hardfiles (HDF), images of a whole hard disk or CF card partitioned with a Rigid Disk Block (RDB), or of a
+//! single partition without one.
+
+use std::fmt;
+
+/// The RDB can be in any of the first 16 blocks of the disk
+const RDB_SEARCH_BLOCKS: usize = 16;
+const RDB_SEARCH_BLOCK_SIZE: usize = 512;
+const RDSK_ID: &[u8; 4] = b"RDSK";
+const PART_ID: &[u8; 4] = b"PART";
+const END_OF_LIST: u32 = 0xFFFFFFFF;
+
+const RDSK_BLOCK_BYTES_LONG: usize = 4;
+const RDSK_PARTITION_LIST_LONG: usize = 7;
+const PART_NEXT_LONG: usize = 4;
+const PART_FLAGS_LONG: usize = 5;
+const PART_FLAG_BOOTABLE: u32 = 1 << 0;
+const PART_DRIVE_NAME_OFFSET: usize = 36;
+
+/// The DosEnvVec in the partition block, as longs from the start of the block
+const ENVIRONMENT_SIZE_BLOCK: usize = 33;
+const ENVIRONMENT_SURFACES: usize = 35;
+const ENVIRONMENT_SECTORS_PER_BLOCK: usize = 36;
+const ENVIRONMENT_BLOCKS_PER_TRACK: usize = 37;
+const ENVIRONMENT_RESERVED: usize = 38;
+const ENVIRONMENT_LOW_CYLINDER: usize = 41;
+const ENVIRONMENT_HIGH_CYLINDER: usize = 42;
+const ENVIRONMENT_BOOT_PRIORITY: usize = 47;
+
+/// SYS: is whichever partition the Amiga boots from
+pub const BOOT_PARTITION_NAME: &str = "SYS";
+
+/// A hardfile without an RDB is a single partition laid out like an ADF
+const UNPARTITIONED_NAME: &str = "DH0";
+const UNPARTITIONED_BLOCK_SIZE: usize = 512;
+const UNPARTITIONED_RESERVED_BLOCKS: u32 = 2;
+
+/// The part of a path which separates the image from the partition and file inside it, e.g. card.hdf:SYS:PC/System/pc.boot
+pub const HDF_PATH_SEPARATOR: &str = ".hdf:";
+
+#[derive(Debug, PartialEq)]
+pub enum HdfError {
+    NoRigidDiskBlock,
+    BadPartitionBlock(u32),
+    PartitionNotFound(String),
+    NoBootablePartition,
+    PartitionOutsideImage(String),
+}
+
+impl fmt::Display for HdfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HdfError::NoRigidDiskBlock => write!(f, "No Rigid Disk Block or AmigaDOS filesystem was found in the hardfile"),
+            HdfError::BadPartitionBlock(block) => write!(f, "The partition block at block {} is damaged", block),
+            HdfError::PartitionNotFound(name) => write!(f, "There is no {}: partition in the hardfile", name),
+            HdfError::NoBootablePartition => write!(f, "There is no bootable partition in the hardfile to use as SYS:"),
+            HdfError::PartitionOutsideImage(name) => write!(f, "The {}: partition extends past the end of the hardfile", name),
+        }
+    }
+}
+
+impl std::error::Error for HdfError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub name: String,
+    pub bootable: bool,
+    pub boot_priority: i32,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub block_size: usize,
+    pub reserved_blocks: u32,
+}
+
+fn long(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes.get(offset..offset + 4).map(|long| u32::from_be_bytes([long[0], long[1], long[2], long[3]]))
+}
+
+/// The longs of an RDB style block, checking its id and that the first SummedLongs longs add up to 0
+fn rdb_block(image_bytes: &[u8], start: usize, id: &[u8; 4]) -> Option<Vec<u32>> {
+    if image_bytes.get(start..start + 4)? != id {
+        return None;
+    }
+
+    let summed_longs = long(image_bytes, start + 4)? as usize;
+    let longs: Vec<u32> = (0..summed_longs).map(|index| long(image_bytes, start + index * 4)).collect::<Option<Vec<u32>>>()?;
+
+    if longs.len() < 2 || longs.iter().fold(0u32, |sum, long| sum.wrapping_add(*long)) != 0 {
+        return None;
+    }
+
+    Some(longs)
+}
+
+/// All the partitions in the hardfile, in the order they are in the partition list
+pub fn partitions(image_bytes: &[u8]) -> Result<Vec<Partition>, HdfError> {
+    let rigid_disk_block = (0..RDB_SEARCH_BLOCKS).find_map(|block| rdb_block(image_bytes, block * RDB_SEARCH_BLOCK_SIZE, RDSK_ID));
+
+    let rigid_disk_block = match rigid_disk_block {
+        Some(rigid_disk_block) => rigid_disk_block,
+        None if image_bytes.starts_with(b"DOS") => {
+            return Ok(vec![Partition {
+                name: UNPARTITIONED_NAME.into(),
+                bootable: true,
+                boot_priority: 0,
+                start_byte: 0,
+                end_byte: image_bytes.len(),
+                block_size: UNPARTITIONED_BLOCK_SIZE,
+                reserved_blocks: UNPARTITIONED_RESERVED_BLOCKS,
+            }]);
+        },
+        None => return Err(HdfError::NoRigidDiskBlock),
+    };
+
+    let block_bytes = rigid_disk_block.get(RDSK_BLOCK_BYTES_LONG).copied().unwrap_or(0) as usize;
+    let mut partitions: Vec<Partition> = Vec::new();
+    let mut block = rigid_disk_block.get(RDSK_PARTITION_LIST_LONG).copied().unwrap_or(END_OF_LIST);
+
+    while block != END_OF_LIST {
+        let start = block as usize * block_bytes;
+        let partition_block = rdb_block(image_bytes, start, PART_ID).ok_or(HdfError::BadPartitionBlock(block))?;
+        if partition_block.len() <= ENVIRONMENT_BOOT_PRIORITY {
+            return Err(HdfError::BadPartitionBlock(block));
+        }
+
+        let name_length = (image_bytes[start + PART_DRIVE_NAME_OFFSET] as usize).min(31);
+        let name_bytes = &image_bytes[start + PART_DRIVE_NAME_OFFSET + 1..start + PART_DRIVE_NAME_OFFSET + 1 + name_length];
+
+        let sector_size = partition_block[ENVIRONMENT_SIZE_BLOCK] as usize * 4;
+        let cylinder_size = partition_block[ENVIRONMENT_SURFACES] as usize * partition_block[ENVIRONMENT_BLOCKS_PER_TRACK] as usize * sector_size;
+        let partition = Partition {
+            name: name_bytes.iter().map(|byte| *byte as char).collect(),
+            bootable: partition_block[PART_FLAGS_LONG] & PART_FLAG_BOOTABLE != 0,
+            boot_priority: partition_block[ENVIRONMENT_BOOT_PRIORITY] as i32,
+            start_byte: partition_block[ENVIRONMENT_LOW_CYLINDER] as usize * cylinder_size,
+            end_byte: (partition_block[ENVIRONMENT_HIGH_CYLINDER] as usize + 1) * cylinder_size,
+            block_size: sector_size * (partition_block[ENVIRONMENT_SECTORS_PER_BLOCK] as usize).max(1),
+            reserved_blocks: partition_block[ENVIRONMENT_RESERVED],
+        };
+
+        if partitions.iter().any(|existing| existing.start_byte == partition.start_byte) {
+            return Err(HdfError::BadPartitionBlock(block));
+        }
+        partitions.push(partition);
+        block = partition_block[PART_NEXT_LONG];
+    }
+
+    Ok(partitions)
+}
+
+/// Find a partition by its device name (e.g. DH0), or SYS for the bootable partition with the highest boot priority
+pub fn find_partition(image_bytes: &[u8], name: &str) -> Result<Partition, HdfError> {
+    let partitions = partitions(image_bytes)?;
+
+    let partition = if name.eq_ignore_ascii_case(BOOT_PARTITION_NAME) {
+        let mut bootable: Vec<Partition> = partitions.into_iter().filter(|partition| partition.bootable).collect();
+        // The sort is stable so the first partition in the list wins a tie, as it does on the Amiga
+        bootable.sort_by_key(|partition| std::cmp::Reverse(partition.boot_priority));
+        bootable.into_iter().next().ok_or(HdfError::NoBootablePartition)?
+    } else {
+        partitions.into_iter()
+            .find(|partition| partition.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| HdfError::PartitionNotFound(name.into()))?
+    };
+
+    if partition.end_byte > image_bytes.len() || partition.start_byte >= partition.end_byte {
+        return Err(HdfError::PartitionOutsideImage(partition.name));
+    }
+
+    Ok(partition)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_amiga_volume, build_rdb_hardfile};
+
+    fn hardfile() -> Vec<u8> {
+        let work = build_amiga_volume(64, 512, 1, &[("pc.boot", b"work")]);
+        let system = build_amiga_volume(64, 512, 1, &[("PC/System/pc.boot", b"system")]);
+        let spare = build_amiga_volume(64, 512, 1, &[]);
+
+        build_rdb_hardfile(&[("DH1", true, -1, &work), ("DH0", true, 0, &system), ("DH2", false, 5, &spare)])
+    }
+
+    #[test]
+    fn test_find_partition() {
+        let image_bytes = hardfile();
+
+        let system = find_partition(&image_bytes, "SYS").unwrap();
+        assert_eq!(system.name, "DH0");
+        assert_eq!(find_partition(&image_bytes, "dh0"), Ok(system.clone()));
+        assert_eq!((system.block_size, system.reserved_blocks, system.end_byte - system.start_byte), (512, 2, 64 * 512));
+        assert_eq!(find_partition(&image_bytes, "DH1").unwrap().start_byte, system.start_byte - 64 * 512);
+        assert_eq!(find_partition(&image_bytes, "DH3"), Err(HdfError::PartitionNotFound("DH3".into())));
+    }
+
+    #[test]
+    fn test_unpartitioned_and_not_a_hardfile() {
+        let image_bytes = build_amiga_volume(64, 512, 0, &[]);
+        assert_eq!(find_partition(&image_bytes, "SYS").unwrap().end_byte, 64 * 512);
+
+        assert_eq!(find_partition(&[0u8; 8192], "SYS"), Err(HdfError::NoRigidDiskBlock));
+    }
+}
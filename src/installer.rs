@@ -0,0 +1,279 @@
+//! Installing the patched pc.boot into a directory which mirrors SYS:PC/System (a UAE host folder or an unpacked disk
+//! image) and putting everything back again. Every step is planned and checked before any file is touched, and if a step
+//! still fails the steps already carried out are undone, so a half finished install doesn't leave a pc.boot.original
+//! which isn't the original.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::file_handler::BACKUP_SUFFIX;
+
+pub const PC_BOOT_FILE_NAME: &str = "pc.boot";
+/// Autoboot control conflicts with the XTIDE, so it is disabled by renaming it
+pub const ABOOT_CTRL_FILE_NAME: &str = "aboot.ctrl";
+
+#[derive(Debug)]
+pub enum InstallerError {
+    NotADirectory(PathBuf),
+    WouldOverwrite(PathBuf),
+    NothingToUninstall(PathBuf),
+    CouldntChange(PathBuf, std::io::Error),
+}
+
+impl fmt::Display for InstallerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstallerError::NotADirectory(path) => write!(f, "{} is not a directory, give the directory which mirrors SYS:PC/System", path.display()),
+            InstallerError::WouldOverwrite(path) => write!(f, "{} already exists and would be overwritten, nothing was changed", path.display()),
+            InstallerError::NothingToUninstall(path) => write!(f, "There is no {}{} or {}{} in {} to put back", PC_BOOT_FILE_NAME, BACKUP_SUFFIX, ABOOT_CTRL_FILE_NAME, BACKUP_SUFFIX, path.display()),
+            InstallerError::CouldntChange(path, e) => write!(f, "Couldn't change {} with error {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for InstallerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstallerError::CouldntChange(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InstallStep {
+    Copy { from: PathBuf, to: PathBuf },
+    /// An earlier backup is kept, as it is more likely to be the real original
+    KeepBackup(PathBuf),
+    WritePatched { path: PathBuf, bytes: Vec<u8> },
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| path.display().to_string())
+}
+
+impl fmt::Display for InstallStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstallStep::Copy { from, to } => write!(f, "Copied {} to {}", file_name(from), file_name(to)),
+            InstallStep::KeepBackup(path) => write!(f, "Kept the existing {}", file_name(path)),
+            InstallStep::WritePatched { path, .. } => write!(f, "Wrote the patched {}", file_name(path)),
+            InstallStep::Rename { from, to } => write!(f, "Renamed {} to {}", file_name(from), file_name(to)),
+        }
+    }
+}
+
+/// How to put back what a step changed
+#[derive(Debug)]
+enum Undo {
+    Nothing,
+    Remove(PathBuf),
+    Restore { path: PathBuf, bytes: Vec<u8> },
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+impl InstallStep {
+    fn apply(&self) -> Result<Undo, InstallerError> {
+        let (path, result) = match self {
+            InstallStep::Copy { from, to } => (to, fs::copy(from, to).map(|_| Undo::Remove(to.clone()))),
+            InstallStep::KeepBackup(_) => return Ok(Undo::Nothing),
+            InstallStep::WritePatched { path, bytes } => {
+                let undo = match fs::read(path) {
+                    Ok(original) => Undo::Restore { path: path.clone(), bytes: original },
+                    Err(_) => Undo::Remove(path.clone()),
+                };
+                (path, fs::write(path, bytes).map(|_| undo))
+            },
+            InstallStep::Rename { from, to } => (from, fs::rename(from, to).map(|_| Undo::Rename { from: to.clone(), to: from.clone() })),
+        };
+
+        result.map_err(|e| InstallerError::CouldntChange(path.clone(), e))
+    }
+}
+
+impl Undo {
+    fn apply(&self) -> std::io::Result<()> {
+        match self {
+            Undo::Nothing => Ok(()),
+            Undo::Remove(path) => fs::remove_file(path),
+            Undo::Restore { path, bytes } => fs::write(path, bytes),
+            Undo::Rename { from, to } => fs::rename(from, to),
+        }
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(BACKUP_SUFFIX);
+    backup_path.into()
+}
+
+fn check_directory(directory: &Path) -> Result<(), InstallerError> {
+    if ! directory.is_dir() {
+        return Err(InstallerError::NotADirectory(directory.into()));
+    }
+    Ok(())
+}
+
+/// Plan the install, patched_pc_boot is None when the pc.boot in the directory is already patched
+pub fn plan_install(directory: &Path, patched_pc_boot: Option<Vec<u8>>) -> Result<Vec<InstallStep>, InstallerError> {
+    check_directory(directory)?;
+    let mut steps: Vec<InstallStep> = Vec::new();
+
+    if let Some(bytes) = patched_pc_boot {
+        let pc_boot = directory.join(PC_BOOT_FILE_NAME);
+        let pc_boot_backup = backup_path(&pc_boot);
+
+        if pc_boot_backup.exists() {
+            steps.push(InstallStep::KeepBackup(pc_boot_backup));
+        } else {
+            steps.push(InstallStep::Copy { from: pc_boot.clone(), to: pc_boot_backup });
+        }
+        steps.push(InstallStep::WritePatched { path: pc_boot, bytes });
+    }
+
+    let aboot_ctrl = directory.join(ABOOT_CTRL_FILE_NAME);
+    if aboot_ctrl.exists() {
+        let aboot_ctrl_backup = backup_path(&aboot_ctrl);
+        if aboot_ctrl_backup.exists() {
+            return Err(InstallerError::WouldOverwrite(aboot_ctrl_backup));
+        }
+        steps.push(InstallStep::Rename { from: aboot_ctrl, to: aboot_ctrl_backup });
+    }
+
+    Ok(steps)
+}
+
+/// Plan putting back the original pc.boot and aboot.ctrl
+pub fn plan_uninstall(directory: &Path) -> Result<Vec<InstallStep>, InstallerError> {
+    check_directory(directory)?;
+    let mut steps: Vec<InstallStep> = Vec::new();
+
+    let pc_boot = directory.join(PC_BOOT_FILE_NAME);
+    if backup_path(&pc_boot).exists() {
+        steps.push(InstallStep::Rename { from: backup_path(&pc_boot), to: pc_boot });
+    }
+
+    let aboot_ctrl = directory.join(ABOOT_CTRL_FILE_NAME);
+    if backup_path(&aboot_ctrl).exists() {
+        if aboot_ctrl.exists() {
+            return Err(InstallerError::WouldOverwrite(aboot_ctrl));
+        }
+        steps.push(InstallStep::Rename { from: backup_path(&aboot_ctrl), to: aboot_ctrl });
+    }
+
+    if steps.is_empty() {
+        return Err(InstallerError::NothingToUninstall(directory.into()));
+    }
+
+    Ok(steps)
+}
+
+/// Carry out the steps in order, stopping at the first which fails and undoing those already carried out
+pub fn apply(steps: &[InstallStep]) -> Result<(), InstallerError> {
+    let mut undos: Vec<Undo> = Vec::new();
+    for step in steps {
+        match step.apply() {
+            Ok(undo) => undos.push(undo),
+            Err(e) => {
+                for undo in undos.iter().rev() {
+                    if let Err(undo_error) = undo.apply() {
+                        log::error!("Couldn't undo {:?} after the install failed: {}", undo, undo_error);
+                    }
+                }
+                return Err(e);
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::create_temp_dir;
+
+    fn pc_system_directory(files: &[(&str, &[u8])]) -> Result<PathBuf, String> {
+        let directory = create_temp_dir()?.into_path();
+        for (name, bytes) in files {
+            if let Err(e) = fs::write(directory.join(name), bytes) {
+                return Err(format!("Failed to write {} with error {}", name, e));
+            }
+        }
+        Ok(directory)
+    }
+
+    fn read(directory: &Path, name: &str) -> Option<Vec<u8>> {
+        fs::read(directory.join(name)).ok()
+    }
+
+    #[test]
+    fn test_install_then_uninstall() -> Result<(), String> {
+        let directory = pc_system_directory(&[("pc.boot", b"original"), ("aboot.ctrl", b"aboot")])?;
+
+        let steps = plan_install(&directory, Some(b"patched".to_vec())).map_err(|e| format!("{}", e))?;
+        let manifest: Vec<String> = steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(manifest, vec!["Copied pc.boot to pc.boot.original", "Wrote the patched pc.boot", "Renamed aboot.ctrl to aboot.ctrl.original"]);
+        apply(&steps).map_err(|e| format!("{}", e))?;
+
+        assert_eq!(read(&directory, "pc.boot"), Some(b"patched".to_vec()));
+        assert_eq!(read(&directory, "pc.boot.original"), Some(b"original".to_vec()));
+        assert_eq!(read(&directory, "aboot.ctrl"), None);
+
+        // Installing again with the pc.boot already patched has nothing left to do
+        assert_eq!(plan_install(&directory, None).map_err(|e| format!("{}", e))?, vec![]);
+
+        let steps = plan_uninstall(&directory).map_err(|e| format!("{}", e))?;
+        apply(&steps).map_err(|e| format!("{}", e))?;
+
+        assert_eq!(read(&directory, "pc.boot"), Some(b"original".to_vec()));
+        assert_eq!(read(&directory, "aboot.ctrl"), Some(b"aboot".to_vec()));
+        assert_eq!(read(&directory, "pc.boot.original"), None);
+        assert_eq!(read(&directory, "aboot.ctrl.original"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_step_undoes_earlier_steps() -> Result<(), String> {
+        let directory = pc_system_directory(&[("pc.boot", b"original"), ("aboot.ctrl", b"aboot")])?;
+
+        // The patched pc.boot can't be written into a directory which doesn't exist
+        let steps = vec![
+            InstallStep::Copy { from: directory.join("pc.boot"), to: directory.join("pc.boot.original") },
+            InstallStep::Rename { from: directory.join("aboot.ctrl"), to: directory.join("aboot.ctrl.original") },
+            InstallStep::WritePatched { path: directory.join("pc.boot"), bytes: b"patched".to_vec() },
+            InstallStep::WritePatched { path: directory.join("missing").join("pc.boot"), bytes: b"patched".to_vec() },
+        ];
+        match apply(&steps) {
+            Err(InstallerError::CouldntChange(path, _)) => assert_eq!(path, directory.join("missing").join("pc.boot")),
+            other => return Err(format!("Expected InstallerError::CouldntChange but got {:?}", other)),
+        }
+
+        assert_eq!(read(&directory, "pc.boot"), Some(b"original".to_vec()));
+        assert_eq!(read(&directory, "aboot.ctrl"), Some(b"aboot".to_vec()));
+        assert_eq!(read(&directory, "pc.boot.original"), None);
+        assert_eq!(read(&directory, "aboot.ctrl.original"), None);
+
+        // So installing again backs up the real original
+        let steps = plan_install(&directory, Some(b"patched".to_vec())).map_err(|e| format!("{}", e))?;
+        assert_eq!(steps[0], InstallStep::Copy { from: directory.join("pc.boot"), to: directory.join("pc.boot.original") });
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_to_overwrite() -> Result<(), String> {
+        let directory = pc_system_directory(&[("pc.boot", b"original"), ("aboot.ctrl", b"aboot"), ("aboot.ctrl.original", b"older")])?;
+
+        match plan_install(&directory, Some(b"patched".to_vec())) {
+            Err(InstallerError::WouldOverwrite(path)) => assert_eq!(path, directory.join("aboot.ctrl.original")),
+            other => return Err(format!("Expected InstallerError::WouldOverwrite but got {:?}", other)),
+        }
+        match plan_uninstall(&directory) {
+            Err(InstallerError::WouldOverwrite(path)) => assert_eq!(path, directory.join("aboot.ctrl")),
+            other => return Err(format!("Expected InstallerError::WouldOverwrite but got {:?}", other)),
+        }
+        Ok(())
+    }
+}
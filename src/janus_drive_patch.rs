@@ -0,0 +1,196 @@
+//! A patch which moves the Janus hard disk to another BIOS drive number, so a hard disk controller in the PC (e.g. an
+//! XTIDE) keeps 80h and boots as C: while the JanusDisk is still there as D:.
+//!
+//! pc.boot checks the drive in the HDD ready check with MOV DL, 80h and its INT 13 handler picks out its own requests
+//! with CMP DL, 80h. Both are changed to the new unit. Only the compares in the code reached from the INT 13 handler
+//! are changed: the INT 19 bootstrap also compares DL with 80h to leave its retry loop, and changing that one alone
+//! would leave a PC with no bootable disk retrying forever.
+
+use crate::disassembler;
+use crate::option_rom::OptionRom;
+use crate::option_rom_patcher::{OptionRomPatcherError, PatchState};
+use crate::rom_patch::RomPatch;
+
+/// The default unit, the second fixed disk
+pub const DEFAULT_JANUS_DRIVE: u8 = 0x81;
+const FIRST_FIXED_DISK: u8 = 0x80;
+
+const X86_MOV_INTO_DL: u8 = 0xb2;
+const X86_CMP_DL_IMMEDIATE: [u8; 2] = [0x80, 0xfa];
+
+/// MOV AH, 10h; MOV DL, unit; INT 13h, the offset of the unit is 3
+fn hdd_ready_check(unit: u8) -> [u8; 6] {
+    [0xb4, 0x10, X86_MOV_INTO_DL, unit, 0xcd, 0x13]
+}
+const HDD_READY_CHECK_UNIT_OFFSET: usize = 3;
+
+fn cmp_dl(unit: u8) -> [u8; 3] {
+    [X86_CMP_DL_IMMEDIATE[0], X86_CMP_DL_IMMEDIATE[1], unit]
+}
+
+/// MOV AL, 13h; MOV DI, handler, which pass the INT 13 handler to the routine setting the vector after the ready check
+const SET_INT_13_HANDLER: [u8; 3] = [0xb0, 0x13, 0xbf];
+
+/// The offset of the unit byte in the HDD ready check
+fn find_ready_check_unit(option_rom: &OptionRom, unit: u8) -> Result<usize, OptionRomPatcherError> {
+    let ready_check = hdd_ready_check(unit);
+    match option_rom.bytes.windows(ready_check.len()).position(|window| window == ready_check) {
+        Some(location) => Ok(location + HDD_READY_CHECK_UNIT_OFFSET),
+        None => Err(OptionRomPatcherError::CouldntLocateHddReadyCheck),
+    }
+}
+
+/// The INT 13 handler, the first set after the HDD ready check
+fn find_int_13_handler(option_rom: &OptionRom, ready_check_unit: usize) -> Result<usize, OptionRomPatcherError> {
+    let handler = option_rom.bytes[ready_check_unit..].windows(SET_INT_13_HANDLER.len() + 2)
+        .find(|window| window.starts_with(&SET_INT_13_HANDLER))
+        .map(|window| u16::from_le_bytes([window[3], window[4]]) as usize)
+        .filter(|handler| *handler < option_rom.bytes.len());
+
+    handler.ok_or_else(|| OptionRomPatcherError::PatchSiteNotFound("INT 13 handler".into()))
+}
+
+/// The offsets of the unit byte in every CMP DL, unit decoded in the code reached from the INT 13 handler
+fn find_handler_compares(option_rom: &OptionRom, handler: usize, unit: u8) -> Vec<usize> {
+    let compare = cmp_dl(unit);
+    disassembler::trace_reachable(&option_rom.bytes, &[handler]).iter()
+        .filter(|instruction| option_rom.bytes.get(instruction.offset..instruction.end()) == Some(&compare[..]))
+        .map(|instruction| instruction.offset + 2)
+        .collect()
+}
+
+/// The offsets of the unit byte in the HDD ready check and the compares of the INT 13 handler
+fn find_unit_sites(option_rom: &OptionRom, unit: u8) -> Result<Vec<usize>, OptionRomPatcherError> {
+    let ready_check_unit = find_ready_check_unit(option_rom, unit)?;
+    let handler = find_int_13_handler(option_rom, ready_check_unit)?;
+
+    let mut sites: Vec<usize> = vec![ready_check_unit];
+    sites.extend(find_handler_compares(option_rom, handler, unit));
+    Ok(sites)
+}
+
+pub struct JanusDriveNumber {
+    unit: u8,
+    name: String,
+}
+
+impl JanusDriveNumber {
+    pub fn new(unit: u8) -> JanusDriveNumber {
+        JanusDriveNumber { unit, name: format!("janus-drive-{:02x}", unit) }
+    }
+
+    fn move_unit(&self, option_rom: &OptionRom, from: u8, to: u8) -> Result<Vec<u8>, OptionRomPatcherError> {
+        let sites = find_unit_sites(option_rom, from)?;
+
+        let mut bytes = option_rom.bytes.clone();
+        for site in sites {
+            log::debug!("Drive number at 0x{:04X} changed from {:02X} to {:02X}", site, from, to);
+            bytes[site] = to;
+        }
+        Ok(bytes)
+    }
+}
+
+impl RomPatch for JanusDriveNumber {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        "Move the Janus hard disk from drive 80h, so a PC hard disk controller boots as C:"
+    }
+
+    fn detect(&self, option_rom: &OptionRom) -> PatchState {
+        if find_unit_sites(option_rom, FIRST_FIXED_DISK).is_ok() {
+            PatchState::Unpatched
+        } else if find_unit_sites(option_rom, self.unit).is_ok() {
+            PatchState::Patched
+        } else {
+            PatchState::Unknown
+        }
+    }
+
+    fn apply(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        if self.unit == FIRST_FIXED_DISK {
+            return Err(OptionRomPatcherError::PreconditionFailed("the Janus hard disk is already drive 80h".into()));
+        }
+        // The handler couldn't tell its own requests from ones already checked for the unit, and revert couldn't either
+        let handler = find_int_13_handler(option_rom, find_ready_check_unit(option_rom, FIRST_FIXED_DISK)?)?;
+        if ! find_handler_compares(option_rom, handler, self.unit).is_empty() {
+            return Err(OptionRomPatcherError::PreconditionFailed(format!("the rom already checks for drive {:02X}h", self.unit)));
+        }
+        self.move_unit(option_rom, FIRST_FIXED_DISK, self.unit)
+    }
+
+    fn revert(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        if self.detect(option_rom) != PatchState::Patched {
+            return Err(OptionRomPatcherError::NotPatched);
+        }
+        self.move_unit(option_rom, self.unit, FIRST_FIXED_DISK)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::build_patchable_rom;
+
+    /// The offset of the INT 13 handler, which the rom passes in DI after the ready check
+    const HANDLER: usize = 0x100;
+
+    fn build_rom_with_handler(handler: &[u8]) -> Vec<u8> {
+        let mut bytes = build_patchable_rom();
+        bytes[18..20].copy_from_slice(&(HANDLER as u16).to_le_bytes());
+        bytes[HANDLER..HANDLER + handler.len()].copy_from_slice(handler);
+        bytes
+    }
+
+    #[test]
+    fn test_move_janus_drive() -> Result<(), String> {
+        // A handler which only serves drive 80h
+        let option_rom = OptionRom::from(build_rom_with_handler(&[0x80, 0xfa, 0x80, 0x75, 0x01, 0xcf, 0xcf]), 0).unwrap();
+
+        let patch = JanusDriveNumber::new(DEFAULT_JANUS_DRIVE);
+        assert_eq!(patch.name(), "janus-drive-81");
+        assert_eq!(patch.detect(&option_rom), PatchState::Unpatched);
+
+        let patched_rom = OptionRom::from(patch.apply(&option_rom).map_err(|e| format!("{}", e))?, 0).unwrap();
+        assert_eq!(patched_rom.bytes[7], 0x81);
+        assert_eq!(patched_rom.bytes[0x102], 0x81);
+        assert_eq!(patch.detect(&patched_rom), PatchState::Patched);
+        assert_eq!(JanusDriveNumber::new(0x82).detect(&patched_rom), PatchState::Unknown);
+
+        assert_eq!(patch.revert(&patched_rom).map_err(|e| format!("{}", e))?, option_rom.bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_janus_drive_refused_when_unit_is_checked() -> Result<(), String> {
+        let option_rom = OptionRom::from(build_rom_with_handler(&[0x80, 0xfa, 0x81, 0x75, 0x01, 0xcf, 0xcf]), 0).unwrap();
+
+        match JanusDriveNumber::new(DEFAULT_JANUS_DRIVE).apply(&option_rom) {
+            Ok(_) => Err("Expected the patch to be refused but it was applied".into()),
+            Err(e) => {
+                assert_eq!(e.to_string(), "The rom doesn't meet the patch's precondition: the rom already checks for drive 81h");
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_drive_check_is_left_alone() -> Result<(), String> {
+        let mut bytes = build_rom_with_handler(&[0x80, 0xfa, 0x80, 0x75, 0x01, 0xcf, 0xcf]);
+        // The end of pc.boot's INT 19 bootstrap retry loop: cmp dl,80h; je done; mov dl,80h; jmp retry; done: pop ax...
+        let bootstrap = [0x80, 0xfa, 0x80, 0x74, 0x04, 0xb2, 0x80, 0xeb, 0xa1, 0x58, 0x5b, 0x59, 0x5a];
+        bytes[0x180..0x180 + bootstrap.len()].copy_from_slice(&bootstrap);
+        let option_rom = OptionRom::from(bytes, 0).unwrap();
+
+        let patch = JanusDriveNumber::new(DEFAULT_JANUS_DRIVE);
+        let patched_rom = OptionRom::from(patch.apply(&option_rom).map_err(|e| format!("{}", e))?, 0).unwrap();
+        assert_eq!(patched_rom.bytes[HANDLER + 2], 0x81);
+        assert_eq!(&patched_rom.bytes[0x180..0x180 + bootstrap.len()], &bootstrap);
+
+        assert_eq!(patch.revert(&patched_rom).map_err(|e| format!("{}", e))?, option_rom.bytes);
+        Ok(())
+    }
+}
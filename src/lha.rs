@@ -0,0 +1,492 @@
+//! Reading files out of LHA (LZH) archives, which the Janus software was distributed in. Only extraction is supported,
+//! for the -lh0- (stored) and -lh4- to -lh7- (LZSS with static Huffman coding) methods with level 0, 1 and 2 headers.
+
+use std::fmt;
+
+/// The parts of paths which separate the archive from the path of the file inside it, e.g. janus.lha:PC/System/pc.boot
+pub const LHA_PATH_SEPARATORS: [&str; 2] = [".lha:", ".lzh:"];
+
+const METHOD_STORED: &[u8; 5] = b"-lh0-";
+const METHOD_DIRECTORY: &[u8; 5] = b"-lhd-";
+/// The compressed methods and the number of bits in their dictionary size
+const COMPRESSED_METHODS: [(&[u8; 5], u32); 4] = [(b"-lh4-", 12), (b"-lh5-", 13), (b"-lh6-", 15), (b"-lh7-", 16)];
+
+const EXTENDED_HEADER_FILE_NAME: u8 = 0x01;
+const EXTENDED_HEADER_DIRECTORY: u8 = 0x02;
+/// Directory names in the extended header are separated by 0xFF
+const DIRECTORY_SEPARATOR: u8 = 0xFF;
+
+/// Literal bytes and then match lengths from 3 to 256
+const CHARACTER_SYMBOLS: usize = 510;
+const CHARACTER_COUNT_BITS: u32 = 9;
+const MIN_MATCH_LENGTH: usize = 3;
+/// The table used to send the character code lengths
+const LENGTH_SYMBOLS: usize = 19;
+const LENGTH_COUNT_BITS: u32 = 5;
+const MAX_CODE_LENGTH: usize = 16;
+/// Matches may reach back before the start of the file, where LHA's dictionary is filled with spaces
+const INITIAL_DICTIONARY_BYTE: u8 = b' ';
+/// The original size comes from the header, so no more is allocated up front than the compressed data could plausibly
+/// expand to, and the output grows from there if it really is bigger
+const MAX_PREALLOCATED_EXPANSION: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum LhaError {
+    BadHeader(usize),
+    UnsupportedHeaderLevel(u8),
+    UnsupportedMethod(String),
+    MemberNotFound(String),
+    AmbiguousMember(String),
+    CorruptData(String),
+    CrcMismatch(String),
+}
+
+impl fmt::Display for LhaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LhaError::BadHeader(offset) => write!(f, "The archive header at byte 0x{:X} is damaged or truncated", offset),
+            LhaError::UnsupportedHeaderLevel(level) => write!(f, "Level {} archive headers aren't supported", level),
+            LhaError::UnsupportedMethod(method) => write!(f, "The compression method {} isn't supported", method),
+            LhaError::MemberNotFound(path) => write!(f, "{} was not found in the archive", path),
+            LhaError::AmbiguousMember(path) => write!(f, "More than one file in the archive is called {}, give its full path", path),
+            LhaError::CorruptData(path) => write!(f, "The compressed data for {} is corrupt", path),
+            LhaError::CrcMismatch(path) => write!(f, "{} failed its CRC check after extracting", path),
+        }
+    }
+}
+
+impl std::error::Error for LhaError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LhaMember {
+    pub path: String,
+    method: [u8; 5],
+    original_size: usize,
+    crc: u16,
+    data_start: usize,
+    data_length: usize,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    bytes.get(offset..offset + 2).map(|value| u16::from_le_bytes([value[0], value[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes.get(offset..offset + 4).map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+/// Names are stored as bytes, with \ or 0xFF separating directories depending on where the archive was made
+fn path_from_bytes(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| match byte {
+            b'\\' | &DIRECTORY_SEPARATOR => '/',
+            _ => *byte as char,
+        })
+        .collect()
+}
+
+/// The type of an extended header and its data
+type ExtendedHeader<'a> = (u8, &'a [u8]);
+
+/// The extended headers, and the offset after them. Each is its size, type and data, with the size
+/// of the first one coming before start
+fn read_extended_headers(archive: &[u8], start: usize, first_size: u16, header_offset: usize) -> Result<(Vec<ExtendedHeader<'_>>, usize), LhaError> {
+    let mut extended_headers: Vec<ExtendedHeader> = Vec::new();
+    let mut size = first_size as usize;
+    let mut position = start;
+
+    while size != 0 {
+        let extended_header = archive.get(position..position + size).ok_or(LhaError::BadHeader(header_offset))?;
+        if size < 3 {
+            return Err(LhaError::BadHeader(header_offset));
+        }
+
+        extended_headers.push((extended_header[0], &extended_header[1..size - 2]));
+        size = u16_at(extended_header, size - 2).unwrap_or(0) as usize;
+        position += extended_header.len();
+    }
+
+    Ok((extended_headers, position))
+}
+
+/// Parse the header at offset, returning the member and the offset of the next header, or None at the end of the archive
+fn read_header(archive: &[u8], offset: usize) -> Result<Option<(LhaMember, usize)>, LhaError> {
+    if archive.get(offset).copied().unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+
+    let bad_header = LhaError::BadHeader(offset);
+    let header = archive.get(offset..offset + 24).ok_or(LhaError::BadHeader(offset))?;
+    let mut method = [0u8; 5];
+    method.copy_from_slice(&header[2..7]);
+    let compressed_size = u32_at(header, 7).ok_or(LhaError::BadHeader(offset))? as usize;
+    let original_size = u32_at(header, 11).ok_or(LhaError::BadHeader(offset))? as usize;
+    let level = header[20];
+
+    let (name, crc, extended_headers, data_start, data_length) = match level {
+        0 | 1 => {
+            let header_size = header[0] as usize + 2;
+            let base_header = archive.get(offset..offset + header_size).ok_or(LhaError::BadHeader(offset))?;
+            let checksum = base_header[2..].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            let name_length = header[21] as usize;
+
+            if checksum != header[1] || header_size < 24 + name_length {
+                return Err(bad_header);
+            }
+
+            let name = &base_header[22..22 + name_length];
+            let crc = u16_at(base_header, 22 + name_length).ok_or(LhaError::BadHeader(offset))?;
+
+            if level == 0 {
+                (name, crc, Vec::new(), offset + header_size, compressed_size)
+            } else {
+                let first_size = u16_at(base_header, header_size - 2).ok_or(LhaError::BadHeader(offset))?;
+                let (extended_headers, data_start) = read_extended_headers(archive, offset + header_size, first_size, offset)?;
+                // The compressed size of a level 1 header includes the extended headers
+                let data_length = compressed_size.checked_sub(data_start - offset - header_size).ok_or(bad_header)?;
+                (name, crc, extended_headers, data_start, data_length)
+            }
+        },
+        2 => {
+            let header_size = u16_at(header, 0).ok_or(LhaError::BadHeader(offset))? as usize;
+            let crc = u16_at(header, 21).ok_or(LhaError::BadHeader(offset))?;
+            let first_size = u16_at(archive, offset + 24).ok_or(LhaError::BadHeader(offset))?;
+            let (extended_headers, extended_headers_end) = read_extended_headers(archive, offset + 26, first_size, offset)?;
+
+            if extended_headers_end > offset + header_size {
+                return Err(bad_header);
+            }
+            (&[][..], crc, extended_headers, offset + header_size, compressed_size)
+        },
+        level => return Err(LhaError::UnsupportedHeaderLevel(level)),
+    };
+
+    let mut file_name = path_from_bytes(name);
+    let mut directory = String::new();
+    for (header_type, data) in extended_headers {
+        match header_type {
+            EXTENDED_HEADER_FILE_NAME => file_name = path_from_bytes(data),
+            EXTENDED_HEADER_DIRECTORY => directory = path_from_bytes(data),
+            _ => {},
+        }
+    }
+
+    let directory = directory.trim_end_matches('/');
+    let path = if directory.is_empty() { file_name } else { format!("{}/{}", directory, file_name) };
+
+    if data_start + data_length > archive.len() {
+        return Err(LhaError::BadHeader(offset));
+    }
+
+    let member = LhaMember { path, method, original_size, crc, data_start, data_length };
+    Ok(Some((member, data_start + data_length)))
+}
+
+/// All the files in the archive, directories are left out
+pub fn members(archive: &[u8]) -> Result<Vec<LhaMember>, LhaError> {
+    let mut members: Vec<LhaMember> = Vec::new();
+    let mut offset = 0;
+
+    while let Some((member, next_offset)) = read_header(archive, offset)? {
+        if &member.method != METHOD_DIRECTORY {
+            members.push(member);
+        }
+        offset = next_offset;
+    }
+
+    Ok(members)
+}
+
+/// Find a member by its path, ignoring case, or by just its file name if that is unique in the archive
+pub fn find_member(archive: &[u8], member_path: &str) -> Result<LhaMember, LhaError> {
+    let members = members(archive)?;
+
+    if let Some(member) = members.iter().find(|member| member.path.eq_ignore_ascii_case(member_path)) {
+        return Ok(member.clone());
+    }
+
+    let same_name: Vec<&LhaMember> = members.iter()
+        .filter(|member| ! member_path.contains('/') && member.path.rsplit('/').next().unwrap_or("").eq_ignore_ascii_case(member_path))
+        .collect();
+
+    match same_name.as_slice() {
+        [member] => Ok((*member).clone()),
+        [] => Err(LhaError::MemberNotFound(member_path.into())),
+        _ => Err(LhaError::AmbiguousMember(member_path.into())),
+    }
+}
+
+pub fn extract(archive: &[u8], member_path: &str) -> Result<Vec<u8>, LhaError> {
+    let member = find_member(archive, member_path)?;
+    let data = &archive[member.data_start..member.data_start + member.data_length];
+
+    let contents = if &member.method == METHOD_STORED {
+        data.to_vec()
+    } else {
+        let dictionary_bits = COMPRESSED_METHODS.iter()
+            .find(|(method, _)| **method == member.method)
+            .map(|(_, dictionary_bits)| *dictionary_bits)
+            .ok_or_else(|| LhaError::UnsupportedMethod(String::from_utf8_lossy(&member.method).into_owned()))?;
+
+        decompress(data, member.original_size, dictionary_bits).ok_or_else(|| LhaError::CorruptData(member.path.clone()))?
+    };
+
+    if contents.len() != member.original_size || crc16(&contents) != member.crc {
+        return Err(LhaError::CrcMismatch(member.path));
+    }
+
+    Ok(contents)
+}
+
+/// The CRC-16 used by LHA (polynomial 0xA001, reflected)
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u16, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 })
+    })
+}
+
+/// Reads bits most significant first, reading zeros past the end like LHA does
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit_position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u32) -> usize {
+        (0..count).fold(0, |value, _| {
+            let byte = self.bytes.get(self.bit_position / 8).copied().unwrap_or(0);
+            let bit = (byte >> (7 - self.bit_position % 8)) & 1;
+            self.bit_position += 1;
+            (value << 1) | bit as usize
+        })
+    }
+
+    fn is_past_end(&self) -> bool {
+        self.bit_position > self.bytes.len() * 8
+    }
+}
+
+/// A canonical Huffman code, or a single symbol sent without any bits
+struct HuffmanTable {
+    single_symbol: Option<usize>,
+    /// The number of codes, first code and index into symbols of the first code for each length
+    lengths: Vec<(usize, usize, usize)>,
+    symbols: Vec<usize>,
+}
+
+impl HuffmanTable {
+    fn single(symbol: usize, symbol_count: usize) -> Option<HuffmanTable> {
+        if symbol >= symbol_count {
+            return None;
+        }
+        Some(HuffmanTable { single_symbol: Some(symbol), lengths: Vec::new(), symbols: Vec::new() })
+    }
+
+    fn from_lengths(code_lengths: &[usize]) -> Option<HuffmanTable> {
+        let mut symbols: Vec<usize> = (0..code_lengths.len()).filter(|symbol| code_lengths[*symbol] != 0).collect();
+        symbols.sort_by_key(|symbol| code_lengths[*symbol]);
+
+        let mut lengths: Vec<(usize, usize, usize)> = Vec::with_capacity(MAX_CODE_LENGTH);
+        let (mut code, mut index) = (0usize, 0usize);
+        for length in 1..=MAX_CODE_LENGTH {
+            let count = code_lengths.iter().filter(|code_length| **code_length == length).count();
+            lengths.push((count, code, index));
+            code = (code + count) << 1;
+            index += count;
+        }
+
+        // Codes longer than 16 bits, or more codes than fit, mean the data is corrupt
+        if index != symbols.len() || code > 1 << (MAX_CODE_LENGTH + 1) {
+            return None;
+        }
+
+        Some(HuffmanTable { single_symbol: None, lengths, symbols })
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Option<usize> {
+        if let Some(symbol) = self.single_symbol {
+            return Some(symbol);
+        }
+
+        let mut code = 0;
+        for (count, first_code, first_index) in &self.lengths {
+            code = (code << 1) | bits.read(1);
+            if code >= *first_code && code < first_code + count {
+                return Some(self.symbols[first_index + code - first_code]);
+            }
+        }
+
+        None
+    }
+}
+
+/// Read the code lengths for the length table or the position table. After the special index up to 3 zero lengths
+/// are sent as a 2 bit count
+fn read_length_table(bits: &mut BitReader, symbol_count: usize, count_bits: u32, special_index: Option<usize>) -> Option<HuffmanTable> {
+    let count = bits.read(count_bits);
+    if count == 0 {
+        return HuffmanTable::single(bits.read(count_bits), symbol_count);
+    }
+    if count > symbol_count {
+        return None;
+    }
+
+    let mut code_lengths = vec![0usize; symbol_count];
+    let mut index = 0;
+    while index < count {
+        // Lengths of 7 and over are 111 followed by a 1 for each extra and then a 0
+        let mut length = bits.read(3);
+        if length == 7 {
+            while bits.read(1) == 1 {
+                length += 1;
+                if length > MAX_CODE_LENGTH {
+                    return None;
+                }
+            }
+        }
+        code_lengths[index] = length;
+        index += 1;
+
+        if Some(index) == special_index {
+            index += bits.read(2);
+        }
+    }
+
+    HuffmanTable::from_lengths(&code_lengths)
+}
+
+/// Read the code lengths for the characters and match lengths, sent using the length table with runs of zeros
+fn read_character_table(bits: &mut BitReader, length_table: &HuffmanTable) -> Option<HuffmanTable> {
+    let count = bits.read(CHARACTER_COUNT_BITS);
+    if count == 0 {
+        return HuffmanTable::single(bits.read(CHARACTER_COUNT_BITS), CHARACTER_SYMBOLS);
+    }
+    if count > CHARACTER_SYMBOLS {
+        return None;
+    }
+
+    let mut code_lengths = vec![0usize; CHARACTER_SYMBOLS];
+    let mut index = 0;
+    while index < count {
+        match length_table.decode(bits)? {
+            0 => index += 1,
+            1 => index += bits.read(4) + 3,
+            2 => index += bits.read(CHARACTER_COUNT_BITS) + 20,
+            length => {
+                code_lengths[index] = length - 2;
+                index += 1;
+            },
+        }
+    }
+
+    if index > count {
+        return None;
+    }
+
+    HuffmanTable::from_lengths(&code_lengths)
+}
+
+fn decompress(data: &[u8], original_size: usize, dictionary_bits: u32) -> Option<Vec<u8>> {
+    let position_symbols = dictionary_bits as usize + 1;
+    let position_count_bits = if dictionary_bits >= 15 { 5 } else { 4 };
+
+    let mut bits = BitReader { bytes: data, bit_position: 0 };
+    let mut output: Vec<u8> = Vec::with_capacity(original_size.min(data.len().saturating_mul(MAX_PREALLOCATED_EXPANSION)));
+
+    while output.len() < original_size {
+        let block_size = bits.read(16);
+        if block_size == 0 || bits.is_past_end() {
+            return None;
+        }
+
+        let length_table = read_length_table(&mut bits, LENGTH_SYMBOLS, LENGTH_COUNT_BITS, Some(3))?;
+        let character_table = read_character_table(&mut bits, &length_table)?;
+        let position_table = read_length_table(&mut bits, position_symbols, position_count_bits, None)?;
+
+        for _ in 0..block_size {
+            if output.len() >= original_size {
+                break;
+            }
+
+            let character = character_table.decode(&mut bits)?;
+            if character < 256 {
+                output.push(character as u8);
+                continue;
+            }
+
+            let length = character - 256 + MIN_MATCH_LENGTH;
+            let position_bits = position_table.decode(&mut bits)?;
+            let distance = match position_bits {
+                0 => 1,
+                _ => (1 << (position_bits - 1)) + bits.read(position_bits as u32 - 1) + 1,
+            };
+
+            for _ in 0..length {
+                let byte = match output.len().checked_sub(distance) {
+                    Some(source) => output[source],
+                    None => INITIAL_DICTIONARY_BYTE,
+                };
+                output.push(byte);
+            }
+        }
+
+        if bits.is_past_end() {
+            return None;
+        }
+    }
+
+    output.truncate(original_size);
+    Some(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::load_fixture;
+
+    // The fixtures were checked against libarchive (bsdtar), janus-lh5.lha has level 1 headers and janus-lh6.lha
+    // has level 2 headers
+    #[test]
+    fn test_members() -> Result<(), String> {
+        let archive = load_fixture("janus-lh5.lha")?;
+        let paths: Vec<String> = members(&archive).unwrap().into_iter().map(|member| member.path).collect();
+
+        assert_eq!(paths, vec!["Janus/PC/System/pc.boot", "Janus/ReadMe", "Janus/empty"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_each_method() -> Result<(), String> {
+        let pc_boot = load_fixture("pc.boot.valid")?;
+
+        assert_eq!(extract(&load_fixture("janus-lh5.lha")?, "Janus/PC/System/pc.boot"), Ok(pc_boot.clone()));
+        assert_eq!(extract(&load_fixture("janus-lh6.lha")?, "pc/system/PC.BOOT"), Ok(pc_boot.clone()));
+        assert_eq!(extract(&load_fixture("janus-lh0.lzh")?, "pc.boot"), Ok(pc_boot));
+
+        let readme = extract(&load_fixture("janus-lh5.lha")?, "ReadMe").unwrap();
+        assert_eq!(extract(&load_fixture("janus-lh6.lha")?, "ReadMe"), Ok(readme));
+        assert_eq!(extract(&load_fixture("janus-lh5.lha")?, "empty"), Ok(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_errors() -> Result<(), String> {
+        let mut archive = load_fixture("janus-lh5.lha")?;
+        assert_eq!(extract(&archive, "aboot.ctrl"), Err(LhaError::MemberNotFound("aboot.ctrl".into())));
+
+        let member = find_member(&archive, "pc.boot").unwrap();
+        archive[member.data_start + 40] ^= 0x10;
+        assert!(matches!(extract(&archive, "pc.boot"), Err(LhaError::CorruptData(_)) | Err(LhaError::CrcMismatch(_))));
+
+        archive[3] = b'x';
+        assert_eq!(extract(&archive, "pc.boot"), Err(LhaError::BadHeader(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_huge_original_size_isnt_allocated() {
+        // A corrupt header claiming a 4GB file ends with the data running out rather than allocating it up front
+        let data = [0xFFu8; 64];
+        assert_eq!(decompress(&data, u32::MAX as usize, 13), None);
+    }
+}
@@ -0,0 +1,54 @@
+//! Diagnostics are logged to stderr so stdout only carries the result of the command, which may be JSON.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The level shown for the number of -v and -q given. Progress and the rom sizes are shown by default, -v adds the
+/// details of the patch, -vv everything, -q only warnings and -qq nothing but the result or error
+pub fn level_filter(verbose: u8, quiet: u8) -> LevelFilter {
+    match i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=-2 => LevelFilter::Off,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+pub fn init(verbose: u8, quiet: u8) {
+    // Only fails when a logger is already set, which leaves that logger in place
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level_filter(verbose, quiet));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() -> Result<(), String> {
+        assert_eq!(level_filter(0, 0), LevelFilter::Info);
+        assert_eq!(level_filter(1, 0), LevelFilter::Debug);
+        assert_eq!(level_filter(3, 0), LevelFilter::Trace);
+        assert_eq!(level_filter(0, 1), LevelFilter::Warn);
+        assert_eq!(level_filter(1, 3), LevelFilter::Off);
+        Ok(())
+    }
+}
@@ -1,10 +1,33 @@
 use std::process::exit;
 
+mod adf;
+mod amiga_filesystem;
+mod assembler;
+mod batch;
 mod cli;
+mod code_cave;
 mod commands;
+mod disassembler;
+mod eprom;
+mod error;
 mod option_rom;
 mod option_rom_patcher;
 mod file_handler;
+mod hdf;
+mod lha;
+mod manifest;
+mod logger;
+mod installer;
+mod janus_drive_patch;
+mod memory_map;
+mod patch_file;
+mod rom_formats;
+mod rom_patch;
+mod shift_bypass_patch;
+mod tui;
+mod verify;
+mod xtide;
+mod xtide_romvars;
 
 #[cfg(test)]
 mod test_helpers;
@@ -15,15 +38,19 @@ use crate::file_handler::FileHandler;
 
 fn main() {
     let args = Cli::new();
+    logger::init(args.verbose, args.quiet);
 
     match commands::process::process(args) {
         Ok(message) => {
-            println!("{}", message);
+            // Nothing is printed when the output went to stdout
+            if ! message.is_empty() {
+                println!("{}", message);
+            }
             exit(0);
         },
         Err(e) => {
             eprintln!("{}", e);
-            exit(1);
+            exit(e.exit_code());
         }
     };
 }
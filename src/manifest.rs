@@ -0,0 +1,240 @@
+//! A JSON manifest written alongside the output of write-rom recording how it was made: the source it came from, where
+//! the rom is, the checksum byte and the bytes each patch changed. check-manifest compares a file with its manifest, so a
+//! pc.boot found later can be traced back to its source and checked it hasn't been changed since.
+
+use std::fmt;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::option_rom::OptionRom;
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Invalid(serde_json::Error),
+    /// The report of the checks, some of which failed
+    DoesntMatch(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManifestError::Invalid(e) => write!(f, "The manifest is invalid: {}", e),
+            ManifestError::DoesntMatch(report) => write!(f, "{}", report),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ManifestError::Invalid(e) => Some(e),
+            ManifestError::DoesntMatch(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchAction {
+    Applied,
+    Reverted,
+}
+
+/// Bytes a patch changed, from start up to but not including end, as offsets in the rom
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangedRange {
+    pub start: usize,
+    pub end: usize,
+    /// The bytes the patch left there, in hex
+    pub bytes: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchRecord {
+    pub name: String,
+    pub action: PatchAction,
+    pub ranges: Vec<ChangedRange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub path: String,
+    pub length: usize,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChecksumByteRecord {
+    /// The offset in the rom of the byte changed to correct the checksum
+    pub offset: usize,
+    pub original: u8,
+    pub new: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RomRecord {
+    /// Where the rom is in the output
+    pub offset: usize,
+    pub size: usize,
+    /// None when nothing changed the rom, so there was no checksum to correct
+    pub checksum_byte: Option<ChecksumByteRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool: String,
+    pub version: String,
+    /// When the output was written, in RFC 3339
+    pub created: String,
+    pub source: FileRecord,
+    pub output: FileRecord,
+    pub rom: RomRecord,
+    pub patches: Vec<PatchRecord>,
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The runs of bytes which differ between the roms before and after a patch, leaving out the checksum byte which is
+/// recorded on its own
+fn changed_ranges(before: &OptionRom, after: &OptionRom, checksum_byte_offset: Option<usize>) -> Vec<ChangedRange> {
+    let changed: Vec<bool> = before.bytes.iter().zip(&after.bytes).enumerate()
+        .map(|(offset, (before, after))| before != after && Some(offset) != checksum_byte_offset)
+        .collect();
+
+    let mut ranges: Vec<ChangedRange> = Vec::new();
+    let mut start = 0;
+    while start < changed.len() {
+        if ! changed[start] {
+            start += 1;
+            continue;
+        }
+        let end = (start..changed.len()).find(|offset| ! changed[*offset]).unwrap_or(changed.len());
+        ranges.push(ChangedRange { start, end, bytes: to_hex(&after.bytes[start..end]) });
+        start = end;
+    }
+    ranges
+}
+
+impl PatchRecord {
+    pub fn new(name: &str, action: PatchAction, before: &OptionRom, after: &OptionRom, checksum_byte_offset: Option<usize>) -> PatchRecord {
+        PatchRecord { name: name.to_string(), action, ranges: changed_ranges(before, after, checksum_byte_offset) }
+    }
+}
+
+impl FileRecord {
+    pub fn new(path: String, bytes: &[u8]) -> FileRecord {
+        FileRecord { path, length: bytes.len(), sha256: sha256_hex(bytes) }
+    }
+}
+
+impl Manifest {
+    pub fn new(source: FileRecord, output: FileRecord, rom: RomRecord, patches: Vec<PatchRecord>) -> Manifest {
+        Manifest {
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            source,
+            output,
+            rom,
+            patches,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+        serde_json::from_str(text).map_err(ManifestError::Invalid)
+    }
+
+    pub fn to_json(&self) -> String {
+        // Serializing plain structs and strings can't fail
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Check the file is the one the manifest describes: the rom where it was written with its checksum byte and the
+    /// bytes of each patch, and the whole file unchanged
+    pub fn check(&self, bytes: &[u8]) -> Result<String, ManifestError> {
+        let mut checks: Vec<(bool, String)> = Vec::new();
+
+        let rom = bytes.get(self.rom.offset..self.rom.offset + self.rom.size)
+            .filter(|rom| rom.starts_with(&crate::option_rom::OPTION_ROM_HEADER) && rom[2] as usize * 512 == self.rom.size);
+        checks.push((rom.is_some(), format!("Rom of {} bytes at 0x{:X}", self.rom.size, self.rom.offset)));
+
+        if let Some(rom) = rom {
+            let checksum = rom.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            checks.push((checksum == 0, "The rom checksum adds up".to_string()));
+            if let Some(checksum_byte) = &self.rom.checksum_byte {
+                checks.push((rom.get(checksum_byte.offset) == Some(&checksum_byte.new),
+                    format!("Checksum byte {:02X} at 0x{:X} in the rom", checksum_byte.new, checksum_byte.offset)));
+            }
+
+            for patch in &self.patches {
+                let matches = patch.ranges.iter().all(|range| rom.get(range.start..range.end).map(to_hex).as_ref() == Some(&range.bytes));
+                let action = match patch.action {
+                    PatchAction::Applied => "applied",
+                    PatchAction::Reverted => "reverted",
+                };
+                let length: usize = patch.ranges.iter().map(|range| range.end.saturating_sub(range.start)).sum();
+                checks.push((matches, format!("{} {}, {} bytes", patch.name, action, length)));
+            }
+        }
+
+        checks.push((bytes.len() == self.output.length && sha256_hex(bytes) == self.output.sha256, format!("SHA-256 {}", self.output.sha256)));
+
+        let mut lines: Vec<String> = checks.iter()
+            .map(|(passed, check)| format!("{}  {}", if *passed { "ok    " } else { "FAILED" }, check))
+            .collect();
+        lines.insert(0, format!("Made from {} (SHA-256 {}) by {} {} at {}", self.source.path, self.source.sha256, self.tool, self.version, self.created));
+
+        let report = lines.join("\n");
+        match checks.iter().all(|(passed, _)| *passed) {
+            true => Ok(report),
+            false => Err(ManifestError::DoesntMatch(report)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::option_rom::ChecksumByteLocation;
+    use crate::option_rom_patcher::SkipHddReadyCheck;
+    use crate::rom_patch;
+    use crate::test_helpers::build_patchable_rom;
+
+    #[test]
+    fn test_manifest_round_trip_and_check() -> Result<(), String> {
+        let mut original_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        original_rom.correct_checksum_in_final_byte();
+        let patched_rom = rom_patch::apply(&SkipHddReadyCheck, &original_rom, ChecksumByteLocation::FinalByte).map_err(|e| e.to_string())?;
+
+        let ranges = changed_ranges(&original_rom, &patched_rom, Some(0x1FF));
+        assert_eq!(ranges, vec![ChangedRange { start: 12, end: 14, bytes: "eb10".into() }]);
+
+        let checksum_byte = ChecksumByteRecord { offset: 0x1FF, original: original_rom.bytes[0x1FF], new: patched_rom.bytes[0x1FF] };
+        let rom = RomRecord { offset: 0, size: 0x200, checksum_byte: Some(checksum_byte) };
+        let patches = vec![PatchRecord { name: "skip-hdd-ready-check".into(), action: PatchAction::Applied, ranges }];
+        let manifest = Manifest::new(FileRecord::new("pc.boot".into(), &original_rom.bytes), FileRecord::new("pc.boot.new".into(), &patched_rom.bytes), rom, patches);
+
+        let manifest = Manifest::parse(&manifest.to_json()).map_err(|e| e.to_string())?;
+        let report = manifest.check(&patched_rom.bytes).map_err(|e| e.to_string())?;
+        assert!(report.contains("ok      skip-hdd-ready-check applied, 2 bytes"));
+
+        let mut changed = patched_rom.bytes.clone();
+        changed[12] = 0x72;
+        match manifest.check(&changed) {
+            Ok(report) => Err(format!("Expected the changed jump to fail the check but got\n{}", report)),
+            Err(e) => {
+                assert!(e.to_string().contains("FAILED  The rom checksum adds up"));
+                assert!(e.to_string().contains("FAILED  skip-hdd-ready-check applied"));
+                Ok(())
+            },
+        }
+    }
+}
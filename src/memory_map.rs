@@ -0,0 +1,299 @@
+//! Planning where option roms, the Janus memory window and anything else sit in the PC upper memory area (C000-FFFF).
+
+use std::fmt;
+
+pub const UPPER_MEMORY_START: u32 = 0xC0000;
+pub const UPPER_MEMORY_END: u32 = 0x100000;
+
+/// The BIOS looks for option roms on every 2K boundary between C800 and EFFF
+pub const OPTION_ROM_ALIGNMENT: u32 = 0x800;
+pub const OPTION_ROM_SCAN_START: u32 = 0xC8000;
+pub const OPTION_ROM_SCAN_END: u32 = 0xF0000;
+
+/// The Janus window segment the README recommends setting in PCPrefs
+pub const DEFAULT_JANUS_WINDOW: &str = "D000";
+pub const DEFAULT_JANUS_WINDOW_SIZE: u32 = 0x10000;
+
+/// Each character in the map covers this many bytes
+const MAP_CELL_SIZE: u32 = 0x800;
+const MAP_CELLS_PER_ROW: u32 = 16;
+
+/// Parse a segment ("CC00", "0xCC00") or segment:offset ("D000:0000") into a linear address
+pub fn parse_segment(value: &str) -> Result<u32, String> {
+    let parse_hex_word = |word: &str| {
+        let digits = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).unwrap_or(word);
+        u16::from_str_radix(digits, 16).map_err(|_| format!("{} isn't a valid segment, use e.g. CC00 or D000:0000", value))
+    };
+
+    let (segment, offset) = match value.split_once(':') {
+        Some((segment, offset)) => (parse_hex_word(segment)?, parse_hex_word(offset)?),
+        None => (parse_hex_word(value)?, 0),
+    };
+
+    Ok(((segment as u32) << 4) + offset as u32)
+}
+
+/// Parse an inclusive range of segments, e.g. "C000-C7FF" is the 32K from C000:0000 to C7FF:000F
+pub fn parse_segment_range(value: &str) -> Result<(u32, u32), String> {
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (parse_segment(start)?, parse_segment(end)?),
+        None => return Err(format!("{} isn't a valid range, use e.g. C000-C7FF", value)),
+    };
+
+    if end < start {
+        return Err(format!("The range {} ends before it starts", value));
+    }
+
+    Ok((start, end + 0x10 - start))
+}
+
+/// Format a linear address as segment:offset
+pub fn format_segment(address: u32) -> String {
+    format!("{:04X}:{:04X}", address >> 4, address & 0xF)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionKind {
+    JanusWindow,
+    /// An option rom, which is expected to be inside the Janus window if it is the pc.boot rom
+    OptionRom { in_janus_window: bool },
+    Reserved,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub kind: RegionKind,
+    pub start: u32,
+    pub length: u32,
+}
+
+impl Region {
+    pub fn end(&self) -> u32 {
+        self.start + self.length
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+
+    /// The pc.boot rom lives inside the Janus window, so that isn't a conflict
+    fn conflicts_with(&self, other: &Region) -> bool {
+        let expected_in_window = |a: &Region, b: &Region| {
+            a.kind == RegionKind::JanusWindow && b.kind == (RegionKind::OptionRom { in_janus_window: true })
+        };
+
+        self.overlaps(other) && ! expected_in_window(self, other) && ! expected_in_window(other, self)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryMapProblem {
+    Overlap(String, String),
+    Misaligned(String),
+    OutsideUpperMemory(String),
+    RomOutsideJanusWindow(String),
+}
+
+impl fmt::Display for MemoryMapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryMapProblem::Overlap(a, b) => write!(f, "{} overlaps {}", a, b),
+            MemoryMapProblem::Misaligned(name) => write!(f, "{} doesn't start on a 2K boundary so the BIOS won't find it", name),
+            MemoryMapProblem::OutsideUpperMemory(name) => write!(f, "{} isn't inside C000-FFFF", name),
+            MemoryMapProblem::RomOutsideJanusWindow(name) => write!(f, "{} doesn't fit inside the Janus window", name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    pub fn add(&mut self, name: &str, kind: RegionKind, start: u32, length: u32) {
+        self.regions.push(Region { name: name.into(), kind, start, length });
+    }
+
+    pub fn problems(&self) -> Vec<MemoryMapProblem> {
+        let mut problems: Vec<MemoryMapProblem> = Vec::new();
+
+        for (i, region) in self.regions.iter().enumerate() {
+            if region.start < UPPER_MEMORY_START || region.end() > UPPER_MEMORY_END {
+                problems.push(MemoryMapProblem::OutsideUpperMemory(region.name.clone()));
+            }
+
+            if let RegionKind::OptionRom { in_janus_window } = region.kind {
+                if region.start % OPTION_ROM_ALIGNMENT != 0 {
+                    problems.push(MemoryMapProblem::Misaligned(region.name.clone()));
+                }
+
+                let inside_window = self.regions.iter()
+                    .any(|window| window.kind == RegionKind::JanusWindow && window.start <= region.start && region.end() <= window.end());
+                if in_janus_window && ! inside_window {
+                    problems.push(MemoryMapProblem::RomOutsideJanusWindow(region.name.clone()));
+                }
+            }
+
+            for other in &self.regions[i + 1..] {
+                if region.conflicts_with(other) {
+                    problems.push(MemoryMapProblem::Overlap(region.name.clone(), other.name.clone()));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Free 2K aligned ranges where the BIOS would find an option rom, as (start, length)
+    pub fn free_slots(&self) -> Vec<(u32, u32)> {
+        let mut slots: Vec<(u32, u32)> = Vec::new();
+        let mut address = OPTION_ROM_SCAN_START;
+
+        while address < OPTION_ROM_SCAN_END {
+            let is_free = ! self.regions.iter().any(|region| region.start < address + OPTION_ROM_ALIGNMENT && address < region.end());
+
+            if is_free {
+                match slots.last_mut() {
+                    Some((start, length)) if *start + *length == address => *length += OPTION_ROM_ALIGNMENT,
+                    _ => slots.push((address, OPTION_ROM_ALIGNMENT)),
+                }
+            }
+
+            address += OPTION_ROM_ALIGNMENT;
+        }
+
+        slots
+    }
+
+    /// The letter used for each region in the map, the Janus window is always J
+    fn region_letter(&self, index: usize) -> char {
+        if self.regions[index].kind == RegionKind::JanusWindow {
+            return 'J';
+        }
+
+        let letter_index = self.regions[..index].iter().filter(|region| region.kind != RegionKind::JanusWindow).count();
+        let letter = (b'A' + (letter_index % 25) as u8) as char;
+        if letter >= 'J' { ((letter as u8) + 1) as char } else { letter }
+    }
+
+    fn cell_character(&self, cell_start: u32) -> char {
+        let cell = Region { name: String::new(), kind: RegionKind::Reserved, start: cell_start, length: MAP_CELL_SIZE };
+        let in_cell: Vec<usize> = (0..self.regions.len()).filter(|i| self.regions[*i].overlaps(&cell)).collect();
+
+        let conflict = in_cell.iter().enumerate()
+            .any(|(n, a)| in_cell[n + 1..].iter().any(|b| self.regions[*a].conflicts_with(&self.regions[*b])));
+
+        if conflict {
+            return '!';
+        }
+
+        // Anything inside the Janus window is shown in preference to the window itself
+        match in_cell.iter().find(|i| self.regions[**i].kind != RegionKind::JanusWindow).or(in_cell.first()) {
+            Some(index) => self.region_letter(*index),
+            None => '.',
+        }
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let row_size = MAP_CELL_SIZE * MAP_CELLS_PER_ROW;
+
+        writeln!(f, "      Each character is 2K, . is free and ! is a conflict")?;
+        for row_start in (UPPER_MEMORY_START..UPPER_MEMORY_END).step_by(row_size as usize) {
+            let row: String = (0..MAP_CELLS_PER_ROW).map(|cell| self.cell_character(row_start + cell * MAP_CELL_SIZE)).collect();
+            writeln!(f, "{:04X}  {}", row_start >> 4, row)?;
+        }
+
+        writeln!(f)?;
+        for (index, region) in self.regions.iter().enumerate() {
+            writeln!(
+                f, "{}  {:<24} {}-{} ({}K)",
+                self.region_letter(index), region.name, format_segment(region.start), format_segment(region.end() - 1), region.length / 1024,
+            )?;
+        }
+
+        let problems = self.problems();
+        if ! problems.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Problems:")?;
+            for problem in problems {
+                writeln!(f, "  {}", problem)?;
+            }
+        }
+
+        writeln!(f)?;
+        write!(f, "Free option rom slots:")?;
+        let free_slots = self.free_slots();
+        if free_slots.is_empty() {
+            write!(f, " none")?;
+        }
+        for (start, length) in free_slots {
+            write!(f, "\n  {:04X}-{:04X} ({}K)", start >> 4, (start + length - 1) >> 4, length / 1024)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bridgeboard_map() -> MemoryMap {
+        let mut memory_map = MemoryMap::default();
+        memory_map.add("Janus window", RegionKind::JanusWindow, 0xD0000, 0x10000);
+        memory_map.add("pc.boot", RegionKind::OptionRom { in_janus_window: true }, 0xD0000, 0x2000);
+        memory_map.add("VGA BIOS", RegionKind::Reserved, 0xC0000, 0x8000);
+        memory_map.add("System BIOS", RegionKind::Reserved, 0xF0000, 0x10000);
+        memory_map
+    }
+
+    #[test]
+    fn test_parse_segment() {
+        assert_eq!(parse_segment("CC00"), Ok(0xCC000));
+        assert_eq!(parse_segment("0xCC00"), Ok(0xCC000));
+        assert_eq!(parse_segment("D000:0000"), Ok(0xD0000));
+        assert_eq!(parse_segment("D000:0010"), Ok(0xD0010));
+        assert!(parse_segment("G000").is_err());
+        assert_eq!(parse_segment_range("C000-C7FF"), Ok((0xC0000, 0x8000)));
+        assert!(parse_segment_range("C7FF-C000").is_err());
+    }
+
+    #[test]
+    fn test_no_problems_with_xtide_below_window() {
+        let mut memory_map = bridgeboard_map();
+        memory_map.add("XTIDE", RegionKind::OptionRom { in_janus_window: false }, 0xCC000, 0x2000);
+
+        assert_eq!(memory_map.problems(), vec![]);
+        assert_eq!(memory_map.free_slots(), vec![(0xC8000, 0x4000), (0xCE000, 0x2000), (0xE0000, 0x10000)]);
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut memory_map = bridgeboard_map();
+        memory_map.add("XTIDE", RegionKind::OptionRom { in_janus_window: false }, 0xD0400, 0x2000);
+
+        assert_eq!(memory_map.problems(), vec![
+            MemoryMapProblem::Overlap("Janus window".into(), "XTIDE".into()),
+            MemoryMapProblem::Overlap("pc.boot".into(), "XTIDE".into()),
+            MemoryMapProblem::Misaligned("XTIDE".into()),
+        ]);
+    }
+
+    #[test]
+    fn test_map_rows() {
+        let mut memory_map = bridgeboard_map();
+        memory_map.add("XTIDE", RegionKind::OptionRom { in_janus_window: false }, 0xCC000, 0x2000);
+
+        let rendered = format!("{}", memory_map);
+        let rows: Vec<&str> = rendered.lines().skip(1).take(8).collect();
+
+        assert_eq!(rows[0], "C000  BBBBBBBBBBBBBBBB");
+        assert_eq!(rows[1], "C800  ........DDDD....");
+        assert_eq!(rows[2], "D000  AAAAJJJJJJJJJJJJ");
+        assert_eq!(rows[7], "F800  CCCCCCCCCCCCCCCC");
+    }
+}
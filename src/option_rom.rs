@@ -1,11 +1,17 @@
 use std::fmt;
 
+use crate::disassembler;
+
 #[derive(Debug, PartialEq)]
 pub enum OptionRomError {
     InvalidOptionRomHeader,
     OptionRomTooSmall,
     OptionRomChecksumInvalid(OptionRom),
-    NoOptionRomFoundInScan
+    NoOptionRomFoundInScan,
+    ChecksumByteOutsideRom(usize),
+    ChecksumByteInHeader(usize),
+    ChecksumByteInCode(usize),
+    NoPaddingForChecksumByte,
 }
 
 impl fmt::Display for OptionRomError {
@@ -15,18 +21,40 @@ impl fmt::Display for OptionRomError {
             OptionRomError::OptionRomTooSmall => write!(f, "The Option Rom is not big enough"),
             OptionRomError::OptionRomChecksumInvalid(_) => write!(f, "The Option Rom had an invalid checksum"),
             OptionRomError::NoOptionRomFoundInScan => write!(f, "No possibly valid Option Rom was found scanning in the source"),
+            OptionRomError::ChecksumByteOutsideRom(offset) => write!(f, "The checksum byte at offset 0x{:X} is outside the Option Rom", offset),
+            OptionRomError::ChecksumByteInHeader(offset) => write!(f, "The checksum byte at offset 0x{:X} is part of the Option Rom header", offset),
+            OptionRomError::ChecksumByteInCode(offset) => write!(f, "The checksum byte at offset 0x{:X} is part of a decoded instruction", offset),
+            OptionRomError::NoPaddingForChecksumByte => write!(f, "No padding was found in the Option Rom to hold the checksum byte"),
         }
     }
 }
 
+impl std::error::Error for OptionRomError {}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct OptionRom {
     pub bytes: Vec<u8>,
     pub rom_size_in_bytes: usize,
 }
 
+/// Which byte of the rom is altered to make the checksum add up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumByteLocation {
+    FinalByte,
+    Offset(usize),
+    /// A byte inside the padding of the rom, preferring the final byte if the padding runs up to it
+    InPadding,
+}
+
 pub const OPTION_ROM_HEADER: [u8; 2] = [0x55, 0xAA];
 
+/// The ROM header is the signature, the size byte and then the entry point
+pub const OPTION_ROM_HEADER_LENGTH: usize = 3;
+pub const OPTION_ROM_ENTRY_POINT: usize = 3;
+
+/// The shortest run of a single repeated byte that we consider to be padding
+pub const MIN_PADDING_RUN_LENGTH: usize = 16;
+
 impl OptionRom {
      pub fn from(bytes: Vec<u8>, start_offset: usize) -> Result<OptionRom, OptionRomError> {
         if ! (bytes[start_offset] == OPTION_ROM_HEADER[0] && bytes[start_offset+1] == OPTION_ROM_HEADER[1]) {
@@ -37,7 +65,7 @@ impl OptionRom {
             return Err(OptionRomError::OptionRomTooSmall)
         }
 
-        let rom_size_in_bytes : usize = (usize::from(bytes[start_offset + 2]) * 512).into();
+        let rom_size_in_bytes : usize = usize::from(bytes[start_offset + 2]) * 512;
 
         let rom_end_in_bytes = start_offset + rom_size_in_bytes;
 
@@ -54,8 +82,8 @@ impl OptionRom {
         Ok(option_rom)
     } 
 
-    pub fn find_option_rom_start_in_bytes(bytes: &Vec<u8>) -> Result<usize, OptionRomError> {
-        for i in 0..bytes.len()-3 {
+    pub fn find_option_rom_start_in_bytes(bytes: &[u8]) -> Result<usize, OptionRomError> {
+        for i in 0..bytes.len().saturating_sub(3) {
             if bytes[i] == OPTION_ROM_HEADER[0] && bytes[i+1] == OPTION_ROM_HEADER[1] {
                 let suspected_rom_length = 512 * (bytes[i+2] as usize);
                 let rom_end_location = i + suspected_rom_length;
@@ -77,12 +105,55 @@ impl OptionRom {
     }
 
     pub fn required_checksum_byte(&self) -> u8 {
-        let remainder: u8 = self.calculate_checksum_remainder();
+        self.required_checksum_byte_at(self.bytes.len() - 1)
+    }
+
+    /// The value the byte at the offset needs for the checksum to add up
+    pub fn required_checksum_byte_at(&self, offset: usize) -> u8 {
+        let bytes_total = self.bytes.iter().enumerate()
+            .filter(|(i, _)| *i != offset)
+            .fold(0u32, |acc, (_, byte)| acc + (*byte as u32));
+
+        ((0x100 - (bytes_total % 0x100)) % 0x100) as u8
+    }
+
+    /// Work out which byte would be altered to correct the checksum, refusing bytes in the header or which are part of
+    /// code reachable from the entry point
+    pub fn checksum_byte_offset(&self, location: ChecksumByteLocation) -> Result<usize, OptionRomError> {
+        let code_map = disassembler::code_map(&self.bytes, &[OPTION_ROM_ENTRY_POINT]);
+
+        let offset = match location {
+            ChecksumByteLocation::FinalByte => self.bytes.len() - 1,
+            ChecksumByteLocation::Offset(offset) => offset,
+            ChecksumByteLocation::InPadding => return self.find_checksum_byte_in_padding(&code_map),
+        };
 
-        match remainder {
-            0 => 0,
-            _ => u8::from(255 - (remainder - 1)),
+        if offset >= self.bytes.len() {
+            return Err(OptionRomError::ChecksumByteOutsideRom(offset));
         }
+
+        if offset < OPTION_ROM_HEADER_LENGTH {
+            return Err(OptionRomError::ChecksumByteInHeader(offset));
+        }
+
+        if code_map[offset] {
+            return Err(OptionRomError::ChecksumByteInCode(offset));
+        }
+
+        Ok(offset)
+    }
+
+    /// Correct the checksum by altering the byte at the location, returning the offset of the byte which was used
+    pub fn correct_checksum_at(&mut self, location: ChecksumByteLocation) -> Result<usize, OptionRomError> {
+        let offset = self.checksum_byte_offset(location)?;
+
+        if offset == self.bytes.len() - 1 {
+            self.correct_checksum_in_final_byte();
+        } else if self.calculate_checksum() != 0 {
+            self.bytes[offset] = self.required_checksum_byte_at(offset);
+        }
+
+        Ok(offset)
     }
 
     pub fn correct_checksum_in_final_byte(&mut self) {
@@ -97,9 +168,34 @@ impl OptionRom {
         self.bytes[bytes_length - 1] = required_checksum
     }
 
-    fn calculate_checksum_remainder(&self) -> u8 {
-        let bytes_total = self.bytes[0..self.bytes.len() - 1].iter().fold(0u32, |acc, byte| acc + (*byte as u32));
-        (bytes_total % 0x100) as u8
+    fn find_checksum_byte_in_padding(&self, code_map: &[bool]) -> Result<usize, OptionRomError> {
+        let final_byte = self.bytes.len() - 1;
+        let mut last_padding_byte: Option<usize> = None;
+        let mut run_start = OPTION_ROM_HEADER_LENGTH;
+
+        while run_start < self.bytes.len() {
+            if code_map[run_start] {
+                run_start += 1;
+                continue;
+            }
+
+            let mut run_end = run_start + 1;
+            while run_end < self.bytes.len() && self.bytes[run_end] == self.bytes[run_start] && ! code_map[run_end] {
+                run_end += 1;
+            }
+
+            if run_end - run_start >= MIN_PADDING_RUN_LENGTH {
+                last_padding_byte = Some(run_end - 1);
+            }
+            run_start = run_end;
+        }
+
+        match last_padding_byte {
+            // Padding running up to the final byte means the final byte is where the checksum is expected
+            Some(offset) if offset + 1 == final_byte && ! code_map[final_byte] => Ok(final_byte),
+            Some(offset) => Ok(offset),
+            None => Err(OptionRomError::NoPaddingForChecksumByte),
+        }
     }
 
     fn calculate_checksum(&self) -> u8 {
@@ -150,7 +246,7 @@ mod test {
 
         match option_rom.validate_checksum() {
             Ok(_) => Ok(()),
-            Err(_) => Err(format!("Option Rom checksum validation failure"))
+            Err(_) => Err("Option Rom checksum validation failure".into())
         }
     }
     
@@ -198,6 +294,45 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_checksum_byte_offset() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.invalid_checksum")?;
+
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::FinalByte), Ok(0x1FFF));
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::Offset(0x1000)), Ok(0x1000));
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::InPadding), Ok(0x1FFF));
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::Offset(2)), Err(OptionRomError::ChecksumByteInHeader(2)));
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::Offset(0x2000)), Err(OptionRomError::ChecksumByteOutsideRom(0x2000)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_byte_offset_refuses_code() {
+        // Entry point JMPs to a RET which is the final byte
+        let mut bytes: Vec<u8> = vec![0x55, 0xAA, 0x01, 0xE9, 0xF9, 0x01];
+        bytes.resize(512, 0x61);
+        bytes[511] = 0xC3;
+        let option_rom = OptionRom::from(bytes, 0).unwrap();
+
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::FinalByte), Err(OptionRomError::ChecksumByteInCode(511)));
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::Offset(4)), Err(OptionRomError::ChecksumByteInCode(4)));
+        assert_eq!(option_rom.checksum_byte_offset(ChecksumByteLocation::InPadding), Ok(510));
+    }
+
+    #[test]
+    fn test_correct_checksum_at_offset() -> Result<(), String> {
+        let mut option_rom = load_option_rom_fixture("pc.boot.invalid_checksum")?;
+
+        assert_eq!(option_rom.correct_checksum_at(ChecksumByteLocation::Offset(0x1000)), Ok(0x1000));
+        assert_eq!(option_rom.bytes[0x1000], 0xF1);
+        assert_eq!(option_rom.bytes[0x1FFF], 0x00);
+
+        match option_rom.validate_checksum() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Expected a valid checksum after correcting it but got {}", e)),
+        }
+    }
+
     #[test]
     fn test_find_option_rom_start_in_bytes_at_location_0() -> Result<(), String> {
         let valid_rom_bytes_with_no_offset = load_fixture("pc.boot.valid")?;
@@ -228,8 +363,8 @@ mod test {
 
         match OptionRom::find_option_rom_start_in_bytes(&valid_rom_bytes_with_no_offset) {
             Err(OptionRomError::NoOptionRomFoundInScan) => Ok(()),
-            Ok(position) => return Err(format!("Option rom was located at position {}, but there should have not been a valid rom located", position)),
-            Err(e) => return Err(format!("Unexpected error '{}' returned from find_option_rom_start_in_bytes", e)),
+            Ok(position) => Err(format!("Option rom was located at position {}, but there should have not been a valid rom located", position)),
+            Err(e) => Err(format!("Unexpected error '{}' returned from find_option_rom_start_in_bytes", e)),
         }
     }
 
@@ -239,8 +374,8 @@ mod test {
 
         match OptionRom::find_option_rom_start_in_bytes(&valid_rom_bytes_with_no_offset) {
             Err(OptionRomError::NoOptionRomFoundInScan) => Ok(()),
-            Ok(position) => return Err(format!("Option rom was located at position {}, but there should have not been a valid rom located", position)),
-            Err(e) => return Err(format!("Unexpected error '{}' returned from find_option_rom_start_in_bytes", e)),
+            Ok(position) => Err(format!("Option rom was located at position {}, but there should have not been a valid rom located", position)),
+            Err(e) => Err(format!("Unexpected error '{}' returned from find_option_rom_start_in_bytes", e)),
         }
     }
 }
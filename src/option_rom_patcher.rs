@@ -1,13 +1,21 @@
 use std::fmt;
+use std::ops::Range;
 
-use crate::option_rom::{OptionRom, OptionRomError};
+use crate::option_rom::{ChecksumByteLocation, OptionRom, OptionRomError};
+use crate::rom_patch::RomPatch;
 
 #[derive(Debug)]
 pub enum OptionRomPatcherError {
     OptionRomGenerationError(OptionRomError),
     CouldntLocateHddReadyCheck,
     CouldntLocateAfterInt13Set,
-    JumpLengthTooBig
+    JumpLengthTooBig,
+    NotPatched,
+    AlreadyPatched,
+    PatchSiteNotFound(String),
+    PreconditionFailed(String),
+    /// The patch didn't detect the state it should have left the rom in
+    VerificationFailed { expected: PatchState, found: PatchState },
 }
 
 impl fmt::Display for OptionRomPatcherError {
@@ -16,11 +24,42 @@ impl fmt::Display for OptionRomPatcherError {
             OptionRomPatcherError::CouldntLocateHddReadyCheck => write!(f, "Couldn't find the HDD ready check."),
             OptionRomPatcherError::CouldntLocateAfterInt13Set => write!(f, "Couldn't find the end of the code which sets the INT13 handler."),
             OptionRomPatcherError::JumpLengthTooBig => write!(f, "The distance to JMP to avoid setting INT13 is too big."),
+            OptionRomPatcherError::NotPatched => write!(f, "The rom doesn't have the patch applied, so it can't be reverted."),
+            OptionRomPatcherError::AlreadyPatched => write!(f, "The rom already has the patch applied."),
+            OptionRomPatcherError::PatchSiteNotFound(site) => write!(f, "Couldn't find the {} site of the patch.", site),
+            OptionRomPatcherError::PreconditionFailed(reason) => write!(f, "The rom doesn't meet the patch's precondition: {}", reason),
+            OptionRomPatcherError::VerificationFailed { expected, found } => write!(f, "The rom should be {} but is {} after the change.", expected, found),
             OptionRomPatcherError::OptionRomGenerationError(e) => write!(f, "{}", e),
         }
     }
 }
 
+impl std::error::Error for OptionRomPatcherError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OptionRomPatcherError::OptionRomGenerationError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl OptionRomPatcherError {
+    /// The name of the variant, for reports which are compared across many files
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            OptionRomPatcherError::OptionRomGenerationError(_) => "OptionRomGenerationError",
+            OptionRomPatcherError::CouldntLocateHddReadyCheck => "CouldntLocateHddReadyCheck",
+            OptionRomPatcherError::CouldntLocateAfterInt13Set => "CouldntLocateAfterInt13Set",
+            OptionRomPatcherError::JumpLengthTooBig => "JumpLengthTooBig",
+            OptionRomPatcherError::NotPatched => "NotPatched",
+            OptionRomPatcherError::AlreadyPatched => "AlreadyPatched",
+            OptionRomPatcherError::PatchSiteNotFound(_) => "PatchSiteNotFound",
+            OptionRomPatcherError::PreconditionFailed(_) => "PreconditionFailed",
+            OptionRomPatcherError::VerificationFailed { .. } => "VerificationFailed",
+        }
+    }
+}
+
 const X86_INT: u8 = 0xCD;
 const X86_MOV_INTO_AH: u8 = 0xb4;
 const X86_MOV_INTO_DL: u8 = 0xb2;
@@ -30,6 +69,9 @@ const X86_POP_ES: u8 = 0x07;
 const X86_JC: u8 = 0x72;
 const X86_JMP: u8 = 0xeb;
 
+/// The length of the JC over the INT 13 handler being set when the HDD isn't ready, in the Janus 2.1 pc.boot
+const HDD_NOT_READY_JUMP_LENGTH: u8 = 0x06;
+
 const X86_MOV_SEGMENT_REGISTER_TO_MEMORY_ADDRESS: u8 = 0x8c;
 const X86_MOV_GENERAL_REGISTER_TO_MEMORY_ADDRESS: u8 = 0x89;
 const X86_ES_SEGMENT_REGISTER: u8 = 0x06;
@@ -50,10 +92,123 @@ const INT_13_SET_FINISHED_SEARCH: [u8; 9] = [
     X86_POP_ES,
 ];
 
-pub fn patch_rom(option_rom: &OptionRom) -> Result<OptionRom, OptionRomPatcherError> {
-    println!("ORIGINAL_ROM_SIZE: 0x{:04X}", option_rom.bytes.len());
+/// Whether a rom already has our patch applied
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchState {
+    Unpatched,
+    Patched,
+    Unknown,
+}
+
+impl fmt::Display for PatchState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchState::Unpatched => write!(f, "unpatched"),
+            PatchState::Patched => write!(f, "patched"),
+            PatchState::Unknown => write!(f, "unknown (the HDD ready check couldn't be found)"),
+        }
+    }
+}
+
+/// The location of the jump after the HDD ready check, whether it is the original JC or our JMP
+fn find_location_of_patched_jump(option_rom: &OptionRom) -> Option<usize> {
+    // The search without the final JC, which is the instruction we patch
+    let hdd_ready_check_without_jump = &HDD_READY_CHECK_SEARCH[0..HDD_READY_CHECK_SEARCH.len() - 1];
+
+    option_rom.bytes.windows(hdd_ready_check_without_jump.len())
+        .position(|window| window == hdd_ready_check_without_jump)
+        .map(|location| location + hdd_ready_check_without_jump.len())
+}
+
+pub fn detect_patch_state(option_rom: &OptionRom) -> PatchState {
+    detect_patch_state_and_jump(option_rom).0
+}
+
+/// The patch state, with the location of the jump after the HDD ready check unless the state is unknown
+fn detect_patch_state_and_jump(option_rom: &OptionRom) -> (PatchState, Option<usize>) {
+    let location_of_jump = match find_location_of_patched_jump(option_rom) {
+        Some(location_of_jump) => location_of_jump,
+        None => return (PatchState::Unknown, None),
+    };
+
+    if location_of_jump + 2 > option_rom.bytes.len() {
+        return (PatchState::Unknown, None);
+    }
+
+    match option_rom.bytes[location_of_jump] {
+        X86_JC => (PatchState::Unpatched, Some(location_of_jump)),
+        X86_JMP => {
+            let jump_target = location_of_jump + 2 + option_rom.bytes[location_of_jump + 1] as usize;
+            match find_location_after_int_13_set(option_rom) {
+                Ok(location) if location == jump_target => (PatchState::Patched, Some(location_of_jump)),
+                _ => (PatchState::Unknown, None),
+            }
+        },
+        _ => (PatchState::Unknown, None),
+    }
+}
+
+/// The bytes the patch looks for: the HDD ready check including the jump which is patched, and the end of setting the
+/// INT 13 handler which the patched jump goes to. Found whether or not the rom is already patched
+pub fn patch_sites(option_rom: &OptionRom) -> Vec<Range<usize>> {
+    let hdd_ready_check_without_jump = &HDD_READY_CHECK_SEARCH[0..HDD_READY_CHECK_SEARCH.len() - 1];
+    let searches: [(&[u8], usize); 2] = [
+        (hdd_ready_check_without_jump, hdd_ready_check_without_jump.len() + 2),
+        (&INT_13_SET_FINISHED_SEARCH, INT_13_SET_FINISHED_SEARCH.len()),
+    ];
+
+    searches.iter()
+        .filter_map(|(search, length)| {
+            let location = option_rom.bytes.windows(search.len()).position(|window| window == *search)?;
+            Some(location..(location + length).min(option_rom.bytes.len()))
+        })
+        .collect()
+}
+
+pub fn patch_rom(option_rom: &OptionRom, checksum_byte_location: ChecksumByteLocation) -> Result<OptionRom, OptionRomPatcherError> {
+    log::info!("ORIGINAL_ROM_SIZE: 0x{:04X}", option_rom.bytes.len());
+    let patched_rom = build_patched_rom(option_rom, checksum_byte_location)?;
+    log::info!("PATCHED_ROM_SIZE: 0x{:04X}", patched_rom.bytes.len());
+    Ok(patched_rom)
+}
+
+/// Our patch: the JC taken when the HDD isn't ready becomes a JMP past setting the INT 13 handler, so the Bridgeboard
+/// keeps the handler of a hard disk controller (e.g. an XTIDE) in the PC
+pub struct SkipHddReadyCheck;
+
+impl RomPatch for SkipHddReadyCheck {
+    fn name(&self) -> &str {
+        "skip-hdd-ready-check"
+    }
+
+    fn description(&self) -> &str {
+        "Keep the INT 13 handler of a PC hard disk controller instead of the Janus one"
+    }
+
+    fn detect(&self, option_rom: &OptionRom) -> PatchState {
+        detect_patch_state(option_rom)
+    }
+
+    fn apply(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        generate_patched_rom(option_rom)
+    }
+
+    fn revert(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        let location_of_jump = match detect_patch_state_and_jump(option_rom) {
+            (PatchState::Patched, Some(location_of_jump)) => location_of_jump,
+            _ => return Err(OptionRomPatcherError::NotPatched),
+        };
+
+        let mut bytes = option_rom.bytes.clone();
+        bytes[location_of_jump..location_of_jump + 2].copy_from_slice(&[X86_JC, HDD_NOT_READY_JUMP_LENGTH]);
+        Ok(bytes)
+    }
+}
+
+/// patch_rom without logging the rom sizes, for trial patches
+pub fn build_patched_rom(option_rom: &OptionRom, checksum_byte_location: ChecksumByteLocation) -> Result<OptionRom, OptionRomPatcherError> {
     let patched_rom_bytes: Vec<u8> = generate_patched_rom(option_rom)?;
-    println!("PATCHED_ROM_SIZE: 0x{:04X}", patched_rom_bytes.len());
     let mut patched_rom = match OptionRom::from(patched_rom_bytes, 0) {
         Ok(patched_rom) => patched_rom,
         Err(e) => {
@@ -61,25 +216,33 @@ pub fn patch_rom(option_rom: &OptionRom) -> Result<OptionRom, OptionRomPatcherEr
         },
     };
 
-    patched_rom.correct_checksum_in_final_byte();
+    let checksum_byte_offset = match patched_rom.correct_checksum_at(checksum_byte_location) {
+        Ok(checksum_byte_offset) => checksum_byte_offset,
+        Err(e) => return Err(OptionRomPatcherError::OptionRomGenerationError(e)),
+    };
+    log::debug!(
+        "Checksum byte at 0x{:04X} changed from {:02X} to {:02X}",
+        checksum_byte_offset, option_rom.bytes[checksum_byte_offset], patched_rom.bytes[checksum_byte_offset],
+    );
     Ok(patched_rom)
 }
 
 fn generate_patched_rom(option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
-    let location_of_hdd_not_ready_jump = match find_location_of_hdd_not_ready_jump(option_rom) {
-        Err(e) => return Err(e),
-        Ok(location) => location,
-    };
-    let location_of_int_13_set_finished = match find_location_after_int_13_set(option_rom) {
-        Err(e) => return Err(e),
-        Ok(location) => location,
-    };
+    if detect_patch_state(option_rom) == PatchState::Patched {
+        return Err(OptionRomPatcherError::AlreadyPatched);
+    }
+
+    let location_of_hdd_not_ready_jump = find_location_of_hdd_not_ready_jump(option_rom)?;
+    log::debug!("HDD not ready jump found at 0x{:04X}", location_of_hdd_not_ready_jump);
+    let location_of_int_13_set_finished = find_location_after_int_13_set(option_rom)?;
+    log::debug!("INT 13 handler set finished at 0x{:04X}", location_of_int_13_set_finished);
 
     // Need to add 2 on the location of the jump since thats where the JMP instruction will count from
     let jump_length: u8 = match u8::try_from(location_of_int_13_set_finished - (location_of_hdd_not_ready_jump+2)) {
         Ok(jump_length) => jump_length,
         Err(_) => return Err(OptionRomPatcherError::JumpLengthTooBig),
     };
+    log::debug!("Jump length 0x{:02X}", jump_length);
 
     let mut new_rom_bytes: Vec<u8> = option_rom.bytes[0..location_of_hdd_not_ready_jump].to_vec();
     new_rom_bytes.push(X86_JMP);
@@ -89,7 +252,7 @@ fn generate_patched_rom(option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatc
 }
 
 fn find_location_of_hdd_not_ready_jump(option_rom: &OptionRom) -> Result<usize, OptionRomPatcherError> {
-    for i in 0..option_rom.bytes.len()-10 {
+    for i in 0..option_rom.bytes.len().saturating_sub(10) {
         if option_rom.bytes[i] == HDD_READY_CHECK_SEARCH[0] &&
            option_rom.bytes[i+1] == HDD_READY_CHECK_SEARCH[1] &&
            option_rom.bytes[i+2] == HDD_READY_CHECK_SEARCH[2] &&
@@ -106,8 +269,8 @@ fn find_location_of_hdd_not_ready_jump(option_rom: &OptionRom) -> Result<usize,
     Err(OptionRomPatcherError::CouldntLocateHddReadyCheck)
 }
 
-fn find_location_after_int_13_set(option_rom: &OptionRom) -> Result<usize, OptionRomPatcherError> {
-    for i in 0..option_rom.bytes.len()-10 {
+pub fn find_location_after_int_13_set(option_rom: &OptionRom) -> Result<usize, OptionRomPatcherError> {
+    for i in 0..option_rom.bytes.len().saturating_sub(10) {
         if option_rom.bytes[i] == INT_13_SET_FINISHED_SEARCH[0] &&
            option_rom.bytes[i+1] == INT_13_SET_FINISHED_SEARCH[1] &&
            option_rom.bytes[i+2] == INT_13_SET_FINISHED_SEARCH[2] &&
@@ -123,3 +286,47 @@ fn find_location_after_int_13_set(option_rom: &OptionRom) -> Result<usize, Optio
 
     Err(OptionRomPatcherError::CouldntLocateAfterInt13Set)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_patchable_rom, load_option_rom_fixture};
+
+    #[test]
+    fn test_patch_rom_and_detect_patch_state() -> Result<(), String> {
+        let option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        assert_eq!(detect_patch_state(&option_rom), PatchState::Unpatched);
+
+        let patched_rom = match patch_rom(&option_rom, ChecksumByteLocation::FinalByte) {
+            Ok(patched_rom) => patched_rom,
+            Err(e) => return Err(format!("Expected the rom to be patched but got error {}", e)),
+        };
+
+        assert_eq!(patched_rom.bytes[12..14], [X86_JMP, 0x10]);
+        assert_eq!(detect_patch_state(&patched_rom), PatchState::Patched);
+        assert_eq!(patch_sites(&option_rom), vec![4..14, 21..30]);
+        assert_eq!(patch_sites(&patched_rom), patch_sites(&option_rom));
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_already_patched_rom() -> Result<(), String> {
+        let option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        let patched_rom = patch_rom(&option_rom, ChecksumByteLocation::FinalByte).map_err(|e| e.to_string())?;
+
+        assert!(matches!(SkipHddReadyCheck.apply(&patched_rom), Err(OptionRomPatcherError::AlreadyPatched)));
+        assert!(matches!(patch_rom(&patched_rom, ChecksumByteLocation::FinalByte), Err(OptionRomPatcherError::AlreadyPatched)));
+
+        let reverted_bytes = SkipHddReadyCheck.revert(&patched_rom).map_err(|e| e.to_string())?;
+        assert_eq!(reverted_bytes[..0x1FF], option_rom.bytes[..0x1FF]);
+        assert!(matches!(SkipHddReadyCheck.revert(&option_rom), Err(OptionRomPatcherError::NotPatched)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_patch_state_without_hdd_ready_check() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        assert_eq!(detect_patch_state(&option_rom), PatchState::Unknown);
+        Ok(())
+    }
+}
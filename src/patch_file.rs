@@ -0,0 +1,392 @@
+//! Patches described in TOML files, so a fix for another pc.boot variant can be shipped without rebuilding. A file names
+//! the sites of the patch by their signature, with ?? for any byte, and gives the bytes written at each site's anchor
+//! to apply and to revert the patch:
+//!
+//! ```toml
+//! name = "skip-hdd-ready-check-file"
+//! description = "Keep the INT 13 handler of a PC hard disk controller"
+//!
+//! [precondition]
+//! rom_size = 0x2000
+//! contains_text = ["Janus 2.1"]
+//!
+//! [[site]]
+//! name = "hdd-ready-check"
+//! signature = "B4 10 B2 80 CD 13 5A 58 ?? ??"
+//! anchor = 8
+//! replace = "EB rel8:int13-set-finished"
+//! revert = "72 06"
+//!
+//! [[site]]
+//! name = "int13-set-finished"
+//! signature = "8C 06 1E 20 89 3E 1C 20 07"
+//! anchor = 9
+//! ```
+//!
+//! `rel8:site` and `rel16:site` are the displacement of a relative jump or call from the end of the bytes being written
+//! to the anchor of the named site. A site which replaces bytes must also say what to revert them to.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::option_rom::OptionRom;
+use crate::option_rom_patcher::{OptionRomPatcherError, PatchState};
+use crate::rom_patch::RomPatch;
+
+#[derive(Debug)]
+pub enum PatchFileError {
+    CouldntRead(std::io::Error),
+    Invalid(toml::de::Error),
+    InvalidSignature(String),
+    InvalidBytes(String),
+    AnchorOutsideSignature(String),
+    UnknownSite(String),
+    NothingToReplace,
+    /// A site with bytes to replace but none to put back
+    NoRevert(String),
+}
+
+impl fmt::Display for PatchFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchFileError::CouldntRead(e) => write!(f, "Couldn't read the patch file with error {}", e),
+            PatchFileError::Invalid(e) => write!(f, "The patch file is invalid: {}", e),
+            PatchFileError::InvalidSignature(site) => write!(f, "The signature of site {} isn't hex bytes and ??", site),
+            PatchFileError::InvalidBytes(site) => write!(f, "The bytes of site {} aren't hex bytes, rel8:site and rel16:site", site),
+            PatchFileError::AnchorOutsideSignature(site) => write!(f, "The anchor of site {} is past the end of its signature", site),
+            PatchFileError::UnknownSite(site) => write!(f, "A jump goes to site {} which isn't in the patch file", site),
+            PatchFileError::NothingToReplace => write!(f, "No site of the patch file has bytes to replace"),
+            PatchFileError::NoRevert(site) => write!(f, "Site {} has bytes to replace but no revert, so the patch couldn't be taken out", site),
+        }
+    }
+}
+
+impl std::error::Error for PatchFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatchFileError::CouldntRead(e) => Some(e),
+            PatchFileError::Invalid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchDefinition {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    precondition: Precondition,
+    #[serde(rename = "site")]
+    sites: Vec<SiteDefinition>,
+}
+
+/// What the rom must be like for the patch to be applied
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Precondition {
+    rom_size: Option<usize>,
+    /// Text which must be somewhere in the rom, e.g. the Janus version
+    #[serde(default)]
+    contains_text: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SiteDefinition {
+    name: String,
+    signature: String,
+    /// Where the bytes are written, from the start of the signature
+    #[serde(default)]
+    anchor: usize,
+    replace: Option<String>,
+    revert: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Template {
+    Byte(u8),
+    Rel8(String),
+    Rel16(String),
+}
+
+impl Template {
+    fn length(&self) -> usize {
+        match self {
+            Template::Byte(_) | Template::Rel8(_) => 1,
+            Template::Rel16(_) => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Site {
+    name: String,
+    /// None matches any byte
+    signature: Vec<Option<u8>>,
+    anchor: usize,
+    replace: Option<Vec<Template>>,
+    revert: Option<Vec<Template>>,
+}
+
+#[derive(Debug)]
+pub struct PatchFile {
+    name: String,
+    description: String,
+    precondition: Precondition,
+    sites: Vec<Site>,
+}
+
+fn parse_signature(site: &SiteDefinition) -> Result<Vec<Option<u8>>, PatchFileError> {
+    let signature: Vec<Option<u8>> = site.signature.split_whitespace()
+        .map(|byte| match byte {
+            "??" => Ok(None),
+            _ => u8::from_str_radix(byte, 16).map(Some),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|_| PatchFileError::InvalidSignature(site.name.clone()))?;
+
+    match signature.is_empty() {
+        true => Err(PatchFileError::InvalidSignature(site.name.clone())),
+        false => Ok(signature),
+    }
+}
+
+fn parse_template(site_name: &str, template: &str) -> Result<Vec<Template>, PatchFileError> {
+    template.split_whitespace()
+        .map(|part| {
+            if let Some(target) = part.strip_prefix("rel8:") {
+                Ok(Template::Rel8(target.to_string()))
+            } else if let Some(target) = part.strip_prefix("rel16:") {
+                Ok(Template::Rel16(target.to_string()))
+            } else {
+                u8::from_str_radix(part, 16).map(Template::Byte).map_err(|_| PatchFileError::InvalidBytes(site_name.to_string()))
+            }
+        })
+        .collect()
+}
+
+impl PatchFile {
+    pub fn parse(text: &str) -> Result<PatchFile, PatchFileError> {
+        let definition: PatchDefinition = toml::from_str(text).map_err(PatchFileError::Invalid)?;
+
+        let mut sites: Vec<Site> = Vec::new();
+        for site in &definition.sites {
+            let signature = parse_signature(site)?;
+            if site.anchor > signature.len() {
+                return Err(PatchFileError::AnchorOutsideSignature(site.name.clone()));
+            }
+            let replace = site.replace.as_deref().map(|template| parse_template(&site.name, template)).transpose()?;
+            let revert = site.revert.as_deref().map(|template| parse_template(&site.name, template)).transpose()?;
+            sites.push(Site { name: site.name.clone(), signature, anchor: site.anchor, replace, revert });
+        }
+
+        for template in sites.iter().flat_map(|site| site.replace.iter().chain(site.revert.iter()).flatten()) {
+            if let Template::Rel8(target) | Template::Rel16(target) = template {
+                if ! sites.iter().any(|site| site.name == *target) {
+                    return Err(PatchFileError::UnknownSite(target.clone()));
+                }
+            }
+        }
+        if ! sites.iter().any(|site| site.replace.is_some()) {
+            return Err(PatchFileError::NothingToReplace);
+        }
+        if let Some(site) = sites.iter().find(|site| site.replace.is_some() && site.revert.is_none()) {
+            return Err(PatchFileError::NoRevert(site.name.clone()));
+        }
+
+        Ok(PatchFile { name: definition.name, description: definition.description, precondition: definition.precondition, sites })
+    }
+
+    pub fn load(path: &Path) -> Result<PatchFile, PatchFileError> {
+        let text = fs::read_to_string(path).map_err(PatchFileError::CouldntRead)?;
+        PatchFile::parse(&text)
+    }
+
+    fn check_precondition(&self, option_rom: &OptionRom) -> Result<(), OptionRomPatcherError> {
+        if let Some(rom_size) = self.precondition.rom_size {
+            if option_rom.rom_size_in_bytes != rom_size {
+                return Err(OptionRomPatcherError::PreconditionFailed(format!("the rom is 0x{:X} bytes, not 0x{:X}", option_rom.rom_size_in_bytes, rom_size)));
+            }
+        }
+        for text in &self.precondition.contains_text {
+            if ! option_rom.bytes.windows(text.len()).any(|window| window == text.as_bytes()) {
+                return Err(OptionRomPatcherError::PreconditionFailed(format!("the rom doesn't contain \"{}\"", text)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The offset of each site's anchor in the rom
+    fn locate_sites(&self, option_rom: &OptionRom) -> Result<HashMap<&str, usize>, OptionRomPatcherError> {
+        self.sites.iter()
+            .map(|site| {
+                let location = option_rom.bytes.windows(site.signature.len())
+                    .position(|window| window.iter().zip(&site.signature).all(|(byte, expected)| expected.is_none_or(|expected| expected == *byte)))
+                    .ok_or_else(|| OptionRomPatcherError::PatchSiteNotFound(site.name.clone()))?;
+                Ok((site.name.as_str(), location + site.anchor))
+            })
+            .collect()
+    }
+
+    fn render(template: &[Template], location: usize, anchors: &HashMap<&str, usize>) -> Result<Vec<u8>, OptionRomPatcherError> {
+        let end = location + template.iter().map(Template::length).sum::<usize>();
+        let displacement = |target: &str| anchors[target] as isize - end as isize;
+
+        let mut bytes: Vec<u8> = Vec::new();
+        for part in template {
+            match part {
+                Template::Byte(byte) => bytes.push(*byte),
+                Template::Rel8(target) => bytes.push(i8::try_from(displacement(target)).map_err(|_| OptionRomPatcherError::JumpLengthTooBig)? as u8),
+                Template::Rel16(target) => bytes.extend_from_slice(&i16::try_from(displacement(target)).map_err(|_| OptionRomPatcherError::JumpLengthTooBig)?.to_le_bytes()),
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// The bytes of the rom with each site's template written at its anchor
+    fn write_sites(&self, option_rom: &OptionRom, template: fn(&Site) -> Option<&Vec<Template>>) -> Result<Vec<u8>, OptionRomPatcherError> {
+        let anchors = self.locate_sites(option_rom)?;
+        let mut bytes = option_rom.bytes.clone();
+
+        for site in &self.sites {
+            if let Some(template) = template(site) {
+                let location = anchors[site.name.as_str()];
+                let site_bytes = PatchFile::render(template, location, &anchors)?;
+                if location + site_bytes.len() > bytes.len() {
+                    return Err(OptionRomPatcherError::PatchSiteNotFound(site.name.clone()));
+                }
+                bytes[location..location + site_bytes.len()].copy_from_slice(&site_bytes);
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl RomPatch for PatchFile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Patched when every site's replacement bytes are in place, unpatched when none are
+    fn detect(&self, option_rom: &OptionRom) -> PatchState {
+        let anchors = match self.locate_sites(option_rom) {
+            Ok(anchors) => anchors,
+            Err(_) => return PatchState::Unknown,
+        };
+
+        let mut replaced_sites = self.sites.iter().filter_map(|site| {
+            let location = anchors[site.name.as_str()];
+            let replacement = PatchFile::render(site.replace.as_ref()?, location, &anchors).ok()?;
+            Some(option_rom.bytes.get(location..location + replacement.len()) == Some(replacement.as_slice()))
+        }).peekable();
+
+        let first = replaced_sites.peek().copied();
+        match (first, replaced_sites.all(|replaced| Some(replaced) == first)) {
+            (Some(true), true) => PatchState::Patched,
+            (Some(false), true) => PatchState::Unpatched,
+            _ => PatchState::Unknown,
+        }
+    }
+
+    fn apply(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        self.check_precondition(option_rom)?;
+        if self.detect(option_rom) == PatchState::Patched {
+            return Err(OptionRomPatcherError::AlreadyPatched);
+        }
+        self.write_sites(option_rom, |site| site.replace.as_ref())
+    }
+
+    fn revert(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        if self.detect(option_rom) != PatchState::Patched {
+            return Err(OptionRomPatcherError::NotPatched);
+        }
+        self.write_sites(option_rom, |site| site.revert.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::option_rom_patcher::SkipHddReadyCheck;
+    use crate::test_helpers::build_patchable_rom;
+
+    fn example_patch_file() -> Result<PatchFile, String> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("patches").join("skip-hdd-ready-check.toml");
+        PatchFile::load(&path).map_err(|e| format!("{}", e))
+    }
+
+    #[test]
+    fn test_patch_file_matches_built_in_patch() -> Result<(), String> {
+        let patch_file = example_patch_file()?;
+        let option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        assert_eq!(patch_file.detect(&option_rom), PatchState::Unpatched);
+
+        let patched_bytes = patch_file.apply(&option_rom).map_err(|e| format!("{}", e))?;
+        assert_eq!(patched_bytes, SkipHddReadyCheck.apply(&option_rom).map_err(|e| format!("{}", e))?);
+
+        let patched_rom = OptionRom::from(patched_bytes, 0).unwrap();
+        assert_eq!(patch_file.detect(&patched_rom), PatchState::Patched);
+        assert!(matches!(patch_file.apply(&patched_rom), Err(OptionRomPatcherError::AlreadyPatched)));
+        assert_eq!(patch_file.revert(&patched_rom).map_err(|e| format!("{}", e))?, option_rom.bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_file_precondition() -> Result<(), String> {
+        let patch_file = PatchFile::parse(r#"
+            name = "needs-janus-2.1"
+            precondition = { contains_text = ["Janus 2.1"] }
+            site = [{ name = "entry", signature = "55 AA ?? CB", anchor = 3, replace = "90", revert = "CB" }]
+        "#).map_err(|e| format!("{}", e))?;
+        let option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+
+        match patch_file.apply(&option_rom) {
+            Ok(_) => Err("Expected the precondition to fail but the patch was applied".into()),
+            Err(e) => {
+                assert_eq!(e.to_string(), "The rom doesn't meet the patch's precondition: the rom doesn't contain \"Janus 2.1\"");
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn test_patch_file_with_unknown_jump_target() -> Result<(), String> {
+        let result = PatchFile::parse(r#"
+            name = "broken"
+            site = [{ name = "entry", signature = "55 AA", replace = "EB rel8:nowhere" }]
+        "#);
+        match result {
+            Ok(_) => Err("Expected an error for a jump to an unknown site".into()),
+            Err(e) => {
+                assert_eq!(e.to_string(), "A jump goes to site nowhere which isn't in the patch file");
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn test_patch_file_without_revert() -> Result<(), String> {
+        let result = PatchFile::parse(r#"
+            name = "one-way"
+            site = [{ name = "entry", signature = "55 AA ?? CB", anchor = 3, replace = "90" }]
+        "#);
+        match result {
+            Ok(_) => Err("Expected an error for a site which can't be reverted".into()),
+            Err(e) => {
+                assert_eq!(e.to_string(), "Site entry has bytes to replace but no revert, so the patch couldn't be taken out");
+                Ok(())
+            },
+        }
+    }
+}
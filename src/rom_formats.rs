@@ -0,0 +1,304 @@
+//! Intel HEX and Motorola S-record files, the text formats EPROM programmer software reads and writes, converted to and
+//! from binary rom images.
+
+use std::fmt;
+
+/// The format rom-only output is written in
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum RomFormat {
+    Binary,
+    /// Intel HEX, with extended linear address records above 64K
+    Ihex,
+    /// S-records with 16 bit addresses
+    S19,
+    /// S-records with 24 bit addresses
+    S28,
+    /// S-records with 32 bit addresses
+    S37,
+}
+
+/// The file name extensions which are read as Intel HEX or S-records rather than as a binary image
+const TEXT_FORMAT_EXTENSIONS: [&str; 8] = ["hex", "ihx", "ihex", "s19", "s28", "s37", "srec", "mot"];
+
+const BYTES_PER_RECORD: usize = 16;
+
+const IHEX_DATA: u8 = 0x00;
+const IHEX_END_OF_FILE: u8 = 0x01;
+const IHEX_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const IHEX_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+/// Gaps between records are filled with the value of erased EPROM
+const GAP_FILL_BYTE: u8 = 0xFF;
+
+/// The 8086 address space, the most a file of roms for it can need
+const MAX_IMAGE_LENGTH: u64 = 0x100000;
+
+#[derive(Debug, PartialEq)]
+pub enum RomFormatError {
+    InvalidRecord(usize),
+    BadRecordChecksum(usize),
+    AddressTooLarge { format: RomFormat, end_address: u64 },
+    /// The records span more than the 8086 address space
+    ImageTooLarge(u64),
+    NoData,
+}
+
+impl fmt::Display for RomFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomFormatError::InvalidRecord(line) => write!(f, "The record on line {} isn't valid Intel HEX or S-record", line),
+            RomFormatError::BadRecordChecksum(line) => write!(f, "The record on line {} has a bad checksum", line),
+            RomFormatError::AddressTooLarge { format, end_address } => write!(
+                f, "The rom would end at address 0x{:X} which is too large for {:?}, give a lower load address or a format with longer addresses",
+                end_address, format,
+            ),
+            RomFormatError::ImageTooLarge(length) => write!(f, "The records span 0x{:X} bytes, more than the 1 MB the 8086 can address", length),
+            RomFormatError::NoData => write!(f, "There are no data records in the file"),
+        }
+    }
+}
+
+impl std::error::Error for RomFormatError {}
+
+impl RomFormat {
+    /// The number of address bytes in a record, or None for formats without addresses
+    fn address_bytes(&self) -> Option<usize> {
+        match self {
+            RomFormat::Binary => None,
+            RomFormat::S19 => Some(2),
+            RomFormat::S28 => Some(3),
+            RomFormat::Ihex | RomFormat::S37 => Some(4),
+        }
+    }
+}
+
+/// Whether a file should be read as Intel HEX or S-records, going by the extension of its name
+pub fn is_text_format_name(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, extension)) => TEXT_FORMAT_EXTENSIONS.iter().any(|text_extension| extension.eq_ignore_ascii_case(text_extension)),
+        None => false,
+    }
+}
+
+fn record_line(prefix: &str, fields: &[u8], checksum: u8) -> String {
+    let hex: String = fields.iter().chain(std::iter::once(&checksum)).map(|byte| format!("{:02X}", byte)).collect();
+    format!("{}{}\r\n", prefix, hex)
+}
+
+fn ihex_record(address: u16, record_type: u8, data: &[u8]) -> String {
+    let mut fields: Vec<u8> = vec![data.len() as u8];
+    fields.extend_from_slice(&address.to_be_bytes());
+    fields.push(record_type);
+    fields.extend_from_slice(data);
+
+    let sum = fields.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record_line(":", &fields, sum.wrapping_neg())
+}
+
+fn srecord(record_type: u8, address: u32, address_bytes: usize, data: &[u8]) -> String {
+    let mut fields: Vec<u8> = vec![(address_bytes + data.len() + 1) as u8];
+    fields.extend_from_slice(&address.to_be_bytes()[4 - address_bytes..]);
+    fields.extend_from_slice(data);
+
+    let sum = fields.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record_line(&format!("S{}", record_type), &fields, !sum)
+}
+
+/// The rom image in the format, with its first byte at load_address
+pub fn encode(bytes: &[u8], format: RomFormat, load_address: u32) -> Result<Vec<u8>, RomFormatError> {
+    let address_bytes = match format.address_bytes() {
+        Some(address_bytes) => address_bytes,
+        None => return Ok(bytes.to_vec()),
+    };
+
+    let end_address = load_address as u64 + bytes.len().max(1) as u64 - 1;
+    if end_address >> (address_bytes * 8) != 0 {
+        return Err(RomFormatError::AddressTooLarge { format, end_address });
+    }
+
+    let mut text = String::new();
+    match format {
+        RomFormat::Ihex => {
+            let mut upper_address: Option<u16> = None;
+            for (index, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+                let address = load_address + (index * BYTES_PER_RECORD) as u32;
+                // A record can't cross a 64K boundary, so split it there
+                let split = chunk.len().min(0x10000 - (address & 0xFFFF) as usize);
+
+                for (address, data) in [(address, &chunk[..split]), (address + split as u32, &chunk[split..])] {
+                    if data.is_empty() {
+                        continue;
+                    }
+                    if upper_address != Some((address >> 16) as u16) && (address >> 16 != 0 || upper_address.is_some()) {
+                        text.push_str(&ihex_record(0, IHEX_EXTENDED_LINEAR_ADDRESS, &((address >> 16) as u16).to_be_bytes()));
+                    }
+                    upper_address = Some((address >> 16) as u16);
+                    text.push_str(&ihex_record(address as u16, IHEX_DATA, data));
+                }
+            }
+            text.push_str(&ihex_record(0, IHEX_END_OF_FILE, &[]));
+        },
+        _ => {
+            text.push_str(&srecord(0, 0, 2, &[]));
+            for (index, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+                text.push_str(&srecord(address_bytes as u8 - 1, load_address + (index * BYTES_PER_RECORD) as u32, address_bytes, chunk));
+            }
+            // The termination record (S9, S8 or S7) holds the start address
+            text.push_str(&srecord(11 - address_bytes as u8, load_address, address_bytes, &[]));
+        },
+    }
+
+    Ok(text.into_bytes())
+}
+
+/// The hex digits of a record as bytes
+fn record_bytes(digits: &str, line: usize) -> Result<Vec<u8>, RomFormatError> {
+    if ! digits.len().is_multiple_of(2) || digits.is_empty() {
+        return Err(RomFormatError::InvalidRecord(line));
+    }
+
+    (0..digits.len()).step_by(2)
+        .map(|index| digits.get(index..index + 2).and_then(|digit| u8::from_str_radix(digit, 16).ok()).ok_or(RomFormatError::InvalidRecord(line)))
+        .collect()
+}
+
+/// The data records in an Intel HEX or S-record file as (address, data)
+fn records(text: &str) -> Result<Vec<(u64, Vec<u8>)>, RomFormatError> {
+    let mut records: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut base_address: u64 = 0;
+
+    for (index, record) in text.lines().enumerate() {
+        let line = index + 1;
+        let record = record.trim();
+
+        if let Some(digits) = record.strip_prefix(':') {
+            let bytes = record_bytes(digits, line)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(RomFormatError::InvalidRecord(line));
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(RomFormatError::BadRecordChecksum(line));
+            }
+
+            let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                IHEX_DATA => records.push((base_address + address, data.to_vec())),
+                IHEX_END_OF_FILE => break,
+                IHEX_EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as u64) << 4,
+                IHEX_EXTENDED_LINEAR_ADDRESS if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as u64) << 16,
+                IHEX_EXTENDED_SEGMENT_ADDRESS | IHEX_EXTENDED_LINEAR_ADDRESS => return Err(RomFormatError::InvalidRecord(line)),
+                // Start address records don't affect the image
+                _ => {},
+            }
+        } else if let Some(record) = record.strip_prefix('S') {
+            let record_type = record.chars().next().ok_or(RomFormatError::InvalidRecord(line))?;
+            let bytes = record_bytes(&record[record_type.len_utf8()..], line)?;
+            if bytes.len() != bytes[0] as usize + 1 {
+                return Err(RomFormatError::InvalidRecord(line));
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+                return Err(RomFormatError::BadRecordChecksum(line));
+            }
+
+            let address_bytes = match record_type {
+                '1' => 2,
+                '2' => 3,
+                '3' => 4,
+                '0' | '5' | '6' | '7' | '8' | '9' => continue,
+                _ => return Err(RomFormatError::InvalidRecord(line)),
+            };
+            if bytes.len() < address_bytes + 2 {
+                return Err(RomFormatError::InvalidRecord(line));
+            }
+
+            let address = bytes[1..=address_bytes].iter().fold(0u64, |address, byte| (address << 8) | *byte as u64);
+            records.push((address, bytes[address_bytes + 1..bytes.len() - 1].to_vec()));
+        } else if ! record.is_empty() {
+            return Err(RomFormatError::InvalidRecord(line));
+        }
+    }
+
+    Ok(records)
+}
+
+/// The binary image in an Intel HEX or S-record file, starting at its lowest address
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>, RomFormatError> {
+    // Records without data don't add to the image wherever they are
+    let records: Vec<(u64, Vec<u8>)> = records(&String::from_utf8_lossy(bytes))?.into_iter().filter(|(_, data)| ! data.is_empty()).collect();
+
+    let start = records.iter().map(|(address, _)| *address).min().ok_or(RomFormatError::NoData)?;
+    let end = records.iter().map(|(address, data)| address + data.len() as u64).max().unwrap_or(start);
+    if end - start > MAX_IMAGE_LENGTH {
+        return Err(RomFormatError::ImageTooLarge(end - start));
+    }
+
+    let mut image = vec![GAP_FILL_BYTE; (end - start) as usize];
+    for (address, data) in records {
+        let offset = (address - start) as usize;
+        image[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::load_fixture;
+
+    #[test]
+    fn test_encode_intel_hex() {
+        let bytes: Vec<u8> = (0..20).collect();
+        let text = String::from_utf8(encode(&bytes, RomFormat::Ihex, 0xD0000).unwrap()).unwrap();
+
+        assert_eq!(text, concat!(
+            ":02000004000DED\r\n",
+            ":10000000000102030405060708090A0B0C0D0E0F78\r\n",
+            ":0400100010111213A6\r\n",
+            ":00000001FF\r\n",
+        ));
+    }
+
+    #[test]
+    fn test_encode_srecords() {
+        let text = String::from_utf8(encode(&[0x55, 0xAA, 0x10], RomFormat::S19, 0x1000).unwrap()).unwrap();
+        assert_eq!(text, "S0030000FC\r\nS106100055AA10DA\r\nS9031000EC\r\n");
+
+        let text = String::from_utf8(encode(&[0x55, 0xAA, 0x10], RomFormat::S28, 0xD0000).unwrap()).unwrap();
+        assert_eq!(text, "S0030000FC\r\nS2070D000055AA10DC\r\nS8040D0000EE\r\n");
+
+        assert_eq!(
+            encode(&[0u8; 0x2000], RomFormat::S19, 0xD0000),
+            Err(RomFormatError::AddressTooLarge { format: RomFormat::S19, end_address: 0xD1FFF }),
+        );
+    }
+
+    #[test]
+    fn test_decode_round_trip() -> Result<(), String> {
+        let pc_boot = load_fixture("pc.boot.valid")?;
+
+        for format in [RomFormat::Ihex, RomFormat::S28, RomFormat::S37] {
+            let text = encode(&pc_boot, format, 0xDFF00).map_err(|e| format!("{}", e))?;
+            assert_eq!(decode(&text), Ok(pc_boot.clone()), "{:?}", format);
+        }
+        assert_eq!(decode(&encode(&pc_boot, RomFormat::S19, 0).unwrap()), Ok(pc_boot));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b":0400100010111213A3\r\n"), Err(RomFormatError::BadRecordChecksum(1)));
+        assert_eq!(decode(b"S0030000FC\nS10610"), Err(RomFormatError::InvalidRecord(2)));
+        assert_eq!(decode(b":00000001FF\n"), Err(RomFormatError::NoData));
+        // A gap between records is filled as erased EPROM
+        assert_eq!(decode(b":0100000055AA\n:01000200AA53\n"), Ok(vec![0x55, 0xFF, 0xAA]));
+        // An empty record below the data is left out
+        assert_eq!(decode(b"S1030000FC\nS104010055A5\n"), Ok(vec![0x55]));
+        // Records at 0 and FFFF0000 would need an image of nearly 4 GB
+        assert_eq!(decode(b":0100000055AA\n:02000004FFFFFC\n:0100000055AA\n"), Err(RomFormatError::ImageTooLarge(0xFFFF0001)));
+
+        assert!(is_text_format_name("PCBOOT.HEX"));
+        assert!(! is_text_format_name("pc.boot"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Patches which can be applied to an option rom by name. Each patch finds its own site in the rom, so a new Bridgeboard
+//! tweak is a new implementation of RomPatch added to built_in_patches.
+
+use crate::janus_drive_patch::{JanusDriveNumber, DEFAULT_JANUS_DRIVE};
+use crate::option_rom::{ChecksumByteLocation, OptionRom};
+use crate::option_rom_patcher::{OptionRomPatcherError, PatchState, SkipHddReadyCheck};
+use crate::shift_bypass_patch::ShiftKeyBypass;
+
+pub trait RomPatch {
+    /// The name given to --patch
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    fn detect(&self, option_rom: &OptionRom) -> PatchState;
+    /// The bytes of the rom with the patch applied, the checksum is corrected afterwards
+    fn apply(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError>;
+    /// The bytes of the rom with the patch taken out again, the checksum is corrected afterwards
+    fn revert(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError>;
+}
+
+pub fn built_in_patches() -> Vec<Box<dyn RomPatch>> {
+    built_in_patches_allowing(false)
+}
+
+/// The built in patches, with the ones which add code allowed to put it in a low confidence code cave
+fn built_in_patches_allowing(low_confidence_cave: bool) -> Vec<Box<dyn RomPatch>> {
+    vec![
+        Box::new(SkipHddReadyCheck),
+        Box::new(JanusDriveNumber::new(DEFAULT_JANUS_DRIVE)),
+        Box::new(ShiftKeyBypass::new(low_confidence_cave)),
+    ]
+}
+
+pub fn find_patch(name: &str) -> Option<Box<dyn RomPatch>> {
+    find_patch_allowing(name, false)
+}
+
+/// Find a patch by name for applying, see built_in_patches_allowing
+pub fn find_patch_allowing(name: &str, low_confidence_cave: bool) -> Option<Box<dyn RomPatch>> {
+    built_in_patches_allowing(low_confidence_cave).into_iter().find(|patch| patch.name() == name)
+}
+
+/// Parse the name given to --patch, listing the patches when it isn't one of them
+pub fn parse_patch_name(name: &str) -> Result<String, String> {
+    match find_patch(name) {
+        Some(patch) => Ok(patch.name().to_string()),
+        None => {
+            let names: Vec<String> = built_in_patches().iter().map(|patch| patch.name().to_string()).collect();
+            Err(format!("{} isn't a known patch, the patches are: {}", name, names.join(", ")))
+        },
+    }
+}
+
+/// Correct the checksum of the patched bytes and check the patch now detects the state it should have left
+fn finish(patch: &dyn RomPatch, bytes: Vec<u8>, checksum_byte_location: ChecksumByteLocation, expected_state: PatchState) -> Result<OptionRom, OptionRomPatcherError> {
+    let mut option_rom = OptionRom::from(bytes, 0).map_err(OptionRomPatcherError::OptionRomGenerationError)?;
+    option_rom.correct_checksum_at(checksum_byte_location).map_err(OptionRomPatcherError::OptionRomGenerationError)?;
+
+    match patch.detect(&option_rom) {
+        state if state == expected_state => Ok(option_rom),
+        state => Err(OptionRomPatcherError::VerificationFailed { expected: expected_state, found: state }),
+    }
+}
+
+pub fn apply(patch: &dyn RomPatch, option_rom: &OptionRom, checksum_byte_location: ChecksumByteLocation) -> Result<OptionRom, OptionRomPatcherError> {
+    log::info!("Applying {}", patch.name());
+    finish(patch, patch.apply(option_rom)?, checksum_byte_location, PatchState::Patched)
+}
+
+pub fn revert(patch: &dyn RomPatch, option_rom: &OptionRom, checksum_byte_location: ChecksumByteLocation) -> Result<OptionRom, OptionRomPatcherError> {
+    log::info!("Reverting {}", patch.name());
+    finish(patch, patch.revert(option_rom)?, checksum_byte_location, PatchState::Unpatched)
+}
+
+/// The patches with their state in the rom, one per line
+pub fn list(patches: &[Box<dyn RomPatch>], option_rom: &OptionRom) -> String {
+    let name_width = patches.iter().map(|patch| patch.name().len()).max().unwrap_or_default();
+
+    let lines: Vec<String> = patches.iter()
+        .map(|patch| {
+            let state = match patch.detect(option_rom) {
+                PatchState::Unpatched => "unpatched",
+                PatchState::Patched => "patched",
+                PatchState::Unknown => "unknown",
+            };
+            format!("{:<name_width$}  {:<9}  {}", patch.name(), state, patch.description(), name_width = name_width)
+        })
+        .collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::build_patchable_rom;
+
+    #[test]
+    fn test_apply_and_revert_by_name() -> Result<(), String> {
+        let option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        let patch = find_patch("skip-hdd-ready-check").ok_or("The patch should be built in")?;
+
+        let patched_rom = apply(patch.as_ref(), &option_rom, ChecksumByteLocation::FinalByte).map_err(|e| format!("{}", e))?;
+        assert_eq!(patch.detect(&patched_rom), PatchState::Patched);
+        assert!(patched_rom.clone().validate_checksum().is_ok());
+        assert!(list(&built_in_patches(), &patched_rom).starts_with("skip-hdd-ready-check  patched    "));
+
+        let reverted_rom = revert(patch.as_ref(), &patched_rom, ChecksumByteLocation::FinalByte).map_err(|e| format!("{}", e))?;
+        assert_eq!(patch.detect(&reverted_rom), PatchState::Unpatched);
+        assert_eq!(reverted_rom.bytes[..0x1FF], option_rom.bytes[..0x1FF]);
+
+        assert!(matches!(patch.revert(&option_rom), Err(OptionRomPatcherError::NotPatched)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unknown_patch_name() -> Result<(), String> {
+        match parse_patch_name("faster-floppy") {
+            Ok(name) => Err(format!("Expected an error for an unknown patch but got {}", name)),
+            Err(message) => {
+                assert_eq!(message, "faster-floppy isn't a known patch, the patches are: skip-hdd-ready-check, janus-drive-81, shift-key-bypass");
+                Ok(())
+            },
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! A patch which lets one pc.boot boot from either disk: holding a shift key while the Bridgeboard starts bypasses the
+//! Janus INT 13 handler so the PC's hard disk controller (e.g. an XTIDE) boots, otherwise the JanusDisk boots as before.
+//!
+//! The POP DX, POP AX and JC after the HDD ready check are replaced with a near JMP to a stub put in a code cave, which
+//! does the same and then checks the shift keys with INT 16h AH=02h (see STUB).
+
+use std::collections::HashMap;
+
+use crate::assembler::{self, AssemblerError};
+use crate::code_cave::{self, Confidence};
+use crate::option_rom::OptionRom;
+use crate::option_rom_patcher::{self, OptionRomPatcherError, PatchState};
+use crate::rom_patch::RomPatch;
+
+const X86_POP_DX: u8 = 0x5a;
+const X86_POP_AX: u8 = 0x58;
+const X86_JC: u8 = 0x72;
+const X86_JMP_NEAR: u8 = 0xe9;
+const X86_MOV_INTO_AH: u8 = 0xb4;
+const X86_MOV_INTO_DL: u8 = 0xb2;
+const X86_INT: u8 = 0xcd;
+
+/// Goes where the POP DX, POP AX and JC were, to the stub
+const JUMP_TO_STUB: &str = "
+        jmp stub
+        nop
+";
+
+/// not_ready is where the JC went, bypass is after the INT 13 handler is set and install is after the JC. 03h in the
+/// shift flags is the right and left shift keys
+const STUB: &str = "
+        pop dx
+        pop ax
+        jnc ready
+        jmp not_ready           ; the HDD isn't ready, as before
+ready:  push ax
+        mov ah, 02h
+        int 16h
+        test al, 03h
+        pop ax
+        jz keep_janus
+        jmp bypass
+keep_janus:
+        jmp install             ; set the Janus INT 13 handler
+";
+
+/// MOV AH, 10h; MOV DL, unit; INT 13h, with any unit so the drive can also be moved by janus-drive-XX
+const HDD_READY_CHECK: [Option<u8>; 6] = [Some(X86_MOV_INTO_AH), Some(0x10), Some(X86_MOV_INTO_DL), None, Some(X86_INT), Some(0x13)];
+
+/// The replaced POP DX, POP AX, JC xx
+const REPLACED_LENGTH: usize = 4;
+const STUB_LENGTH: usize = 23;
+/// The offsets in the stub of the jumps to not_ready, bypass and install, each a JMP near
+const NOT_READY_JUMP: usize = 4;
+const BYPASS_JUMP: usize = 17;
+const INSTALL_JUMP: usize = 20;
+
+/// The POP DX after the HDD ready check, which is where the JMP to the stub goes
+fn find_replaced_location(option_rom: &OptionRom) -> Option<usize> {
+    option_rom.bytes.windows(HDD_READY_CHECK.len())
+        .position(|window| window.iter().zip(HDD_READY_CHECK).all(|(byte, expected)| expected.is_none_or(|expected| *byte == expected)))
+        .map(|location| location + HDD_READY_CHECK.len())
+        .filter(|replaced| replaced + REPLACED_LENGTH <= option_rom.bytes.len())
+}
+
+fn near_target(bytes: &[u8], location: usize) -> usize {
+    let displacement = u16::from_le_bytes([bytes[location + 1], bytes[location + 2]]);
+    (location + 3).wrapping_add(displacement as i16 as usize) & 0xFFFF
+}
+
+fn build_stub(stub_start: usize, not_ready_target: usize, bypass_target: usize, install_target: usize) -> Result<Vec<u8>, AssemblerError> {
+    let symbols = HashMap::from([
+        ("not_ready".to_string(), not_ready_target),
+        ("bypass".to_string(), bypass_target),
+        ("install".to_string(), install_target),
+    ]);
+    assembler::assemble(STUB, stub_start, &symbols)
+}
+
+/// The stub the patched JMP goes to, if the rom is patched
+fn find_stub(option_rom: &OptionRom) -> Option<usize> {
+    let bytes = &option_rom.bytes;
+    let replaced = find_replaced_location(option_rom)?;
+    if bytes[replaced] != X86_JMP_NEAR {
+        return None;
+    }
+
+    let stub_start = near_target(bytes, replaced);
+    let stub = bytes.get(stub_start..stub_start + STUB_LENGTH)?;
+    let targets = [NOT_READY_JUMP, BYPASS_JUMP, INSTALL_JUMP].map(|jump| near_target(bytes, stub_start + jump));
+    (stub == build_stub(stub_start, targets[0], targets[1], targets[2]).ok()?).then_some(stub_start)
+}
+
+pub struct ShiftKeyBypass {
+    /// Put the stub in a cave of some other repeated byte, which could be a table pc.boot reads, when there's no better
+    /// one. The only cave in pc.boot is such a run of 61h
+    allow_low_confidence_cave: bool,
+}
+
+impl ShiftKeyBypass {
+    pub fn new(allow_low_confidence_cave: bool) -> ShiftKeyBypass {
+        ShiftKeyBypass { allow_low_confidence_cave }
+    }
+}
+
+impl RomPatch for ShiftKeyBypass {
+    fn name(&self) -> &str {
+        "shift-key-bypass"
+    }
+
+    fn description(&self) -> &str {
+        "Hold shift while the Bridgeboard starts to keep the PC hard disk controller's INT 13 handler"
+    }
+
+    fn detect(&self, option_rom: &OptionRom) -> PatchState {
+        let replaced = match find_replaced_location(option_rom) {
+            Some(replaced) => replaced,
+            None => return PatchState::Unknown,
+        };
+
+        match option_rom.bytes[replaced..replaced + 3] {
+            [X86_POP_DX, X86_POP_AX, X86_JC] => PatchState::Unpatched,
+            _ if find_stub(option_rom).is_some() => PatchState::Patched,
+            _ => PatchState::Unknown,
+        }
+    }
+
+    fn apply(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        match self.detect(option_rom) {
+            PatchState::Unpatched => {},
+            PatchState::Patched => return Err(OptionRomPatcherError::AlreadyPatched),
+            PatchState::Unknown => return Err(OptionRomPatcherError::CouldntLocateHddReadyCheck),
+        }
+        let replaced = find_replaced_location(option_rom).ok_or(OptionRomPatcherError::CouldntLocateHddReadyCheck)?;
+        let bypass_target = option_rom_patcher::find_location_after_int_13_set(option_rom)?;
+        let cave = code_cave::find_space(option_rom, STUB_LENGTH)
+            .ok_or_else(|| OptionRomPatcherError::PreconditionFailed(format!("there's no free space for the {} byte stub", STUB_LENGTH)))?;
+        if cave.confidence == Confidence::Low {
+            if ! self.allow_low_confidence_cave {
+                return Err(OptionRomPatcherError::PreconditionFailed(format!(
+                    "the only space for the {} byte stub is a run of {:02X} at 0x{:04X}, which could be data, use --allow-low-confidence-cave to put it there anyway",
+                    STUB_LENGTH, cave.filler, cave.offset)));
+            }
+            log::warn!("The stub is going in a run of {:02X} at 0x{:04X}, which could be data", cave.filler, cave.offset);
+        }
+
+        let install_target = replaced + REPLACED_LENGTH;
+        let not_ready_target = install_target.wrapping_add(option_rom.bytes[replaced + 3] as i8 as usize);
+        log::debug!("Shift key stub placed in the code cave at 0x{:04X}", cave.offset);
+
+        let couldnt_assemble = |e: AssemblerError| OptionRomPatcherError::PreconditionFailed(format!("the stub couldn't be assembled: {}", e));
+        let stub = build_stub(cave.offset, not_ready_target, bypass_target, install_target).map_err(couldnt_assemble)?;
+        let jump_to_stub = assembler::assemble(JUMP_TO_STUB, replaced, &HashMap::from([("stub".to_string(), cave.offset)])).map_err(couldnt_assemble)?;
+
+        let mut bytes = option_rom.bytes.clone();
+        bytes[cave.offset..cave.offset + STUB_LENGTH].copy_from_slice(&stub);
+        bytes[replaced..replaced + REPLACED_LENGTH].copy_from_slice(&jump_to_stub);
+        Ok(bytes)
+    }
+
+    fn revert(&self, option_rom: &OptionRom) -> Result<Vec<u8>, OptionRomPatcherError> {
+        let stub_start = find_stub(option_rom).ok_or(OptionRomPatcherError::NotPatched)?;
+        let replaced = find_replaced_location(option_rom).ok_or(OptionRomPatcherError::NotPatched)?;
+
+        let bytes = &option_rom.bytes;
+        let not_ready_target = near_target(bytes, stub_start + NOT_READY_JUMP);
+        let jump_length = not_ready_target.wrapping_sub(replaced + REPLACED_LENGTH) as u8;
+        // The stub is put in a cave with at least one filler byte after it
+        let filler = bytes.get(stub_start + STUB_LENGTH).copied().unwrap_or(0xFF);
+
+        let mut bytes = bytes.clone();
+        bytes[replaced..replaced + REPLACED_LENGTH].copy_from_slice(&[X86_POP_DX, X86_POP_AX, X86_JC, jump_length]);
+        bytes[stub_start..stub_start + STUB_LENGTH].fill(filler);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::build_patchable_rom;
+
+    #[test]
+    fn test_shift_key_bypass() -> Result<(), String> {
+        let option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        // The only cave in the padding is a run of 61h
+        let patch = ShiftKeyBypass::new(true);
+        assert_eq!(patch.detect(&option_rom), PatchState::Unpatched);
+
+        let patched_rom = OptionRom::from(patch.apply(&option_rom).map_err(|e| format!("{}", e))?, 0).unwrap();
+        // JMP 001F to the stub in the padding after the ready check, then the INT 16h check of the shift keys
+        assert_eq!(patched_rom.bytes[10..14], [0xe9, 0x12, 0x00, 0x90]);
+        assert_eq!(patched_rom.bytes[0x1F..0x1F + 7], [0x5a, 0x58, 0x73, 0x03, 0xe9, 0xee, 0xff]);
+        assert_eq!(patched_rom.bytes[0x1F + 7..0x1F + 14], [0x50, 0xb4, 0x02, 0xcd, 0x16, 0xa8, 0x03]);
+        assert_eq!(patch.detect(&patched_rom), PatchState::Patched);
+
+        assert_eq!(patch.revert(&patched_rom).map_err(|e| format!("{}", e))?, option_rom.bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stub_matches_hand_assembly() -> Result<(), String> {
+        let stub = build_stub(0x1C00, 0x0100, 0x0200, 0x0300).map_err(|e| e.to_string())?;
+        assert_eq!(stub, vec![
+            0x5a, 0x58,                   // pop dx; pop ax
+            0x73, 0x03,                   // jnc 1C07
+            0xe9, 0xf9, 0xe4,             // jmp 0100
+            0x50, 0xb4, 0x02, 0xcd, 0x16, // push ax; mov ah, 02h; int 16h
+            0xa8, 0x03, 0x58,             // test al, 03h; pop ax
+            0x74, 0x03,                   // jz 1C14
+            0xe9, 0xec, 0xe5,             // jmp 0200
+            0xe9, 0xe9, 0xe6,             // jmp 0300
+        ]);
+        assert_eq!(stub.len(), STUB_LENGTH);
+        assert_eq!([NOT_READY_JUMP, BYPASS_JUMP, INSTALL_JUMP].map(|jump| stub[jump]), [X86_JMP_NEAR; 3]);
+        Ok(())
+    }
+
+    /// An 8K rom laid out like pc.boot, where the only cave is the alignment padding of 61h from 0x1C00 up to the
+    /// checksum byte
+    fn build_rom_with_pc_boot_caves() -> OptionRom {
+        let mut bytes = build_patchable_rom();
+        bytes.resize(0x2000, 0x61);
+        bytes[2] = 0x10;
+        // The rest of the code, which has no runs of a repeated byte
+        for (offset, byte) in bytes.iter_mut().enumerate().take(0x1C00).skip(30) {
+            *byte = offset as u8;
+        }
+        OptionRom::from(bytes, 0).unwrap()
+    }
+
+    #[test]
+    fn test_low_confidence_cave_refused() -> Result<(), String> {
+        let option_rom = build_rom_with_pc_boot_caves();
+
+        match ShiftKeyBypass::new(false).apply(&option_rom) {
+            Ok(_) => Err("Expected the patch to be refused but it was applied".into()),
+            Err(e) => {
+                assert_eq!(e.to_string(), "The rom doesn't meet the patch's precondition: the only space for the 23 byte stub is a run of 61 at 0x1C00, which could be data, use --allow-low-confidence-cave to put it there anyway");
+                Ok(())
+            },
+        }
+    }
+
+    #[test]
+    fn test_low_confidence_cave_allowed() -> Result<(), String> {
+        let option_rom = build_rom_with_pc_boot_caves();
+        let patch = ShiftKeyBypass::new(true);
+
+        let patched_rom = OptionRom::from(patch.apply(&option_rom).map_err(|e| format!("{}", e))?, 0).unwrap();
+        // JMP 1C00
+        assert_eq!(patched_rom.bytes[10..14], [0xe9, 0xf3, 0x1b, 0x90]);
+        assert_eq!(patched_rom.bytes[0x1C00..0x1C04], [0x5a, 0x58, 0x73, 0x03]);
+        assert_eq!(patch.detect(&patched_rom), PatchState::Patched);
+        Ok(())
+    }
+}
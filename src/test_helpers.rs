@@ -2,6 +2,7 @@ use std::path::PathBuf;
 use tempfile::{tempdir, TempDir};
 
 use crate::option_rom::OptionRom;
+use crate::xtide::{ROMVARS_FLAGS_OFFSET, ROMVARS_SIGNATURE_OFFSET, ROMVARS_TITLE_OFFSET, ROMVARS_VERSION_OFFSET};
 
 pub fn fixture_path(fixture_file: &str) -> PathBuf {
     let all_path_parts: Vec<&str> = vec![
@@ -48,3 +49,221 @@ pub fn assert_file_has_bytes(file_path: &PathBuf, expected_bytes: &Vec<u8>) -> R
         Err(e) => Err(format!("Failed to open file {} to check bytes with error {}", file_path.display(), e)),
     }
 }
+
+/// Build a 512 byte rom with just the code around the HDD ready check and setting the INT13 handler, which patches
+pub fn build_patchable_rom() -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0x55, 0xAA, 0x01, 0xcb];
+    bytes.extend_from_slice(&[
+        0xb4, 0x10, 0xb2, 0x80, 0xcd, 0x13, 0x5a, 0x58, 0x72, 0x06,
+        0x06, 0xb0, 0x13, 0xbf, 0x6e, 0x02, 0x07,
+        0x8c, 0x06, 0x1e, 0x20, 0x89, 0x3e, 0x1c, 0x20, 0x07, 0xa1,
+    ]);
+    bytes.resize(512, 0x61);
+    bytes
+}
+
+/// Build an 8K XTIDE Universal BIOS image with just enough of ROMVARS filled in to be identified
+pub fn build_xtide_rom(title: &str, version: &str, flags: u16) -> Vec<u8> {
+    let mut bytes = vec![0u8; 0x2000];
+    bytes[0..3].copy_from_slice(&[0x55, 0xAA, 0x10]);
+    bytes[ROMVARS_SIGNATURE_OFFSET..ROMVARS_SIGNATURE_OFFSET + 8].copy_from_slice(b"XTIDE220");
+    bytes[ROMVARS_TITLE_OFFSET..ROMVARS_TITLE_OFFSET + title.len()].copy_from_slice(title.as_bytes());
+    bytes[ROMVARS_VERSION_OFFSET..ROMVARS_VERSION_OFFSET + version.len()].copy_from_slice(version.as_bytes());
+    bytes[ROMVARS_FLAGS_OFFSET..ROMVARS_FLAGS_OFFSET + 2].copy_from_slice(&flags.to_le_bytes());
+    bytes
+}
+
+pub const ADF_DD_BLOCKS: u32 = 1760;
+
+/// Build an Amiga OFS (dos_type 0) or FFS (dos_type 1) filesystem holding the given files, creating their directories.
+/// It is written independently of amiga_filesystem so each can check the other, and only supports one bitmap block.
+pub fn build_amiga_volume(num_blocks: u32, block_size: usize, dos_type: u8, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let longs = block_size / 4;
+    let table_size = longs - 56;
+    let is_ffs = dos_type & 1 != 0;
+    let root = num_blocks.div_ceil(2);
+    let bitmap = root + 1;
+
+    let mut bytes = vec![0u8; num_blocks as usize * block_size];
+    bytes[0..4].copy_from_slice(&[b'D', b'O', b'S', dos_type]);
+
+    let set_long = |bytes: &mut Vec<u8>, block: u32, long: usize, value: u32| {
+        let offset = block as usize * block_size + long * 4;
+        bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    };
+    let get_long = |bytes: &Vec<u8>, block: u32, long: usize| {
+        let offset = block as usize * block_size + long * 4;
+        u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    };
+    let set_name = |bytes: &mut Vec<u8>, block: u32, name: &str| {
+        let offset = block as usize * block_size + block_size - 80;
+        bytes[offset] = name.len() as u8;
+        bytes[offset + 1..offset + 1 + name.len()].copy_from_slice(name.as_bytes());
+    };
+    let hash = |name: &str| {
+        let hash = name.bytes().fold(name.len() as u32, |hash, byte| (hash * 13 + byte.to_ascii_uppercase() as u32) & 0x7FF);
+        hash as usize % table_size
+    };
+
+    let mut used = vec![false; num_blocks as usize];
+    used[0] = true;
+    used[1] = true;
+    used[root as usize] = true;
+    used[bitmap as usize] = true;
+    let allocate = |used: &mut Vec<bool>| {
+        let block = (root..num_blocks).chain(2..root).find(|block| ! used[*block as usize]).expect("test volume is full");
+        used[block as usize] = true;
+        block
+    };
+
+    let mut header_blocks: Vec<u32> = vec![root];
+    let mut directories: Vec<(String, u32)> = vec![(String::new(), root)];
+
+    set_long(&mut bytes, root, 0, 2);
+    set_long(&mut bytes, root, 3, table_size as u32);
+    set_long(&mut bytes, root, longs - 50, 0xFFFFFFFF);
+    set_long(&mut bytes, root, longs - 49, bitmap);
+    set_long(&mut bytes, root, longs - 1, 1);
+    set_name(&mut bytes, root, "Workbench");
+
+    for (path, contents) in files {
+        let names: Vec<&str> = path.split('/').collect();
+        let mut parent = root;
+
+        for (depth, name) in names.iter().enumerate() {
+            let sub_path = names[..=depth].join("/");
+            if let Some((_, block)) = directories.iter().find(|(directory, _)| *directory == sub_path) {
+                parent = *block;
+                continue;
+            }
+
+            let block = allocate(&mut used);
+            let is_file = depth == names.len() - 1;
+            header_blocks.push(block);
+            set_long(&mut bytes, block, 0, 2);
+            set_long(&mut bytes, block, 1, block);
+            set_long(&mut bytes, block, longs - 3, parent);
+            set_long(&mut bytes, block, longs - 1, if is_file { -3i32 as u32 } else { 2 });
+            set_name(&mut bytes, block, name);
+
+            let slot = 6 + hash(name);
+            let chain = get_long(&bytes, parent, slot);
+            set_long(&mut bytes, block, longs - 4, chain);
+            set_long(&mut bytes, parent, slot, block);
+
+            if ! is_file {
+                directories.push((sub_path, block));
+                parent = block;
+                continue;
+            }
+
+            let data_per_block = if is_ffs { block_size } else { block_size - 24 };
+            let chunks: Vec<&[u8]> = contents.chunks(data_per_block).collect();
+            let data_blocks: Vec<u32> = chunks.iter().map(|_| allocate(&mut used)).collect();
+
+            for (index, chunk) in chunks.iter().enumerate() {
+                let data_block = data_blocks[index];
+                let start = data_block as usize * block_size;
+                if is_ffs {
+                    bytes[start..start + chunk.len()].copy_from_slice(chunk);
+                } else {
+                    header_blocks.push(data_block);
+                    set_long(&mut bytes, data_block, 0, 8);
+                    set_long(&mut bytes, data_block, 1, block);
+                    set_long(&mut bytes, data_block, 2, index as u32 + 1);
+                    set_long(&mut bytes, data_block, 3, chunk.len() as u32);
+                    set_long(&mut bytes, data_block, 4, data_blocks.get(index + 1).copied().unwrap_or(0));
+                    bytes[start + 24..start + 24 + chunk.len()].copy_from_slice(chunk);
+                }
+            }
+
+            let mut holder = block;
+            for (table_index, table) in data_blocks.chunks(table_size).enumerate() {
+                if table_index > 0 {
+                    let extension = allocate(&mut used);
+                    header_blocks.push(extension);
+                    set_long(&mut bytes, holder, longs - 2, extension);
+                    set_long(&mut bytes, extension, 0, 16);
+                    set_long(&mut bytes, extension, 1, extension);
+                    set_long(&mut bytes, extension, longs - 3, block);
+                    set_long(&mut bytes, extension, longs - 1, -3i32 as u32);
+                    holder = extension;
+                }
+                set_long(&mut bytes, holder, 2, table.len() as u32);
+                for (position, data_block) in table.iter().enumerate() {
+                    set_long(&mut bytes, holder, 6 + table_size - 1 - position, *data_block);
+                }
+            }
+
+            set_long(&mut bytes, block, 4, data_blocks.first().copied().unwrap_or(0));
+            set_long(&mut bytes, block, longs - 47, contents.len() as u32);
+        }
+    }
+
+    for block in 2..num_blocks {
+        if ! used[block as usize] {
+            let index = (block - 2) as usize;
+            let long = 1 + index / 32;
+            let value = get_long(&bytes, bitmap, long) | 1 << (index % 32);
+            set_long(&mut bytes, bitmap, long, value);
+        }
+    }
+
+    let block_sum = |bytes: &Vec<u8>, block: u32| (0..longs).fold(0u32, |sum, long| sum.wrapping_add(get_long(bytes, block, long)));
+    let sum = block_sum(&bytes, bitmap);
+    set_long(&mut bytes, bitmap, 0, sum.wrapping_neg());
+    for block in header_blocks {
+        let sum = block_sum(&bytes, block);
+        set_long(&mut bytes, block, 5, sum.wrapping_neg());
+    }
+
+    bytes
+}
+
+/// Build an RDB hardfile with one partition per cylinder sized volume given as (name, bootable, boot priority, volume).
+/// Cylinder 0 holds the RDB and partition blocks.
+pub fn build_rdb_hardfile(partitions: &[(&str, bool, i32, &[u8])]) -> Vec<u8> {
+    let cylinder_blocks = 64usize;
+    let set_long = |bytes: &mut Vec<u8>, offset: usize, value: u32| bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    let fix_checksum = |bytes: &mut Vec<u8>, start: usize| {
+        let sum = (0..64).fold(0u32, |sum, long| {
+            let offset = start + long * 4;
+            sum.wrapping_add(u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]))
+        });
+        set_long(bytes, start + 8, sum.wrapping_neg());
+    };
+
+    let mut bytes = vec![0u8; cylinder_blocks * 512];
+    bytes[0..4].copy_from_slice(b"RDSK");
+    set_long(&mut bytes, 4, 64);
+    set_long(&mut bytes, 16, 512);
+    set_long(&mut bytes, 28, if partitions.is_empty() { 0xFFFFFFFF } else { 1 });
+
+    let mut low_cylinder = 1;
+    for (index, (name, bootable, boot_priority, volume)) in partitions.iter().enumerate() {
+        let start = (index + 1) * 512;
+        let cylinders = volume.len() / (cylinder_blocks * 512);
+        let next = if index + 1 < partitions.len() { index as u32 + 2 } else { 0xFFFFFFFF };
+
+        bytes[start..start + 4].copy_from_slice(b"PART");
+        set_long(&mut bytes, start + 4, 64);
+        set_long(&mut bytes, start + 16, next);
+        set_long(&mut bytes, start + 20, *bootable as u32);
+        bytes[start + 36] = name.len() as u8;
+        bytes[start + 37..start + 37 + name.len()].copy_from_slice(name.as_bytes());
+
+        let environment = [16, 128, 0, 1, 1, cylinder_blocks as u32, 2, 0, 0, low_cylinder, low_cylinder + cylinders as u32 - 1, 30, 0, 0xFFFFFF, 0x7FFFFFFE, *boot_priority as u32, 0x444F5301];
+        for (long, value) in environment.iter().enumerate() {
+            set_long(&mut bytes, start + 128 + long * 4, *value);
+        }
+
+        fix_checksum(&mut bytes, start);
+        low_cylinder += cylinders as u32;
+    }
+    fix_checksum(&mut bytes, 0);
+
+    for (_, _, _, volume) in partitions {
+        bytes.extend_from_slice(volume);
+    }
+    bytes
+}
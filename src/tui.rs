@@ -0,0 +1,530 @@
+//! A full screen view of a file for working on its option roms: a hex view which can be edited, the disassembly around
+//! the cursor and the roms found in the file, with the patch sites and the checksum byte highlighted.
+
+use std::io;
+use std::ops::Range;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::disassembler::{self, Flow};
+use crate::file_handler::{FileHandler, FileLocation};
+use crate::option_rom::{ChecksumByteLocation, OptionRom, OptionRomError, OPTION_ROM_ENTRY_POINT};
+use crate::option_rom_patcher::{self, PatchState};
+
+const BYTES_PER_ROW: usize = 16;
+
+/// Every option rom in the bytes, each scan starting after the end of the rom before
+pub fn find_option_roms(bytes: &[u8]) -> Vec<usize> {
+    let mut rom_starts: Vec<usize> = Vec::new();
+    let mut scan_start = 0;
+
+    while let Ok(location) = OptionRom::find_option_rom_start_in_bytes(&bytes[scan_start..]) {
+        let rom_start = scan_start + location;
+        rom_starts.push(rom_start);
+        // A rom with a size of zero still has its header skipped
+        scan_start = rom_start + (512 * bytes[rom_start + 2] as usize).max(2);
+    }
+
+    rom_starts
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChecksumStatus {
+    Valid,
+    Invalid { offset: usize, required_checksum_byte: u8 },
+    /// The checksum is wrong and the checksum byte chosen can't be altered
+    Unfixable(String),
+    NoRom(String),
+}
+
+pub struct Editor {
+    pub bytes: Vec<u8>,
+    original_bytes: Vec<u8>,
+    rom_starts: Vec<usize>,
+    selected_rom: usize,
+    pub cursor: usize,
+    /// The high nibble has been typed and the cursor stays on the byte for the low nibble
+    high_nibble_typed: bool,
+    /// Each entry is the bytes changed by one edit and what they were before
+    undo: Vec<Vec<(usize, u8)>>,
+    checksum_byte_location: ChecksumByteLocation,
+    output_path: Option<FileLocation>,
+    force: bool,
+    saved: bool,
+    modified: bool,
+    confirm_quit: bool,
+    message: Option<String>,
+    hex_top_row: usize,
+}
+
+impl Editor {
+    pub fn new(bytes: Vec<u8>, rom_starts: Vec<usize>, selected_rom: usize, checksum_byte_location: ChecksumByteLocation, output_path: Option<FileLocation>, force: bool) -> Editor {
+        let cursor = rom_starts.get(selected_rom).copied().unwrap_or_default();
+        Editor {
+            original_bytes: bytes.clone(),
+            bytes,
+            rom_starts,
+            selected_rom,
+            cursor,
+            high_nibble_typed: false,
+            undo: Vec::new(),
+            checksum_byte_location,
+            output_path,
+            force,
+            saved: false,
+            modified: false,
+            confirm_quit: false,
+            message: None,
+            hex_top_row: 0,
+        }
+    }
+
+    /// The output, once the file has been saved to it
+    pub fn saved_to(&self) -> Option<&FileLocation> {
+        self.output_path.as_ref().filter(|_| self.saved)
+    }
+
+    fn rom_start(&self) -> usize {
+        self.rom_starts[self.selected_rom]
+    }
+
+    /// The selected rom as it is after the edits so far, which fails when the size byte has been edited too big
+    pub fn option_rom(&self) -> Result<OptionRom, OptionRomError> {
+        OptionRom::from(self.bytes.clone(), self.rom_start())
+    }
+
+    fn rom_range(&self) -> Range<usize> {
+        match self.option_rom() {
+            Ok(option_rom) => self.rom_start()..self.rom_start() + option_rom.bytes.len(),
+            Err(_) => self.rom_start()..self.rom_start(),
+        }
+    }
+
+    pub fn checksum_status(&self) -> ChecksumStatus {
+        let option_rom = match self.option_rom() {
+            Ok(option_rom) => option_rom,
+            Err(e) => return ChecksumStatus::NoRom(format!("{}", e)),
+        };
+
+        match option_rom.validate_checksum() {
+            Ok(_) => ChecksumStatus::Valid,
+            Err(OptionRomError::OptionRomChecksumInvalid(option_rom)) => match option_rom.checksum_byte_offset(self.checksum_byte_location) {
+                Ok(offset) => ChecksumStatus::Invalid { offset, required_checksum_byte: option_rom.required_checksum_byte_at(offset) },
+                Err(e) => ChecksumStatus::Unfixable(format!("{}", e)),
+            },
+            Err(e) => ChecksumStatus::NoRom(format!("{}", e)),
+        }
+    }
+
+    /// The file offsets of the patch sites and of the checksum byte in the selected rom
+    fn highlights(&self) -> (Vec<Range<usize>>, Option<usize>) {
+        let option_rom = match self.option_rom() {
+            Ok(option_rom) => option_rom,
+            Err(_) => return (Vec::new(), None),
+        };
+        let rom_start = self.rom_start();
+
+        let patch_sites = option_rom_patcher::patch_sites(&option_rom).into_iter()
+            .map(|site| rom_start + site.start..rom_start + site.end)
+            .collect();
+        let checksum_byte = option_rom.checksum_byte_offset(self.checksum_byte_location).ok().map(|offset| rom_start + offset);
+        (patch_sites, checksum_byte)
+    }
+
+    /// Change bytes as one edit which can be undone
+    fn change(&mut self, changes: Vec<(usize, u8)>) {
+        let previous: Vec<(usize, u8)> = changes.iter().map(|(offset, _)| (*offset, self.bytes[*offset])).collect();
+        for (offset, value) in changes {
+            self.bytes[offset] = value;
+        }
+        self.undo.push(previous);
+        self.modified = true;
+    }
+
+    pub fn type_hex_digit(&mut self, digit: u8) {
+        let byte = self.bytes[self.cursor];
+        if self.high_nibble_typed {
+            // Part of the same edit as the high nibble, so one undo puts back the whole byte
+            self.bytes[self.cursor] = (byte & 0xF0) | digit;
+            self.high_nibble_typed = false;
+            self.move_cursor(1);
+        } else {
+            self.change(vec![(self.cursor, (digit << 4) | (byte & 0x0F))]);
+            self.high_nibble_typed = true;
+        }
+    }
+
+    pub fn undo(&mut self) {
+        self.high_nibble_typed = false;
+        match self.undo.pop() {
+            Some(previous) => {
+                for (offset, value) in previous {
+                    self.bytes[offset] = value;
+                }
+                self.modified = true;
+            },
+            None => self.message = Some("Nothing to undo".into()),
+        }
+    }
+
+    pub fn apply_patch(&mut self) {
+        let option_rom = match self.option_rom() {
+            Ok(option_rom) => option_rom,
+            Err(e) => {
+                self.message = Some(format!("{}", e));
+                return;
+            },
+        };
+        if option_rom_patcher::detect_patch_state(&option_rom) == PatchState::Patched {
+            self.message = Some("The rom is already patched".into());
+            return;
+        }
+
+        match option_rom_patcher::build_patched_rom(&option_rom, self.checksum_byte_location) {
+            Ok(patched_rom) => {
+                let rom_start = self.rom_start();
+                let changes = patched_rom.bytes.iter().enumerate()
+                    .filter(|(offset, byte)| option_rom.bytes[*offset] != **byte)
+                    .map(|(offset, byte)| (rom_start + offset, *byte))
+                    .collect();
+                self.change(changes);
+                self.message = Some("Patched".into());
+            },
+            Err(e) => self.message = Some(format!("Failed patching ROM with error: {}", e)),
+        }
+    }
+
+    pub fn fix_checksum(&mut self) {
+        match self.checksum_status() {
+            ChecksumStatus::Invalid { offset, required_checksum_byte } => self.change(vec![(self.rom_start() + offset, required_checksum_byte)]),
+            ChecksumStatus::Valid => self.message = Some("The checksum is already valid".into()),
+            ChecksumStatus::Unfixable(message) | ChecksumStatus::NoRom(message) => self.message = Some(message),
+        }
+    }
+
+    /// Write the whole file to the output, an existing output is only overwritten with --force or once this has
+    /// written it
+    pub fn save(&mut self) {
+        let output_path = match &self.output_path {
+            Some(output_path) => output_path,
+            None => {
+                self.message = Some("There is no output to save to, start with --output-path".into());
+                return;
+            },
+        };
+        if output_path.exists() && ! self.force && ! self.saved {
+            self.message = Some(format!("{} exists and the force option was not specified", output_path));
+            return;
+        }
+
+        match FileHandler::write_output(output_path, &self.bytes) {
+            Ok(..) => {
+                self.message = Some(format!("Saved to {}", output_path));
+                self.saved = true;
+                self.modified = false;
+            },
+            Err(e) => self.message = Some(format!("{}", e)),
+        }
+    }
+
+    fn move_cursor(&mut self, distance: isize) {
+        self.high_nibble_typed = false;
+        self.cursor = self.cursor.saturating_add_signed(distance).min(self.bytes.len() - 1);
+    }
+
+    fn select_next_rom(&mut self) {
+        self.selected_rom = (self.selected_rom + 1) % self.rom_starts.len();
+        self.cursor = self.rom_start();
+        self.high_nibble_typed = false;
+    }
+
+    /// Returns false when the editor should close
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        self.message = None;
+        let quitting = std::mem::take(&mut self.confirm_quit);
+
+        match key.code {
+            KeyCode::Left => self.move_cursor(-1),
+            KeyCode::Right => self.move_cursor(1),
+            KeyCode::Up => self.move_cursor(-(BYTES_PER_ROW as isize)),
+            KeyCode::Down => self.move_cursor(BYTES_PER_ROW as isize),
+            KeyCode::PageUp => self.move_cursor(-16 * BYTES_PER_ROW as isize),
+            KeyCode::PageDown => self.move_cursor(16 * BYTES_PER_ROW as isize),
+            KeyCode::Home => self.move_cursor(-(self.cursor as isize)),
+            KeyCode::End => self.move_cursor(self.bytes.len() as isize),
+            KeyCode::Tab => self.select_next_rom(),
+            KeyCode::Char(character) if character.is_ascii_hexdigit() => {
+                // is_ascii_hexdigit means to_digit can't fail
+                self.type_hex_digit(character.to_digit(16).unwrap_or_default() as u8)
+            },
+            KeyCode::Char('u') => self.undo(),
+            KeyCode::Char('p') => self.apply_patch(),
+            KeyCode::Char('k') => self.fix_checksum(),
+            KeyCode::Char('s') => self.save(),
+            KeyCode::Char('q') | KeyCode::Esc => {
+                if ! self.modified || quitting {
+                    return false;
+                }
+                self.confirm_quit = true;
+                self.message = Some("There are unsaved changes, press q again to quit without saving".into());
+            },
+            _ => {},
+        }
+        true
+    }
+
+    pub fn render(&mut self, frame: &mut Frame) {
+        let [main_area, status_area] = Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [roms_area, hex_area, disassembly_area] = Layout::horizontal([
+            Constraint::Length(24),
+            Constraint::Length(10 + BYTES_PER_ROW as u16 * 4),
+            Constraint::Min(20),
+        ]).areas(main_area);
+
+        self.render_roms(frame, roms_area);
+        self.render_hex(frame, hex_area);
+        self.render_disassembly(frame, disassembly_area);
+        self.render_status(frame, status_area);
+    }
+
+    fn render_roms(&self, frame: &mut Frame, area: Rect) {
+        let lines: Vec<Line> = self.rom_starts.iter().enumerate()
+            .map(|(index, rom_start)| {
+                let size = self.bytes[rom_start + 2] as usize * 512;
+                let style = if index == self.selected_rom { Style::new().add_modifier(Modifier::REVERSED) } else { Style::new() };
+                Line::styled(format!("0x{:06X}  0x{:04X}", rom_start, size), style)
+            })
+            .collect();
+
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Option roms (Tab)")), area);
+    }
+
+    fn render_hex(&mut self, frame: &mut Frame, area: Rect) {
+        let visible_rows = (area.height as usize).saturating_sub(2).max(1);
+        let cursor_row = self.cursor / BYTES_PER_ROW;
+        if cursor_row < self.hex_top_row {
+            self.hex_top_row = cursor_row;
+        } else if cursor_row >= self.hex_top_row + visible_rows {
+            self.hex_top_row = cursor_row + 1 - visible_rows;
+        }
+
+        let (patch_sites, checksum_byte) = self.highlights();
+        let rom_range = self.rom_range();
+
+        let byte_style = |offset: usize| -> Style {
+            let mut style = Style::new();
+            if ! rom_range.contains(&offset) {
+                style = style.fg(Color::DarkGray);
+            }
+            if self.bytes[offset] != self.original_bytes[offset] {
+                style = style.fg(Color::Red);
+            }
+            if patch_sites.iter().any(|site| site.contains(&offset)) {
+                style = style.bg(Color::Yellow).fg(Color::Black);
+            }
+            if checksum_byte == Some(offset) {
+                style = style.bg(Color::Magenta).fg(Color::Black);
+            }
+            if offset == self.cursor {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            style
+        };
+
+        let lines: Vec<Line> = (self.hex_top_row..self.hex_top_row + visible_rows)
+            .map(|row| row * BYTES_PER_ROW)
+            .take_while(|row_start| *row_start < self.bytes.len())
+            .map(|row_start| {
+                let row_end = (row_start + BYTES_PER_ROW).min(self.bytes.len());
+                let mut spans: Vec<Span> = vec![Span::raw(format!("{:06X}  ", row_start))];
+                for offset in row_start..row_end {
+                    spans.push(Span::styled(format!("{:02X}", self.bytes[offset]), byte_style(offset)));
+                    spans.push(Span::raw(" "));
+                }
+                spans.push(Span::raw(" ".repeat((row_start + BYTES_PER_ROW - row_end) * 3 + 1)));
+                for offset in row_start..row_end {
+                    let character = match self.bytes[offset] {
+                        byte @ 0x20..=0x7E => byte as char,
+                        _ => '.',
+                    };
+                    spans.push(Span::styled(character.to_string(), byte_style(offset)));
+                }
+                Line::from(spans)
+            })
+            .collect();
+
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Hex")), area);
+    }
+
+    fn render_disassembly(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("Disassembly (rom offsets)");
+        let option_rom = match self.option_rom() {
+            Ok(option_rom) => option_rom,
+            Err(e) => return frame.render_widget(Paragraph::new(format!("{}", e)).block(block), area),
+        };
+
+        let instructions = disassembler::trace_reachable(&option_rom.bytes, &[OPTION_ROM_ENTRY_POINT]);
+        let cursor_in_rom = self.cursor.checked_sub(self.rom_start());
+        let visible_rows = (area.height as usize).saturating_sub(2);
+
+        let cursor_index = cursor_in_rom
+            .map(|offset| instructions.iter().position(|instruction| instruction.end() > offset).unwrap_or(instructions.len()))
+            .unwrap_or_default();
+        let first = cursor_index.saturating_sub(visible_rows / 3);
+
+        let lines: Vec<Line> = instructions.iter().skip(first).take(visible_rows)
+            .map(|instruction| {
+                let bytes: Vec<String> = option_rom.bytes[instruction.offset..instruction.end()].iter().map(|byte| format!("{:02X}", byte)).collect();
+                let flow = match instruction.flow {
+                    Flow::Sequential => String::new(),
+                    Flow::Jump(target) => format!("jmp {:04X}", target),
+                    Flow::Branch(target) => format!("branch {:04X}", target),
+                    Flow::Call(target) => format!("call {:04X}", target),
+                    Flow::Return => "ret".into(),
+                    Flow::Stop => "stop".into(),
+                };
+                let style = match cursor_in_rom {
+                    Some(offset) if (instruction.offset..instruction.end()).contains(&offset) => Style::new().add_modifier(Modifier::REVERSED),
+                    _ => Style::new(),
+                };
+                Line::styled(format!("{:04X}  {:<18} {}", instruction.offset, bytes.join(" "), flow), style)
+            })
+            .collect();
+
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn render_status(&self, frame: &mut Frame, area: Rect) {
+        let (checksum_text, checksum_color) = match self.checksum_status() {
+            ChecksumStatus::Valid => ("Checksum valid".to_string(), Color::Green),
+            ChecksumStatus::Invalid { offset, required_checksum_byte } => (format!("Checksum invalid, needs {:02X} at 0x{:X}", required_checksum_byte, offset), Color::Red),
+            ChecksumStatus::Unfixable(message) => (format!("Checksum invalid, {}", message), Color::Red),
+            ChecksumStatus::NoRom(message) => (message, Color::Red),
+        };
+        let patch_state = match self.option_rom() {
+            Ok(option_rom) => format!("{}", option_rom_patcher::detect_patch_state(&option_rom)),
+            Err(_) => "-".into(),
+        };
+        let cursor = match self.cursor.checked_sub(self.rom_start()) {
+            Some(offset_in_rom) => format!("0x{:06X} (rom 0x{:04X})", self.cursor, offset_in_rom),
+            None => format!("0x{:06X}", self.cursor),
+        };
+
+        let mut spans = vec![
+            Span::styled(checksum_text, Style::new().fg(checksum_color)),
+            Span::raw(format!("  |  Patch: {}  |  {}", patch_state, cursor)),
+        ];
+        if self.modified {
+            spans.push(Span::raw("  |  modified"));
+        }
+        let second_line = match &self.message {
+            Some(message) => Line::styled(message.clone(), Style::new().add_modifier(Modifier::BOLD)),
+            None => Line::raw("0-9 a-f edit  u undo  p patch  k fix checksum  s save  q quit"),
+        };
+
+        frame.render_widget(Paragraph::new(vec![Line::from(spans), second_line]).block(Block::bordered()), area);
+    }
+}
+
+pub fn run(editor: &mut Editor) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, editor);
+    ratatui::restore();
+    result
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, editor: &mut Editor) -> io::Result<()> {
+    loop {
+        terminal.draw(|frame| editor.render(frame))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && ! editor.handle_key(key) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_patchable_rom, create_temp_dir, load_fixture};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn editor(bytes: Vec<u8>, output_path: Option<FileLocation>) -> Editor {
+        let rom_starts = find_option_roms(&bytes);
+        Editor::new(bytes, rom_starts, 0, ChecksumByteLocation::FinalByte, output_path, false)
+    }
+
+    #[test]
+    fn test_find_option_roms() -> Result<(), String> {
+        let mut bytes = vec![0u8; 0x100];
+        bytes.extend(build_patchable_rom());
+        bytes.extend(load_fixture("8k-option-rom")?);
+        assert_eq!(find_option_roms(&bytes), vec![0x100, 0x300]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_and_undo() -> Result<(), String> {
+        let mut editor = editor(load_fixture("pc.boot.valid")?, None);
+        assert_eq!(editor.checksum_status(), ChecksumStatus::Valid);
+
+        let original_byte = editor.bytes[0x1000];
+        editor.cursor = 0x1000;
+        editor.type_hex_digit(0xA);
+        editor.type_hex_digit(0x5);
+        assert_eq!(editor.bytes[0x1000], 0xA5);
+        assert_eq!(editor.cursor, 0x1001);
+        assert!(matches!(editor.checksum_status(), ChecksumStatus::Invalid { offset: 0x1FFF, .. }));
+
+        editor.undo();
+        assert_eq!(editor.bytes[0x1000], original_byte);
+        assert_eq!(editor.checksum_status(), ChecksumStatus::Valid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_and_save() -> Result<(), String> {
+        let output_path = create_temp_dir()?.into_path().join("pc.boot.new");
+        let mut editor = editor(build_patchable_rom(), Some(output_path.clone().into()));
+        editor.fix_checksum();
+        editor.apply_patch();
+
+        let patched_rom = editor.option_rom().map_err(|e| format!("{}", e))?;
+        assert_eq!(option_rom_patcher::detect_patch_state(&patched_rom), PatchState::Patched);
+        assert_eq!(editor.checksum_status(), ChecksumStatus::Valid);
+
+        editor.save();
+        assert_eq!(std::fs::read(&output_path).map_err(|e| format!("{}", e))?, editor.bytes);
+
+        editor.undo();
+        let unpatched_rom = editor.option_rom().map_err(|e| format!("{}", e))?;
+        assert_eq!(option_rom_patcher::detect_patch_state(&unpatched_rom), PatchState::Unpatched);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_highlights_patch_sites() -> Result<(), String> {
+        let mut editor = editor(build_patchable_rom(), None);
+        let mut terminal = Terminal::new(TestBackend::new(140, 20)).map_err(|e| format!("{}", e))?;
+        terminal.draw(|frame| editor.render(frame)).map_err(|e| format!("{}", e))?;
+
+        let buffer = terminal.backend().buffer();
+        let screen: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("0x000000  0x0200"));
+        assert!(screen.contains("Checksum invalid, needs"));
+        assert!(screen.contains("Patch: unpatched"));
+
+        // The jump which is patched, in the first row of the hex view after the 8 characters of the offset
+        let jump_cell = &buffer[(24 + 1 + 8 + 12 * 3, 1)];
+        assert_eq!(jump_cell.symbol(), "7");
+        assert_eq!(jump_cell.bg, Color::Yellow);
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Checking the output by reading it back after it is written, so a write which didn't do what was intended, such as a
+//! disk image which didn't take the whole file or a rom written somewhere it wasn't expected, fails rather than being
+//! reported as a success.
+
+use std::fmt;
+
+use crate::file_handler::FileHandlerError;
+use crate::option_rom::{OptionRom, OptionRomError, OPTION_ROM_HEADER};
+use crate::option_rom_patcher::PatchState;
+use crate::rom_patch::RomPatch;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    CouldntReadBack(FileHandlerError),
+    LengthChanged { expected: usize, found: usize },
+    NoRomHeader(usize),
+    RomSizeChanged { expected: usize, found: usize },
+    ChecksumNotZero(u8),
+    PatchStateChanged { patch: String, expected: PatchState, found: PatchState },
+    /// The offset in the rom of the first byte which isn't what was written
+    RomChanged(usize),
+    /// The offset in the file of the first byte outside the rom which doesn't match the source
+    OutsideRomChanged(usize),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::CouldntReadBack(e) => write!(f, "Couldn't read the output back to verify it: {}", e),
+            VerifyError::LengthChanged { expected, found } => write!(f, "The output read back is {} bytes but {} were written", found, expected),
+            VerifyError::NoRomHeader(offset) => write!(f, "The output read back has no option rom header at 0x{:X}", offset),
+            VerifyError::RomSizeChanged { expected, found } => write!(f, "The rom read back is {} bytes but a {} byte rom was written", found, expected),
+            VerifyError::ChecksumNotZero(sum) => write!(f, "The checksum of the rom read back is {:02X} rather than 00", sum),
+            VerifyError::PatchStateChanged { patch, expected, found } => write!(f, "{} is {} in the rom read back but should be {}", patch, found, expected),
+            VerifyError::RomChanged(offset) => write!(f, "The rom read back differs from what was written at offset 0x{:X} in the rom", offset),
+            VerifyError::OutsideRomChanged(offset) => write!(f, "The output read back differs from the source at 0x{:X}, outside the rom", offset),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VerifyError::CouldntReadBack(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn first_difference(left: &[u8], right: &[u8]) -> Option<usize> {
+    left.iter().zip(right).position(|(left, right)| left != right)
+}
+
+/// Check the output read back is the length of the expected bytes and holds the rom at rom_offset, with the header,
+/// size, checksum and each patch in the state it's in in the rom written. Outside the rom it must hold the source the
+/// rom was written into, with zeros where the write policy padded the gap up to the rom, or the expected bytes when
+/// there's no source as only the rom was written
+pub fn verify_output(written: &[u8], expected: &[u8], source: Option<&[u8]>, rom_offset: usize, option_rom: &OptionRom, patches: &[&dyn RomPatch]) -> Result<(), VerifyError> {
+    if written.len() != expected.len() {
+        return Err(VerifyError::LengthChanged { expected: expected.len(), found: written.len() });
+    }
+
+    if written.get(rom_offset..rom_offset + OPTION_ROM_HEADER.len()) != Some(&OPTION_ROM_HEADER[..]) {
+        return Err(VerifyError::NoRomHeader(rom_offset));
+    }
+    let found_size = written.get(rom_offset + OPTION_ROM_HEADER.len()).map(|size| *size as usize * 512).unwrap_or_default();
+    if found_size != option_rom.rom_size_in_bytes {
+        return Err(VerifyError::RomSizeChanged { expected: option_rom.rom_size_in_bytes, found: found_size });
+    }
+    let written_rom = match OptionRom::from(written.to_vec(), rom_offset) {
+        Ok(written_rom) => written_rom,
+        Err(_) => return Err(VerifyError::LengthChanged { expected: expected.len(), found: written.len() }),
+    };
+
+    let written_rom = match written_rom.validate_checksum() {
+        Ok(written_rom) => written_rom,
+        Err(OptionRomError::OptionRomChecksumInvalid(bad_rom)) => {
+            let sum = bad_rom.bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            return Err(VerifyError::ChecksumNotZero(sum));
+        },
+        Err(_) => return Err(VerifyError::NoRomHeader(rom_offset)),
+    };
+
+    // A later patch can rewrite the site of one reverted earlier, so the state expected is the one in the rom written
+    for patch in patches {
+        let expected_state = patch.detect(option_rom);
+        let found = patch.detect(&written_rom);
+        if found != expected_state {
+            return Err(VerifyError::PatchStateChanged { patch: patch.name().to_string(), expected: expected_state, found });
+        }
+    }
+
+    if let Some(offset) = first_difference(&written_rom.bytes, &option_rom.bytes) {
+        return Err(VerifyError::RomChanged(offset));
+    }
+
+    let rom_end = rom_offset + option_rom.rom_size_in_bytes;
+    let (before_rom, after_rom) = match source {
+        Some(source) => {
+            let mut before_rom = source[..rom_offset.min(source.len())].to_vec();
+            before_rom.resize(rom_offset, 0);
+            (before_rom, source.get(rom_end..).unwrap_or_default().to_vec())
+        },
+        None => (expected[..rom_offset].to_vec(), expected[rom_end..].to_vec()),
+    };
+    if let Some(offset) = first_difference(&written[..rom_offset], &before_rom) {
+        return Err(VerifyError::OutsideRomChanged(offset));
+    }
+    if written.len() - rom_end != after_rom.len() {
+        return Err(VerifyError::LengthChanged { expected: rom_end + after_rom.len(), found: written.len() });
+    }
+    if let Some(offset) = first_difference(&written[rom_end..], &after_rom) {
+        return Err(VerifyError::OutsideRomChanged(rom_end + offset));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_handler::{FileHandler, WritePolicy};
+    use crate::option_rom::ChecksumByteLocation;
+    use crate::option_rom_patcher::SkipHddReadyCheck;
+    use crate::rom_patch;
+    use crate::shift_bypass_patch::ShiftKeyBypass;
+    use crate::test_helpers::build_patchable_rom;
+
+    /// A source with a rom in the middle of it, the rom patched and what the file written should hold
+    fn written_file() -> (Vec<u8>, OptionRom, Vec<u8>) {
+        let mut option_rom = OptionRom::from(build_patchable_rom(), 0).unwrap();
+        option_rom.correct_checksum_in_final_byte();
+        let patched_rom = rom_patch::apply(&SkipHddReadyCheck, &option_rom, ChecksumByteLocation::FinalByte).unwrap();
+
+        let mut source = vec![0x11; 0x100];
+        source.extend_from_slice(&option_rom.bytes);
+        source.extend_from_slice(&[0x22; 0x100]);
+        let file = FileHandler::place_rom_in_bytes(&source, patched_rom.clone(), 0x100, WritePolicy::Strict).unwrap();
+        (source, patched_rom, file)
+    }
+
+    fn verify(written: &[u8], expected: &[u8], source: &[u8], option_rom: &OptionRom) -> Result<(), String> {
+        verify_output(written, expected, Some(source), 0x100, option_rom, &[&SkipHddReadyCheck]).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_verify_output() -> Result<(), String> {
+        let (source, option_rom, expected) = written_file();
+        verify(&expected, &expected, &source, &option_rom)?;
+
+        let mut written = expected.clone();
+        written[0x80] = 0x12;
+        assert_eq!(verify(&written, &expected, &source, &option_rom), Err("The output read back differs from the source at 0x80, outside the rom".into()));
+        // The bytes outside the rom are checked against the source, not what the writer meant to write
+        assert_eq!(verify(&written, &written, &source, &option_rom), Err("The output read back differs from the source at 0x80, outside the rom".into()));
+
+        let mut written = expected.clone();
+        written[0x100 + 0x20] = written[0x100 + 0x20].wrapping_add(1);
+        assert_eq!(verify(&written, &expected, &source, &option_rom), Err("The checksum of the rom read back is 01 rather than 00".into()));
+
+        assert_eq!(verify(&expected[..0x200], &expected, &source, &option_rom), Err("The output read back is 512 bytes but 1024 were written".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_padded_output() -> Result<(), String> {
+        let (source, option_rom, _) = written_file();
+        // The rom written past the end of a shorter source, with the gap padded
+        let source = &source[..0x80];
+        let expected = FileHandler::place_rom_in_bytes(source, option_rom.clone(), 0x100, WritePolicy::AllowPad).map_err(|e| e.to_string())?;
+        verify(&expected, &expected, source, &option_rom)?;
+
+        let mut written = expected.clone();
+        written[0xC0] = 0xFF;
+        assert_eq!(verify(&written, &written, source, &option_rom), Err("The output read back differs from the source at 0xC0, outside the rom".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_output_patch_state() -> Result<(), String> {
+        let (source, option_rom, expected) = written_file();
+
+        // The original JC with the checksum still adding up
+        let mut written = expected.clone();
+        written[0x100 + 12..0x100 + 14].copy_from_slice(&[0x72, 0x06]);
+        written[0x100 + 0x1FF] = written[0x100 + 0x1FF].wrapping_add(0xEB - 0x72).wrapping_add(expected[0x100 + 13].wrapping_sub(0x06));
+        assert_eq!(verify(&written, &expected, &source, &option_rom), Err("skip-hdd-ready-check is unpatched in the rom read back but should be patched".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_reverted_patch_site_reused() -> Result<(), String> {
+        let (source, patched_rom, _) = written_file();
+        // Switching to the shift key bypass, which rewrites the site of the patch reverted first
+        let reverted_rom = rom_patch::revert(&SkipHddReadyCheck, &patched_rom, ChecksumByteLocation::FinalByte).map_err(|e| e.to_string())?;
+        let shift_key_bypass = ShiftKeyBypass::new(true);
+        let option_rom = rom_patch::apply(&shift_key_bypass, &reverted_rom, ChecksumByteLocation::FinalByte).map_err(|e| e.to_string())?;
+
+        let expected = FileHandler::place_rom_in_bytes(&source, option_rom.clone(), 0x100, WritePolicy::Strict).map_err(|e| e.to_string())?;
+        verify_output(&expected, &expected, Some(&source), 0x100, &option_rom, &[&SkipHddReadyCheck, &shift_key_bypass]).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,297 @@
+//! Identification of XTIDE Universal BIOS images.
+//!
+//! Offsets follow the ROMVARS structure at the start of the XTIDE Universal BIOS v2.0.0 beta 3 (r6xx) images, see
+//! Inc/RomVars.inc in the XTIDE Universal BIOS source.
+
+use std::fmt;
+
+use crate::option_rom::{OptionRom, OPTION_ROM_HEADER};
+use crate::option_rom_patcher::PatchState;
+
+pub const ROMVARS_SIGNATURE_OFFSET: usize = 0x08;
+pub const ROMVARS_TITLE_OFFSET: usize = 0x10;
+pub const ROMVARS_VERSION_OFFSET: usize = 0x2F;
+pub const ROMVARS_FLAGS_OFFSET: usize = 0x48;
+
+pub const ROMVARS_SIGNATURE_PREFIX: &[u8] = b"XTIDE";
+const ROMVARS_SIGNATURE_LENGTH: usize = 8;
+const ROMVARS_TITLE_LENGTH: usize = 31;
+const ROMVARS_VERSION_LENGTH: usize = 25;
+
+pub const FLG_ROMVARS_LATE_INIT: u16 = 1 << 1;
+pub const FLG_ROMVARS_MODULE_VERY_LATE_INIT: u16 = 1 << 13;
+
+/// The earliest revision the Bridgeboard patch is known to work with
+pub const MINIMUM_WORKING_REVISION: u32 = 625;
+
+/// Option roms in flash dumps start on a 512 byte boundary
+const FLASH_DUMP_SCAN_ALIGNMENT: usize = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XtideFlavour {
+    Xt,
+    XtPlus,
+    At,
+    I386,
+    Unknown,
+}
+
+impl fmt::Display for XtideFlavour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XtideFlavour::Xt => write!(f, "XT"),
+            XtideFlavour::XtPlus => write!(f, "XT+"),
+            XtideFlavour::At => write!(f, "AT"),
+            XtideFlavour::I386 => write!(f, "386"),
+            XtideFlavour::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XtideInitMode {
+    Normal,
+    Late,
+    VeryLate,
+}
+
+impl fmt::Display for XtideInitMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XtideInitMode::Normal => write!(f, "normal"),
+            XtideInitMode::Late => write!(f, "late"),
+            XtideInitMode::VeryLate => write!(f, "very late"),
+        }
+    }
+}
+
+/// The Bridgeboard models, which matters for the instructions the XTIDE build can use
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Bridgeboard {
+    /// 8088 CPU
+    A2088,
+    /// 80286 CPU
+    A2286,
+    /// 80386SX CPU
+    A2386,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct XtideBuild {
+    /// Where the XTIDE rom starts in the bytes it was found in
+    pub rom_start_location: usize,
+    pub rom_size_in_bytes: usize,
+    pub signature: String,
+    pub title: String,
+    pub version: String,
+    pub revision: Option<u32>,
+    pub flavour: XtideFlavour,
+    pub init_mode: XtideInitMode,
+}
+
+impl XtideBuild {
+    /// Identify an XTIDE Universal BIOS option rom, returning None if the rom isn't one
+    pub fn identify(option_rom: &OptionRom, rom_start_location: usize) -> Option<XtideBuild> {
+        let bytes = &option_rom.bytes;
+
+        if bytes.len() < ROMVARS_FLAGS_OFFSET + 2 || ! bytes[ROMVARS_SIGNATURE_OFFSET..].starts_with(ROMVARS_SIGNATURE_PREFIX) {
+            return None;
+        }
+
+        let signature = read_string(bytes, ROMVARS_SIGNATURE_OFFSET, ROMVARS_SIGNATURE_LENGTH);
+        let title = read_string(bytes, ROMVARS_TITLE_OFFSET, ROMVARS_TITLE_LENGTH);
+        let version = read_string(bytes, ROMVARS_VERSION_OFFSET, ROMVARS_VERSION_LENGTH);
+        let flags = u16::from_le_bytes([bytes[ROMVARS_FLAGS_OFFSET], bytes[ROMVARS_FLAGS_OFFSET + 1]]);
+
+        let init_mode = if flags & FLG_ROMVARS_MODULE_VERY_LATE_INIT != 0 {
+            XtideInitMode::VeryLate
+        } else if flags & FLG_ROMVARS_LATE_INIT != 0 {
+            XtideInitMode::Late
+        } else {
+            XtideInitMode::Normal
+        };
+
+        Some(XtideBuild {
+            rom_start_location,
+            rom_size_in_bytes: option_rom.rom_size_in_bytes,
+            revision: parse_revision(&version),
+            flavour: parse_flavour(&title),
+            signature,
+            title,
+            version,
+            init_mode,
+        })
+    }
+
+    /// Find every XTIDE Universal BIOS in the bytes, which may be a single rom image or a dump of a whole flash chip
+    pub fn find_all_in_bytes(bytes: &[u8]) -> Vec<XtideBuild> {
+        let mut builds: Vec<XtideBuild> = Vec::new();
+        let mut offset = 0;
+
+        while offset + OPTION_ROM_HEADER.len() < bytes.len() {
+            if bytes[offset..].starts_with(&OPTION_ROM_HEADER) {
+                if let Ok(option_rom) = OptionRom::from(bytes.to_vec(), offset) {
+                    if let Some(build) = XtideBuild::identify(&option_rom, offset) {
+                        offset += build.rom_size_in_bytes.max(FLASH_DUMP_SCAN_ALIGNMENT);
+                        builds.push(build);
+                        continue;
+                    }
+                }
+            }
+            offset += FLASH_DUMP_SCAN_ALIGNMENT;
+        }
+
+        builds
+    }
+}
+
+impl XtideBuild {
+    /// Reasons this build is known not to work with the Bridgeboard and the pc.boot in its patch state
+    pub fn compatibility_warnings(&self, bridgeboard: Bridgeboard, pc_boot_patch_state: PatchState) -> Vec<String> {
+        let mut warnings: Vec<String> = Vec::new();
+
+        match self.revision {
+            Some(revision) if revision >= MINIMUM_WORKING_REVISION => {},
+            Some(revision) => warnings.push(format!("Revision r{} is older than r{}, the earliest known to work with the Bridgeboard", revision, MINIMUM_WORKING_REVISION)),
+            None => warnings.push(format!("The revision couldn't be determined, r{} or later is needed", MINIMUM_WORKING_REVISION)),
+        }
+
+        if self.init_mode != XtideInitMode::VeryLate {
+            warnings.push(format!("The BIOS uses {} initialisation, it must be built with VeryLateInit to initialise after the Bridgeboard", self.init_mode));
+        }
+
+        let runs_on_cpu = match (self.flavour, bridgeboard) {
+            (XtideFlavour::Unknown, _) => {
+                warnings.push("The build flavour (XT, XT+, AT or 386) couldn't be determined from the title".into());
+                true
+            },
+            (XtideFlavour::XtPlus | XtideFlavour::At | XtideFlavour::I386, Bridgeboard::A2088) => false,
+            (XtideFlavour::I386, Bridgeboard::A2286) => false,
+            _ => true,
+        };
+
+        if ! runs_on_cpu {
+            warnings.push(format!("The {} build uses instructions the {:?} CPU doesn't support", self.flavour, bridgeboard));
+        }
+
+        match pc_boot_patch_state {
+            PatchState::Patched => {},
+            PatchState::Unpatched => warnings.push("pc.boot isn't patched, the Bridgeboard will hook INT13 and the XTIDE disk won't be usable".into()),
+            PatchState::Unknown => warnings.push("Couldn't tell whether pc.boot is patched".into()),
+        }
+
+        warnings
+    }
+}
+
+impl fmt::Display for XtideBuild {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let revision = match self.revision {
+            Some(revision) => format!("r{}", revision),
+            None => "unknown".into(),
+        };
+
+        writeln!(f, "XTIDE Universal BIOS at byte 0x{:X} (0x{:X} bytes)", self.rom_start_location, self.rom_size_in_bytes)?;
+        writeln!(f, "  Title: {}", self.title)?;
+        writeln!(f, "  Version: {}", self.version)?;
+        writeln!(f, "  Revision: {}", revision)?;
+        writeln!(f, "  Flavour: {}", self.flavour)?;
+        write!(f, "  Initialisation: {}", self.init_mode)
+    }
+}
+
+/// Read a NUL terminated string from a fixed length field, replacing anything outside printable ASCII
+fn read_string(bytes: &[u8], offset: usize, max_length: usize) -> String {
+    bytes[offset..(offset + max_length).min(bytes.len())].iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '?' })
+        .collect()
+}
+
+/// The flavour is in brackets in the title, e.g. "-=XTIDE Universal BIOS (XT+)=-"
+fn parse_flavour(title: &str) -> XtideFlavour {
+    let flavour = title.split_once('(').and_then(|(_, rest)| rest.split_once(')')).map(|(flavour, _)| flavour);
+
+    match flavour {
+        Some("XT") => XtideFlavour::Xt,
+        Some("XT+") => XtideFlavour::XtPlus,
+        Some("AT") => XtideFlavour::At,
+        Some("386") => XtideFlavour::I386,
+        _ => XtideFlavour::Unknown,
+    }
+}
+
+/// The revision is an "r" followed by digits somewhere in the version string, e.g. "v2.0.0?3+ r625"
+fn parse_revision(version: &str) -> Option<u32> {
+    version.split(|c: char| ! c.is_ascii_alphanumeric())
+        .filter_map(|word| word.strip_prefix('r'))
+        .find_map(|digits| digits.parse::<u32>().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_xtide_rom, load_option_rom_fixture};
+
+    #[test]
+    fn test_identify_xtide_rom() {
+        let bytes = build_xtide_rom("-=XTIDE Universal BIOS (XT)=-", "v2.0.0B3+ r625", FLG_ROMVARS_MODULE_VERY_LATE_INIT);
+        let option_rom = OptionRom::from(bytes, 0).unwrap();
+
+        let build = XtideBuild::identify(&option_rom, 0).expect("Expected the rom to be identified as XTIDE");
+
+        assert_eq!(build.signature, "XTIDE220");
+        assert_eq!(build.revision, Some(625));
+        assert_eq!(build.flavour, XtideFlavour::Xt);
+        assert_eq!(build.init_mode, XtideInitMode::VeryLate);
+    }
+
+    #[test]
+    fn test_identify_non_xtide_rom() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        assert_eq!(XtideBuild::identify(&option_rom, 0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_flavour_and_revision() {
+        assert_eq!(parse_flavour("-=XTIDE Universal BIOS (XT+)=-"), XtideFlavour::XtPlus);
+        assert_eq!(parse_flavour("-=XTIDE Universal BIOS (AT)=-"), XtideFlavour::At);
+        assert_eq!(parse_flavour("-=XTIDE Universal BIOS (386)=-"), XtideFlavour::I386);
+        assert_eq!(parse_flavour("-=XTIDE Universal BIOS=-"), XtideFlavour::Unknown);
+        assert_eq!(parse_revision("v2.0.0?3 (r580)"), Some(580));
+        assert_eq!(parse_revision("v2.0.0?3 (2013-05-01)"), None);
+    }
+
+    #[test]
+    fn test_compatibility_warnings() {
+        let bytes = build_xtide_rom("-=XTIDE Universal BIOS (AT)=-", "v2.0.0B3 r600", FLG_ROMVARS_LATE_INIT);
+        let build = XtideBuild::identify(&OptionRom::from(bytes, 0).unwrap(), 0).unwrap();
+
+        assert_eq!(build.compatibility_warnings(Bridgeboard::A2088, PatchState::Unpatched), vec![
+            "Revision r600 is older than r625, the earliest known to work with the Bridgeboard".to_string(),
+            "The BIOS uses late initialisation, it must be built with VeryLateInit to initialise after the Bridgeboard".to_string(),
+            "The AT build uses instructions the A2088 CPU doesn't support".to_string(),
+            "pc.boot isn't patched, the Bridgeboard will hook INT13 and the XTIDE disk won't be usable".to_string(),
+        ]);
+
+        let bytes = build_xtide_rom("-=XTIDE Universal BIOS (AT)=-", "v2.0.0B3+ r625", FLG_ROMVARS_MODULE_VERY_LATE_INIT);
+        let build = XtideBuild::identify(&OptionRom::from(bytes, 0).unwrap(), 0).unwrap();
+
+        assert!(build.compatibility_warnings(Bridgeboard::A2286, PatchState::Patched).is_empty());
+    }
+
+    #[test]
+    fn test_find_all_in_flash_dump() {
+        let mut dump = vec![0xFFu8; 0x8000];
+        let xtide = build_xtide_rom("-=XTIDE Universal BIOS (AT)=-", "v2.0.0B3 r600", FLG_ROMVARS_LATE_INIT);
+        dump[0x4000..0x6000].copy_from_slice(&xtide);
+
+        let builds = XtideBuild::find_all_in_bytes(&dump);
+
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].rom_start_location, 0x4000);
+        assert_eq!(builds[0].flavour, XtideFlavour::At);
+        assert_eq!(builds[0].init_mode, XtideInitMode::Late);
+    }
+}
@@ -0,0 +1,409 @@
+//! Reading and editing the ROMVARS configuration block of an XTIDE Universal BIOS image, the settings xtidecfg changes.
+//!
+//! Offsets follow the XTIDE Universal BIOS v2.0.0 beta 3 (r6xx) Inc/RomVars.inc, as in the xtide module.
+
+use std::fmt;
+
+use clap_num::maybe_hex;
+
+use crate::option_rom::OptionRom;
+use crate::xtide::{XtideBuild, FLG_ROMVARS_LATE_INIT, FLG_ROMVARS_MODULE_VERY_LATE_INIT, ROMVARS_FLAGS_OFFSET};
+
+const ROMVARS_DISPLAY_MODE_OFFSET: usize = 0x4A;
+const ROMVARS_BOOT_TIMEOUT_OFFSET: usize = 0x4C;
+const ROMVARS_IDE_COUNT_OFFSET: usize = 0x4E;
+const ROMVARS_BOOT_DRIVE_OFFSET: usize = 0x4F;
+const ROMVARS_MIN_FLOPPY_COUNT_OFFSET: usize = 0x50;
+const ROMVARS_STEAL_SIZE_OFFSET: usize = 0x51;
+const ROMVARS_IDLE_TIMEOUT_OFFSET: usize = 0x52;
+const ROMVARS_IDE_VARS_OFFSET: usize = 0x54;
+
+const IDEVARS_SIZE: usize = 18;
+const IDEVARS_BASE_PORT_OFFSET: usize = 0;
+const IDEVARS_CONTROL_BLOCK_PORT_OFFSET: usize = 2;
+const IDEVARS_DEVICE_OFFSET: usize = 4;
+const IDEVARS_IRQ_OFFSET: usize = 5;
+const IDEVARS_DRIVE_PARAMS_OFFSET: usize = 6;
+
+const DRVPARAMS_SIZE: usize = 6;
+const DRVPARAMS_FLAGS_OFFSET: usize = 0;
+const DRVPARAMS_CYLINDERS_OFFSET: usize = 2;
+const DRVPARAMS_HEADS_OFFSET: usize = 4;
+const DRVPARAMS_SECTORS_OFFSET: usize = 5;
+
+pub const MAX_IDE_CONTROLLERS: usize = 4;
+const ROMVARS_END: usize = ROMVARS_IDE_VARS_OFFSET + MAX_IDE_CONTROLLERS * IDEVARS_SIZE;
+
+pub const FLG_ROMVARS_FULLMODE: u16 = 1 << 0;
+pub const FLG_ROMVARS_DRVXLAT: u16 = 1 << 2;
+
+pub const FLG_DRVPARAMS_BLOCKMODE: u16 = 1 << 1;
+pub const FLG_DRVPARAMS_USERCHS: u16 = 1 << 2;
+
+/// The system timer ticks 18.2 times a second, the boot menu timeout is stored in ticks
+const TIMER_TICKS_PER_SECOND: f64 = 18.2;
+
+const MAX_CYLINDERS: u16 = 16383;
+const MAX_HEADS: u8 = 16;
+const MAX_SECTORS: u8 = 63;
+
+#[derive(Debug, PartialEq)]
+pub enum RomVarsError {
+    NotAnXtideRom,
+    RomTooSmall,
+    UnknownSetting(String),
+    InvalidValue { setting: String, value: String },
+}
+
+impl fmt::Display for RomVarsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomVarsError::NotAnXtideRom => write!(f, "The Option Rom isn't an XTIDE Universal BIOS"),
+            RomVarsError::RomTooSmall => write!(f, "The Option Rom is too small to hold the XTIDE configuration"),
+            RomVarsError::UnknownSetting(setting) => write!(f, "Unknown XTIDE setting {}", setting),
+            RomVarsError::InvalidValue { setting, value } => write!(f, "Invalid value {} for XTIDE setting {}", value, setting),
+        }
+    }
+}
+
+impl std::error::Error for RomVarsError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriveParams {
+    pub flags: u16,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdeVars {
+    pub base_port: u16,
+    pub control_block_port: u16,
+    pub device: u8,
+    pub irq: u8,
+    pub master: DriveParams,
+    pub slave: DriveParams,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomVars {
+    pub flags: u16,
+    pub display_mode: u16,
+    pub boot_timeout_ticks: u16,
+    pub ide_controller_count: u8,
+    pub boot_drive: u8,
+    pub min_floppy_count: u8,
+    pub steal_size_kb: u8,
+    pub idle_timeout: u8,
+    pub controllers: [IdeVars; MAX_IDE_CONTROLLERS],
+}
+
+impl RomVars {
+    pub fn read(option_rom: &OptionRom) -> Result<RomVars, RomVarsError> {
+        if XtideBuild::identify(option_rom, 0).is_none() {
+            return Err(RomVarsError::NotAnXtideRom);
+        }
+
+        let bytes = &option_rom.bytes;
+        if bytes.len() < ROMVARS_END {
+            return Err(RomVarsError::RomTooSmall);
+        }
+
+        let controllers = std::array::from_fn(|controller| {
+            let ide_vars = ROMVARS_IDE_VARS_OFFSET + controller * IDEVARS_SIZE;
+            IdeVars {
+                base_port: read_u16(bytes, ide_vars + IDEVARS_BASE_PORT_OFFSET),
+                control_block_port: read_u16(bytes, ide_vars + IDEVARS_CONTROL_BLOCK_PORT_OFFSET),
+                device: bytes[ide_vars + IDEVARS_DEVICE_OFFSET],
+                irq: bytes[ide_vars + IDEVARS_IRQ_OFFSET],
+                master: read_drive_params(bytes, ide_vars + IDEVARS_DRIVE_PARAMS_OFFSET),
+                slave: read_drive_params(bytes, ide_vars + IDEVARS_DRIVE_PARAMS_OFFSET + DRVPARAMS_SIZE),
+            }
+        });
+
+        Ok(RomVars {
+            flags: read_u16(bytes, ROMVARS_FLAGS_OFFSET),
+            display_mode: read_u16(bytes, ROMVARS_DISPLAY_MODE_OFFSET),
+            boot_timeout_ticks: read_u16(bytes, ROMVARS_BOOT_TIMEOUT_OFFSET),
+            ide_controller_count: bytes[ROMVARS_IDE_COUNT_OFFSET],
+            boot_drive: bytes[ROMVARS_BOOT_DRIVE_OFFSET],
+            min_floppy_count: bytes[ROMVARS_MIN_FLOPPY_COUNT_OFFSET],
+            steal_size_kb: bytes[ROMVARS_STEAL_SIZE_OFFSET],
+            idle_timeout: bytes[ROMVARS_IDLE_TIMEOUT_OFFSET],
+            controllers,
+        })
+    }
+
+    /// Write the configuration back into the rom and correct its checksum
+    pub fn write(&self, option_rom: &mut OptionRom) -> Result<(), RomVarsError> {
+        if option_rom.bytes.len() < ROMVARS_END {
+            return Err(RomVarsError::RomTooSmall);
+        }
+
+        let bytes = &mut option_rom.bytes;
+        write_u16(bytes, ROMVARS_FLAGS_OFFSET, self.flags);
+        write_u16(bytes, ROMVARS_DISPLAY_MODE_OFFSET, self.display_mode);
+        write_u16(bytes, ROMVARS_BOOT_TIMEOUT_OFFSET, self.boot_timeout_ticks);
+        bytes[ROMVARS_IDE_COUNT_OFFSET] = self.ide_controller_count;
+        bytes[ROMVARS_BOOT_DRIVE_OFFSET] = self.boot_drive;
+        bytes[ROMVARS_MIN_FLOPPY_COUNT_OFFSET] = self.min_floppy_count;
+        bytes[ROMVARS_STEAL_SIZE_OFFSET] = self.steal_size_kb;
+        bytes[ROMVARS_IDLE_TIMEOUT_OFFSET] = self.idle_timeout;
+
+        for (controller, ide_vars) in self.controllers.iter().enumerate() {
+            let offset = ROMVARS_IDE_VARS_OFFSET + controller * IDEVARS_SIZE;
+            write_u16(bytes, offset + IDEVARS_BASE_PORT_OFFSET, ide_vars.base_port);
+            write_u16(bytes, offset + IDEVARS_CONTROL_BLOCK_PORT_OFFSET, ide_vars.control_block_port);
+            bytes[offset + IDEVARS_DEVICE_OFFSET] = ide_vars.device;
+            bytes[offset + IDEVARS_IRQ_OFFSET] = ide_vars.irq;
+            write_drive_params(bytes, offset + IDEVARS_DRIVE_PARAMS_OFFSET, &ide_vars.master);
+            write_drive_params(bytes, offset + IDEVARS_DRIVE_PARAMS_OFFSET + DRVPARAMS_SIZE, &ide_vars.slave);
+        }
+
+        option_rom.correct_checksum_in_final_byte();
+        Ok(())
+    }
+
+    /// Change a setting by name, e.g. "ide0.port" with "0x300". See SETTINGS_HELP for the names.
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), RomVarsError> {
+        let invalid_value = || RomVarsError::InvalidValue { setting: setting.into(), value: value.into() };
+
+        match setting.split('.').collect::<Vec<&str>>().as_slice() {
+            ["controllers"] => {
+                let count = parse_number(value).ok_or_else(invalid_value)?;
+                if count == 0 || count > MAX_IDE_CONTROLLERS {
+                    return Err(invalid_value());
+                }
+                self.ide_controller_count = count as u8;
+            },
+            ["boot", "timeout"] => {
+                let seconds: f64 = value.parse().map_err(|_| invalid_value())?;
+                let ticks = (seconds * TIMER_TICKS_PER_SECOND).round();
+                if ! (0.0..=u16::MAX as f64).contains(&ticks) {
+                    return Err(invalid_value());
+                }
+                self.boot_timeout_ticks = ticks as u16;
+            },
+            ["boot", "drive"] => self.boot_drive = parse_u8(value).ok_or_else(invalid_value)?,
+            ["boot", "display-mode"] => self.display_mode = parse_u16(value).ok_or_else(invalid_value)?,
+            ["boot", "min-floppies"] => self.min_floppy_count = parse_u8(value).ok_or_else(invalid_value)?,
+            ["full-mode"] => set_flag(&mut self.flags, FLG_ROMVARS_FULLMODE, parse_on_off(value).ok_or_else(invalid_value)?),
+            ["drive-translation"] => set_flag(&mut self.flags, FLG_ROMVARS_DRVXLAT, parse_on_off(value).ok_or_else(invalid_value)?),
+            [controller, field @ ..] if controller.starts_with("ide") => {
+                let index = controller[3..].parse::<usize>().ok()
+                    .filter(|index| *index < MAX_IDE_CONTROLLERS)
+                    .ok_or_else(|| RomVarsError::UnknownSetting(setting.into()))?;
+                let ide_vars = &mut self.controllers[index];
+
+                match field {
+                    ["port"] => ide_vars.base_port = parse_u16(value).ok_or_else(invalid_value)?,
+                    ["control-port"] => ide_vars.control_block_port = parse_u16(value).ok_or_else(invalid_value)?,
+                    ["device"] => ide_vars.device = parse_u8(value).ok_or_else(invalid_value)?,
+                    ["irq"] => {
+                        let irq = parse_u8(value).filter(|irq| *irq <= 15).ok_or_else(invalid_value)?;
+                        ide_vars.irq = irq;
+                    },
+                    [drive @ ("master" | "slave"), drive_field] => {
+                        let drive_params = if *drive == "master" { &mut ide_vars.master } else { &mut ide_vars.slave };
+                        match *drive_field {
+                            "chs" => set_user_chs(drive_params, value).ok_or_else(invalid_value)?,
+                            "block-mode" => set_flag(&mut drive_params.flags, FLG_DRVPARAMS_BLOCKMODE, parse_on_off(value).ok_or_else(invalid_value)?),
+                            _ => return Err(RomVarsError::UnknownSetting(setting.into())),
+                        }
+                    },
+                    _ => return Err(RomVarsError::UnknownSetting(setting.into())),
+                }
+            },
+            _ => return Err(RomVarsError::UnknownSetting(setting.into())),
+        }
+
+        Ok(())
+    }
+}
+
+pub const SETTINGS_HELP: &str = "controllers, boot.timeout (seconds), boot.drive, boot.display-mode, boot.min-floppies, \
+full-mode (on/off), drive-translation (on/off), ideN.port, ideN.control-port, ideN.device, ideN.irq, \
+ideN.master.chs / ideN.slave.chs (C/H/S or auto), ideN.master.block-mode / ideN.slave.block-mode (on/off)";
+
+impl fmt::Display for DriveParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.flags & FLG_DRVPARAMS_USERCHS != 0 {
+            write!(f, "CHS {}/{}/{}", self.cylinders, self.heads, self.sectors)?;
+        } else {
+            write!(f, "CHS auto")?;
+        }
+        write!(f, ", block mode {}", on_off(self.flags & FLG_DRVPARAMS_BLOCKMODE != 0))
+    }
+}
+
+impl fmt::Display for RomVars {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let init_mode = if self.flags & FLG_ROMVARS_MODULE_VERY_LATE_INIT != 0 {
+            "very late"
+        } else if self.flags & FLG_ROMVARS_LATE_INIT != 0 {
+            "late"
+        } else {
+            "normal"
+        };
+
+        writeln!(f, "Full mode: {}", on_off(self.flags & FLG_ROMVARS_FULLMODE != 0))?;
+        writeln!(f, "Drive translation: {}", on_off(self.flags & FLG_ROMVARS_DRVXLAT != 0))?;
+        writeln!(f, "Initialisation: {}", init_mode)?;
+        writeln!(f, "Boot menu timeout: {:.1}s", self.boot_timeout_ticks as f64 / TIMER_TICKS_PER_SECOND)?;
+        writeln!(f, "Boot menu default drive: 0x{:02X}", self.boot_drive)?;
+        writeln!(f, "Boot menu display mode: 0x{:X}", self.display_mode)?;
+        writeln!(f, "Minimum floppy drives: {}", self.min_floppy_count)?;
+        writeln!(f, "Base RAM stolen: {}KB", self.steal_size_kb)?;
+        write!(f, "IDE controllers: {}", self.ide_controller_count)?;
+
+        for (index, ide_vars) in self.controllers.iter().take(self.ide_controller_count as usize).enumerate() {
+            writeln!(f)?;
+            writeln!(f, "ide{}: port 0x{:X}, control port 0x{:X}, device {}, IRQ {}", index, ide_vars.base_port, ide_vars.control_block_port, ide_vars.device, ide_vars.irq)?;
+            writeln!(f, "  master: {}", ide_vars.master)?;
+            write!(f, "  slave: {}", ide_vars.slave)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_drive_params(bytes: &[u8], offset: usize) -> DriveParams {
+    DriveParams {
+        flags: read_u16(bytes, offset + DRVPARAMS_FLAGS_OFFSET),
+        cylinders: read_u16(bytes, offset + DRVPARAMS_CYLINDERS_OFFSET),
+        heads: bytes[offset + DRVPARAMS_HEADS_OFFSET],
+        sectors: bytes[offset + DRVPARAMS_SECTORS_OFFSET],
+    }
+}
+
+fn write_drive_params(bytes: &mut [u8], offset: usize, drive_params: &DriveParams) {
+    write_u16(bytes, offset + DRVPARAMS_FLAGS_OFFSET, drive_params.flags);
+    write_u16(bytes, offset + DRVPARAMS_CYLINDERS_OFFSET, drive_params.cylinders);
+    bytes[offset + DRVPARAMS_HEADS_OFFSET] = drive_params.heads;
+    bytes[offset + DRVPARAMS_SECTORS_OFFSET] = drive_params.sectors;
+}
+
+fn set_flag(flags: &mut u16, flag: u16, enabled: bool) {
+    if enabled {
+        *flags |= flag;
+    } else {
+        *flags &= ! flag;
+    }
+}
+
+/// Set user specified CHS from "C/H/S", or "auto" to detect it from the drive
+fn set_user_chs(drive_params: &mut DriveParams, value: &str) -> Option<()> {
+    if value == "auto" {
+        set_flag(&mut drive_params.flags, FLG_DRVPARAMS_USERCHS, false);
+        return Some(());
+    }
+
+    let parts: Vec<&str> = value.split('/').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let cylinders = parts[0].parse::<u16>().ok().filter(|c| (1..=MAX_CYLINDERS).contains(c))?;
+    let heads = parts[1].parse::<u8>().ok().filter(|h| (1..=MAX_HEADS).contains(h))?;
+    let sectors = parts[2].parse::<u8>().ok().filter(|s| (1..=MAX_SECTORS).contains(s))?;
+
+    drive_params.cylinders = cylinders;
+    drive_params.heads = heads;
+    drive_params.sectors = sectors;
+    set_flag(&mut drive_params.flags, FLG_DRVPARAMS_USERCHS, true);
+    Some(())
+}
+
+fn parse_number(value: &str) -> Option<usize> {
+    maybe_hex::<usize>(value).ok()
+}
+
+fn parse_u8(value: &str) -> Option<u8> {
+    parse_number(value).and_then(|number| u8::try_from(number).ok())
+}
+
+fn parse_u16(value: &str) -> Option<u16> {
+    parse_number(value).and_then(|number| u16::try_from(number).ok())
+}
+
+fn parse_on_off(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{build_xtide_rom, load_option_rom_fixture};
+
+    fn xtide_option_rom() -> OptionRom {
+        let mut bytes = build_xtide_rom("-=XTIDE Universal BIOS (XT)=-", "v2.0.0B3+ r625", FLG_ROMVARS_MODULE_VERY_LATE_INIT);
+        bytes[ROMVARS_IDE_COUNT_OFFSET] = 1;
+        bytes[ROMVARS_IDE_VARS_OFFSET..ROMVARS_IDE_VARS_OFFSET + 6].copy_from_slice(&[0x00, 0x03, 0x08, 0x03, 0x08, 0x00]);
+        OptionRom::from(bytes, 0).unwrap()
+    }
+
+    #[test]
+    fn test_read_not_an_xtide_rom() -> Result<(), String> {
+        let option_rom = load_option_rom_fixture("pc.boot.valid")?;
+        assert_eq!(RomVars::read(&option_rom), Err(RomVarsError::NotAnXtideRom));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read() {
+        let rom_vars = RomVars::read(&xtide_option_rom()).unwrap();
+
+        assert_eq!(rom_vars.ide_controller_count, 1);
+        assert_eq!(rom_vars.controllers[0].base_port, 0x300);
+        assert_eq!(rom_vars.controllers[0].control_block_port, 0x308);
+        assert_eq!(rom_vars.controllers[0].device, 8);
+    }
+
+    #[test]
+    fn test_set_and_write() {
+        let mut option_rom = xtide_option_rom();
+        let mut rom_vars = RomVars::read(&option_rom).unwrap();
+
+        rom_vars.set("ide0.port", "0x320").unwrap();
+        rom_vars.set("ide0.irq", "5").unwrap();
+        rom_vars.set("ide1.master.chs", "615/4/17").unwrap();
+        rom_vars.set("boot.timeout", "10").unwrap();
+        rom_vars.set("full-mode", "on").unwrap();
+        rom_vars.write(&mut option_rom).unwrap();
+
+        let rom_vars = RomVars::read(&option_rom).unwrap();
+        assert_eq!(rom_vars.controllers[0].base_port, 0x320);
+        assert_eq!(rom_vars.controllers[0].irq, 5);
+        assert_eq!(rom_vars.controllers[1].master, DriveParams { flags: FLG_DRVPARAMS_USERCHS, cylinders: 615, heads: 4, sectors: 17 });
+        assert_eq!(rom_vars.boot_timeout_ticks, 182);
+        assert_eq!(rom_vars.flags, FLG_ROMVARS_MODULE_VERY_LATE_INIT | FLG_ROMVARS_FULLMODE);
+        assert!(option_rom.validate_checksum().is_ok());
+    }
+
+    #[test]
+    fn test_set_invalid() {
+        let mut rom_vars = RomVars::read(&xtide_option_rom()).unwrap();
+
+        assert_eq!(rom_vars.set("ide4.port", "0x300"), Err(RomVarsError::UnknownSetting("ide4.port".into())));
+        assert_eq!(rom_vars.set("boot.colour", "1"), Err(RomVarsError::UnknownSetting("boot.colour".into())));
+        assert_eq!(rom_vars.set("ide0.irq", "16"), Err(RomVarsError::InvalidValue { setting: "ide0.irq".into(), value: "16".into() }));
+        assert_eq!(rom_vars.set("ide0.slave.chs", "615/17/17"), Err(RomVarsError::InvalidValue { setting: "ide0.slave.chs".into(), value: "615/17/17".into() }));
+    }
+}